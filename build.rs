@@ -0,0 +1,12 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/storage.proto");
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/storage.proto"], &["proto"])
+            .unwrap();
+    }
+}
@@ -1,20 +1,260 @@
 use proc_macro::TokenStream;
 use quote::quote;
+use syn::{parse_quote, Data, DeriveInput, Field, Fields, GenericParam, Ident, LitInt, LitStr};
 
-#[proc_macro_derive(StorageData)]
+#[proc_macro_derive(StorageData, attributes(storage))]
 pub fn storage_data_macro_derive(input: TokenStream) -> TokenStream {
-    let ast = syn::parse(input).unwrap();
+    let ast = syn::parse_macro_input!(input as DeriveInput);
     impl_storage_data_macro(&ast)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
 }
 
-fn impl_storage_data_macro(ast: &syn::DeriveInput) -> TokenStream {
+// options parsed from `#[storage(...)]`
+#[derive(Default)]
+struct StorageAttrs {
+    name: Option<LitStr>,
+    codec: Option<Ident>,
+    compress: bool,
+    history: Option<LitInt>,
+}
+
+impl StorageAttrs {
+    fn parse(ast: &DeriveInput) -> syn::Result<Self> {
+        let mut attrs = Self::default();
+        for attr in ast.attrs.iter().filter(|a| a.path().is_ident("storage")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    let name: LitStr = meta.value()?.parse()?;
+                    if name.value().contains('/') || name.value().contains("::") {
+                        return Err(syn::Error::new(
+                            name.span(),
+                            "storage name can't contain '/' or '::'",
+                        ));
+                    }
+                    attrs.name = Some(name);
+                    Ok(())
+                } else if meta.path.is_ident("codec") {
+                    let codec: LitStr = meta.value()?.parse()?;
+                    let variant = match codec.value().as_str() {
+                        "bincode" => "Bincode",
+                        "json" => "Json",
+                        "msgpack" => "MessagePack",
+                        "postcard" => "Postcard",
+                        _ => return Err(syn::Error::new(codec.span(), "unknown storage codec")),
+                    };
+                    attrs.codec = Some(Ident::new(variant, codec.span()));
+                    Ok(())
+                } else if meta.path.is_ident("compress") {
+                    attrs.compress = true;
+                    Ok(())
+                } else if meta.path.is_ident("history") {
+                    let history: LitInt = meta.value()?.parse()?;
+                    history.base10_parse::<usize>()?;
+                    attrs.history = Some(history);
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported storage attribute"))
+                }
+            })?;
+        }
+        Ok(attrs)
+    }
+}
+
+// fields marked with `#[storage(...)]`
+#[derive(Default)]
+struct FieldAttrs<'a> {
+    key: Option<&'a Field>,
+    content: Vec<&'a Field>,
+}
+
+impl<'a> FieldAttrs<'a> {
+    fn parse(ast: &'a DeriveInput) -> syn::Result<Self> {
+        let mut attrs = Self::default();
+        let Data::Struct(data) = &ast.data else {
+            return Ok(attrs);
+        };
+        let Fields::Named(fields) = &data.fields else {
+            return Ok(attrs);
+        };
+        for field in &fields.named {
+            for attr in field.attrs.iter().filter(|a| a.path().is_ident("storage")) {
+                attr.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("key") {
+                        if attrs.key.is_some() {
+                            return Err(meta.error("only one field can be the storage key"));
+                        }
+                        attrs.key = Some(field);
+                        Ok(())
+                    } else if meta.path.is_ident("content") {
+                        attrs.content.push(field);
+                        Ok(())
+                    } else {
+                        Err(meta.error("unsupported storage field attribute"))
+                    }
+                })?;
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+fn impl_storage_data_macro(ast: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let attrs = StorageAttrs::parse(ast)?;
+    let fields = FieldAttrs::parse(ast)?;
     let name = &ast.ident;
+    let tree_name = match attrs.name {
+        Some(tree_name) => quote!(#tree_name),
+        None => quote!(stringify!(#name)),
+    };
+    let format = match attrs.codec {
+        Some(codec) => quote! {
+            fn format() -> Option<::storage_hal::Format> {
+                Some(::storage_hal::Format::#codec)
+            }
+        },
+        None => quote!(),
+    };
+    let compress = if attrs.compress {
+        quote! {
+            fn compress() -> bool {
+                true
+            }
+        }
+    } else {
+        quote!()
+    };
+    let history = match attrs.history {
+        Some(history) => quote! {
+            fn history() -> usize {
+                #history
+            }
+        },
+        None => quote!(),
+    };
+
+    // generic types get one tree per instantiation, e.g. `Wrapper<u32>`, named by the
+    // `TypeName` of their parameters, which is stable unlike `std::any::type_name`
+    let params: Vec<_> = ast
+        .generics
+        .params
+        .iter()
+        .filter_map(|param| match param {
+            GenericParam::Type(t) => {
+                let ident = &t.ident;
+                Some(quote!(<#ident as ::storage_hal::TypeName>::type_name()))
+            }
+            GenericParam::Const(c) => {
+                let ident = &c.ident;
+                Some(quote!(#ident.to_string()))
+            }
+            GenericParam::Lifetime(_) => None,
+        })
+        .collect();
+    let body = if params.is_empty() {
+        quote!(#tree_name.to_string())
+    } else {
+        quote! {
+            ::storage_hal::cached_type_name::<Self>(|| {
+                let name = format!("{}<{}>", #tree_name, [#(#params),*].join(","));
+                // '/' ends tree names in cache keys, "::" separates namespaces
+                assert!(
+                    !name.contains('/') && !name.contains("::"),
+                    "invalid storage name {:?}",
+                    name
+                );
+                name
+            })
+        }
+    };
+
+    let mut generics = ast.generics.clone();
+    let (_, ty_generics, _) = ast.generics.split_for_impl();
+    if !ast.generics.params.is_empty() {
+        let where_clause = generics.make_where_clause();
+        // the name is cached by the type's `TypeId`
+        where_clause.predicates.push(parse_quote! {
+            #name #ty_generics: 'static
+                + ::std::fmt::Debug
+                + ::std::clone::Clone
+                + ::std::default::Default
+                + ::serde::Serialize
+                + for<'de> ::serde::Deserialize<'de>
+        });
+        for param in ast.generics.type_params() {
+            let ident = &param.ident;
+            where_clause
+                .predicates
+                .push(parse_quote!(#ident: ::storage_hal::TypeName));
+        }
+    }
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+    // `#[storage(content)]` fields hold a `ContentHash`, or an `Option` or `Vec` of them
+    let content_refs = if fields.content.is_empty() {
+        quote!()
+    } else {
+        let idents = fields.content.iter().map(|field| &field.ident);
+        quote! {
+            fn content_refs(&self) -> Vec<::storage_hal::ContentHash> {
+                let mut refs = Vec::new();
+                #(::storage_hal::ContentRefs::collect_refs(&self.#idents, &mut refs);)*
+                refs
+            }
+        }
+    };
+
+    let helpers = match fields.key {
+        Some(field) => {
+            let ident = &field.ident;
+            let ty = &field.ty;
+            // keyed like the typed API, `u64` fields like `key_u64`
+            let mut generics = generics.clone();
+            generics.make_where_clause().predicates.push(parse_quote! {
+                #ty: ::std::clone::Clone + ::std::convert::Into<::storage_hal::StorageKey>
+            });
+            let (impl_generics, _, where_clause) = generics.split_for_impl();
+            quote! {
+                impl #impl_generics #name #ty_generics #where_clause {
+                    /// Store this value under its `#[storage(key)]` field.
+                    pub fn save(&self, storage: &::storage_hal::Storage) -> Option<Self> {
+                        storage.insert(self.#ident.clone(), self.clone())
+                    }
+
+                    /// Load the value stored under `key`.
+                    #[allow(clippy::ptr_arg)]
+                    pub fn load(storage: &::storage_hal::Storage, key: &#ty) -> Option<Self> {
+                        storage.get(key.clone())
+                    }
+                }
+            }
+        }
+        None => quote!(),
+    };
+
     let gen = quote! {
-        impl StorageData for #name {
+        impl #impl_generics ::storage_hal::StorageData for #name #ty_generics #where_clause {
             fn name() -> String {
-                stringify!(#name).to_string()
+                #body
             }
+
+            #format
+
+            #compress
+
+            #history
+
+            #content_refs
         }
+
+        impl #impl_generics ::storage_hal::TypeName for #name #ty_generics #where_clause {
+            fn type_name() -> String {
+                <Self as ::storage_hal::StorageData>::name()
+            }
+        }
+
+        #helpers
     };
-    gen.into()
+    Ok(gen)
 }
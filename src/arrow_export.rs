@@ -0,0 +1,124 @@
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, RecordBatch, StringArray};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, FieldRef, Schema};
+use color_eyre::eyre::Result;
+use serde_arrow::schema::{SchemaLike, TracingOptions};
+
+use crate::{Storage, StorageData};
+
+const EXPORT_BATCH_SIZE: usize = 8192;
+
+// EXPORT
+impl Storage {
+    /// Write all records of `T` as an Arrow IPC file.
+    ///
+    /// The first column is the record key, the others are traced from the serde layout
+    /// of `T`. Records are read from a `snapshot`, so the export holds the tree as it
+    /// was when it started, and are never rewritten. Undecodable records are skipped.
+    /// Returns the number of exported records.
+    pub fn export_arrow<T: StorageData, W: Write>(&self, writer: W) -> Result<usize> {
+        let value_fields = Vec::<FieldRef>::from_type::<T>(TracingOptions::default())?;
+        let mut fields = vec![Arc::new(Field::new("key", DataType::Utf8, false))];
+        fields.extend(value_fields.iter().cloned());
+        let schema = Arc::new(Schema::new(fields));
+
+        let mut writer = FileWriter::try_new(writer, &schema)?;
+        let snapshot = self.snapshot()?;
+        let mut keys = Vec::with_capacity(EXPORT_BATCH_SIZE);
+        let mut values = Vec::with_capacity(EXPORT_BATCH_SIZE);
+        let mut exported = 0;
+
+        let mut flush = |keys: &mut Vec<String>, values: &mut Vec<T>| -> Result<()> {
+            if keys.is_empty() {
+                return Ok(());
+            }
+            let batch = serde_arrow::to_record_batch(&value_fields, values)?;
+            let mut columns: Vec<ArrayRef> =
+                vec![Arc::new(StringArray::from(std::mem::take(keys)))];
+            columns.extend(batch.columns().iter().cloned());
+            writer.write(&RecordBatch::try_new(schema.clone(), columns)?)?;
+            values.clear();
+            Ok(())
+        };
+
+        for (key, value) in snapshot.iter::<T>()? {
+            keys.push(String::from_utf8_lossy(&key).to_string());
+            values.push(value);
+            exported += 1;
+            if keys.len() == EXPORT_BATCH_SIZE {
+                flush(&mut keys, &mut values)?;
+            }
+        }
+        flush(&mut keys, &mut values)?;
+        writer.finish()?;
+
+        Ok(exported)
+    }
+}
+
+#[test]
+fn arrow_export() {
+    use arrow_ipc::reader::FileReader;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize)]
+    struct ArrowTest {
+        a: u32,
+        b: String,
+    }
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    for i in 0..10u32 {
+        store.insert(
+            i.to_string(),
+            ArrowTest {
+                a: i,
+                b: i.to_string(),
+            },
+        );
+    }
+
+    let mut buf = vec![];
+    assert_eq!(10, store.export_arrow::<ArrowTest, _>(&mut buf).unwrap());
+    let reader = FileReader::try_new(std::io::Cursor::new(buf), None).unwrap();
+    let names: Vec<_> = reader
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| f.name().clone())
+        .collect();
+    assert_eq!(vec!["key", "a", "b"], names);
+    let rows: usize = reader.map(|b| b.unwrap().num_rows()).sum();
+    assert_eq!(10, rows);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn arrow_export_read_only() {
+    use serde::{Deserialize, Serialize};
+
+    use crate::{envelope, Format, StorageConfig};
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize)]
+    struct ArrowTest {
+        a: u32,
+    }
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        format: Format::Json,
+        ..Default::default()
+    });
+    // written before switching codecs, rewritten by `get` but not by the export
+    let bytes = envelope::checksummed(
+        envelope::encode(Format::Bincode, None, &ArrowTest { a: 1 }).unwrap(),
+    );
+    let tree = store.db.open_tree(ArrowTest::name()).unwrap();
+    tree.insert("a", bytes.clone()).unwrap();
+
+    assert_eq!(1, store.export_arrow::<ArrowTest, _>(vec![]).unwrap());
+    assert_eq!(bytes, tree.get("a").unwrap().unwrap().to_vec());
+}
@@ -0,0 +1,153 @@
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{Storage, Tree};
+
+pub(crate) const AUDIT_TREE_NAME: &str = "AUDIT";
+
+/// One mutation recorded by the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub at: SystemTime,
+    /// The actor set with `Storage::with_actor`, if any.
+    pub actor: Option<String>,
+    /// The operation, like `insert` or `remove`.
+    pub operation: String,
+    pub tree: String,
+    pub key: Vec<u8>,
+    /// blake3 hash of the stored bytes of the written value, `None` for removals.
+    pub value_hash: Option<[u8; 32]>,
+}
+
+pub(crate) fn micros(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
+}
+
+pub(crate) fn prune_audit_tree(tree: &Tree, before: SystemTime) -> Result<usize> {
+    let mut pruned = 0;
+    for key in tree.range(..micros(before).to_be_bytes()).keys() {
+        tree.remove(key?)?;
+        pruned += 1;
+    }
+    Ok(pruned)
+}
+
+// AUDIT LOG
+// With `audit` set, every mutation of a data tree through the storage appends an entry
+// to the audit tree, keyed by its record version clock reading in microseconds, so
+// entries are unique and ordered by time. Entries are only ever appended, the log grows
+// until it is pruned with `prune_audit_log` or by `StorageConfig::audit_retention`.
+// Writes to the db from elsewhere and re-encryption are not recorded.
+impl Storage {
+    /// A clone of this storage that records `actor` with its mutations in the audit log.
+    pub fn with_actor(&self, actor: impl Into<String>) -> Storage {
+        Storage {
+            actor: Some(Arc::from(actor.into())),
+            ..self.clone()
+        }
+    }
+
+    // record `operation` on `key`, `value` being the stored bytes it wrote, in the audit
+    // log and the change log
+    pub(crate) fn audit(&self, operation: &str, tree: &str, key: &[u8], value: Option<&[u8]>) {
+        self.log_change(tree, key);
+        if !self.audit {
+            return;
+        }
+        if let Err(e) = self.try_audit(operation, tree, key, value) {
+            warn!(
+                "Audit {} tree({}) key({}) failed: {}",
+                operation,
+                tree,
+                String::from_utf8_lossy(key),
+                e
+            );
+        }
+    }
+
+    fn try_audit(
+        &self,
+        operation: &str,
+        tree: &str,
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<()> {
+        let at = self.next_revision();
+        let entry = AuditEntry {
+            at: UNIX_EPOCH + Duration::from_micros(at),
+            actor: self.actor.as_deref().map(str::to_string),
+            operation: operation.to_string(),
+            tree: tree.to_string(),
+            key: key.to_vec(),
+            value_hash: value.map(|value| *blake3::hash(value).as_bytes()),
+        };
+        self.tree(AUDIT_TREE_NAME)?
+            .insert(at.to_be_bytes(), bincode::serialize(&entry)?)?;
+        Ok(())
+    }
+
+    /// The audit entries recorded within `range`, oldest first.
+    pub fn audit_log(&self, range: Range<SystemTime>) -> Result<Vec<AuditEntry>> {
+        let tree = self.tree(AUDIT_TREE_NAME)?;
+        let (start, end) = (micros(range.start), micros(range.end));
+        let mut entries = vec![];
+        for r in tree.range(start.to_be_bytes()..end.to_be_bytes()) {
+            let (_, v) = r?;
+            entries.push(bincode::deserialize(&v)?);
+        }
+        Ok(entries)
+    }
+
+    /// Drop the audit entries recorded before `before`. Returns how many were dropped.
+    pub fn prune_audit_log(&self, before: SystemTime) -> Result<usize> {
+        prune_audit_tree(&self.tree(AUDIT_TREE_NAME)?, before)
+    }
+}
+
+#[test]
+fn audit() {
+    use crate::{StorageConfig, StorageData};
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        audit: true,
+        ..Default::default()
+    });
+    let start = SystemTime::now();
+    store.insert("a", "1".to_string());
+    let alice = store.with_actor("alice");
+    alice.insert("b", "2".to_string());
+    alice.remove::<String>("a");
+    alice
+        .update::<String, _>("b", |v| v.map(|v| v + "!"))
+        .unwrap();
+    let end = SystemTime::now() + Duration::from_secs(1);
+
+    let log = store.audit_log(start..end).unwrap();
+    assert_eq!(
+        vec![
+            (None, "insert", b"a".to_vec()),
+            (Some("alice".to_string()), "insert", b"b".to_vec()),
+            (Some("alice".to_string()), "remove", b"a".to_vec()),
+            (Some("alice".to_string()), "update", b"b".to_vec()),
+        ],
+        log.iter()
+            .map(|e| (e.actor.clone(), e.operation.as_str(), e.key.clone()))
+            .collect::<Vec<_>>()
+    );
+    assert!(log.iter().all(|e| e.tree == String::name()));
+    assert_eq!(None, log[2].value_hash);
+    assert_ne!(log[1].value_hash, log[3].value_hash);
+    let tree = store.db.open_tree(String::name()).unwrap();
+    let stored = tree.get(&log[3].key).unwrap().unwrap();
+    assert_eq!(Some(*blake3::hash(&stored).as_bytes()), log[3].value_hash);
+
+    assert_eq!(4, store.prune_audit_log(end).unwrap());
+    assert!(store.audit_log(start..end).unwrap().is_empty());
+}
@@ -0,0 +1,588 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use tracing::{debug, info, warn};
+
+use crate::meta::META_TREE_NAME;
+use crate::shutdown::Worker;
+use crate::verify::Types;
+use crate::{IntegrityReport, Storage, StorageConfig, StorageData};
+
+const BACKUP_PREFIX: &str = "backup-";
+
+// suffix of the directory a restore is staged in before it is renamed into place
+const RESTORE_SUFFIX: &str = ".restoring";
+
+// suffix a forced restore moves the db it replaces to until the restored one is in place
+const REPLACED_SUFFIX: &str = ".replaced";
+
+// meta key of the marker `migrate_backend` leaves in the new db
+const BACKEND_MIGRATION_META_KEY: &str = "backend_migration";
+
+/// Marker of a completed `Storage::migrate_backend`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackendMigration {
+    /// `db_path` of the source db.
+    pub from: String,
+    /// Number of records copied, the marker itself not included.
+    pub records: usize,
+}
+
+/// Periodic backups started by `Storage::start_backup_schedule`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupSchedule {
+    /// Directory the backups are written to, one db per backup.
+    pub dir: PathBuf,
+    pub interval: Duration,
+    /// Number of backups kept, older ones are removed after each new backup.
+    pub retain: usize,
+}
+
+/// The running backup schedule, stopped when dropped or when the storage is closed.
+#[derive(Debug)]
+pub struct BackupTask {
+    worker: Arc<Worker>,
+}
+
+impl BackupTask {
+    /// Stop the schedule, waiting for a running backup to finish.
+    pub fn stop(self) {}
+}
+
+impl Drop for BackupTask {
+    fn drop(&mut self) {
+        self.worker.stop();
+    }
+}
+
+// copy the root tree and every named tree of `src` into `dst`, returns the record count
+pub(crate) fn copy_db(src: &Db, dst: &Db) -> Result<usize> {
+    let mut records = 0;
+    for r in src.iter() {
+        let (k, v) = r?;
+        dst.insert(k, v)?;
+        records += 1;
+    }
+    for (_, name, _) in src.export() {
+        let (from, to) = (src.open_tree(&name)?, dst.open_tree(&name)?);
+        for r in from.iter() {
+            let (k, v) = r?;
+            to.insert(k, v)?;
+            records += 1;
+        }
+    }
+    dst.flush()?;
+    Ok(records)
+}
+
+// whether `path` is missing or an empty directory
+pub(crate) fn is_vacant(path: &Path) -> Result<bool> {
+    Ok(!path.exists() || path.read_dir()?.next().is_none())
+}
+
+// fail unless every tree of `src` has as many records in `dst`
+pub(crate) fn check_counts(src: &Db, dst: &Db) -> Result<()> {
+    let mismatch = |name: &str, src: usize, dst: usize| {
+        eyre!(
+            "tree({}) has {} records in the source but {} in the copy",
+            name,
+            src,
+            dst
+        )
+    };
+    if src.len() != dst.len() {
+        return Err(mismatch("", src.len(), dst.len()));
+    }
+    for (_, name, _) in src.export() {
+        let (from, to) = (src.open_tree(&name)?, dst.open_tree(&name)?);
+        if from.len() != to.len() {
+            return Err(mismatch(
+                &String::from_utf8_lossy(&name),
+                from.len(),
+                to.len(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// whether `db` holds no records at all, internal ones included
+fn is_empty(db: &Db) -> Result<bool> {
+    if !db.is_empty()? {
+        return Ok(false);
+    }
+    for (_, name, _) in db.export() {
+        if !db.open_tree(name)?.is_empty()? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+impl Storage {
+    /// Write a copy of the db to a new db at `path`, opened with this storage's sled
+    /// options.
+    ///
+    /// Like `snapshot`, the trees are copied while writes continue and the keys written
+    /// meanwhile are copied again at the end, so the backup holds the db as it was then,
+    /// with either all or none of each write and the pending write-back values. Values
+    /// are copied as stored, still compressed and encrypted.
+    ///
+    /// Returns the number of records written, fails if `path` is not empty.
+    pub fn backup(&self, path: impl AsRef<Path>) -> Result<usize> {
+        self.check_open()?;
+        let path = path.as_ref();
+        if !is_vacant(path)? {
+            return Err(eyre!("backup path {} is not empty", path.display()));
+        }
+        let backup = self.sled.open(path)?;
+        let records = self.copy_live(&backup, || copy_db(&self.db, &backup), |_| true)?;
+        backup.flush()?;
+        info!("Backed up {} records to {}", records, path.display());
+        Ok(records)
+    }
+
+    /// Restore the backup at `backup_path` into a new db at `db_path` with the default
+    /// options, see `restore_with`. Values are only checked against their checksums, the
+    /// trees holding them are reported as orphaned.
+    pub fn restore(
+        backup_path: impl AsRef<Path>,
+        db_path: impl AsRef<Path>,
+    ) -> Result<IntegrityReport> {
+        Self::restore_with(backup_path, db_path, &RestoreOptions::default())
+    }
+
+    /// Restore the backup at `backup_path` into a new db at `db_path`, no storage needs
+    /// to be open.
+    ///
+    /// The backup is checked like `verify` before anything is written, values of the
+    /// types registered with `RestoreOptions::register_type` must decode with the codecs
+    /// and keys of `options.config`. It is copied into a staging directory next to
+    /// `db_path` that is renamed into place once every tree has as many records as the
+    /// backup, so `db_path` holds either the whole backup or what it held before. Fails
+    /// if `db_path` is not empty, unless `options.force` is set and no storage has it
+    /// open.
+    pub fn restore_with(
+        backup_path: impl AsRef<Path>,
+        db_path: impl AsRef<Path>,
+        options: &RestoreOptions,
+    ) -> Result<IntegrityReport> {
+        let (backup_path, db_path) = (backup_path.as_ref(), db_path.as_ref());
+        if is_vacant(backup_path)? {
+            return Err(eyre!("backup {} does not exist", backup_path.display()));
+        }
+        let replace = !is_vacant(db_path)?;
+        if replace && !options.force {
+            return Err(eyre!("restore path {} is not empty", db_path.display()));
+        }
+        let sled = &options.config.sled;
+        let backup = sled.open(backup_path)?;
+        // decodes with the codecs and keys of the config
+        let mut checker = Self::open(&StorageConfig {
+            temporary: true,
+            ..options.config.clone()
+        })?;
+        checker.types = options.types.clone();
+        let report = checker.verify_db(&backup)?;
+        if !report.corrupt.is_empty() {
+            return Err(eyre!(
+                "backup {} failed verification: {}",
+                backup_path.display(),
+                report
+            ));
+        }
+
+        let staging = suffixed(db_path, RESTORE_SUFFIX);
+        // left behind by a restore that did not finish
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        let records = {
+            let restored = sled.open(&staging)?;
+            let records = copy_db(&backup, &restored)?;
+            check_counts(&backup, &restored)?;
+            records
+        };
+        if replace {
+            // fails with `StorageError::AlreadyLocked` while it is open
+            drop(sled.open(db_path)?);
+            let replaced = suffixed(db_path, REPLACED_SUFFIX);
+            if replaced.exists() {
+                std::fs::remove_dir_all(&replaced)?;
+            }
+            std::fs::rename(db_path, &replaced)?;
+            if let Err(e) = std::fs::rename(&staging, db_path) {
+                std::fs::rename(&replaced, db_path)?;
+                return Err(e.into());
+            }
+            std::fs::remove_dir_all(&replaced)?;
+        } else {
+            if db_path.exists() {
+                std::fs::remove_dir(db_path)?;
+            }
+            std::fs::rename(&staging, db_path)?;
+        }
+        info!(
+            "Restored {} records from {} to {}",
+            records,
+            backup_path.display(),
+            db_path.display()
+        );
+        Ok(report)
+    }
+}
+
+/// How `Storage::restore_with` restores a backup.
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    /// Sled options the backup and the restored db are opened with, and the codecs and
+    /// keys the values are checked with. `db_path` is not used.
+    pub config: StorageConfig,
+    /// Replace a `db_path` that is not empty.
+    pub force: bool,
+    // the types whose values must decode
+    types: Types,
+}
+
+impl RestoreOptions {
+    /// Check that the values of `T` in the backup decode, like `Storage::register_type`.
+    pub fn register_type<T: StorageData>(self) -> Self {
+        self.types.register::<T>();
+        self
+    }
+}
+
+// `path` with `suffix` appended to its last component
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut suffixed = path.as_os_str().to_owned();
+    suffixed.push(suffix);
+    PathBuf::from(suffixed)
+}
+
+// BACKEND MIGRATION
+impl Storage {
+    /// Move all data, internal trees like sequences and counters included, from the db
+    /// of `src` to a new db at the path of `dst`, with neither of them open.
+    ///
+    /// After the copy the record counts of every tree are compared, and only then a
+    /// `BackendMigration` marker is written into the new db, so a db without the marker
+    /// is an incomplete copy. Fails if the new db holds any record.
+    pub fn migrate_backend(src: &StorageConfig, dst: &StorageConfig) -> Result<BackendMigration> {
+        let from = src.sled.open(&src.db_path)?;
+        let to = dst.sled.open(&dst.db_path)?;
+        if !is_empty(&to)? {
+            return Err(eyre!(
+                "refusing to migrate into the non-empty db {}",
+                dst.db_path
+            ));
+        }
+        let records = copy_db(&from, &to)?;
+        check_counts(&from, &to)?;
+
+        let migration = BackendMigration {
+            from: src.db_path.clone(),
+            records,
+        };
+        to.open_tree(META_TREE_NAME)?
+            .insert(BACKEND_MIGRATION_META_KEY, bincode::serialize(&migration)?)?;
+        to.flush()?;
+        info!(
+            "Migrated {} records from {} to {}",
+            records, src.db_path, dst.db_path
+        );
+        Ok(migration)
+    }
+
+    /// The marker left by `migrate_backend` when this db was created by it.
+    pub fn backend_migration(&self) -> Option<BackendMigration> {
+        self.get_meta(BACKEND_MIGRATION_META_KEY)
+    }
+}
+
+// SCHEDULED BACKUP
+// A worker thread writes `backup-<unix millis>` dbs into the schedule's directory, so
+// names sort by age, and removes all but the newest `retain` ones afterwards.
+// The worker is registered with the lifecycle, so `close` stops it before the db.
+impl Storage {
+    pub fn start_backup_schedule(&self, schedule: BackupSchedule) -> Result<BackupTask> {
+        self.check_open()?;
+        std::fs::create_dir_all(&schedule.dir)?;
+        let (tx, rx) = mpsc::channel::<()>();
+        let store = self.clone();
+        let handle = std::thread::Builder::new()
+            .name("storage-backup".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(schedule.interval) {
+                    if let Err(e) = store.scheduled_backup(&schedule) {
+                        warn!(
+                            "Scheduled backup to {} failed: {}",
+                            schedule.dir.display(),
+                            e
+                        );
+                    }
+                }
+                debug!("Stopped backups to {}", schedule.dir.display());
+            })?;
+        let worker = self.register_worker(tx, handle);
+        Ok(BackupTask { worker })
+    }
+
+    fn scheduled_backup(&self, schedule: &BackupSchedule) -> Result<()> {
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        self.backup(
+            schedule
+                .dir
+                .join(format!("{}{:020}", BACKUP_PREFIX, millis)),
+        )?;
+
+        let mut backups = vec![];
+        for entry in std::fs::read_dir(&schedule.dir)? {
+            let path = entry?.path();
+            if path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with(BACKUP_PREFIX))
+            {
+                backups.push(path);
+            }
+        }
+        backups.sort();
+        let expired = backups.len().saturating_sub(schedule.retain);
+        for path in &backups[..expired] {
+            std::fs::remove_dir_all(path)?;
+            debug!("Removed expired backup {}", path.display());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn backup() {
+    use crate::StorageData;
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert("test", "test".to_string());
+    store.set_meta("flag", &true).unwrap();
+    store.db.insert("root", "root").unwrap();
+    let dir = crate::TestDir::new("backup");
+    let copy = dir.path("copy.db");
+    // the value, its version stamp, the meta flag and the root key
+    assert_eq!(4, store.backup(&copy).unwrap());
+    assert!(store.backup(&copy).is_err());
+
+    let backup = sled::open(&copy).unwrap();
+    let tree = store.db.open_tree(String::name()).unwrap();
+    assert_eq!(
+        tree.get("test").unwrap(),
+        backup
+            .open_tree(String::name())
+            .unwrap()
+            .get("test")
+            .unwrap()
+    );
+    assert_eq!(Some("root".into()), backup.get("root").unwrap());
+
+    // values still pending in write-back mode are in the backup
+    let write_back = Storage::builder().temporary().write_back().open().unwrap();
+    write_back.insert("pending", "pending".to_string());
+    let copy = dir.path("write_back.db");
+    assert_eq!(2, write_back.backup(&copy).unwrap());
+    let backup = sled::open(&copy).unwrap();
+    let tree = backup.open_tree(String::name()).unwrap();
+    assert!(tree.contains_key("pending").unwrap());
+
+    // writes continue during the copy, the backup holds the ones before some moment
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    let live = Storage::builder().temporary().open().unwrap();
+    for i in 0..20_000 {
+        live.insert(format!("r{:05}", i), i.to_string());
+    }
+    let (stop, written) = (
+        Arc::new(AtomicBool::new(false)),
+        Arc::new(AtomicUsize::new(0)),
+    );
+    let writer = {
+        let (live, stop, written) = (live.clone(), stop.clone(), written.clone());
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let n = written.load(Ordering::Relaxed);
+                live.insert(format!("w{:08}", n), n.to_string());
+                written.store(n + 1, Ordering::Relaxed);
+            }
+        })
+    };
+    while written.load(Ordering::Relaxed) == 0 {
+        std::thread::yield_now();
+    }
+    let copy = dir.path("live.db");
+    let before = written.load(Ordering::Relaxed);
+    let records = live.backup(&copy).unwrap();
+    let after = written.load(Ordering::Relaxed);
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+    let backup = sled::open(&copy).unwrap();
+    let tree = backup.open_tree(String::name()).unwrap();
+    let copied = tree.scan_prefix("w").count();
+    assert!((before..=after).contains(&copied));
+    for (n, r) in tree.scan_prefix("w").enumerate() {
+        assert_eq!(format!("w{:08}", n).as_bytes(), &*r.unwrap().0);
+    }
+    assert_eq!(20_000 + copied, tree.len());
+    // and the version stamp
+    assert_eq!(20_001 + copied, records);
+}
+
+#[test]
+fn restore() {
+    use crate::StorageConfig;
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert("test", "test".to_string());
+    let dir = crate::TestDir::new("restore");
+    let (copy, newer) = (dir.path("copy.db"), dir.path("newer.db"));
+    store.backup(&copy).unwrap();
+    store.insert("other", "other".to_string());
+    store.backup(&newer).unwrap();
+
+    let target = dir.path("target.db");
+    // without registered types, the records are only checksummed
+    let report = Storage::restore(&copy, &target).unwrap();
+    assert!(report.corrupt.is_empty());
+    assert_eq!(vec![(String::name(), 1)], report.orphaned);
+    assert!(Storage::restore(&newer, &target).is_err());
+    assert!(!Path::new(&dir.path("target.db.restoring")).exists());
+    let config = StorageConfig {
+        db_path: target.clone(),
+        ..Default::default()
+    };
+    {
+        // the restored db is a new one, the backed up storage keeps its records
+        let restored: Storage = Storage::new(&config);
+        assert_eq!(Some("test".to_string()), restored.get::<String>("test"));
+        assert_eq!(None, restored.get::<String>("other"));
+        // not while it is open
+        let force = RestoreOptions {
+            force: true,
+            ..Default::default()
+        };
+        assert!(Storage::restore_with(&newer, &target, &force).is_err());
+        assert_eq!(None, restored.get::<String>("other"));
+    }
+    let force = RestoreOptions {
+        force: true,
+        ..Default::default()
+    };
+    Storage::restore_with(&newer, &target, &force).unwrap();
+    assert!(!Path::new(&dir.path("target.db.replaced")).exists());
+    let restored: Storage = Storage::new(&config);
+    assert_eq!(Some("other".to_string()), restored.get::<String>("other"));
+
+    let (damaged, damaged_target) = (dir.path("damaged.db"), dir.path("damaged_target.db"));
+    {
+        let damaged = sled::open(&damaged).unwrap();
+        damaged
+            .open_tree(String::name())
+            .unwrap()
+            .insert("test", vec![0xff])
+            .unwrap();
+    }
+    let options = RestoreOptions::default().register_type::<String>();
+    assert!(Storage::restore_with(&damaged, &damaged_target, &options).is_err());
+    assert!(!Path::new(&damaged_target).exists());
+}
+
+#[test]
+fn backup_schedule() {
+    use crate::StorageData;
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert("test", "test".to_string());
+    let copies = crate::TestDir::new("backup_schedule");
+    let dir = PathBuf::from(copies.path("copies"));
+    let task = store
+        .start_backup_schedule(BackupSchedule {
+            dir: dir.clone(),
+            interval: Duration::from_millis(50),
+            retain: 2,
+        })
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(400));
+    task.stop();
+
+    let backups = std::fs::read_dir(&dir).unwrap().count();
+    assert_eq!(2, backups);
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let backup = sled::open(entry.unwrap().path()).unwrap();
+        let tree = backup.open_tree(String::name()).unwrap();
+        assert!(tree.contains_key("test").unwrap());
+    }
+
+    // closing the storage stops a schedule whose task is still alive
+    std::fs::remove_dir_all(&dir).unwrap();
+    let task = store
+        .start_backup_schedule(BackupSchedule {
+            dir: dir.clone(),
+            interval: Duration::from_millis(50),
+            retain: 2,
+        })
+        .unwrap();
+    store.close().unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(0, std::fs::read_dir(&dir).unwrap().count());
+    assert!(task.worker.handle.lock().is_none());
+    assert!(store
+        .start_backup_schedule(BackupSchedule {
+            dir: dir.clone(),
+            interval: Duration::from_millis(50),
+            retain: 2,
+        })
+        .is_err());
+}
+
+#[test]
+fn migrate_backend() {
+    use crate::sequence::SEQUENCE_TREE_NAME;
+    use crate::{envelope, Format, StorageData};
+
+    let dir = crate::TestDir::new("migrate_backend");
+    let src = StorageConfig {
+        db_path: dir.path("db"),
+        ..Default::default()
+    };
+    let dst = StorageConfig {
+        db_path: dir.path("new.db"),
+        ..Default::default()
+    };
+    {
+        // an open `Storage` would keep the db locked
+        let db = sled::open(&src.db_path).unwrap();
+        let mut value = envelope::encode(Format::Bincode, None, &"test".to_string()).unwrap();
+        envelope::append_checksum(&mut value);
+        db.open_tree(String::name())
+            .unwrap()
+            .insert("test", value)
+            .unwrap();
+        db.open_tree(SEQUENCE_TREE_NAME)
+            .unwrap()
+            .insert("ids", 7u64.to_be_bytes())
+            .unwrap();
+        db.insert("root", "root").unwrap();
+    }
+    let migration = Storage::migrate_backend(&src, &dst).unwrap();
+    assert_eq!(3, migration.records);
+    assert!(Storage::migrate_backend(&src, &dst).is_err());
+
+    let store: Storage = Storage::new(&dst);
+    assert_eq!(Some(migration), store.backend_migration());
+    assert_eq!(Some("test".to_string()), store.get::<String>("test"));
+    assert!(store
+        .db
+        .open_tree(SEQUENCE_TREE_NAME)
+        .unwrap()
+        .contains_key("ids")
+        .unwrap());
+}
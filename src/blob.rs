@@ -0,0 +1,378 @@
+use std::io::{Read, Write};
+
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use sled::InlineArray;
+use tracing::warn;
+
+use crate::{Batch, Storage, StorageError, StorageKey, Tree};
+
+pub(crate) const BLOB_TREE_NAME: &str = "__blobs";
+pub(crate) const DEFAULT_BLOB_CHUNK_SIZE: usize = 1024 * 1024;
+// `| MANIFEST | key |` -> the bincode `Manifest` of the blob
+const MANIFEST: u8 = 0;
+// `| CHUNK | blob id (8, be) | chunk index (8, be) |` -> the bytes of the chunk
+const CHUNK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Manifest {
+    // every write of a blob gets a new id, so its chunks never overwrite the ones
+    // readers of the previous content are reading
+    id: u64,
+    len: u64,
+    chunk_size: u64,
+    crc: u32,
+}
+
+impl Manifest {
+    const fn chunks(&self) -> u64 {
+        self.len.div_ceil(self.chunk_size)
+    }
+}
+
+fn manifest_key(key: &[u8]) -> Vec<u8> {
+    let mut manifest_key = Vec::with_capacity(1 + key.len());
+    manifest_key.push(MANIFEST);
+    manifest_key.extend_from_slice(key);
+    manifest_key
+}
+
+fn chunk_key(id: u64, index: u64) -> [u8; 17] {
+    let mut chunk_key = [CHUNK; 17];
+    chunk_key[1..9].copy_from_slice(&id.to_be_bytes());
+    chunk_key[9..].copy_from_slice(&index.to_be_bytes());
+    chunk_key
+}
+
+fn read_manifest(tree: &Tree, key: &[u8]) -> Result<Option<Manifest>> {
+    match tree.get(manifest_key(key))? {
+        Some(v) => Ok(Some(bincode::deserialize(&v)?)),
+        None => Ok(None),
+    }
+}
+
+fn remove_chunks(tree: &Tree, manifest: &Manifest) -> Result<()> {
+    let mut batch = Batch::default();
+    for index in 0..manifest.chunks() {
+        batch.remove(chunk_key(manifest.id, index));
+    }
+    tree.apply_batch(batch)?;
+    Ok(())
+}
+
+/// Streams a blob into storage chunk by chunk, see `Storage::blob_writer`.
+///
+/// Nothing is visible under the key before `finish`, a writer dropped unfinished
+/// removes the chunks it wrote.
+#[derive(Debug)]
+pub struct BlobWriter {
+    storage: Storage,
+    tree: Tree,
+    key: StorageKey,
+    // `len` counts the bytes of the chunks written so far
+    manifest: Manifest,
+    crc: crc32fast::Hasher,
+    chunk: Vec<u8>,
+    finished: bool,
+}
+
+impl BlobWriter {
+    fn write_chunk(&mut self) -> std::io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let _writes = self.storage.write_gate();
+        let index = self.manifest.chunks();
+        self.tree
+            .insert(chunk_key(self.manifest.id, index), self.chunk.as_slice())?;
+        self.manifest.len += self.chunk.len() as u64;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Store what was written under the key, replacing the blob stored there.
+    pub fn finish(mut self) -> Result<()> {
+        self.write_chunk()?;
+        self.manifest.crc = self.crc.clone().finalize();
+        let _writes = self.storage.write_gate();
+        let old = self
+            .tree
+            .insert(manifest_key(&self.key), bincode::serialize(&self.manifest)?)?;
+        self.finished = true;
+        self.storage
+            .audit("put_blob", BLOB_TREE_NAME, &self.key, None);
+        if let Some(old) = old {
+            remove_chunks(&self.tree, &bincode::deserialize(&old)?)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for BlobWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf
+            .len()
+            .min(self.storage.blob_chunk_size - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..n]);
+        self.crc.update(&buf[..n]);
+        if self.chunk.len() == self.storage.blob_chunk_size {
+            self.write_chunk()?;
+        }
+        Ok(n)
+    }
+
+    // chunks are written as they fill up, the blob is committed by `finish`
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Err(e) = remove_chunks(&self.tree, &self.manifest) {
+            warn!("Discard unfinished blob key({}) failed: {}", self.key, e);
+        }
+    }
+}
+
+/// Streams a blob out of storage chunk by chunk, see `Storage::blob_reader`.
+///
+/// The content is checked against its checksum when the end is reached, a damaged
+/// blob fails the last read with an `InvalidData` error wrapping
+/// `StorageError::Corrupted`.
+#[derive(Debug)]
+pub struct BlobReader {
+    tree: Tree,
+    key: StorageKey,
+    manifest: Manifest,
+    next: u64,
+    chunk: InlineArray,
+    pos: usize,
+    crc: crc32fast::Hasher,
+}
+
+impl BlobReader {
+    /// Size of the blob in bytes.
+    pub const fn len(&self) -> u64 {
+        self.manifest.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.manifest.len == 0
+    }
+
+    fn corrupted(&self) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            StorageError::Corrupted(self.key.to_string()),
+        )
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            if self.next == self.manifest.chunks() {
+                if self.crc.clone().finalize() != self.manifest.crc {
+                    return Err(self.corrupted());
+                }
+                return Ok(0);
+            }
+            let Some(chunk) = self.tree.get(chunk_key(self.manifest.id, self.next))? else {
+                let current =
+                    read_manifest(&self.tree, &self.key).map_err(std::io::Error::other)?;
+                if current == Some(self.manifest) {
+                    return Err(self.corrupted());
+                }
+                return Err(std::io::Error::other(format!(
+                    "blob key({}) was replaced while reading",
+                    self.key
+                )));
+            };
+            self.crc.update(&chunk);
+            (self.chunk, self.pos, self.next) = (chunk, 0, self.next + 1);
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// BLOBS
+// Blobs are stored in `__blobs` as chunks of `StorageConfig::blob_chunk_size` bytes and
+// a manifest per key, past the cache, quotas and `max_value_size`. The chunks of a new
+// write go in first and the manifest last, so readers see the old or the new content,
+// never a mix. A reader streaming a blob that gets replaced fails instead, `get_blob`
+// starts over. A crash during a write leaves its chunks behind without a manifest.
+impl Storage {
+    /// Store `value` under `key`, replacing the blob stored there.
+    pub fn put_blob(&self, key: impl Into<StorageKey>, value: &[u8]) -> Result<()> {
+        let mut writer = self.blob_writer(key)?;
+        writer.write_all(value)?;
+        writer.finish()
+    }
+
+    /// A writer storing the blob under `key` once it is finished, holding one chunk in
+    /// memory at a time.
+    pub fn blob_writer(&self, key: impl Into<StorageKey>) -> Result<BlobWriter> {
+        self.check_writable()?;
+        Ok(BlobWriter {
+            storage: self.clone(),
+            tree: self.tree(BLOB_TREE_NAME)?,
+            key: key.into(),
+            manifest: Manifest {
+                id: self.try_next(BLOB_TREE_NAME)?,
+                len: 0,
+                chunk_size: self.blob_chunk_size as u64,
+                crc: 0,
+            },
+            crc: crc32fast::Hasher::new(),
+            chunk: Vec::with_capacity(self.blob_chunk_size),
+            finished: false,
+        })
+    }
+
+    /// A reader of the blob stored under `key`, holding one chunk in memory at a time.
+    pub fn blob_reader(&self, key: impl Into<StorageKey>) -> Result<Option<BlobReader>> {
+        self.check_open()?;
+        let key = key.into();
+        let tree = self.tree(BLOB_TREE_NAME)?;
+        let Some(manifest) = read_manifest(&tree, &key)? else {
+            return Ok(None);
+        };
+        Ok(Some(BlobReader {
+            tree,
+            key,
+            manifest,
+            next: 0,
+            chunk: InlineArray::from(&[][..]),
+            pos: 0,
+            crc: crc32fast::Hasher::new(),
+        }))
+    }
+
+    /// The blob stored under `key`, checked against the checksum of its manifest.
+    pub fn get_blob(&self, key: impl Into<StorageKey>) -> Result<Option<Vec<u8>>> {
+        self.check_open()?;
+        let key = key.into();
+        let tree = self.tree(BLOB_TREE_NAME)?;
+        'read: loop {
+            let Some(manifest) = read_manifest(&tree, &key)? else {
+                return Ok(None);
+            };
+            let mut value = Vec::with_capacity(manifest.len as usize);
+            for index in 0..manifest.chunks() {
+                match tree.get(chunk_key(manifest.id, index))? {
+                    Some(chunk) => value.extend_from_slice(&chunk),
+                    // replaced while reading, the chunks of the new content are complete
+                    None if read_manifest(&tree, &key)? != Some(manifest) => continue 'read,
+                    None => break,
+                }
+            }
+            if value.len() as u64 != manifest.len || crc32fast::hash(&value) != manifest.crc {
+                return Err(eyre!(StorageError::Corrupted(key.to_string())));
+            }
+            return Ok(Some(value));
+        }
+    }
+
+    /// Size of the blob stored under `key`, without reading it.
+    pub fn blob_len(&self, key: impl Into<StorageKey>) -> Result<Option<u64>> {
+        let tree = self.tree(BLOB_TREE_NAME)?;
+        Ok(read_manifest(&tree, &key.into())?.map(|manifest| manifest.len))
+    }
+
+    /// Remove the blob stored under `key`. False when there is none.
+    pub fn remove_blob(&self, key: impl Into<StorageKey>) -> Result<bool> {
+        self.check_writable()?;
+        let key = key.into();
+        let tree = self.tree(BLOB_TREE_NAME)?;
+        let _writes = self.write_gate();
+        let Some(old) = tree.remove(manifest_key(&key))? else {
+            return Ok(false);
+        };
+        self.audit("remove_blob", BLOB_TREE_NAME, &key, None);
+        remove_chunks(&tree, &bincode::deserialize(&old)?)?;
+        Ok(true)
+    }
+}
+
+#[test]
+fn blobs() {
+    let store = Storage::builder()
+        .temporary()
+        .blob_chunk_size(4)
+        .open()
+        .unwrap();
+    assert_eq!(None, store.get_blob("a").unwrap());
+    let value: Vec<u8> = (0..10).collect();
+    store.put_blob("a", &value).unwrap();
+    assert_eq!(Some(value), store.get_blob("a").unwrap());
+    assert_eq!(Some(10), store.blob_len("a").unwrap());
+    let tree = store.tree(BLOB_TREE_NAME).unwrap();
+    assert_eq!(4, tree.len());
+
+    // the chunks of the replaced content are dropped
+    store.put_blob("a", b"short").unwrap();
+    assert_eq!(Some(b"short".to_vec()), store.get_blob("a").unwrap());
+    assert_eq!(3, tree.len());
+    store.put_blob("empty", b"").unwrap();
+    assert_eq!(Some(vec![]), store.get_blob("empty").unwrap());
+
+    let manifest = read_manifest(&tree, b"a").unwrap().unwrap();
+    tree.insert(chunk_key(manifest.id, 1), b"S".as_slice())
+        .unwrap();
+    let err = store.get_blob("a").unwrap_err();
+    assert_eq!(
+        Some(&StorageError::Corrupted("a".to_string())),
+        err.downcast_ref::<StorageError>()
+    );
+
+    assert!(store.remove_blob("a").unwrap());
+    assert!(!store.remove_blob("a").unwrap());
+    assert_eq!(None, store.get_blob("a").unwrap());
+    assert_eq!(1, tree.len());
+}
+
+#[test]
+fn blob_streams() {
+    let store = Storage::builder()
+        .temporary()
+        .blob_chunk_size(4)
+        .open()
+        .unwrap();
+    let value: Vec<u8> = (0..100).collect();
+    let mut writer = store.blob_writer("a").unwrap();
+    for part in value.chunks(7) {
+        writer.write_all(part).unwrap();
+    }
+    // not visible before it is finished
+    assert_eq!(None, store.get_blob("a").unwrap());
+    writer.finish().unwrap();
+
+    let mut reader = store.blob_reader("a").unwrap().unwrap();
+    assert_eq!(100, reader.len());
+    let mut read = vec![];
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(value, read);
+    assert!(store.blob_reader("b").unwrap().is_none());
+
+    // an abandoned writer leaves nothing behind
+    let tree = store.tree(BLOB_TREE_NAME).unwrap();
+    let before = tree.len();
+    let mut writer = store.blob_writer("b").unwrap();
+    writer.write_all(&value).unwrap();
+    drop(writer);
+    assert_eq!(before, tree.len());
+
+    // replaced in the middle of a read
+    let mut reader = store.blob_reader("a").unwrap().unwrap();
+    let mut first = [0; 4];
+    reader.read_exact(&mut first).unwrap();
+    store.put_blob("a", b"new").unwrap();
+    assert!(reader.read_to_end(&mut vec![]).is_err());
+}
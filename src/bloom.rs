@@ -0,0 +1,133 @@
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use color_eyre::eyre::Result;
+use dashmap::DashMap;
+use tracing::debug;
+
+use crate::{split_ckey, Storage};
+
+// bits and hashes per key for a false positive rate of about 1%
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+
+// filters are sized for this many keys at least, and for twice the keys at build time
+const MIN_KEYS: usize = 1024;
+
+// bloom filters of the data trees by name, shared by the clones of a storage
+pub(crate) type Filters = Arc<DashMap<Vec<u8>, Arc<Bloom>>>;
+
+#[derive(Debug)]
+pub(crate) struct Bloom {
+    bits: Vec<AtomicU64>,
+    hasher: RandomState,
+    // set once all keys of the tree are in, a filter being built may miss some
+    ready: AtomicBool,
+}
+
+impl Bloom {
+    fn with_capacity(keys: usize) -> Self {
+        let words = (keys.max(MIN_KEYS) * BITS_PER_KEY).div_ceil(64);
+        Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            hasher: RandomState::new(),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    // the bit positions of `key`, by double hashing
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = self.hasher.hash_one(key);
+        let step = hash.rotate_left(32) | 1;
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (hash.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+
+    fn insert(&self, key: &[u8]) {
+        for bit in self.positions(key) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(key)
+            .all(|bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+}
+
+// BLOOM FILTERS
+// With `bloom_filters` set, `recover` builds a filter of the keys of each data tree it
+// loads and writes through the cache add their keys, so `contains_key` and `get` of
+// keys that were never written skip the db. Bloom filters can't forget keys, removed
+// keys stay in until the next `recover`, and a tree far outgrowing its filter gets
+// more false positives, only ever costing a db lookup.
+impl Storage {
+    /// Build the filter of the data tree `name` from all its keys, one pass over the
+    /// tree, replacing the current one. Does nothing unless `bloom_filters` is set.
+    pub(crate) fn rebuild_filter(&self, name: &str) -> Result<()> {
+        let Some(filters) = &self.filters else {
+            return Ok(());
+        };
+        let tree = self.tree(name)?;
+        let filter = Arc::new(Bloom::with_capacity(tree.len() * 2));
+        // registered before the scan, so keys written during it are added as well
+        filters.insert(self.tree_name(name).as_bytes().to_vec(), filter.clone());
+        for key in tree.iter().keys() {
+            filter.insert(&key?);
+        }
+        filter.ready.store(true, Ordering::Release);
+        debug!("Built bloom filter of tree({})", name);
+        Ok(())
+    }
+
+    // whether the key of `ckey` is certainly not in its tree
+    pub(crate) fn definitely_absent(&self, ckey: &[u8]) -> bool {
+        let (Some(filters), Some((tree, key))) = (&self.filters, split_ckey(ckey)) else {
+            return false;
+        };
+        filters
+            .get(tree)
+            .is_some_and(|filter| filter.ready.load(Ordering::Acquire) && !filter.may_contain(key))
+    }
+
+    pub(crate) fn filter_insert(&self, ckey: &[u8]) {
+        let (Some(filters), Some((tree, key))) = (&self.filters, split_ckey(ckey)) else {
+            return;
+        };
+        if let Some(filter) = filters.get(tree) {
+            filter.insert(key);
+        }
+    }
+}
+
+#[test]
+fn bloom_filter() {
+    use crate::StorageConfig;
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        bloom_filters: true,
+        ..Default::default()
+    });
+    for i in 0..100u64 {
+        store.insert(i, i.to_string());
+    }
+    // no filter before `recover`
+    assert!(!store.definitely_absent(&store.ckey::<String>(b"absent")));
+
+    store.recover::<String>().unwrap();
+    assert!(!store.definitely_absent(&store.ckey::<String>(crate::key_u64(7).as_bytes())));
+    let absent = (0..1000u64)
+        .filter(|i| {
+            store.definitely_absent(&store.ckey::<String>(format!("absent{}", i).as_bytes()))
+        })
+        .count();
+    assert!(absent > 900);
+
+    store.insert("new", "new".to_string());
+    assert!(!store.definitely_absent(&store.ckey::<String>(b"new")));
+    assert!(store.contains_key::<String>("new"));
+    assert!(store.contains_key::<String>(99u64));
+    assert!(!store.contains_key::<String>("absent"));
+}
@@ -0,0 +1,369 @@
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use tracing::debug;
+
+use crate::{
+    CachePolicy, Format, KeyRules, Quota, RecoveryPolicy, ReplicationSecret, SledConfig, Storage,
+    StorageConfig, StorageData, StorageError,
+};
+
+/// Validating alternative to building a `StorageConfig` by hand, see `Storage::builder`.
+#[derive(Debug, Clone, Default)]
+pub struct StorageBuilder {
+    config: StorageConfig,
+    lock_retries: u32,
+    lock_backoff: Duration,
+}
+
+impl Storage {
+    pub fn builder() -> StorageBuilder {
+        StorageBuilder::default()
+    }
+}
+
+impl From<StorageConfig> for StorageBuilder {
+    fn from(config: StorageConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+}
+
+// BUILDER
+// Durations are stored in the config's whole seconds, shorter ones fail validation
+// instead of silently becoming zero.
+impl StorageBuilder {
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.config.db_path = path.into();
+        self
+    }
+
+    /// Open a db in a new temporary directory, deleted with the storage.
+    pub const fn temporary(mut self) -> Self {
+        self.config.temporary = true;
+        self
+    }
+
+    /// Persist inserts to the db in batches, see `StorageConfig::write_back`.
+    pub const fn write_back(mut self) -> Self {
+        self.config.write_back = true;
+        self
+    }
+
+    /// Bound the cache to this many bytes of keys and values.
+    pub const fn cache_capacity(mut self, bytes: u64) -> Self {
+        self.config.cache_max_capacity = Some(bytes);
+        self
+    }
+
+    /// Don't cache values whose stored bytes are larger than this.
+    pub const fn cache_max_value_size(mut self, bytes: usize) -> Self {
+        self.config.cache_max_value_size = Some(bytes);
+        self
+    }
+
+    /// Refuse to write values whose stored bytes are larger than this.
+    pub const fn max_value_size(mut self, bytes: usize) -> Self {
+        self.config.max_value_size = Some(bytes);
+        self
+    }
+
+    /// Split blobs into chunks of this many bytes.
+    pub const fn blob_chunk_size(mut self, bytes: usize) -> Self {
+        self.config.blob_chunk_size = bytes;
+        self
+    }
+
+    pub const fn cas_gc_grace(mut self, grace: Duration) -> Self {
+        self.config.cas_gc_grace_ms = grace.as_millis() as u64;
+        self
+    }
+
+    pub const fn key_rules(mut self, rules: KeyRules) -> Self {
+        self.config.key_rules = rules;
+        self
+    }
+
+    pub const fn cache_segments(mut self, segments: usize) -> Self {
+        self.config.cache_num_segments = segments;
+        self
+    }
+
+    /// Expire values this long after they were written. Expired values are removed from
+    /// the db too.
+    pub const fn ttl(mut self, ttl: Duration) -> Self {
+        self.config.cache_time_to_live = Some(ttl.as_secs());
+        self
+    }
+
+    /// Expire values this long after they were last accessed. Expired values are removed
+    /// from the db too.
+    pub const fn tti(mut self, tti: Duration) -> Self {
+        self.config.cache_time_to_idle = Some(tti.as_secs());
+        self
+    }
+
+    /// Expire the cached values of `T` by `policy` instead of the cache wide settings.
+    pub fn cache_policy<T: StorageData>(mut self, policy: CachePolicy) -> Self {
+        self.config.cache_policies.insert(T::name(), policy);
+        self
+    }
+
+    /// Recover the records of `T` by `policy`.
+    pub fn recovery_policy<T: StorageData>(mut self, policy: RecoveryPolicy) -> Self {
+        self.config.recovery_policies.insert(T::name(), policy);
+        self
+    }
+
+    /// Limit the records of `T` in each namespace.
+    pub fn quota<T: StorageData>(mut self, quota: Quota) -> Self {
+        self.config.quotas.insert(T::name(), quota);
+        self
+    }
+
+    /// Limit the records of all types in the namespace `name` together.
+    pub fn namespace_quota(mut self, name: &str, quota: Quota) -> Self {
+        self.config.namespace_quotas.insert(name.to_string(), quota);
+        self
+    }
+
+    /// Keep the last `versions` replaced values per key of `T`.
+    pub fn history<T: StorageData>(mut self, versions: usize) -> Self {
+        self.config.history_limits.insert(T::name(), versions);
+        self
+    }
+
+    pub const fn admission_window(mut self, window: Duration) -> Self {
+        self.config.cache_admission_window = Some(window.as_secs());
+        self
+    }
+
+    pub const fn negative_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.config.negative_cache_ttl_ms = Some(ttl.as_millis() as u64);
+        self
+    }
+
+    pub const fn audit(mut self, enabled: bool) -> Self {
+        self.config.audit = enabled;
+        self
+    }
+
+    /// Keep audit entries this long, see `Storage::apply_retention`.
+    pub const fn audit_retention(mut self, retention: Duration) -> Self {
+        self.config.audit_retention = Some(retention.as_secs());
+        self
+    }
+
+    /// Record mutations for replicas, see `Storage::serve_replication`.
+    pub const fn change_log(mut self, enabled: bool) -> Self {
+        self.config.change_log = enabled;
+        self
+    }
+
+    /// Secret shared with the primary or the replicas, see `Storage::serve_replication`.
+    pub fn replication_secret(mut self, secret: &str) -> Self {
+        self.config.replication_secret = Some(ReplicationSecret::new(secret));
+        self
+    }
+
+    /// Refuse writes of records, see `Storage::follow`.
+    pub const fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    pub const fn bloom_filters(mut self, enabled: bool) -> Self {
+        self.config.bloom_filters = enabled;
+        self
+    }
+
+    pub const fn sled(mut self, sled: SledConfig) -> Self {
+        self.config.sled = sled;
+        self
+    }
+
+    pub const fn format(mut self, format: Format) -> Self {
+        self.config.format = format;
+        self
+    }
+
+    pub const fn slow_op_threshold(mut self, threshold: Duration) -> Self {
+        self.config.slow_op_threshold_ms = Some(threshold.as_millis() as u64);
+        self
+    }
+
+    pub const fn quarantine_corrupt(mut self, quarantine: bool) -> Self {
+        self.config.quarantine_corrupt = quarantine;
+        self
+    }
+
+    /// Retry opening a db that is `StorageError::AlreadyLocked` up to `retries` times,
+    /// waiting `backoff` before the first retry and twice as long before each next one.
+    pub const fn retry_locked(mut self, retries: u32, backoff: Duration) -> Self {
+        self.lock_retries = retries;
+        self.lock_backoff = backoff;
+        self
+    }
+
+    /// The config `open` would use.
+    pub const fn config(&self) -> &StorageConfig {
+        &self.config
+    }
+
+    /// Validate the config and open the db, failing with `StorageError::InvalidConfig`
+    /// before touching the disk.
+    pub fn open(self) -> Result<Storage> {
+        let mut backoff = self.lock_backoff;
+        for _ in 0..self.lock_retries {
+            match Storage::open(&self.config) {
+                Err(e) if matches!(e.downcast_ref(), Some(StorageError::AlreadyLocked { .. })) => {
+                    debug!("{}, retrying in {:?}", e, backoff);
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                r => return r,
+            }
+        }
+        Storage::open(&self.config)
+    }
+}
+
+impl StorageConfig {
+    /// Check the options and their combinations, `Storage::new` panics on a config that
+    /// fails here.
+    pub fn validate(&self) -> std::result::Result<(), StorageError> {
+        let invalid = |reason: &str| Err(StorageError::InvalidConfig(reason.to_string()));
+        if self.db_path.is_empty() && !self.temporary {
+            return invalid("db_path is empty");
+        }
+        if self.cache_num_segments == 0 {
+            return invalid("cache_num_segments must be at least 1");
+        }
+        for (name, secs) in [
+            ("cache_time_to_live", self.cache_time_to_live),
+            ("cache_time_to_idle", self.cache_time_to_idle),
+            ("cache_admission_window", self.cache_admission_window),
+            ("audit_retention", self.audit_retention),
+        ] {
+            if secs == Some(0) {
+                return invalid(&format!("{} must be at least one second", name));
+            }
+        }
+        if let (Some(ttl), Some(tti)) = (self.cache_time_to_live, self.cache_time_to_idle) {
+            if tti >= ttl {
+                return invalid("cache_time_to_idle must be shorter than cache_time_to_live");
+            }
+        }
+        if self.blob_chunk_size == 0 {
+            return invalid("blob_chunk_size must be at least one byte");
+        }
+        if self.key_rules.max_len == Some(0) {
+            return invalid("key_rules.max_len must be at least one byte");
+        }
+        if self.max_value_size == Some(0) {
+            return invalid("max_value_size must be at least one byte");
+        }
+        let expires = self.cache_time_to_live.is_some()
+            || self.cache_time_to_idle.is_some()
+            || self
+                .cache_policies
+                .values()
+                .any(|policy| policy.time_to_live.is_some() || policy.time_to_idle.is_some());
+        if self.read_only && expires {
+            return invalid(
+                "read_only storages can't expire values, expiry removes them from the db",
+            );
+        }
+        if self.change_log && self.write_back {
+            return invalid("change_log is not available with write_back");
+        }
+        // replicas would keep the values the primary expired
+        if self.change_log && expires {
+            return invalid("change_log is not available with cache expiry");
+        }
+        if self.negative_cache_ttl_ms == Some(0) {
+            return invalid("negative_cache_ttl_ms must be at least one millisecond");
+        }
+        for (name, policy) in &self.cache_policies {
+            if policy.time_to_live == Some(0) || policy.time_to_idle == Some(0) {
+                return invalid(&format!(
+                    "cache policy of {} must expire after at least one second",
+                    name
+                ));
+            }
+            if let (Some(ttl), Some(tti)) = (policy.time_to_live, policy.time_to_idle) {
+                if tti >= ttl {
+                    return invalid(&format!(
+                        "cache policy of {} must idle shorter than it lives",
+                        name
+                    ));
+                }
+            }
+        }
+        if self.sled.entry_cache_percent.is_some_and(|v| v > 100) {
+            return invalid("sled.entry_cache_percent must be at most 100");
+        }
+        if self
+            .sled
+            .heap_file_fill_ratio
+            .is_some_and(|v| !(v > 0.0 && v <= 1.0))
+        {
+            return invalid("sled.heap_file_fill_ratio must be within (0, 1]");
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn builder() {
+    let store = Storage::builder()
+        .temporary()
+        .cache_capacity(1 << 20)
+        .ttl(Duration::from_secs(60))
+        .tti(Duration::from_secs(10))
+        .open()
+        .unwrap();
+    store.insert("a", "1".to_string());
+    assert_eq!(Some("1".to_string()), store.get::<String>("a"));
+
+    let invalid = |builder: StorageBuilder| {
+        builder
+            .open()
+            .unwrap_err()
+            .downcast_ref::<StorageError>()
+            .cloned()
+    };
+    assert!(matches!(
+        invalid(Storage::builder().path("")),
+        Some(StorageError::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        invalid(
+            Storage::builder()
+                .path("test_builder_invalid.db")
+                .ttl(Duration::from_millis(500))
+        ),
+        Some(StorageError::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        invalid(
+            Storage::builder()
+                .path("test_builder_invalid.db")
+                .ttl(Duration::from_secs(10))
+                .tti(Duration::from_secs(60))
+        ),
+        Some(StorageError::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        invalid(
+            Storage::builder()
+                .temporary()
+                .change_log(true)
+                .ttl(Duration::from_secs(60))
+        ),
+        Some(StorageError::InvalidConfig(_))
+    ));
+    assert!(!std::path::Path::new("test_builder_invalid.db").exists());
+}
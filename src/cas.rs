@@ -0,0 +1,312 @@
+use std::fmt::Display;
+use std::time::SystemTime;
+
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use sled::CompareAndSwapError;
+use tracing::warn;
+
+use crate::audit::micros;
+use crate::{Storage, StorageData, StorageError, Tree};
+
+pub(crate) const CAS_TREE_NAME: &str = "__cas";
+// hash -> `| references (8, be) | last put or release, micros (8, be) |`
+pub(crate) const CAS_REFS_TREE_NAME: &str = "__cas_refs";
+pub(crate) const DEFAULT_CAS_GC_GRACE_MS: u64 = 10 * 60 * 1000;
+
+/// blake3 hash of content stored with `Storage::put_cas`, its key in the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ContentHash([u8; 32]);
+
+impl ContentHash {
+    pub fn of(content: &[u8]) -> Self {
+        Self(*blake3::hash(content).as_bytes())
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Parse a hash from 64 hex digits, as displayed.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        Ok(Self(*blake3::Hash::from_hex(hex.trim())?.as_bytes()))
+    }
+}
+
+impl From<[u8; 32]> for ContentHash {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", blake3::Hash::from_bytes(self.0).to_hex())
+    }
+}
+
+// the references of a value stored at a key of a db tree, for records dropped without
+// being read
+pub(crate) type RefsFn = fn(&Storage, &str, &[u8], &[u8]) -> Vec<ContentHash>;
+
+pub(crate) fn refs_of<T: StorageData>(
+    storage: &Storage,
+    tree: &str,
+    key: &[u8],
+    bytes: &[u8],
+) -> Vec<ContentHash> {
+    storage
+        .decode_in::<T>(tree, key, bytes, false)
+        .map(|value| value.content_refs())
+        .unwrap_or_default()
+}
+
+/// Fields holding content hashes, collected by `#[storage(content)]`.
+pub trait ContentRefs {
+    fn collect_refs(&self, refs: &mut Vec<ContentHash>);
+}
+
+impl ContentRefs for ContentHash {
+    fn collect_refs(&self, refs: &mut Vec<ContentHash>) {
+        refs.push(*self);
+    }
+}
+
+impl<T: ContentRefs> ContentRefs for Option<T> {
+    fn collect_refs(&self, refs: &mut Vec<ContentHash>) {
+        if let Some(v) = self {
+            v.collect_refs(refs);
+        }
+    }
+}
+
+impl<T: ContentRefs> ContentRefs for Vec<T> {
+    fn collect_refs(&self, refs: &mut Vec<ContentHash>) {
+        for v in self {
+            v.collect_refs(refs);
+        }
+    }
+}
+
+fn read_refs(v: Option<&[u8]>) -> (u64, u64) {
+    let Some(v) = v.filter(|v| v.len() == 16) else {
+        return (0, 0);
+    };
+    let count = u64::from_be_bytes(v[..8].try_into().unwrap());
+    let touched = u64::from_be_bytes(v[8..].try_into().unwrap());
+    (count, touched)
+}
+
+// add `delta` to the references of `hash`, restarting its grace period when none are
+// left
+fn adjust_refs(tree: &Tree, hash: &ContentHash, delta: i64) -> Result<()> {
+    let now = micros(SystemTime::now());
+    tree.fetch_and_update(hash.0, |v| {
+        let (count, touched) = read_refs(v);
+        let count = count.saturating_add_signed(delta);
+        let touched = if count == 0 { now } else { touched };
+        let mut refs = count.to_be_bytes().to_vec();
+        refs.extend_from_slice(&touched.to_be_bytes());
+        Some(refs)
+    })?;
+    Ok(())
+}
+
+// CONTENT ADDRESSED STORAGE
+// Content lives in `__cas` under its hash, stored once however often it is put, and
+// read back past the cache. Reads check the content against its hash, putting damaged
+// content again repairs it.
+//
+// Records reference content through `StorageData::content_refs`, counted in
+// `__cas_refs` by the writes of this storage. Values replaced by `insert`, `update`,
+// swaps and versioned inserts, and records removed by `remove` release their
+// references, as do records evicted by a quota when their type is the one written or
+// registered with `register_type`. Records dropped by cache expiry or evicted from
+// trees of unregistered types keep theirs, so that content is never collected, and
+// archived versions hold none. `gc_blobs` removes
+// content without references once `cas_gc_grace_ms` passed since it was last put or
+// released, which covers content put just before the record referencing it. It holds
+// all writes of this storage while it sweeps.
+impl Storage {
+    /// Store `content` under its hash, unless it is stored already.
+    pub fn put_cas(&self, content: &[u8]) -> Result<ContentHash> {
+        self.check_writable()?;
+        self.check_value_size(content)?;
+        let hash = ContentHash::of(content);
+        let tree = self.tree(CAS_TREE_NAME)?;
+        let _writes = self.write_gate();
+        adjust_refs(&self.tree(CAS_REFS_TREE_NAME)?, &hash, 0)?;
+        match tree.compare_and_swap(hash.0, None::<&[u8]>, Some(content))? {
+            Ok(_) => self.audit("put_cas", CAS_TREE_NAME, &hash.0, None),
+            Err(CompareAndSwapError {
+                current: Some(current),
+                ..
+            }) if current.as_ref() != content => {
+                tree.insert(hash.0, content)?;
+                self.audit("put_cas", CAS_TREE_NAME, &hash.0, None);
+            }
+            Err(_) => {}
+        }
+        Ok(hash)
+    }
+
+    /// The content stored under `hash`, checked against it.
+    pub fn get_cas(&self, hash: &ContentHash) -> Result<Option<Vec<u8>>> {
+        self.check_open()?;
+        let Some(content) = self.tree(CAS_TREE_NAME)?.get(hash.0)? else {
+            return Ok(None);
+        };
+        if ContentHash::of(&content) != *hash {
+            return Err(eyre!(StorageError::Corrupted(hash.to_string())));
+        }
+        Ok(Some(content.to_vec()))
+    }
+
+    pub fn contains_cas(&self, hash: &ContentHash) -> Result<bool> {
+        Ok(self.tree(CAS_TREE_NAME)?.contains_key(hash.0)?)
+    }
+
+    /// Number of stored records referencing `hash`.
+    pub fn cas_refs(&self, hash: &ContentHash) -> Result<u64> {
+        let tree = self.tree(CAS_REFS_TREE_NAME)?;
+        Ok(read_refs(tree.get(hash.0)?.as_deref()).0)
+    }
+
+    // after a write replaced the references `old` with `new`, under the write gate
+    pub(crate) fn swap_refs(&self, old: &[ContentHash], new: &[ContentHash]) {
+        if old.is_empty() && new.is_empty() {
+            return;
+        }
+        if let Err(e) = self.try_swap_refs(old, new) {
+            warn!("Update content references failed: {}", e);
+        }
+    }
+
+    fn try_swap_refs(&self, old: &[ContentHash], new: &[ContentHash]) -> Result<()> {
+        let tree = self.tree(CAS_REFS_TREE_NAME)?;
+        for hash in new {
+            adjust_refs(&tree, hash, 1)?;
+        }
+        for hash in old {
+            adjust_refs(&tree, hash, -1)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the content no record references, see `StorageConfig::cas_gc_grace_ms`.
+    /// Returns how many were removed.
+    pub fn gc_blobs(&self) -> Result<usize> {
+        self.check_writable()?;
+        let (content, refs) = (self.tree(CAS_TREE_NAME)?, self.tree(CAS_REFS_TREE_NAME)?);
+        let cutoff =
+            micros(SystemTime::now()).saturating_sub(self.cas_gc_grace_ms.saturating_mul(1000));
+        // no write takes or releases a reference during the sweep
+        let _writes = self.writes.write();
+        let mut removed = 0;
+        for r in content.iter().keys() {
+            let hash = r?;
+            let (count, touched) = read_refs(refs.get(&hash)?.as_deref());
+            if count == 0 && touched <= cutoff {
+                content.remove(&hash)?;
+                refs.remove(&hash)?;
+                self.audit("gc_blobs", CAS_TREE_NAME, &hash, None);
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[test]
+fn cas() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let hash = store.put_cas(b"proof").unwrap();
+    assert_eq!(hash, store.put_cas(b"proof").unwrap());
+    assert_ne!(hash, store.put_cas(b"other proof").unwrap());
+    assert_eq!(2, store.tree(CAS_TREE_NAME).unwrap().len());
+    assert_eq!(Some(b"proof".to_vec()), store.get_cas(&hash).unwrap());
+    assert_eq!(hash, ContentHash::from_hex(&hash.to_string()).unwrap());
+    assert!(!store.contains_cas(&ContentHash::of(b"missing")).unwrap());
+
+    // damaged content is detected, and repaired by putting it again
+    store
+        .tree(CAS_TREE_NAME)
+        .unwrap()
+        .insert(hash.0, b"tampered".as_slice())
+        .unwrap();
+    let err = store.get_cas(&hash).unwrap_err();
+    assert_eq!(
+        Some(&StorageError::Corrupted(hash.to_string())),
+        err.downcast_ref::<StorageError>()
+    );
+    store.put_cas(b"proof").unwrap();
+    assert_eq!(Some(b"proof".to_vec()), store.get_cas(&hash).unwrap());
+}
+
+#[test]
+fn cas_gc() {
+    use std::time::Duration;
+
+    use crate::StorageData;
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+    struct Proof {
+        #[storage(content)]
+        body: Option<ContentHash>,
+        #[storage(content)]
+        attachments: Vec<ContentHash>,
+    }
+
+    let dir = crate::TestDir::new("cas_gc");
+    let store = Storage::builder()
+        .path(dir.path("db"))
+        .cas_gc_grace(Duration::ZERO)
+        .open()
+        .unwrap();
+    let (a, b, c) = (
+        store.put_cas(b"a").unwrap(),
+        store.put_cas(b"b").unwrap(),
+        store.put_cas(b"c").unwrap(),
+    );
+    let proof = Proof {
+        body: Some(a),
+        attachments: vec![b],
+    };
+    store.insert("p1", proof.clone());
+    store.insert("p2", proof);
+    assert_eq!(2, store.cas_refs(&a).unwrap());
+    assert_eq!(1, store.gc_blobs().unwrap());
+    assert!(!store.contains_cas(&c).unwrap());
+
+    // replacing a value releases what it referenced
+    store.insert(
+        "p1",
+        Proof {
+            body: Some(a),
+            attachments: vec![],
+        },
+    );
+    store
+        .update::<Proof, _>("p2", |v| {
+            v.map(|v| Proof {
+                attachments: vec![],
+                ..v
+            })
+        })
+        .unwrap();
+    assert_eq!(1, store.gc_blobs().unwrap());
+    assert!(!store.contains_cas(&b).unwrap());
+    assert!(store.contains_cas(&a).unwrap());
+
+    store.remove::<Proof>("p1");
+    assert_eq!(0, store.gc_blobs().unwrap());
+    store.remove::<Proof>("p2");
+    assert_eq!(1, store.gc_blobs().unwrap());
+    assert_eq!(None, store.get_cas(&a).unwrap());
+
+    // content put moments ago is kept for the record about to reference it
+    drop(store);
+    let store = Storage::builder().path(dir.path("db")).open().unwrap();
+    store.put_cas(b"d").unwrap();
+    assert_eq!(0, store.gc_blobs().unwrap());
+}
@@ -0,0 +1,542 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::ops::Range;
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Result};
+use parking_lot::RwLock;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{envelope, Storage, StorageData};
+
+/// Serialization of stored values.
+///
+/// The built-in codecs are picked with `Format`. Implement this for an encoding they
+/// don't cover and register it for a type with `Storage::register_codec`.
+pub trait Codec: 'static {
+    /// Identifier written into the value envelope, so records are always read back with
+    /// the codec they were written with. Registered codecs use one of
+    /// `CUSTOM_CODEC_IDS`, the others belong to `Format`.
+    const ID: u8;
+
+    /// Append the encoding of `value` to `buf`.
+    fn serialize_into<T: Serialize + ?Sized>(buf: &mut Vec<u8>, value: &T) -> Result<()>;
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// The codec ids left to codecs registered with `Storage::register_codec`.
+pub const CUSTOM_CODEC_IDS: Range<u8> = 8..16;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Bincode;
+
+impl Codec for Bincode {
+    const ID: u8 = 0;
+
+    fn serialize_into<T: Serialize + ?Sized>(buf: &mut Vec<u8>, value: &T) -> Result<()> {
+        buf.reserve(bincode::serialized_size(value)? as usize);
+        bincode::serialize_into(buf, value)?;
+        Ok(())
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+// JSON values, readable in the raw db bytes when debugging
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    const ID: u8 = 1;
+
+    fn serialize_into<T: Serialize + ?Sized>(buf: &mut Vec<u8>, value: &T) -> Result<()> {
+        serde_json::to_writer(buf, value)?;
+        Ok(())
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+// MessagePack values, the payload is exactly what `rmp_serde::to_vec` produces
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    const ID: u8 = 2;
+
+    fn serialize_into<T: Serialize + ?Sized>(buf: &mut Vec<u8>, value: &T) -> Result<()> {
+        rmp_serde::encode::write(buf, value)?;
+        Ok(())
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+// Postcard values, varint encoded and noticeably smaller than bincode for integer-heavy
+// structs
+#[cfg(feature = "postcard")]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    const ID: u8 = 3;
+
+    fn serialize_into<T: Serialize + ?Sized>(buf: &mut Vec<u8>, value: &T) -> Result<()> {
+        let encoded = postcard::to_extend(value, std::mem::take(buf))?;
+        *buf = encoded;
+        Ok(())
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(postcard::from_bytes(bytes)?)
+    }
+}
+
+/// The built-in codec new values are written with.
+///
+/// Set per `Storage` with `StorageConfig::format`, or per type with
+/// `StorageData::format`. Reads always use the codec recorded in the value. All but
+/// bincode are behind the `json`, `msgpack` and `postcard` features. A `Codec` of
+/// your own is generic over the value, so it is registered per type rather than made
+/// the storage default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Bincode,
+    #[cfg(feature = "json")]
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "postcard")]
+    Postcard,
+}
+
+impl Format {
+    pub const fn id(self) -> u8 {
+        match self {
+            Self::Bincode => Bincode::ID,
+            #[cfg(feature = "json")]
+            Self::Json => Json::ID,
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack::ID,
+            #[cfg(feature = "postcard")]
+            Self::Postcard => Postcard::ID,
+        }
+    }
+
+    pub const fn from_id(id: u8) -> Option<Self> {
+        match id {
+            Bincode::ID => Some(Self::Bincode),
+            #[cfg(feature = "json")]
+            Json::ID => Some(Self::Json),
+            #[cfg(feature = "msgpack")]
+            MessagePack::ID => Some(Self::MessagePack),
+            #[cfg(feature = "postcard")]
+            Postcard::ID => Some(Self::Postcard),
+            _ => None,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn serialize_into<T: Serialize + ?Sized>(
+        self,
+        buf: &mut Vec<u8>,
+        value: &T,
+    ) -> Result<()> {
+        match self {
+            Self::Bincode => Bincode::serialize_into(buf, value),
+            #[cfg(feature = "json")]
+            Self::Json => Json::serialize_into(buf, value),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack::serialize_into(buf, value),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => Postcard::serialize_into(buf, value),
+        }
+    }
+
+    pub(crate) fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Self::Bincode => Bincode::deserialize(bytes),
+            #[cfg(feature = "json")]
+            Self::Json => Json::deserialize(bytes),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack::deserialize(bytes),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => Postcard::deserialize(bytes),
+        }
+    }
+}
+
+// how values of `T` are written by a storage, with a built-in format or the codec
+// registered for `T`
+pub(crate) struct TypeCodec<T> {
+    pub(crate) id: u8,
+    serialize: fn(&mut Vec<u8>, &T) -> Result<()>,
+    deserialize: fn(&[u8]) -> Result<T>,
+}
+
+impl<T> Clone for TypeCodec<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for TypeCodec<T> {}
+
+impl<T: StorageData> TypeCodec<T> {
+    fn of<C: Codec>() -> Self {
+        Self {
+            id: C::ID,
+            serialize: C::serialize_into::<T>,
+            deserialize: C::deserialize::<T>,
+        }
+    }
+
+    pub(crate) fn serialize_into(self, buf: &mut Vec<u8>, value: &T) -> Result<()> {
+        (self.serialize)(buf, value)
+    }
+
+    pub(crate) fn deserialize(self, bytes: &[u8]) -> Result<T> {
+        (self.deserialize)(bytes)
+    }
+}
+
+impl<T: StorageData> From<Format> for TypeCodec<T> {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Bincode => Self::of::<Bincode>(),
+            #[cfg(feature = "json")]
+            Format::Json => Self::of::<Json>(),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => Self::of::<MessagePack>(),
+            #[cfg(feature = "postcard")]
+            Format::Postcard => Self::of::<Postcard>(),
+        }
+    }
+}
+
+// the codecs registered with `register_codec`, keyed by tree name. Each holds the
+// `TypeCodec` of the type it was registered for.
+#[derive(Clone, Default)]
+pub(crate) struct Codecs(Arc<RwLock<HashMap<String, Box<dyn Any + Send + Sync>>>>);
+
+impl Codecs {
+    pub(crate) fn get<T: StorageData>(&self) -> Option<TypeCodec<T>> {
+        self.0.read().get(&T::name())?.downcast_ref().copied()
+    }
+}
+
+impl Debug for Codecs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.read().keys()).finish()
+    }
+}
+
+impl Storage {
+    /// Write new values of `T` with the codec `C`, rather than `StorageData::format` or
+    /// the storage default.
+    ///
+    /// Values already stored keep the codec they were written with, and are rewritten
+    /// with `C` when read like any value in another codec. Register it before the
+    /// migrations of `T`, they write with the codec `T` has when they are registered.
+    /// Fails for an id outside `CUSTOM_CODEC_IDS`.
+    pub fn register_codec<T: StorageData, C: Codec>(&self) -> Result<()> {
+        if !CUSTOM_CODEC_IDS.contains(&C::ID) {
+            return Err(eyre!(
+                "codec id {} is outside {:?}",
+                C::ID,
+                CUSTOM_CODEC_IDS
+            ));
+        }
+        self.codecs
+            .0
+            .write()
+            .insert(T::name(), Box::new(TypeCodec::<T>::of::<C>()));
+        Ok(())
+    }
+
+    // a payload written with the codec `id`, `None` for a codec the storage doesn't
+    // know for `T`
+    pub(crate) fn deserialize_as<T: StorageData>(&self, id: u8, payload: &[u8]) -> Option<T> {
+        let codec = self.codec_of::<T>();
+        if id == codec.id {
+            return codec.deserialize(payload).ok();
+        }
+        Format::from_id(id)?.deserialize(payload).ok()
+    }
+}
+
+// CODEC FALLBACK
+// Values in another codec than the one `T` is written with are read with the codec in
+// their envelope, values without an envelope with the first codec of the chain that
+// can read them. Either way the record is rewritten in the current codec, unless
+// migrations must be explicit or the storage is read-only.
+impl Storage {
+    pub(crate) fn decode_foreign<T: StorageData>(
+        &self,
+        key: &[u8],
+        bytes: &[u8],
+        payload: &[u8],
+        persist: bool,
+    ) -> Option<T> {
+        let target = self.format_of::<T>();
+        let (header, _) = envelope::split(bytes);
+        let value = if envelope::is_enveloped(bytes) {
+            self.deserialize_as(header.format, payload)?
+        } else {
+            let Some((format, value)) = std::iter::once(target)
+                .chain(self.format_fallbacks.iter().copied())
+                .find_map(|format| Some((format, format.deserialize(payload).ok()?)))
+            else {
+                warn!(
+                    "tree({}) key({}) is not readable by any codec",
+                    T::name(),
+                    String::from_utf8_lossy(key)
+                );
+                return None;
+            };
+            if format.id() == self.codec_of::<T>().id {
+                // the original layout of the codec, only missing the envelope
+                return Some(value);
+            }
+            value
+        };
+
+        if persist && !self.migrations.require_explicit && self.check_writable().is_ok() {
+            let Ok(new) = self.encode(key, &value, Some(bytes)) else {
+                return Some(value);
+            };
+            let Ok(tree) = self.tree(T::name()) else {
+                return Some(value);
+            };
+            // a concurrent write wins over the rewrite
+            if let Ok(true) = self.rewrite::<T>(&tree, key, bytes, new, "recode") {
+                debug!(
+                    "Rewrote tree({}) key({}) with codec {}",
+                    T::name(),
+                    String::from_utf8_lossy(key),
+                    self.codec_of::<T>().id
+                );
+            }
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+fn roundtrip(format: Format) {
+    assert_eq!(Some(format), Format::from_id(format.id()));
+    let mut buf = vec![];
+    format
+        .serialize_into(&mut buf, &("test".to_string(), 7u64))
+        .unwrap();
+    assert_eq!(
+        ("test".to_string(), 7u64),
+        format.deserialize::<(String, u64)>(&buf).unwrap()
+    );
+}
+
+#[test]
+fn codec() {
+    roundtrip(Format::Bincode);
+    #[cfg(feature = "json")]
+    roundtrip(Format::Json);
+    #[cfg(feature = "msgpack")]
+    {
+        roundtrip(Format::MessagePack);
+        let mut buf = vec![];
+        Format::MessagePack
+            .serialize_into(&mut buf, &("test".to_string(), 7u64))
+            .unwrap();
+        assert_eq!(rmp_serde::to_vec(&("test".to_string(), 7u64)).unwrap(), buf);
+    }
+    #[cfg(feature = "postcard")]
+    {
+        roundtrip(Format::Postcard);
+        let (mut compact, mut bincode) = (vec![], vec![]);
+        Format::Postcard
+            .serialize_into(&mut compact, &[1u64, 2, 3])
+            .unwrap();
+        Format::Bincode
+            .serialize_into(&mut bincode, &[1u64, 2, 3])
+            .unwrap();
+        assert!(compact.len() < bincode.len());
+    }
+    assert_eq!(None, Format::from_id(u8::MAX));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_codec() {
+    use crate::{envelope, Storage, StorageConfig, StorageData};
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+    #[storage(codec = "json")]
+    struct JsonTest {
+        a: u32,
+    }
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert("test", JsonTest { a: 7 });
+    store.insert("test", "bincode".to_string());
+    let tree = store.db.open_tree(JsonTest::name()).unwrap();
+    let stored = tree.get("test").unwrap().unwrap();
+    assert_eq!(br#"{"a":7}"#, envelope::split(&stored).1);
+    assert_eq!(Some(JsonTest { a: 7 }), store.get::<JsonTest>("test"));
+
+    // the type's codec wins over the storage default, values keep the codec they have
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        format: Format::Json,
+        ..Default::default()
+    });
+    let tree = store.db.open_tree(String::name()).unwrap();
+    tree.insert("legacy", bincode::serialize("legacy").unwrap())
+        .unwrap();
+    store.insert("test", "json".to_string());
+    let stored = tree.get("test").unwrap().unwrap();
+    assert_eq!(br#""json""#, envelope::split(&stored).1);
+    assert_eq!(Some("legacy".to_string()), store.get::<String>("legacy"));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn codec_fallback() {
+    use crate::StorageConfig;
+
+    let config = StorageConfig {
+        temporary: true,
+        format: Format::Json,
+        audit: true,
+        ..Default::default()
+    };
+    let store: Storage = Storage::new(&config);
+    let start = std::time::SystemTime::now();
+    // written before switching codecs
+    let tree = store.db.open_tree(String::name()).unwrap();
+    tree.insert(
+        "enveloped",
+        envelope::checksummed(
+            envelope::encode(Format::Bincode, None, &"bincode".to_string()).unwrap(),
+        ),
+    )
+    .unwrap();
+    tree.insert("legacy", bincode::serialize("legacy").unwrap())
+        .unwrap();
+
+    for (key, value) in [("enveloped", "bincode"), ("legacy", "legacy")] {
+        assert_eq!(Some(value.to_string()), store.get::<String>(key));
+        let stored = tree.get(key).unwrap().unwrap();
+        let (header, payload) = envelope::split(&stored);
+        assert_eq!(Some(Format::Json), header.codec());
+        assert_eq!(format!("{:?}", value).as_bytes(), payload);
+    }
+    let log = store
+        .audit_log(start..std::time::SystemTime::now() + std::time::Duration::from_secs(1))
+        .unwrap();
+    assert_eq!(
+        vec![b"enveloped".to_vec(), b"legacy".to_vec()],
+        log.iter()
+            .filter(|e| e.operation == "recode")
+            .map(|e| e.key.clone())
+            .collect::<Vec<_>>()
+    );
+
+    // a read-only storage reads the old codec without rewriting it
+    let follower: Storage = Storage::new(&StorageConfig {
+        read_only: true,
+        ..config
+    });
+    let legacy = bincode::serialize("legacy").unwrap();
+    let tree = follower.db.open_tree(String::name()).unwrap();
+    tree.insert("legacy", legacy.clone()).unwrap();
+    assert_eq!(Some("legacy".to_string()), follower.get::<String>("legacy"));
+    assert_eq!(legacy, tree.get("legacy").unwrap().unwrap().to_vec());
+}
+
+#[test]
+fn custom_codec() {
+    // bincode with every byte inverted
+    struct Inverted;
+
+    impl Codec for Inverted {
+        const ID: u8 = 8;
+
+        fn serialize_into<T: Serialize + ?Sized>(buf: &mut Vec<u8>, value: &T) -> Result<()> {
+            buf.extend(bincode::serialize(value)?.iter().map(|b| !b));
+            Ok(())
+        }
+
+        fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+            Ok(bincode::deserialize(
+                &bytes.iter().map(|b| !b).collect::<Vec<_>>(),
+            )?)
+        }
+    }
+
+    struct Taken;
+
+    impl Codec for Taken {
+        const ID: u8 = 1;
+
+        fn serialize_into<T: Serialize + ?Sized>(_: &mut Vec<u8>, _: &T) -> Result<()> {
+            unreachable!()
+        }
+
+        fn deserialize<T: DeserializeOwned>(_: &[u8]) -> Result<T> {
+            unreachable!()
+        }
+    }
+
+    #[derive(crate::StorageData, Debug, Clone, Default, Deserialize, Serialize)]
+    struct Other;
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    assert!(store.register_codec::<String, Taken>().is_err());
+    store.insert("before", "bincode".to_string());
+    store.register_codec::<String, Inverted>().unwrap();
+    store.insert("test", "inverted".to_string());
+    store.insert("test", Other);
+
+    let tree = store.db.open_tree(String::name()).unwrap();
+    let stored = tree.get("test").unwrap().unwrap();
+    let (header, payload) = envelope::split(&stored);
+    assert_eq!(Inverted::ID, header.format);
+    assert_eq!(
+        Inverted::deserialize::<String>(payload).unwrap(),
+        "inverted"
+    );
+    assert_eq!(Some("inverted".to_string()), store.get::<String>("test"));
+    // other types keep the storage default
+    let stored = store
+        .db
+        .open_tree(Other::name())
+        .unwrap()
+        .get("test")
+        .unwrap();
+    assert_eq!(
+        Format::Bincode.id(),
+        envelope::split(&stored.unwrap()).0.format
+    );
+
+    // values written before are read in their codec and rewritten
+    store.cache.invalidate_all();
+    assert_eq!(Some("bincode".to_string()), store.get::<String>("before"));
+    let stored = tree.get("before").unwrap().unwrap();
+    assert_eq!(Inverted::ID, envelope::split(&stored).0.format);
+}
@@ -0,0 +1,128 @@
+use std::marker::PhantomData;
+
+use color_eyre::eyre::Result;
+
+use crate::{Durability, Storage, StorageData, StorageKey, Tree};
+
+/// Typed handle on the structured data of `T`.
+///
+/// The tree is opened once when the collection is created and the cache key prefix is
+/// computed up front, so repeated operations don't go through `open_tree`.
+#[derive(Debug, Clone)]
+pub struct Collection<T: StorageData> {
+    storage: Storage,
+    tree: Tree,
+    prefix: Vec<u8>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl Storage {
+    pub fn collection<T: StorageData>(&self) -> Result<Collection<T>> {
+        Ok(Collection {
+            storage: self.clone(),
+            tree: self.tree(T::name())?,
+            prefix: self.ckey::<T>(b""),
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: StorageData> Collection<T> {
+    fn ckey(&self, key: &[u8]) -> Vec<u8> {
+        let mut ckey = Vec::with_capacity(self.prefix.len() + key.len());
+        ckey.extend_from_slice(&self.prefix);
+        ckey.extend_from_slice(key);
+        ckey
+    }
+
+    pub fn contains_key(&self, key: impl Into<StorageKey>) -> bool {
+        let key = key.into();
+        self.storage
+            .contains_key_in(&self.tree, &self.ckey(&key), &key)
+    }
+
+    pub fn get(&self, key: impl Into<StorageKey>) -> Option<T> {
+        let key = key.into();
+        self.storage.get_in(&self.tree, self.ckey(&key), &key)
+    }
+
+    pub fn insert(&self, key: impl Into<StorageKey>, value: T) -> Option<T> {
+        let key = key.into();
+        self.storage
+            .insert_in(&self.tree, self.ckey(&key), &key, value)
+    }
+
+    /// Like `insert`, flushing the db after the write when `durability` is `Flush`.
+    pub fn insert_with_durability(
+        &self,
+        key: impl Into<StorageKey>,
+        value: T,
+        durability: Durability,
+    ) -> Result<Option<T>> {
+        let key = key.into();
+        let previous = self
+            .storage
+            .try_insert_in(&self.tree, self.ckey(&key), &key, value)?;
+        self.storage.make_durable(durability)?;
+        Ok(previous)
+    }
+
+    pub fn remove(&self, key: impl Into<StorageKey>) -> Option<T> {
+        let key = key.into();
+        self.storage
+            .remove_in::<T>(&self.tree, &self.ckey(&key), &key)
+    }
+
+    /// Iterate all records in key order, read from the db without touching the cache.
+    pub fn iter(&self) -> impl Iterator<Item = (StorageKey, T)> + '_ {
+        self.tree.iter().filter_map(move |r| {
+            let (k, v) = r.ok()?;
+            let value = self.storage.decode_with(&k, &v, false)?;
+            Some((StorageKey::from(k.as_ref()), value))
+        })
+    }
+}
+
+#[test]
+fn collection() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let strings = store.collection::<String>().unwrap();
+    strings.insert("a", "1".to_string());
+    strings.insert("b", "2".to_string());
+    assert!(strings.contains_key("a"));
+    assert_eq!(Some("1".to_string()), store.get::<String>("a"));
+    assert_eq!(
+        vec![
+            (StorageKey::from("a"), "1".to_string()),
+            (StorageKey::from("b"), "2".to_string())
+        ],
+        strings.iter().collect::<Vec<_>>()
+    );
+    strings.remove("a");
+    assert_eq!(None, strings.get("a"));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn collection_iter_read_only() {
+    use crate::{envelope, Format, StorageConfig};
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        format: Format::Json,
+        ..Default::default()
+    });
+    // written before switching codecs, rewritten by `get` but not by iteration
+    let bytes = envelope::checksummed(
+        envelope::encode(Format::Bincode, None, &"bincode".to_string()).unwrap(),
+    );
+    let tree = store.db.open_tree(String::name()).unwrap();
+    tree.insert("a", bytes.clone()).unwrap();
+
+    let strings = store.collection::<String>().unwrap();
+    assert_eq!(
+        vec![(StorageKey::from("a"), "bincode".to_string())],
+        strings.iter().collect::<Vec<_>>()
+    );
+    assert_eq!(bytes, tree.get("a").unwrap().unwrap().to_vec());
+}
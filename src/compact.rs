@@ -0,0 +1,130 @@
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Result};
+use tracing::info;
+
+use crate::backup::{check_counts, copy_db};
+use crate::{Storage, StorageConfig};
+
+/// The outcome of `Storage::compact`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+    /// Size of the db directory before compacting, in bytes.
+    pub before: u64,
+    pub after: u64,
+    /// Number of records in the compacted db.
+    pub records: usize,
+}
+
+impl Compaction {
+    pub const fn reclaimed(&self) -> u64 {
+        self.before.saturating_sub(self.after)
+    }
+}
+
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+// COMPACTION
+// sled reuses the space of removed records but never gives it back, its files only
+// shrink when the live objects at their end go away. Copying the records into a fresh
+// db packs them densely, so `compact` rebuilds the db that way next to the old one and
+// swaps the directories once the copy is complete.
+impl Storage {
+    /// Size of the db directory in bytes.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    /// Rebuild the db of `config` to reclaim the space of removed records. The db must
+    /// not be open, a `Storage` on it keeps it locked.
+    ///
+    /// The records are copied into `<db_path>.compacting` first, which needs space for a
+    /// copy of the live data. The old db is only removed once the record counts of all
+    /// trees match.
+    pub fn compact(config: &StorageConfig) -> Result<Compaction> {
+        let path = Path::new(&config.db_path);
+        if !path.exists() {
+            return Err(eyre!("db {} does not exist", config.db_path));
+        }
+        let compacting = format!("{}.compacting", config.db_path);
+        let old = format!("{}.old", config.db_path);
+        for leftover in [&compacting, &old] {
+            if Path::new(leftover).exists() {
+                std::fs::remove_dir_all(leftover)?;
+            }
+        }
+
+        let before = dir_size(path)?;
+        let records = {
+            let (src, dst) = (config.sled.open(path)?, config.sled.open(&compacting)?);
+            let records = copy_db(&src, &dst)?;
+            check_counts(&src, &dst)?;
+            records
+        };
+        std::fs::rename(path, &old)?;
+        std::fs::rename(&compacting, path)?;
+        std::fs::remove_dir_all(&old)?;
+
+        let compaction = Compaction {
+            before,
+            after: dir_size(path)?,
+            records,
+        };
+        info!(
+            "Compacted {} from {} to {} bytes",
+            config.db_path, compaction.before, compaction.after
+        );
+        Ok(compaction)
+    }
+}
+
+#[test]
+fn compact() {
+    let dir = crate::TestDir::new("compact");
+    let config = StorageConfig {
+        db_path: dir.path("db"),
+        ..Default::default()
+    };
+    {
+        // an open `Storage` would keep the db locked
+        let db = sled::open(&config.db_path).unwrap();
+        let tree = db.open_tree("Compact").unwrap();
+        // incompressible values, sled compresses its files
+        let mut seed = 1u64;
+        for i in 0..2000u64 {
+            let value: Vec<u8> = (0..1024)
+                .map(|_| {
+                    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    (seed >> 56) as u8
+                })
+                .collect();
+            tree.insert(i.to_be_bytes(), value).unwrap();
+        }
+        db.flush().unwrap();
+        for i in 0..1800u64 {
+            tree.remove(i.to_be_bytes()).unwrap();
+        }
+    }
+
+    let compaction = Storage::compact(&config).unwrap();
+    assert_eq!(200, compaction.records);
+    assert!(compaction.reclaimed() > compaction.after);
+
+    let store: Storage = Storage::new(&config);
+    assert!(store.size_on_disk().unwrap() < compaction.before);
+    let tree = store.db.open_tree("Compact").unwrap();
+    assert_eq!(200, tree.len());
+    assert!(tree.contains_key(1999u64.to_be_bytes()).unwrap());
+}
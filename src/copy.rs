@@ -0,0 +1,148 @@
+use bytes::Bytes;
+use color_eyre::eyre::Result;
+use tracing::info;
+
+use crate::Storage;
+
+// records between two progress reports
+const PROGRESS_INTERVAL: usize = 1000;
+
+/// Progress of `Storage::copy_to_with`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyProgress {
+    /// The tree being copied.
+    pub tree: String,
+    /// Records copied from this tree so far.
+    pub copied: usize,
+    /// Records copied from all trees so far.
+    pub total: usize,
+    /// Whether the tree is done.
+    pub done: bool,
+}
+
+// COPY
+// Records are copied as stored, `other` must be able to read them, with the same codecs
+// and encryption keys, and in the same namespace as encrypted values are bound to their
+// db tree. Values cached by `other` are replaced, the source cache is not
+// used. Like `backup`, writes racing the copy may or may not be copied. The copied
+// records are written like other writes of `other`, recorded in its audit and change
+// logs.
+impl Storage {
+    /// Copy the records of the trees named in `types` into `other`, all data trees when
+    /// `types` is empty. Returns the number of copied records.
+    pub fn copy_to(&self, other: &Storage, types: &[&str]) -> Result<usize> {
+        self.copy_to_with(other, types, &[], |_| {})
+    }
+
+    /// Like `copy_to`, only copying the keys starting with `prefix` and calling
+    /// `progress` every thousand records and after each tree.
+    pub fn copy_to_with<F: FnMut(&CopyProgress)>(
+        &self,
+        other: &Storage,
+        types: &[&str],
+        prefix: &[u8],
+        mut progress: F,
+    ) -> Result<usize> {
+        other.check_writable()?;
+        let names = if types.is_empty() {
+            self.data_tree_names()
+        } else {
+            types.iter().map(|name| name.to_string()).collect()
+        };
+        let mut total = 0;
+        for name in names {
+            let (from, to) = (self.tree(&name)?, other.tree(&name)?);
+            let mut report = CopyProgress {
+                tree: name,
+                copied: 0,
+                total,
+                done: false,
+            };
+            for r in from.scan_prefix(prefix) {
+                let (k, v) = r?;
+                let _writes = other.write_gate();
+                to.insert(&k, &v)?;
+                other.audit("copy", &report.tree, &k, Some(&v));
+                let ckey = self.tree_ckey(&report.tree, &k);
+                if other.cache.contains_key(&ckey) {
+                    other.cache_put(ckey, Bytes::from(v.to_vec()));
+                } else {
+                    other.forget_missing(&ckey);
+                    other.filter_insert(&ckey);
+                }
+                report.copied += 1;
+                report.total += 1;
+                if report.copied.is_multiple_of(PROGRESS_INTERVAL) {
+                    progress(&report);
+                }
+            }
+            other.recount(&report.tree);
+            report.done = true;
+            progress(&report);
+            info!("Copied {} records of tree({})", report.copied, report.tree);
+            total = report.total;
+        }
+        Ok(total)
+    }
+}
+
+#[test]
+fn copy_to() {
+    use crate::StorageData;
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let other: Storage = Storage::builder().temporary().open().unwrap();
+    for i in 0..1500u64 {
+        store.insert(i, i.to_string());
+    }
+    store.insert("user/a", "a".to_string());
+    store.insert("user/b", "b".to_string());
+    store
+        .db
+        .open_tree("Other")
+        .unwrap()
+        .insert("other", "other")
+        .unwrap();
+    other.insert("user/a", "cached".to_string());
+
+    let mut reports = vec![];
+    let copied = store
+        .copy_to_with(&other, &[&String::name()], b"user/", |p| {
+            reports.push(p.clone())
+        })
+        .unwrap();
+    assert_eq!(2, copied);
+    assert_eq!(1, reports.len());
+    assert!(reports[0].done);
+    assert_eq!(Some("a".to_string()), other.get::<String>("user/a"));
+    assert!(!other.contains_key::<String>(7u64));
+
+    let mut reports = vec![];
+    let copied = store
+        .copy_to_with(&other, &[], &[], |p| reports.push(p.clone()))
+        .unwrap();
+    assert_eq!(1503, copied);
+    let strings: Vec<_> = reports
+        .iter()
+        .filter(|p| p.tree == String::name())
+        .collect();
+    assert_eq!(
+        vec![1000, 1502],
+        strings.iter().map(|p| p.copied).collect::<Vec<_>>()
+    );
+    assert_eq!(Some("7".to_string()), other.get::<String>(7u64));
+    assert!(other
+        .db
+        .open_tree("Other")
+        .unwrap()
+        .contains_key("other")
+        .unwrap());
+
+    let follower: Storage = Storage::builder()
+        .temporary()
+        .read_only(true)
+        .open()
+        .unwrap();
+    assert!(store.copy_to(&follower, &[]).is_err());
+    assert!(!follower.contains_key::<String>(7u64));
+}
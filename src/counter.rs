@@ -0,0 +1,79 @@
+use color_eyre::eyre::Result;
+
+use crate::Storage;
+
+pub(crate) const COUNTER_TREE_NAME: &str = "COUNTER";
+
+fn decode_counter(v: Option<&[u8]>) -> u64 {
+    v.and_then(|v| v.try_into().ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or(0)
+}
+
+// COUNTER
+// Counters are plain big-endian u64 values updated in place, they don't go
+// through the cache or the structured data envelope.
+impl Storage {
+    /// Add `delta` to the counter and return the new value, saturating at `u64::MAX`.
+    /// 0 if it could not be updated.
+    pub fn incr(&self, name: &str, delta: u64) -> u64 {
+        self.try_incr(name, delta).unwrap_or(0)
+    }
+
+    pub fn try_incr(&self, name: &str, delta: u64) -> Result<u64> {
+        self.update_counter(name, |n| n.saturating_add(delta))
+    }
+
+    /// Subtract `delta` from the counter and return the new value, saturating at 0.
+    /// 0 if it could not be updated.
+    pub fn decr(&self, name: &str, delta: u64) -> u64 {
+        self.try_decr(name, delta).unwrap_or(0)
+    }
+
+    pub fn try_decr(&self, name: &str, delta: u64) -> Result<u64> {
+        self.update_counter(name, |n| n.saturating_sub(delta))
+    }
+
+    pub fn counter(&self, name: &str) -> u64 {
+        let Ok(tree) = self.tree(COUNTER_TREE_NAME) else {
+            return 0;
+        };
+        decode_counter(tree.get(name).ok().flatten().as_deref())
+    }
+
+    fn update_counter(&self, name: &str, f: impl Fn(u64) -> u64) -> Result<u64> {
+        self.check_writable()?;
+        let tree = self.tree(COUNTER_TREE_NAME)?;
+        let _writes = self.write_gate();
+        let previous =
+            tree.fetch_and_update(name, |v| Some(f(decode_counter(v)).to_be_bytes().to_vec()))?;
+        Ok(f(decode_counter(previous.as_deref())))
+    }
+}
+#[test]
+fn counter() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    assert_eq!(0, store.counter("test"));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    store.incr("test", 2);
+                }
+            })
+        })
+        .collect();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+    assert_eq!(800, store.counter("test"));
+    assert_eq!(790, store.decr("test", 10));
+    assert_eq!(0, store.decr("test", 1000));
+
+    let follower: Storage = Storage::builder()
+        .temporary()
+        .read_only(true)
+        .open()
+        .unwrap();
+    assert!(follower.try_incr("test", 1).is_err());
+    assert_eq!(0, follower.counter("test"));
+}
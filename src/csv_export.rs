@@ -0,0 +1,111 @@
+use std::io::Write;
+
+use color_eyre::eyre::Result;
+use csv::{ReaderBuilder, WriterBuilder};
+use tracing::debug;
+
+use crate::{Storage, StorageData};
+
+// the column names serde gives the fields of `value`
+fn columns<T: StorageData>(value: &T) -> Result<Vec<String>> {
+    let mut writer = WriterBuilder::new().from_writer(vec![]);
+    writer.serialize(value)?;
+    let encoded = writer.into_inner()?;
+    let mut reader = ReaderBuilder::new().from_reader(encoded.as_slice());
+    Ok(reader.headers()?.iter().map(str::to_string).collect())
+}
+
+// EXPORT
+impl Storage {
+    /// Write all records of `T` as csv, with the record key in the first column and one
+    /// column per field of `T` after it.
+    ///
+    /// Only flat types work, fields that are structs, maps or sequences fail the export.
+    /// Records are read from the db without rewriting them, undecodable ones are
+    /// skipped. Returns the number of exported records.
+    pub fn export_csv<T: StorageData, W: Write>(&self, writer: W) -> Result<usize> {
+        let mut writer = WriterBuilder::new().has_headers(false).from_writer(writer);
+        let tree = self.tree(T::name())?;
+        let mut exported = 0;
+        for r in tree.iter() {
+            let (k, v) = r?;
+            let key = String::from_utf8_lossy(&k);
+            let Some(value) = self.decode_with::<T>(&k, &v, false) else {
+                debug!("Skip undecodable tree({}) key({})", T::name(), key);
+                continue;
+            };
+            if exported == 0 {
+                writer.write_field("key")?;
+                writer.write_record(columns(&value)?)?;
+            }
+            writer.write_field(key.as_bytes())?;
+            writer.serialize(&value)?;
+            exported += 1;
+        }
+        writer.flush()?;
+        Ok(exported)
+    }
+}
+
+#[test]
+fn csv_export() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize)]
+    struct CsvTest {
+        a: u32,
+        b: String,
+        c: Option<f64>,
+    }
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert(
+        "1",
+        CsvTest {
+            a: 1,
+            b: "one, with comma".to_string(),
+            c: Some(0.5),
+        },
+    );
+    store.insert(
+        "2",
+        CsvTest {
+            a: 2,
+            b: "two".to_string(),
+            c: None,
+        },
+    );
+
+    let mut out = vec![];
+    assert_eq!(2, store.export_csv::<CsvTest, _>(&mut out).unwrap());
+    assert_eq!(
+        "key,a,b,c\n1,1,\"one, with comma\",0.5\n2,2,two,\n",
+        String::from_utf8(out).unwrap()
+    );
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn csv_export_read_only() {
+    use serde::{Deserialize, Serialize};
+
+    use crate::{envelope, Format, StorageConfig};
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize)]
+    struct CsvTest {
+        a: u32,
+    }
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        format: Format::Json,
+        ..Default::default()
+    });
+    let bytes =
+        envelope::checksummed(envelope::encode(Format::Bincode, None, &CsvTest { a: 1 }).unwrap());
+    let tree = store.db.open_tree(CsvTest::name()).unwrap();
+    tree.insert("a", bytes.clone()).unwrap();
+
+    assert_eq!(1, store.export_csv::<CsvTest, _>(vec![]).unwrap());
+    assert_eq!(bytes, tree.get("a").unwrap().unwrap().to_vec());
+}
@@ -0,0 +1,140 @@
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use parking_lot::Mutex;
+use tracing::warn;
+
+use crate::{Storage, StorageData, StorageError, StorageKey};
+
+// threads running deadline operations, shared by every storage of the process
+const DEADLINE_WORKERS: usize = 4;
+// operations waiting for a worker, beyond it calls time out at once
+const DEADLINE_QUEUE: usize = 16;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// DEADLINE
+// The db work runs on a worker of a small pool, the caller stops waiting once the
+// deadline passes. A stalled operation is not cancelled and keeps its worker until it
+// completes. When every worker is busy and the queue is full the call times out without
+// waiting, so stalls cannot pile up threads.
+impl Storage {
+    pub fn get_with_deadline<T: StorageData + Send + 'static>(
+        &self,
+        key: impl Into<StorageKey>,
+        deadline: Duration,
+    ) -> Result<Option<T>> {
+        let key = key.into();
+        if let Some(v) = self.cache_get(&self.ckey::<T>(&key)) {
+            self.cache_counters.hit();
+            return Ok(self.decode(&key, &v));
+        }
+        let store = self.clone();
+        run_with_deadline(deadline, move || store.get::<T>(key))
+    }
+
+    pub fn insert_with_deadline<T: StorageData + Send + 'static>(
+        &self,
+        key: impl Into<StorageKey>,
+        value: T,
+        deadline: Duration,
+    ) -> Result<Option<T>> {
+        let key = key.into();
+        let store = self.clone();
+        run_with_deadline(deadline, move || store.insert(key, value))
+    }
+}
+
+fn run_with_deadline<R, F>(deadline: Duration, f: F) -> Result<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> R + Send + 'static,
+{
+    let (tx, rx) = mpsc::sync_channel(1);
+    let job: Job = Box::new(move || {
+        let _ = tx.send(f());
+    });
+    // disconnected only when no worker could be spawned
+    if pool().try_send(job).is_err() {
+        return Err(StorageError::Timeout(deadline).into());
+    }
+    rx.recv_timeout(deadline)
+        .map_err(|_| StorageError::Timeout(deadline).into())
+}
+
+fn pool() -> &'static SyncSender<Job> {
+    static POOL: OnceLock<SyncSender<Job>> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let (tx, rx) = mpsc::sync_channel::<Job>(DEADLINE_QUEUE);
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..DEADLINE_WORKERS {
+            let rx = rx.clone();
+            let spawned = std::thread::Builder::new()
+                .name(format!("storage-deadline-{}", i))
+                .spawn(move || work(&rx));
+            if let Err(e) = spawned {
+                warn!("Spawn deadline worker failed: {}", e);
+            }
+        }
+        tx
+    })
+}
+
+// run jobs until the pool is gone, which is never as it lives in a static
+fn work(jobs: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = jobs.lock().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
+
+#[test]
+fn deadline() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let budget = Duration::from_secs(5);
+    store
+        .insert_with_deadline("test", "test".to_string(), budget)
+        .unwrap();
+    assert_eq!(
+        Some("test".to_string()),
+        store.get_with_deadline::<String>("test", budget).unwrap()
+    );
+
+    let err = run_with_deadline(Duration::from_millis(10), || {
+        std::thread::sleep(Duration::from_millis(200))
+    })
+    .unwrap_err();
+    assert_eq!(
+        Some(&StorageError::Timeout(Duration::from_millis(10))),
+        err.downcast_ref::<StorageError>()
+    );
+
+    // with every worker stalled and the queue full, calls fail without waiting
+    let stall = Arc::new(parking_lot::RwLock::new(()));
+    let stalled = stall.write();
+    let (started, running) = mpsc::channel();
+    for _ in 0..DEADLINE_WORKERS {
+        let (stall, started) = (stall.clone(), started.clone());
+        let _ = run_with_deadline(Duration::ZERO, move || {
+            started.send(()).unwrap();
+            drop(stall.read());
+        });
+    }
+    // the workers may still be starting, or finishing the sleep above
+    for _ in 0..DEADLINE_WORKERS {
+        running.recv().unwrap();
+    }
+    for _ in 0..DEADLINE_QUEUE {
+        let stall = stall.clone();
+        let _ = run_with_deadline(Duration::ZERO, move || drop(stall.read()));
+    }
+    let started = std::time::Instant::now();
+    assert!(store.get_with_deadline::<String>("other", budget).is_err());
+    assert!(started.elapsed() < budget);
+    drop(stalled);
+}
@@ -0,0 +1,73 @@
+use std::path::Path;
+
+use color_eyre::eyre::Report;
+use tracing::debug;
+
+use crate::StorageError;
+
+// file in the db directory naming the process that opened it last
+const PID_FILE_NAME: &str = "storage_hal.pid";
+
+// DIRECTORY LOCK
+// sled locks the db directory for as long as it is open and fails with a bare
+// `WouldBlock` when another process (or another open in this one) holds it. The pid
+// file is only read in that case, so while the lock is contended it names the holder,
+// unless the holder opened the db with plain sled.
+
+// `e` from opening the db at `path`, as `StorageError::AlreadyLocked` when the lock
+// is held
+pub(crate) fn open_error(path: &Path, e: std::io::Error) -> Report {
+    if e.kind() != fs4::lock_contended_error().kind() {
+        return e.into();
+    }
+    let holder_pid = std::fs::read_to_string(path.join(PID_FILE_NAME))
+        .ok()
+        .and_then(|pid| pid.trim().parse().ok());
+    StorageError::AlreadyLocked {
+        path: path.display().to_string(),
+        holder_pid,
+    }
+    .into()
+}
+
+pub(crate) fn write_pid(path: &Path) {
+    if let Err(e) = std::fs::write(path.join(PID_FILE_NAME), std::process::id().to_string()) {
+        debug!("Write pid file of {} failed: {}", path.display(), e);
+    }
+}
+
+#[test]
+fn already_locked() {
+    use std::time::Duration;
+
+    use crate::{Storage, StorageConfig};
+
+    let dir = crate::TestDir::new("already_locked");
+    let config = StorageConfig {
+        db_path: dir.path("db"),
+        ..Default::default()
+    };
+    let _store: Storage = Storage::new(&config);
+    let e = Storage::builder().path(&config.db_path).open().unwrap_err();
+    assert_eq!(
+        Some(&StorageError::AlreadyLocked {
+            path: config.db_path.clone(),
+            holder_pid: Some(std::process::id()),
+        }),
+        e.downcast_ref::<StorageError>()
+    );
+
+    let retry = dir.path("retry.db");
+    let db = sled::open(&retry).unwrap();
+    let holder = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        drop(db);
+    });
+    let store = Storage::builder()
+        .path(&retry)
+        .retry_locked(6, Duration::from_millis(20))
+        .open()
+        .unwrap();
+    holder.join().unwrap();
+    store.insert("a", "1".to_string());
+}
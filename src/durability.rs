@@ -0,0 +1,66 @@
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{Storage, StorageData, StorageKey};
+
+/// When a write is considered done.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Durability {
+    /// Return once the write is in the db, it reaches disk with the next flush.
+    #[default]
+    Eventual,
+    /// Flush the db before returning, the write survives a power failure.
+    Flush,
+}
+
+// DURABILITY
+// sled flushes the whole db, a `Flush` write also makes all earlier `Eventual` writes
+// durable, including the pending ones of write-back mode.
+impl Storage {
+    /// Like `insert`, flushing the db after the write when `durability` is `Flush`.
+    pub fn insert_with_durability<T: StorageData>(
+        &self,
+        key: impl Into<StorageKey>,
+        value: T,
+        durability: Durability,
+    ) -> Result<Option<T>> {
+        let key = key.into();
+        let tree = self.tree(T::name())?;
+        let previous = self.try_insert_in(&tree, self.ckey::<T>(&key), &key, value)?;
+        self.make_durable(durability)?;
+        Ok(previous)
+    }
+
+    pub(crate) fn make_durable(&self, durability: Durability) -> Result<()> {
+        if durability == Durability::Flush {
+            let _slow = self.slow_db_op("flush");
+            if let Some(write_back) = self.write_back() {
+                let _writes = self.write_gate();
+                write_back.persist(&self.db)?;
+            }
+            self.db.flush()?;
+            self.record_flush();
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn durability() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store
+        .insert_with_durability("lazy", "1".to_string(), Durability::Eventual)
+        .unwrap();
+
+    let previous = store
+        .insert_with_durability("lazy", "2".to_string(), Durability::Flush)
+        .unwrap();
+    assert_eq!(Some("1".to_string()), previous);
+    assert!(store.health().last_flush_age.is_some());
+
+    let strings = store.collection::<String>().unwrap();
+    strings
+        .insert_with_durability("final", "3".to_string(), Durability::Flush)
+        .unwrap();
+    assert_eq!(Some("3".to_string()), store.get::<String>("final"));
+}
@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::envelope::{self, Header, FLAG_CHECKSUM, FLAG_ENCRYPTED, HEADER_LEN};
+use crate::{Storage, StorageConfig};
+
+// the payload is `| key id (4, be) | nonce (12) | AES-256-GCM ciphertext and tag |`,
+// authenticated together with the header (without the checksum flag, the checksum is
+// added after encryption) and, with `FLAG_BOUND`, the place of the record
+// `| db tree name length (4, be) | db tree name | key |`, so a value copied to another
+// key or tree fails to decrypt. Values written before key ids existed lack the id and
+// were encrypted with key 0.
+const KEY_ID_LEN: usize = 4;
+// envelope flag, the encrypted payload starts with the id of its key
+const FLAG_KEY_ID: u8 = 4;
+// envelope flag, the place of the record is authenticated with the header
+const FLAG_BOUND: u8 = 64;
+const NONCE_LEN: usize = 12;
+
+/// 256-bit key for encrypting values at rest.
+// not `Copy`, so key material isn't duplicated implicitly
+#[allow(missing_copy_implementations)]
+#[derive(Clone, Deserialize)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub const fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Parse a key from 64 hex digits.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim().as_bytes();
+        if hex.len() != 64 {
+            return Err(eyre!("encryption key must be 64 hex digits"));
+        }
+        let mut bytes = [0; 32];
+        for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+            let pair = std::str::from_utf8(pair)?;
+            *byte = u8::from_str_radix(pair, 16)
+                .map_err(|_| eyre!("encryption key must be 64 hex digits"))?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Source of encryption keys by id, e.g. a KMS client.
+pub trait KeyProvider: Debug + Send + Sync {
+    fn key(&self, id: u32) -> Result<EncryptionKey>;
+}
+
+/// Keys from environment variables named `{prefix}{id}`, holding 64 hex digits.
+#[derive(Debug, Clone)]
+pub struct EnvKeyProvider {
+    pub prefix: String,
+}
+
+impl KeyProvider for EnvKeyProvider {
+    fn key(&self, id: u32) -> Result<EncryptionKey> {
+        let name = format!("{}{}", self.prefix, id);
+        let hex = std::env::var(&name).map_err(|e| eyre!("key env({}): {}", name, e))?;
+        EncryptionKey::from_hex(&hex)
+    }
+}
+
+/// Keys from files named `{id}.key` in `dir`, holding 32 raw bytes or 64 hex digits.
+#[derive(Debug, Clone)]
+pub struct FileKeyProvider {
+    pub dir: PathBuf,
+}
+
+impl KeyProvider for FileKeyProvider {
+    fn key(&self, id: u32) -> Result<EncryptionKey> {
+        let path = self.dir.join(format!("{}.key", id));
+        let bytes = std::fs::read(&path).map_err(|e| eyre!("key file({:?}): {}", path, e))?;
+        match <[u8; 32]>::try_from(bytes.as_slice()) {
+            Ok(key) => Ok(EncryptionKey::new(key)),
+            Err(_) => EncryptionKey::from_hex(std::str::from_utf8(&bytes)?),
+        }
+    }
+}
+
+// the active key new values are encrypted with, and every key values can be read with
+pub(crate) struct Keyring {
+    active: u32,
+    ciphers: RwLock<HashMap<u32, Aes256Gcm>>,
+    provider: Option<Arc<dyn KeyProvider>>,
+}
+
+impl Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("active", &self.active)
+            .field("ids", &self.ciphers.read().keys().collect::<Vec<_>>())
+            .field("provider", &self.provider)
+            .finish()
+    }
+}
+
+impl Keyring {
+    pub(crate) fn new(
+        active: u32,
+        key: &EncryptionKey,
+        retired: &HashMap<u32, EncryptionKey>,
+    ) -> Self {
+        let mut ciphers: HashMap<_, _> = retired
+            .iter()
+            .map(|(id, key)| (*id, Aes256Gcm::new(&key.0.into())))
+            .collect();
+        ciphers.insert(active, Aes256Gcm::new(&key.0.into()));
+        Self {
+            active,
+            ciphers: RwLock::new(ciphers),
+            provider: None,
+        }
+    }
+
+    // `None` when encryption is not configured. The active key is resolved right away,
+    // so a missing key fails the open rather than the first write.
+    pub(crate) fn from_config(config: &StorageConfig) -> Result<Option<Self>> {
+        let key = match (&config.encryption_key, &config.key_provider) {
+            (Some(key), _) => key.clone(),
+            (None, Some(provider)) => provider.key(config.encryption_key_id)?,
+            (None, None) => return Ok(None),
+        };
+        let mut keyring = Self::new(config.encryption_key_id, &key, &config.decryption_keys);
+        keyring.provider = config.key_provider.clone();
+        Ok(Some(keyring))
+    }
+
+    fn cipher(&self, id: u32) -> Result<Aes256Gcm> {
+        if let Some(cipher) = self.ciphers.read().get(&id) {
+            return Ok(cipher.clone());
+        }
+        let provider = self
+            .provider
+            .as_ref()
+            .ok_or_else(|| eyre!("no encryption key with id {}", id))?;
+        let cipher = Aes256Gcm::new(&provider.key(id)?.0.into());
+        self.ciphers.write().insert(id, cipher.clone());
+        Ok(cipher)
+    }
+
+    // the id of the key an encrypted value was written with
+    pub(crate) fn key_id(bytes: &[u8]) -> Result<u32> {
+        let (header, payload) = envelope::split(bytes);
+        if header.flags & FLAG_KEY_ID == 0 {
+            return Ok(0);
+        }
+        let id = payload
+            .get(..KEY_ID_LEN)
+            .ok_or_else(|| eyre!("encrypted value is truncated"))?;
+        Ok(u32::from_be_bytes([id[0], id[1], id[2], id[3]]))
+    }
+
+    // encrypt the payload of an enveloped value stored at `key` of the db tree `tree`
+    // with the active key and a fresh nonce
+    pub(crate) fn seal(&self, buf: Vec<u8>, tree: &str, key: &[u8]) -> Result<Vec<u8>> {
+        self.seal_at(buf, Some((tree, key)))
+    }
+
+    // `place: None` seals like values written before records were bound
+    fn seal_at(&self, buf: Vec<u8>, place: Option<(&str, &[u8])>) -> Result<Vec<u8>> {
+        let (mut header, payload) = envelope::split(&buf);
+        header.flags |= FLAG_ENCRYPTED | FLAG_KEY_ID;
+        if place.is_some() {
+            header.flags |= FLAG_BOUND;
+        }
+        let mut sealed = Vec::with_capacity(buf.len() + KEY_ID_LEN + NONCE_LEN + 16);
+        header.write(&mut sealed);
+        let aad = match place {
+            Some((tree, key)) => bound(&sealed, tree, key),
+            None => sealed.clone(),
+        };
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher(self.active)?
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: payload,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| eyre!("encryption failed"))?;
+        sealed.extend_from_slice(&self.active.to_be_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    // the enveloped value stored at `key` of the db tree `tree` as it was before `seal`,
+    // without the checksum
+    pub(crate) fn unseal(&self, bytes: &[u8], tree: &str, key: &[u8]) -> Result<Vec<u8>> {
+        let (header, mut payload) = envelope::split(bytes);
+        let mut aad = Vec::with_capacity(HEADER_LEN);
+        Header {
+            flags: header.flags & !FLAG_CHECKSUM,
+            ..header
+        }
+        .write(&mut aad);
+        if header.flags & FLAG_BOUND != 0 {
+            aad = bound(&aad, tree, key);
+        }
+        let id = Self::key_id(bytes)?;
+        if header.flags & FLAG_KEY_ID != 0 {
+            payload = &payload[KEY_ID_LEN..];
+        }
+        if payload.len() < NONCE_LEN {
+            return Err(eyre!("encrypted value is truncated"));
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher(id)?
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| eyre!("decryption failed, wrong key or damaged value"))?;
+        let mut buf = Vec::with_capacity(HEADER_LEN + plaintext.len());
+        Header {
+            flags: header.flags & !(FLAG_ENCRYPTED | FLAG_KEY_ID | FLAG_BOUND | FLAG_CHECKSUM),
+            ..header
+        }
+        .write(&mut buf);
+        buf.extend_from_slice(&plaintext);
+        Ok(buf)
+    }
+}
+
+// the associated data of a value with the header `header`, stored at `key` of the db
+// tree `tree`
+fn bound(header: &[u8], tree: &str, key: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(header.len() + 4 + tree.len() + key.len());
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(&(tree.len() as u32).to_be_bytes());
+    aad.extend_from_slice(tree.as_bytes());
+    aad.extend_from_slice(key);
+    aad
+}
+
+impl Storage {
+    /// Re-encrypt all values written under a retired key with the active key.
+    ///
+    /// Values that are not encrypted are left as they are. Once this returns, retired
+    /// keys can be dropped from `StorageConfig::decryption_keys`. Returns the number of
+    /// rewritten records.
+    pub fn rewrap_all(&self) -> Result<usize> {
+        let keyring = self
+            .keyring
+            .as_ref()
+            .ok_or_else(|| eyre!("no encryption key configured"))?;
+        let mut rewrapped = 0;
+        for name in self.data_tree_names() {
+            let tree = self.tree(&name)?;
+            let db_name = self.tree_name(&name);
+            for r in tree.iter() {
+                let (k, v) = r?;
+                if envelope::split(&v).0.flags & FLAG_ENCRYPTED == 0
+                    || Keyring::key_id(&v)? == keyring.active
+                {
+                    continue;
+                }
+                let new = keyring
+                    .unseal(&v, &db_name, &k)
+                    .and_then(|plain| keyring.seal(plain, &db_name, &k))
+                    .map(|mut new| {
+                        envelope::append_checksum(&mut new);
+                        new
+                    })
+                    .map_err(|e| {
+                        eyre!("tree({}) key({}): {}", name, String::from_utf8_lossy(&k), e)
+                    })?;
+                // a concurrent write wins, it is under the active key already
+                if tree
+                    .compare_and_swap(&k, Some(&v), Some(new.clone()))?
+                    .is_ok()
+                {
+                    let ckey = self.tree_ckey(&name, &k);
+                    if self.cache.contains_key(&ckey) {
+                        self.cache_put(ckey, Bytes::from(new));
+                    }
+                    rewrapped += 1;
+                }
+            }
+        }
+        info!(
+            "Rewrapped {} records under key {}",
+            rewrapped, keyring.active
+        );
+        Ok(rewrapped)
+    }
+}
+
+#[test]
+fn encryption() {
+    use crate::{Storage, StorageConfig, StorageData};
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        encryption_key: Some(EncryptionKey::new([7; 32])),
+        ..Default::default()
+    });
+    let tree = store.db.open_tree(String::name()).unwrap();
+    tree.insert(
+        "plain",
+        envelope::checksummed(
+            envelope::encode(crate::Format::Bincode, None, &"plain".to_string()).unwrap(),
+        ),
+    )
+    .unwrap();
+    store.insert("secret", "secret".to_string());
+
+    let stored = tree.get("secret").unwrap().unwrap();
+    assert_eq!(
+        FLAG_ENCRYPTED,
+        envelope::split(&stored).0.flags & FLAG_ENCRYPTED
+    );
+    assert!(!stored.windows(6).any(|w| w == b"secret"));
+    assert_eq!(Some("secret".to_string()), store.decode(b"secret", &stored));
+    // unencrypted values stay readable
+    assert_eq!(Some("plain".to_string()), store.get::<String>("plain"));
+    // the expected value of a swap is compared in plaintext
+    store
+        .compare_and_swap(
+            "secret",
+            Some(&"secret".to_string()),
+            Some("new".to_string()),
+        )
+        .unwrap();
+    assert_eq!(Some("new".to_string()), store.get::<String>("secret"));
+
+    let keyring = |id, key| Keyring::new(id, &EncryptionKey::new(key), &HashMap::new());
+    // the record version survives encryption
+    let unseal = |key, bytes: &[u8]| keyring(0, key).unseal(bytes, "String", b"secret");
+    assert_eq!(
+        envelope::revision(&stored),
+        envelope::revision(&unseal([7; 32], &stored).unwrap())
+    );
+    assert!(unseal([8; 32], &stored).is_err());
+    let mut damaged = stored.to_vec();
+    damaged[HEADER_LEN + KEY_ID_LEN + NONCE_LEN] ^= 1;
+    assert!(unseal([7; 32], &damaged).is_err());
+
+    // values are bound to their record, a copy elsewhere doesn't decrypt
+    assert!(store.rename::<String>("secret", "renamed").unwrap());
+    assert_eq!(Some("new".to_string()), store.get::<String>("renamed"));
+    assert!(store.rename::<String>("renamed", "secret").unwrap());
+    let stored = tree.get("secret").unwrap().unwrap();
+    tree.insert("copy", stored.clone()).unwrap();
+    assert_eq!(None, store.get::<String>("copy"));
+    let tenant = store.namespace("tenant");
+    tenant.insert("other", "other".to_string());
+    let other = store
+        .db
+        .open_tree(tenant.tree_name("String").as_bytes())
+        .unwrap();
+    other.insert("secret", stored).unwrap();
+    assert_eq!(None, tenant.get::<String>("secret"));
+    let all: Vec<_> = store.iter_all_namespaced::<String>().collect();
+    assert_eq!(1, all.len());
+    assert_eq!("other", all[0].2);
+    store.register_type::<String>();
+    let corrupt: Vec<_> = store
+        .verify()
+        .unwrap()
+        .corrupt
+        .into_iter()
+        .map(|entry| entry.key.to_string())
+        .collect();
+    assert_eq!(vec!["copy", "secret"], corrupt);
+    // written before records were bound
+    let plain = envelope::encode(crate::Format::Bincode, None, &"unbound".to_string()).unwrap();
+    let unbound = keyring(0, [7; 32]).seal_at(plain, None).unwrap();
+    tree.insert("unbound", envelope::checksummed(unbound))
+        .unwrap();
+    assert_eq!(Some("unbound".to_string()), store.get::<String>("unbound"));
+}
+
+#[test]
+fn key_rotation() {
+    use crate::{Format, StorageConfig, StorageData};
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        encryption_key: Some(EncryptionKey::new([2; 32])),
+        encryption_key_id: 2,
+        decryption_keys: HashMap::from([(1, EncryptionKey::new([1; 32]))]),
+        ..Default::default()
+    });
+    // written before the rotation, under key 1
+    let old = Keyring::new(1, &EncryptionKey::new([1; 32]), &HashMap::new());
+    let tree = store.db.open_tree(String::name()).unwrap();
+    let plain = envelope::encode(Format::Bincode, None, &"old".to_string()).unwrap();
+    let sealed = old.seal(plain, "String", b"old").unwrap();
+    tree.insert("old", envelope::checksummed(sealed)).unwrap();
+    store.insert("new", "new".to_string());
+
+    assert_eq!(Some("old".to_string()), store.get::<String>("old"));
+    assert_eq!(1, store.rewrap_all().unwrap());
+    assert_eq!(0, store.rewrap_all().unwrap());
+    let stored = tree.get("old").unwrap().unwrap();
+    assert_eq!(2, Keyring::key_id(&stored).unwrap());
+    assert_eq!(Some("old".to_string()), store.get::<String>("old"));
+    assert_eq!(Some("new".to_string()), store.get::<String>("new"));
+}
+
+#[test]
+fn key_provider() {
+    use crate::StorageData;
+
+    let dir = crate::TestDir::new("key_provider");
+    std::fs::write(dir.path("1.key"), [1; 32]).unwrap();
+    std::fs::write(dir.path("2.key"), "02".repeat(32)).unwrap();
+    let provider = FileKeyProvider {
+        dir: dir.path("").into(),
+    };
+    assert!(provider.key(3).is_err());
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        encryption_key_id: 2,
+        key_provider: Some(Arc::new(provider)),
+        ..Default::default()
+    });
+    // key 1 is only fetched when a value written under it is read
+    let old = Keyring::new(1, &EncryptionKey::new([1; 32]), &HashMap::new());
+    let plain = envelope::encode(crate::Format::Bincode, None, &"old".to_string()).unwrap();
+    let tree = store.db.open_tree(String::name()).unwrap();
+    let sealed = old.seal(plain, "String", b"old").unwrap();
+    tree.insert("old", envelope::checksummed(sealed)).unwrap();
+    store.insert("new", "new".to_string());
+    assert_eq!(Some("old".to_string()), store.get::<String>("old"));
+    let stored = tree.get("new").unwrap().unwrap();
+    assert_eq!(2, Keyring::key_id(&stored).unwrap());
+    assert_eq!(Some("new".to_string()), store.decode(b"new", &stored));
+
+    assert!(EncryptionKey::from_hex("0g".repeat(32).as_str()).is_err());
+}
@@ -0,0 +1,435 @@
+use std::borrow::Cow;
+
+use color_eyre::eyre::{eyre, Result};
+
+use crate::codec::TypeCodec;
+use crate::{Format, StorageData};
+
+// every stored value starts with this header:
+// | magic (4) | layout (1) | format (1) | type version (4, be) | flags (1) | payload |
+// with `FLAG_REVISION` the record version (8, be) sits between flags and payload,
+// followed by the creation time (8, be) with `FLAG_CREATED`. Values written before the
+// envelope existed are bare bincode, the magic, layout and format byte all have to
+// match for a value to be read as enveloped.
+pub(crate) const MAGIC: [u8; 4] = *b"\xf5SHE";
+// the layout of the header above
+const LAYOUT: u8 = 1;
+// codec ids are below this, the ones this build doesn't know are from newer builds
+const FORMAT_IDS: u8 = 16;
+pub(crate) const HEADER_LEN: usize = 11;
+const FLAGS_AT: usize = HEADER_LEN - 1;
+
+// the payload is zstd compressed
+pub(crate) const FLAG_ZSTD: u8 = 1;
+// the payload is encrypted, see `encryption`
+pub(crate) const FLAG_ENCRYPTED: u8 = 2;
+// 4 and 64 are taken by `encryption`, for the key id and the bound record
+// the value ends with a CRC32 (be) of everything before it. Every stored value has
+// one, the flag only tells them from values still being written.
+pub(crate) const FLAG_CHECKSUM: u8 = 8;
+const CHECKSUM_LEN: usize = 4;
+// the header is followed by the record version, see `revision`
+pub(crate) const FLAG_REVISION: u8 = 16;
+const REVISION_LEN: usize = 8;
+// the header is followed by the creation time of the record, see `metadata`
+pub(crate) const FLAG_CREATED: u8 = 32;
+const CREATED_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub format: u8,
+    pub version: u32,
+    pub flags: u8,
+    // only written with `FLAG_REVISION`
+    pub revision: u64,
+    // microseconds since the epoch, only written with `FLAG_CREATED`
+    pub created: u64,
+}
+
+impl Header {
+    pub(crate) const fn new(format: u8, version: u32) -> Self {
+        Self {
+            format,
+            version,
+            flags: 0,
+            revision: 0,
+            created: 0,
+        }
+    }
+
+    // values written before the envelope existed are plain bincode at version 0
+    pub(crate) const fn legacy() -> Self {
+        Self::new(Format::Bincode.id(), 0)
+    }
+
+    // `None` for a codec this build doesn't know
+    pub(crate) const fn codec(&self) -> Option<Format> {
+        Format::from_id(self.format)
+    }
+
+    pub(crate) fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&MAGIC);
+        buf.push(LAYOUT);
+        buf.push(self.format);
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.push(self.flags);
+        if self.flags & FLAG_REVISION != 0 {
+            buf.extend_from_slice(&self.revision.to_be_bytes());
+        }
+        if self.flags & FLAG_CREATED != 0 {
+            buf.extend_from_slice(&self.created.to_be_bytes());
+        }
+    }
+}
+
+// payloads larger than `compress_above` bytes are compressed, when that saves space
+pub(crate) fn encode<T: StorageData>(
+    codec: impl Into<TypeCodec<T>>,
+    compress_above: Option<usize>,
+    value: &T,
+) -> Result<Vec<u8>> {
+    let codec = codec.into();
+    let mut buf = Vec::with_capacity(HEADER_LEN);
+    let mut header = Header::new(codec.id, T::version());
+    header.write(&mut buf);
+    codec.serialize_into(&mut buf, value)?;
+    if compress_above.is_some_and(|threshold| buf.len() - HEADER_LEN > threshold) {
+        let compressed = zstd::encode_all(&buf[HEADER_LEN..], 0)?;
+        if compressed.len() < buf.len() - HEADER_LEN {
+            header.flags |= FLAG_ZSTD;
+            buf.clear();
+            header.write(&mut buf);
+            buf.extend_from_slice(&compressed);
+        }
+    }
+    Ok(buf)
+}
+
+// whether the value was written with an envelope, rather than before it existed
+pub(crate) fn is_enveloped(bytes: &[u8]) -> bool {
+    bytes.len() >= HEADER_LEN
+        && bytes[..MAGIC.len()] == MAGIC
+        && bytes[MAGIC.len()] == LAYOUT
+        && bytes[MAGIC.len() + 1] < FORMAT_IDS
+}
+
+// split stored bytes into header and payload, without the checksum
+pub(crate) fn split(bytes: &[u8]) -> (Header, &[u8]) {
+    if !is_enveloped(bytes) {
+        return (Header::legacy(), bytes);
+    }
+    let mut header = Header {
+        format: bytes[5],
+        version: u32::from_be_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]),
+        flags: bytes[FLAGS_AT],
+        revision: 0,
+        created: 0,
+    };
+    let mut start = HEADER_LEN;
+    if header.flags & FLAG_REVISION != 0 {
+        if let Some(revision) = bytes.get(start..start + REVISION_LEN) {
+            header.revision = u64::from_be_bytes(revision.try_into().unwrap());
+            start += REVISION_LEN;
+        }
+    }
+    if header.flags & FLAG_CREATED != 0 {
+        if let Some(created) = bytes.get(start..start + CREATED_LEN) {
+            header.created = u64::from_be_bytes(created.try_into().unwrap());
+            start += CREATED_LEN;
+        }
+    }
+    let mut end = bytes.len();
+    if header.flags & FLAG_CHECKSUM != 0 {
+        end = end.saturating_sub(CHECKSUM_LEN).max(start);
+    }
+    (header, &bytes[start..end])
+}
+
+// rewrite the header of an encoded value, before it is sealed
+fn restamp(buf: &mut Vec<u8>, stamp: impl FnOnce(&mut Header)) {
+    if !is_enveloped(buf) {
+        return;
+    }
+    let (mut header, payload) = split(buf);
+    stamp(&mut header);
+    let mut stamped = Vec::with_capacity(HEADER_LEN + REVISION_LEN + CREATED_LEN + payload.len());
+    header.write(&mut stamped);
+    stamped.extend_from_slice(payload);
+    *buf = stamped;
+}
+
+// stamp an encoded value, before it is sealed, with its record version
+pub(crate) fn set_revision(buf: &mut Vec<u8>, revision: u64) {
+    restamp(buf, |header| {
+        header.flags |= FLAG_REVISION;
+        header.revision = revision;
+    });
+}
+
+// stamp an encoded value, before it is sealed, with the creation time of its record
+pub(crate) fn set_created(buf: &mut Vec<u8>, created: u64) {
+    restamp(buf, |header| {
+        header.flags |= FLAG_CREATED;
+        header.created = created;
+    });
+}
+
+// the encoded value as it was before `set_revision` and `set_created`
+pub(crate) fn without_stamps(bytes: &[u8]) -> Cow<'_, [u8]> {
+    let (header, payload) = split(bytes);
+    if !is_enveloped(bytes) || header.flags & (FLAG_REVISION | FLAG_CREATED) == 0 {
+        return Cow::Borrowed(bytes);
+    }
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    Header {
+        flags: header.flags & !(FLAG_REVISION | FLAG_CREATED),
+        ..header
+    }
+    .write(&mut buf);
+    buf.extend_from_slice(payload);
+    Cow::Owned(buf)
+}
+
+// the record version of stored bytes, 0 for values written without one
+pub(crate) fn revision(bytes: &[u8]) -> u64 {
+    split(bytes).0.revision
+}
+
+// the creation time of stored bytes, 0 for values written without one
+pub(crate) fn created(bytes: &[u8]) -> u64 {
+    split(bytes).0.created
+}
+
+// the last step of writing a value
+pub(crate) fn append_checksum(buf: &mut Vec<u8>) {
+    buf[FLAGS_AT] |= FLAG_CHECKSUM;
+    let checksum = crc32fast::hash(buf);
+    buf.extend_from_slice(&checksum.to_be_bytes());
+}
+
+// `buf` as the last step of writing it leaves it, for tests storing values themselves
+#[cfg(test)]
+pub(crate) fn checksummed(mut buf: Vec<u8>) -> Vec<u8> {
+    append_checksum(&mut buf);
+    buf
+}
+
+// legacy values pass, enveloped ones without a checksum are damaged
+pub(crate) fn verify(bytes: &[u8]) -> bool {
+    if !is_enveloped(bytes) {
+        return true;
+    }
+    if bytes[FLAGS_AT] & FLAG_CHECKSUM == 0 || bytes.len() < HEADER_LEN + CHECKSUM_LEN {
+        return false;
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+    crc32fast::hash(body).to_be_bytes() == checksum
+}
+
+// the value as `encode` wrote it
+pub(crate) fn without_checksum(bytes: &[u8]) -> Cow<'_, [u8]> {
+    let (header, payload) = split(bytes);
+    if !is_enveloped(bytes) || header.flags & FLAG_CHECKSUM == 0 {
+        return Cow::Borrowed(bytes);
+    }
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    Header {
+        flags: header.flags & !FLAG_CHECKSUM,
+        ..header
+    }
+    .write(&mut buf);
+    buf.extend_from_slice(payload);
+    Cow::Owned(buf)
+}
+
+// split stored bytes into header and the payload as the codec wrote it
+pub(crate) fn open(bytes: &[u8]) -> Result<(Header, Cow<'_, [u8]>)> {
+    let (header, payload) = split(bytes);
+    // decrypted by the storage before it gets here
+    if header.flags & FLAG_ENCRYPTED != 0 {
+        return Err(eyre!("value is encrypted"));
+    }
+    if header.flags & FLAG_ZSTD == 0 {
+        return Ok((header, Cow::Borrowed(payload)));
+    }
+    Ok((header, Cow::Owned(zstd::decode_all(payload)?)))
+}
+
+#[test]
+fn upgrade_on_get() {
+    use serde::{Deserialize, Serialize};
+
+    use crate::Storage;
+
+    #[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+    struct Test {
+        a: u32,
+        b: bool,
+    }
+
+    impl StorageData for Test {
+        fn name() -> String {
+            "EnvelopeTest".to_string()
+        }
+
+        fn version() -> u32 {
+            1
+        }
+    }
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let tree = store.db.open_tree(Test::name()).unwrap();
+    tree.insert("test", bincode::serialize(&3u32).unwrap())
+        .unwrap();
+
+    // without a migration the old layout is reported instead of misread
+    assert_eq!(None, store.get::<Test>("test"));
+
+    store.register_migration::<Test, _>(0, |old| Test {
+        a: bincode::deserialize(old).unwrap(),
+        b: true,
+    });
+    assert_eq!(Some(Test { a: 3, b: true }), store.get::<Test>("test"));
+    let stored = tree.get("test").unwrap().unwrap();
+    let header = split(&stored).0;
+    assert_eq!(
+        Header {
+            flags: FLAG_CHECKSUM | FLAG_REVISION,
+            revision: header.revision,
+            ..Header::new(Format::Bincode.id(), 1)
+        },
+        header
+    );
+
+    // a read-only storage upgrades in memory only
+    let follower: Storage = Storage::builder()
+        .temporary()
+        .read_only(true)
+        .open()
+        .unwrap();
+    let old = bincode::serialize(&3u32).unwrap();
+    let tree = follower.db.open_tree(Test::name()).unwrap();
+    tree.insert("test", old.clone()).unwrap();
+    follower.register_migration::<Test, _>(0, |old| Test {
+        a: bincode::deserialize(old).unwrap(),
+        b: true,
+    });
+    assert_eq!(Some(Test { a: 3, b: true }), follower.get::<Test>("test"));
+    assert_eq!(old, tree.get("test").unwrap().unwrap().to_vec());
+}
+
+#[test]
+fn compression() {
+    use crate::{Storage, StorageConfig};
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        compress_threshold: Some(4096),
+        ..Default::default()
+    });
+    let large = "a".repeat(16 * 1024);
+    store.insert("large", large.clone());
+    store.insert("small", "a".to_string());
+    let tree = store.db.open_tree(String::name()).unwrap();
+    let stored = tree.get("large").unwrap().unwrap();
+    assert_eq!(FLAG_ZSTD, split(&stored).0.flags & FLAG_ZSTD);
+    assert!(stored.len() < 1024);
+    assert_eq!(Some(large), store.decode::<String>(b"large", &stored));
+    let stored = tree.get("small").unwrap().unwrap();
+    assert_eq!(0, split(&stored).0.flags & FLAG_ZSTD);
+    assert_eq!(
+        Some("a".to_string()),
+        store.decode::<String>(b"small", &stored)
+    );
+}
+
+#[test]
+fn compress_attribute() {
+    use serde::{Deserialize, Serialize};
+
+    use crate::Storage;
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+    #[storage(compress)]
+    struct Block {
+        txs: Vec<u64>,
+    }
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let block = Block { txs: vec![7; 1024] };
+    store.insert("block", block.clone());
+    store.insert("small", "a".repeat(1024));
+    let stored = store
+        .db
+        .open_tree(Block::name())
+        .unwrap()
+        .get("block")
+        .unwrap()
+        .unwrap();
+    assert_eq!(FLAG_ZSTD, split(&stored).0.flags & FLAG_ZSTD);
+    assert_eq!(Some(block), store.decode::<Block>(b"block", &stored));
+    let stored = store
+        .db
+        .open_tree(String::name())
+        .unwrap()
+        .get("small")
+        .unwrap()
+        .unwrap();
+    assert_eq!(0, split(&stored).0.flags & FLAG_ZSTD);
+}
+
+#[test]
+fn checksum() {
+    use crate::{Storage, StorageError};
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert("test", "test".to_string());
+    let tree = store.db.open_tree(String::name()).unwrap();
+    let stored = tree.get("test").unwrap().unwrap();
+    assert!(verify(&stored));
+    assert_eq!(bincode::serialize("test").unwrap(), split(&stored).1);
+
+    let mut damaged = stored.to_vec();
+    damaged[HEADER_LEN] ^= 1;
+    tree.insert("damaged", damaged).unwrap();
+    let e = store.try_get::<String>("damaged").unwrap_err();
+    assert_eq!(
+        Some(&StorageError::Corrupted("damaged".to_string())),
+        e.downcast_ref::<StorageError>()
+    );
+    assert_eq!(None, store.get::<String>("damaged"));
+    assert_eq!(
+        Some("test".to_string()),
+        store.try_get::<String>("test").unwrap()
+    );
+
+    // a flipped flag doesn't turn the check off
+    let mut unflagged = stored.to_vec();
+    unflagged[FLAGS_AT] &= !FLAG_CHECKSUM;
+    tree.insert("unflagged", unflagged).unwrap();
+    assert!(store.try_get::<String>("unflagged").is_err());
+}
+
+#[test]
+fn legacy_lookalike() {
+    use crate::Storage;
+
+    // the length prefix of this bincode string starts with b"SH"
+    let legacy = "x".repeat(0x4853);
+    let bytes = bincode::serialize(&legacy).unwrap();
+    assert_eq!(b"SH", &bytes[..2]);
+    assert!(!is_enveloped(&bytes));
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let tree = store.db.open_tree(String::name()).unwrap();
+    tree.insert("legacy", bytes).unwrap();
+    assert_eq!(Some(legacy), store.get::<String>("legacy"));
+
+    // the magic alone doesn't make an envelope
+    let mut lookalike = MAGIC.to_vec();
+    lookalike.extend_from_slice(&[LAYOUT + 1, 0, 0, 0, 0, 0, 0]);
+    assert!(!is_enveloped(&lookalike));
+    lookalike[MAGIC.len()] = LAYOUT;
+    lookalike[MAGIC.len() + 1] = FORMAT_IDS;
+    assert!(!is_enveloped(&lookalike));
+}
@@ -0,0 +1,96 @@
+use std::fmt::{Debug, Display};
+use std::time::Duration;
+
+/// Errors reported by storage operations.
+///
+/// They are returned inside `color_eyre::eyre::Report`, use `downcast_ref::<StorageError>()`
+/// to match on them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageError {
+    /// The operation did not finish within its deadline.
+    Timeout(Duration),
+    /// The sequence reached `u64::MAX` under `SequenceOverflow::Error`.
+    SequenceOverflow(String),
+    /// The stored bytes of the key don't match their checksum.
+    Corrupted(String),
+    /// The target key of a `rename` or `move_to` holds a value already.
+    KeyExists(String),
+    /// The write would exceed the quota of the tree or namespace, see `Quota`.
+    QuotaExceeded(String),
+    /// The stored bytes of the value are larger than `StorageConfig::max_value_size`.
+    ValueTooLarge { size: usize, limit: usize },
+    /// A key refused by `KeyRules`, with the reason.
+    InvalidKey(String),
+    /// The storage was closed with `Storage::close`.
+    Closed,
+    /// The storage was opened with `StorageConfig::read_only`, see `Storage::follow`.
+    ReadOnly,
+    /// The config failed `StorageConfig::validate`, with the reason.
+    InvalidConfig(String),
+    /// The db at `path` is held open by another process, or another `Storage` of this
+    /// one. `holder_pid` is the holder's process id when it left one behind.
+    AlreadyLocked {
+        path: String,
+        holder_pid: Option<u32>,
+    },
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout(deadline) => write!(f, "operation timed out after {:?}", deadline),
+            Self::SequenceOverflow(name) => write!(f, "sequence({}) overflowed", name),
+            Self::Corrupted(key) => write!(f, "key({}) is corrupted", key),
+            Self::KeyExists(key) => write!(f, "key({}) exists", key),
+            Self::QuotaExceeded(scope) => write!(f, "quota of {} exceeded", scope),
+            Self::ValueTooLarge { size, limit } => {
+                write!(
+                    f,
+                    "value of {} bytes exceeds the limit of {} bytes",
+                    size, limit
+                )
+            }
+            Self::InvalidKey(reason) => write!(f, "invalid key: {}", reason),
+            Self::Closed => write!(f, "storage is closed"),
+            Self::ReadOnly => write!(f, "storage is read-only"),
+            Self::InvalidConfig(reason) => write!(f, "invalid config: {}", reason),
+            Self::AlreadyLocked {
+                path,
+                holder_pid: Some(pid),
+            } => write!(f, "db {} is locked by process {}", path, pid),
+            Self::AlreadyLocked { path, .. } => write!(f, "db {} is locked", path),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// Failure of `Storage::compare_and_swap`.
+#[derive(Debug)]
+pub enum CasError<T> {
+    /// The stored value was not the expected one.
+    Conflict {
+        current: Option<T>,
+        proposed: Option<T>,
+    },
+    Io(std::io::Error),
+}
+
+impl<T> From<std::io::Error> for CasError<T> {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl<T: Debug> Display for CasError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Conflict { current, .. } => {
+                write!(f, "compare and swap conflict, current value: {:?}", current)
+            }
+            Self::Io(e) => write!(f, "compare and swap failed: {}", e),
+        }
+    }
+}
+
+impl<T: Debug> std::error::Error for CasError<T> {}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use moka::Expiry;
+use serde::{Deserialize, Serialize};
+
+use crate::{split_ckey, StorageConfig};
+
+/// Expiry of the cached values of one type, replacing `cache_time_to_live` and
+/// `cache_time_to_idle` for it. In seconds, `None` never expires.
+///
+/// Like with the cache wide settings, expired values are removed from the db too.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CachePolicy {
+    pub time_to_live: Option<u64>,
+    pub time_to_idle: Option<u64>,
+}
+
+impl CachePolicy {
+    // time left of a value last written at `modified_at`, as of `now`
+    fn remaining(&self, modified_at: Instant, now: Instant) -> Option<Duration> {
+        let ttl = self
+            .time_to_live
+            .map(|v| Duration::from_secs(v).saturating_sub(now - modified_at));
+        let tti = self.time_to_idle.map(Duration::from_secs);
+        match (ttl, tti) {
+            (Some(ttl), Some(tti)) => Some(ttl.min(tti)),
+            (ttl, tti) => ttl.or(tti),
+        }
+    }
+}
+
+// EXPIRY
+// Only installed when `cache_policies` is set, it then also implements the cache wide
+// settings, which moka would apply on top of any per entry expiry.
+#[derive(Debug)]
+pub(crate) struct TypeExpiry {
+    default: CachePolicy,
+    // by tree name
+    types: HashMap<Vec<u8>, CachePolicy>,
+}
+
+impl TypeExpiry {
+    pub(crate) fn from_config(config: &StorageConfig) -> Option<Self> {
+        if config.cache_policies.is_empty() {
+            return None;
+        }
+        Some(Self {
+            default: CachePolicy {
+                time_to_live: config.cache_time_to_live,
+                time_to_idle: config.cache_time_to_idle,
+            },
+            types: config
+                .cache_policies
+                .iter()
+                .map(|(name, policy)| (name.as_bytes().to_vec(), *policy))
+                .collect(),
+        })
+    }
+
+    fn policy(&self, ckey: &[u8]) -> &CachePolicy {
+        split_ckey(ckey)
+            .and_then(|(tree, _)| self.types.get(tree))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl Expiry<Vec<u8>, Bytes> for TypeExpiry {
+    fn expire_after_create(
+        &self,
+        key: &Vec<u8>,
+        _: &Bytes,
+        created_at: Instant,
+    ) -> Option<Duration> {
+        self.policy(key).remaining(created_at, created_at)
+    }
+
+    fn expire_after_read(
+        &self,
+        key: &Vec<u8>,
+        _: &Bytes,
+        read_at: Instant,
+        _: Option<Duration>,
+        last_modified_at: Instant,
+    ) -> Option<Duration> {
+        self.policy(key).remaining(last_modified_at, read_at)
+    }
+
+    fn expire_after_update(
+        &self,
+        key: &Vec<u8>,
+        _: &Bytes,
+        updated_at: Instant,
+        _: Option<Duration>,
+    ) -> Option<Duration> {
+        self.policy(key).remaining(updated_at, updated_at)
+    }
+}
+
+#[test]
+fn cache_policies() {
+    use crate::{Storage, StorageData};
+
+    #[derive(StorageData, Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+    struct Proposal(u32);
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        cache_time_to_live: Some(1),
+        cache_policies: HashMap::from([(String::name(), CachePolicy::default())]),
+        ..Default::default()
+    });
+    store.insert("config", "never expires".to_string());
+    store.insert("proposal", Proposal(1));
+    std::thread::sleep(Duration::from_millis(1500));
+    store.run_pending_tasks();
+    assert_eq!(
+        Some("never expires".to_string()),
+        store.get::<String>("config")
+    );
+    assert_eq!(None, store.get::<Proposal>("proposal"));
+}
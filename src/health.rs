@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{eyre, Result};
+use tracing::warn;
+
+use crate::meta::META_TREE_NAME;
+use crate::Storage;
+
+// meta key written and read back by the probe
+const HEALTH_PROBE_META_KEY: &str = "health_probe";
+
+/// The state reported by `Storage::health`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Health {
+    /// Whether the storage is not closed and a write and read back of a probe record
+    /// succeeded, only the read on a `read_only` storage.
+    pub db_open: bool,
+    /// Time since this `Storage` last flushed the db, `None` before its first flush.
+    /// sled also flushes on its own in the background, which isn't tracked.
+    pub last_flush_age: Option<Duration>,
+    /// Bytes available to the db on its filesystem, `None` if that can't be queried.
+    pub disk_free: Option<u64>,
+    /// Weighted size of the cache relative to `cache_max_capacity`, `None` when the
+    /// cache is unbounded.
+    pub cache_pressure: Option<f64>,
+}
+
+impl Storage {
+    /// Cheap checks for readiness and liveness endpoints, the probe writes one record
+    /// into the meta tree unless the storage is `read_only`.
+    pub fn health(&self) -> Health {
+        let db_open = !self.is_closed()
+            && self
+                .probe()
+                .inspect_err(|e| warn!("Health probe failed: {}", e))
+                .is_ok();
+        let cache_pressure = self
+            .cache
+            .policy()
+            .max_capacity()
+            .filter(|max| *max > 0)
+            .map(|max| self.cache.weighted_size() as f64 / max as f64);
+        Health {
+            db_open,
+            last_flush_age: self.last_flush.lock().map(|at| at.elapsed()),
+            disk_free: fs4::available_space(&self.db_path).ok(),
+            cache_pressure,
+        }
+    }
+
+    fn probe(&self) -> Result<()> {
+        let tree = self.tree(META_TREE_NAME)?;
+        if self.read_only {
+            tree.get(HEALTH_PROBE_META_KEY)?;
+            return Ok(());
+        }
+        let _writes = self.write_gate();
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let probe = bincode::serialize(&nanos)?;
+        tree.insert(HEALTH_PROBE_META_KEY, probe.clone())?;
+        match tree.get(HEALTH_PROBE_META_KEY)? {
+            Some(v) if *v == *probe => Ok(()),
+            _ => Err(eyre!("probe record read back wrong")),
+        }
+    }
+
+    pub(crate) fn record_flush(&self) {
+        *self.last_flush.lock() = Some(Instant::now());
+    }
+}
+
+#[test]
+fn health() {
+    use crate::StorageConfig;
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        cache_max_capacity: Some(1 << 20),
+        ..Default::default()
+    });
+    let health = store.health();
+    assert!(health.db_open);
+    assert_eq!(None, health.last_flush_age);
+    assert!(health.disk_free.is_some_and(|free| free > 0));
+
+    store.insert("test", "x".repeat(1024));
+    store.run_pending_tasks();
+    let health = store.health();
+    assert!(health.last_flush_age.is_some());
+    assert!(health.cache_pressure.is_some_and(|p| p > 0.0 && p < 1.0));
+}
+
+#[test]
+fn health_closed_and_read_only() {
+    let store: Storage = Storage::builder()
+        .temporary()
+        .read_only(true)
+        .open()
+        .unwrap();
+    assert!(store.health().db_open);
+    assert_eq!(None, store.get_meta::<u128>(HEALTH_PROBE_META_KEY));
+
+    store.close().unwrap();
+    assert!(!store.health().db_open);
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.close().unwrap();
+    assert!(!store.health().db_open);
+    assert_eq!(None, store.get_meta::<u128>(HEALTH_PROBE_META_KEY));
+}
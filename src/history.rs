@@ -0,0 +1,161 @@
+use color_eyre::eyre::Result;
+use tracing::warn;
+
+use crate::{Storage, StorageData, StorageKey};
+
+const HISTORY_TREE_SUFFIX: &str = "__history";
+
+// the tree keeping the replaced values of `name`
+pub(crate) fn history_tree_name(name: &str) -> String {
+    format!("{}{}", name, HISTORY_TREE_SUFFIX)
+}
+
+pub(crate) fn is_history_tree(name: &str) -> bool {
+    name.ends_with(HISTORY_TREE_SUFFIX)
+}
+
+// the length of `key` first, so the entries of one key aren't a prefix of another's
+fn history_prefix(key: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(4 + key.len() + 8);
+    prefix.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(key);
+    prefix
+}
+
+fn history_key(key: &[u8], version: u64) -> Vec<u8> {
+    let mut history_key = history_prefix(key);
+    history_key.extend_from_slice(&version.to_be_bytes());
+    history_key
+}
+
+fn version_of(history_key: &[u8]) -> Option<u64> {
+    let version = history_key.get(history_key.len().checked_sub(8)?..)?;
+    Some(u64::from_be_bytes(version.try_into().ok()?))
+}
+
+// HISTORY
+// With a history limit, `insert` archives the value it replaces into `<name>__history`,
+// keyed by the record key and a version from the sequence of that tree, and keeps the
+// newest `limit` versions of each key. Archived values are stored as they were, so
+// they are verified, exported and re-encrypted like the live ones. Removals, `update`
+// and `compare_and_swap` don't archive.
+impl Storage {
+    // the configured limit of `T` first, then its `StorageData::history`
+    pub(crate) fn history_limit<T: StorageData>(&self) -> usize {
+        self.history_limits
+            .get(&T::name())
+            .copied()
+            .unwrap_or_else(T::history)
+    }
+
+    // keep the stored bytes `previous` of `key` as its newest version
+    pub(crate) fn archive<T: StorageData>(&self, key: &[u8], previous: &[u8]) {
+        let limit = self.history_limit::<T>();
+        if limit == 0 {
+            return;
+        }
+        if let Err(e) = self.try_archive::<T>(key, previous, limit) {
+            warn!(
+                "Archive tree({}) key({}) failed: {}",
+                T::name(),
+                String::from_utf8_lossy(key),
+                e
+            );
+        }
+    }
+
+    fn try_archive<T: StorageData>(&self, key: &[u8], previous: &[u8], limit: usize) -> Result<()> {
+        let name = history_tree_name(&T::name());
+        let tree = self.tree(&name)?;
+        let version = self.try_next(&name)?;
+        tree.insert(history_key(key, version), previous)?;
+        let versions = tree
+            .scan_prefix(history_prefix(key))
+            .keys()
+            .collect::<std::io::Result<Vec<_>>>()?;
+        for old in &versions[..versions.len().saturating_sub(limit)] {
+            tree.remove(old)?;
+        }
+        Ok(())
+    }
+
+    /// The archived values of `key`, oldest first, with the version to pass to
+    /// `restore_version`. Values that fail to decode are skipped.
+    pub fn history<T: StorageData>(&self, key: impl Into<StorageKey>) -> Result<Vec<(u64, T)>> {
+        let key = key.into();
+        let tree = self.tree(history_tree_name(&T::name()))?;
+        let mut versions = vec![];
+        for r in tree.scan_prefix(history_prefix(&key)) {
+            let (k, v) = r?;
+            let (Some(version), Some(value)) = (version_of(&k), self.decode_with(&key, &v, false))
+            else {
+                continue;
+            };
+            versions.push((version, value));
+        }
+        Ok(versions)
+    }
+
+    /// Insert the archived `version` of `key` again, archiving the current value.
+    /// Returns false when there is no such version.
+    pub fn restore_version<T: StorageData>(
+        &self,
+        key: impl Into<StorageKey>,
+        version: u64,
+    ) -> Result<bool> {
+        let key = key.into();
+        let tree = self.tree(history_tree_name(&T::name()))?;
+        let Some(archived) = tree.get(history_key(&key, version))? else {
+            return Ok(false);
+        };
+        let Some(value) = self.decode_with::<T>(&key, &archived, false) else {
+            return Ok(false);
+        };
+        self.insert_checked(key, value)?;
+        Ok(true)
+    }
+}
+
+#[test]
+fn history() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+    #[storage(history = 2)]
+    struct Params {
+        fee: u64,
+    }
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    for fee in 1..=4 {
+        store.insert("params", Params { fee });
+    }
+    // another key with the first as prefix
+    store.insert("params2", Params { fee: 10 });
+    store.insert("params2", Params { fee: 11 });
+
+    let history = store.history::<Params>("params").unwrap();
+    assert_eq!(
+        vec![Params { fee: 2 }, Params { fee: 3 }],
+        history.iter().map(|(_, v)| v.clone()).collect::<Vec<_>>()
+    );
+    assert!(store
+        .restore_version::<Params>("params", history[0].0)
+        .unwrap());
+    assert_eq!(Some(Params { fee: 2 }), store.get::<Params>("params"));
+    assert_eq!(
+        vec![Params { fee: 3 }, Params { fee: 4 }],
+        store
+            .history::<Params>("params")
+            .unwrap()
+            .into_iter()
+            .map(|(_, v)| v)
+            .collect::<Vec<_>>()
+    );
+    assert!(!store.restore_version::<Params>("params", 0).unwrap());
+
+    // no history for types without a limit
+    store.insert("a", "1".to_string());
+    store.insert("a", "2".to_string());
+    assert!(store.history::<String>("a").unwrap().is_empty());
+}
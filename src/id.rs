@@ -0,0 +1,124 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{eyre, Result};
+use parking_lot::Mutex;
+
+use crate::{Storage, Tree};
+
+pub(crate) const ID_TREE_NAME: &str = "ID";
+
+// 2024-01-01T00:00:00Z
+const EPOCH_MS: u64 = 1_704_067_200_000;
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const MAX_NODE_ID: u16 = (1 << NODE_BITS) - 1;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+// how far ahead of the clock the persisted high-water mark is kept
+const RESERVE: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct State {
+    last_ms: u64,
+    sequence: u64,
+    reserved_until: u64,
+}
+
+/// Time ordered 64-bit ids: 41 bits of milliseconds since 2024, 10 bits of node id and
+/// 12 bits of per-millisecond sequence.
+///
+/// The generator persists a timestamp high-water mark slightly ahead of the ids it
+/// issues, and a restarted generator never goes below it, so ids are not reused even if
+/// the clock moved backwards in between. A generator never goes backwards in time either,
+/// on clock skew or sequence exhaustion it borrows from the next millisecond.
+#[derive(Debug)]
+pub struct IdGenerator {
+    storage: Storage,
+    tree: Tree,
+    key: String,
+    node_id: u16,
+    state: Mutex<State>,
+}
+
+impl Storage {
+    pub fn id_generator(&self, name: &str, node_id: u16) -> Result<IdGenerator> {
+        if node_id > MAX_NODE_ID {
+            return Err(eyre!("node id {} is above {}", node_id, MAX_NODE_ID));
+        }
+        let tree = self.tree(ID_TREE_NAME)?;
+        let key = format!("{}/{}", name, node_id);
+        let reserved_until = tree
+            .get(&key)?
+            .and_then(|v| v.as_ref().try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0);
+        Ok(IdGenerator {
+            storage: self.clone(),
+            tree,
+            key,
+            node_id,
+            state: Mutex::new(State {
+                last_ms: reserved_until,
+                sequence: 0,
+                reserved_until,
+            }),
+        })
+    }
+}
+
+impl IdGenerator {
+    pub fn next_id(&self) -> Result<u64> {
+        self.storage.check_open()?;
+        let mut state = self.state.lock();
+        let mut now = now_ms().max(state.last_ms);
+        if now == state.last_ms {
+            state.sequence += 1;
+            if state.sequence > MAX_SEQUENCE {
+                now += 1;
+                state.sequence = 0;
+            }
+        } else {
+            state.sequence = 0;
+        }
+        if now >= state.reserved_until {
+            let reserved_until = now + RESERVE.as_millis() as u64;
+            // durable before any id above the old mark is issued, once per `RESERVE`
+            let _writes = self.storage.write_gate();
+            self.tree
+                .insert(&self.key, reserved_until.to_be_bytes().to_vec())?;
+            self.tree.flush()?;
+            state.reserved_until = reserved_until;
+        }
+        state.last_ms = now;
+
+        Ok(((now - EPOCH_MS) << (NODE_BITS + SEQUENCE_BITS))
+            | ((self.node_id as u64) << SEQUENCE_BITS)
+            | state.sequence)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+        .max(EPOCH_MS)
+}
+
+#[test]
+fn id_generator() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    assert!(store.id_generator("test", 1024).is_err());
+
+    let ids = store.id_generator("test", 7).unwrap();
+    let first: Vec<u64> = (0..10000).map(|_| ids.next_id().unwrap()).collect();
+    assert!(first.windows(2).all(|w| w[0] < w[1]));
+    assert!(first.iter().all(|id| (id >> SEQUENCE_BITS) & 0x3ff == 7));
+
+    // a restarted generator continues above everything issued before
+    drop(ids);
+    let ids = store.id_generator("test", 7).unwrap();
+    assert!(ids.next_id().unwrap() > *first.last().unwrap());
+
+    store.close().unwrap();
+    assert!(ids.next_id().is_err());
+}
@@ -0,0 +1,100 @@
+use bytes::Bytes;
+use color_eyre::eyre::Result;
+
+use crate::{split_ckey, Storage, StorageData, StorageKey};
+
+// INVALIDATION
+// Drops cached values without deleting them, the next read loads them from the db
+// again. Meant for values changed behind the cache, by another process writing the db
+// or a raw sled handle. Pinned keys stay pinned with the value now in the db.
+impl Storage {
+    /// Drop the cached value of `key`, keeping it in the db. In write-back mode its
+    /// value is written to the db first.
+    pub fn invalidate<T: StorageData>(&self, key: impl Into<StorageKey>) -> Result<()> {
+        self.invalidate_ckey(&self.ckey::<T>(&key.into()))
+    }
+
+    /// Drop all cached values, keeping them in the db. In write-back mode every cached
+    /// value is written to the db first, dirty or not.
+    pub fn invalidate_all(&self) -> Result<()> {
+        if self.write_back().is_some() {
+            let ckeys: Vec<Vec<u8>> = self
+                .cache
+                .iter()
+                .map(|(ckey, _)| ckey.to_vec())
+                .chain(self.pinned.iter().map(|e| e.key().clone()))
+                .collect();
+            for ckey in ckeys {
+                self.invalidate_ckey(&ckey)?;
+            }
+        } else {
+            self.cache.invalidate_all();
+            let pinned: Vec<Vec<u8>> = self.pinned.iter().map(|e| e.key().clone()).collect();
+            for ckey in pinned {
+                self.reload_pinned(&ckey)?;
+            }
+        }
+        self.forget_all_missing();
+        Ok(())
+    }
+
+    pub(crate) fn invalidate_ckey(&self, ckey: &Vec<u8>) -> Result<()> {
+        match self.write_back() {
+            Some(write_back) => {
+                let _writes = self.write_gate();
+                write_back.invalidate(&self.db, ckey)?
+            }
+            None => {
+                self.cache.remove(ckey);
+            }
+        }
+        self.forget_missing(ckey);
+        self.reload_pinned(ckey)
+    }
+
+    fn reload_pinned(&self, ckey: &Vec<u8>) -> Result<()> {
+        if !self.pinned.contains_key(ckey) {
+            return Ok(());
+        }
+        let stored = match split_ckey(ckey) {
+            Some((tree, key)) => self.db_tree(tree)?.get(key)?,
+            None => self.db.get(ckey.as_slice())?,
+        };
+        match stored {
+            Some(v) => {
+                self.pinned.insert(ckey.clone(), Bytes::from(v.to_vec()));
+            }
+            None => {
+                self.pinned.remove(ckey);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn invalidate() {
+    use crate::StorageConfig;
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert("a", "1".to_string());
+    store.insert("b", "2".to_string());
+    store.invalidate::<String>("a").unwrap();
+    assert!(!store.cache.contains_key(&store.ckey::<String>(b"a")));
+    assert_eq!(Some("1".to_string()), store.get::<String>("a"));
+
+    store.invalidate_all().unwrap();
+    store.run_pending_tasks();
+    assert_eq!(0, store.cache.entry_count());
+    assert_eq!(Some("2".to_string()), store.get::<String>("b"));
+
+    let write_back: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        write_back: true,
+        ..Default::default()
+    });
+    write_back.insert("a", "pending".to_string());
+    write_back.invalidate_all().unwrap();
+    assert!(!write_back.cache.contains_key(&store.ckey::<String>(b"a")));
+    assert_eq!(Some("pending".to_string()), write_back.get::<String>("a"));
+}
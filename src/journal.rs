@@ -0,0 +1,163 @@
+use color_eyre::eyre::Result;
+
+use crate::{Storage, Tree};
+
+const JOURNAL_TREE_SUFFIX: &str = "__journal";
+// the empty key sorts before all offsets, it holds the offset `truncate_before` kept
+// from, so offsets are not issued again once the journal is truncated empty
+const START: &[u8] = b"";
+
+pub(crate) fn is_journal_tree(name: &str) -> bool {
+    name.ends_with(JOURNAL_TREE_SUFFIX)
+}
+
+fn read_offset(v: &[u8]) -> u64 {
+    v.try_into().map(u64::from_be_bytes).unwrap_or(0)
+}
+
+/// Append-only log of byte entries at increasing offsets, see `Storage::journal`.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    pub(crate) storage: Storage,
+    pub(crate) name: String,
+    pub(crate) tree: Tree,
+}
+
+// JOURNAL
+// The entries of a journal live in `<name>__journal` under their offset, 8 bytes big
+// endian. An append takes the offset after the last entry with a compare-and-swap
+// against an absent key, so offsets are gapless and an entry is only visible once all
+// entries before it are, a reader tailing with `read_from` never skips one. Entries
+// bypass the cache and quotas.
+impl Storage {
+    pub fn journal(&self, name: &str) -> Journal {
+        let name = format!("{}{}", name, JOURNAL_TREE_SUFFIX);
+        Journal {
+            storage: self.clone(),
+            tree: self.tree(&name).unwrap(),
+            name,
+        }
+    }
+}
+
+impl Journal {
+    /// The offset of the oldest entry kept, the ones before it were truncated.
+    pub fn start_offset(&self) -> Result<u64> {
+        Ok(self.tree.get(START)?.map_or(0, |v| read_offset(&v)))
+    }
+
+    /// The offset the next entry is appended at.
+    pub fn next_offset(&self) -> Result<u64> {
+        let last = self.tree.last()?.filter(|(k, _)| k.len() == 8);
+        let start = self.start_offset()?;
+        Ok(last.map_or(start, |(k, _)| read_offset(&k) + 1).max(start))
+    }
+
+    /// Append `entry`, returning its offset.
+    pub fn append(&self, entry: &[u8]) -> Result<u64> {
+        self.storage.check_writable()?;
+        self.storage.check_value_size(entry)?;
+        let _writes = self.storage.write_gate();
+        let offset = self.push(entry)?;
+        self.storage
+            .audit("append", &self.name, &offset.to_be_bytes(), Some(entry));
+        Ok(offset)
+    }
+
+    // append without checks or audit, under the write gate
+    pub(crate) fn push(&self, entry: &[u8]) -> Result<u64> {
+        loop {
+            let offset = self.next_offset()?;
+            if self
+                .tree
+                .compare_and_swap(offset.to_be_bytes(), None::<&[u8]>, Some(entry))?
+                .is_ok()
+            {
+                return Ok(offset);
+            }
+            // lost to a concurrent append, which took this offset
+        }
+    }
+
+    /// Up to `max` entries from `offset` on, with their offsets. Empty when there are
+    /// none yet.
+    pub fn read_from(&self, offset: u64, max: usize) -> Result<Vec<(u64, Vec<u8>)>> {
+        self.storage.check_open()?;
+        self.tree
+            .range(offset.to_be_bytes()..)
+            .take(max)
+            .map(|r| {
+                let (k, v) = r?;
+                Ok((read_offset(&k), v.to_vec()))
+            })
+            .collect()
+    }
+
+    /// Drop the entries before `offset`. Returns how many were dropped.
+    pub fn truncate_before(&self, offset: u64) -> Result<usize> {
+        self.storage.check_writable()?;
+        let _writes = self.storage.write_gate();
+        self.tree.fetch_and_update(START, |v| {
+            let start = v.map_or(0, read_offset).max(offset);
+            Some(start.to_be_bytes().to_vec())
+        })?;
+        let mut dropped = 0;
+        for key in self
+            .tree
+            .range(0u64.to_be_bytes()..offset.to_be_bytes())
+            .keys()
+        {
+            self.tree.remove(key?)?;
+            dropped += 1;
+        }
+        self.storage
+            .audit("truncate", &self.name, &offset.to_be_bytes(), None);
+        Ok(dropped)
+    }
+}
+
+#[test]
+fn journal() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let journal = store.journal("events");
+    assert!(journal.read_from(0, 10).unwrap().is_empty());
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let journal = journal.clone();
+            std::thread::spawn(move || {
+                for j in 0..25 {
+                    journal.append(format!("{}-{}", i, j).as_bytes()).unwrap();
+                }
+            })
+        })
+        .collect();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+    let entries = journal.read_from(0, 1000).unwrap();
+    assert_eq!(
+        (0..100).collect::<Vec<_>>(),
+        entries
+            .iter()
+            .map(|(offset, _)| *offset)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![90, 91],
+        journal
+            .read_from(90, 2)
+            .unwrap()
+            .into_iter()
+            .map(|(offset, _)| offset)
+            .collect::<Vec<_>>()
+    );
+
+    assert_eq!(50, journal.truncate_before(50).unwrap());
+    assert_eq!(50, journal.read_from(0, 10).unwrap()[0].0);
+    // offsets are not issued again after truncating everything
+    assert_eq!(50, journal.truncate_before(200).unwrap());
+    assert_eq!(200, journal.append(b"next").unwrap());
+    assert_eq!(
+        vec![(200, b"next".to_vec())],
+        journal.read_from(0, 10).unwrap()
+    );
+    assert!(store.journal("other").read_from(0, 1).unwrap().is_empty());
+}
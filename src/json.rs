@@ -0,0 +1,180 @@
+use std::io::{Read, Write};
+
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::{Storage, StorageData};
+
+// the json of a registered type's value, `None` when it doesn't decode
+pub(crate) type ToJsonFn = fn(&Storage, &[u8], &[u8]) -> Option<Value>;
+
+pub(crate) fn to_json<T: StorageData>(
+    storage: &Storage,
+    key: &[u8],
+    bytes: &[u8],
+) -> Option<Value> {
+    serde_json::to_value(storage.decode_with::<T>(key, bytes, false)?).ok()
+}
+
+// one exported record, keys are written lossy as utf-8
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct JsonRecord<V> {
+    pub(crate) key: String,
+    pub(crate) value: V,
+}
+
+// JSON EXPORT
+// Records are written as a json array with one `{"key":..,"value":..}` object per line,
+// in key order, so dumps of the same data are identical and diff line by line. Like
+// `export_arrow`, records are read from the db and undecodable ones are skipped.
+impl Storage {
+    /// Write all records of `T` as json, returns the number of exported records.
+    pub fn export_json<T: StorageData, W: Write>(&self, writer: W) -> Result<usize> {
+        self.write_json_records(&T::name(), to_json::<T>, writer)
+    }
+
+    /// Write the records of every type registered with `register_type` as a json object
+    /// of record arrays keyed by tree name. Returns the number of exported records.
+    pub fn export_all_json<W: Write>(&self, mut writer: W) -> Result<usize> {
+        let mut types: Vec<_> = self
+            .types
+            .0
+            .read()
+            .iter()
+            .map(|(name, fns)| (name.clone(), fns.to_json))
+            .collect();
+        types.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut exported = 0;
+        writer.write_all(b"{")?;
+        for (i, (name, to_json)) in types.iter().enumerate() {
+            if i > 0 {
+                writer.write_all(b",")?;
+            }
+            writer.write_all(b"\n")?;
+            serde_json::to_writer(&mut writer, name)?;
+            writer.write_all(b": ")?;
+            exported += self.write_json_records(name, *to_json, &mut writer)?;
+        }
+        writer.write_all(b"\n}\n")?;
+        Ok(exported)
+    }
+
+    fn write_json_records<W: Write>(
+        &self,
+        name: &str,
+        to_json: ToJsonFn,
+        mut writer: W,
+    ) -> Result<usize> {
+        let tree = self.tree(name)?;
+        let mut exported = 0;
+        writer.write_all(b"[")?;
+        for r in tree.iter() {
+            let (k, v) = r?;
+            let key = String::from_utf8_lossy(&k).to_string();
+            let Some(value) = to_json(self, &k, &v) else {
+                debug!("Skip undecodable tree({}) key({})", name, key);
+                continue;
+            };
+            writer.write_all(if exported == 0 { b"\n" } else { b",\n" })?;
+            serde_json::to_writer(&mut writer, &JsonRecord { key, value })?;
+            exported += 1;
+        }
+        writer.write_all(b"\n]")?;
+        Ok(exported)
+    }
+}
+
+// JSON IMPORT
+impl Storage {
+    /// Insert the `{"key":..,"value":..}` records of `reader` as `T`, given as json
+    /// arrays like `export_json` writes them, as a stream of records one per line, or
+    /// any mix of both. Records are inserted in order, later ones overwrite earlier ones
+    /// with the same key, and the import stops at the first invalid record or the first
+    /// that can't be written, like on a full or read-only storage.
+    ///
+    /// Returns the number of imported records.
+    pub fn import_json<T: StorageData, R: Read>(&self, reader: R) -> Result<usize> {
+        let mut imported = 0;
+        for item in serde_json::Deserializer::from_reader(reader).into_iter::<Value>() {
+            let records = match item? {
+                Value::Array(records) => records,
+                record => vec![record],
+            };
+            for record in records {
+                let JsonRecord { key, value } = serde_json::from_value::<JsonRecord<T>>(record)
+                    .map_err(|e| eyre!("invalid {} record {}: {}", T::name(), imported, e))?;
+                self.insert_checked(key, value)?;
+                imported += 1;
+            }
+        }
+        info!("Imported {} records into tree({})", imported, T::name());
+        Ok(imported)
+    }
+}
+
+#[test]
+fn json_export() {
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize)]
+    struct JsonExportTest {
+        a: u32,
+    }
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert("b", JsonExportTest { a: 2 });
+    store.insert("a", JsonExportTest { a: 1 });
+    store.insert("test", "test".to_string());
+    store
+        .db
+        .open_tree(JsonExportTest::name())
+        .unwrap()
+        .insert("garbage", vec![0xff])
+        .unwrap();
+
+    let mut out = vec![];
+    assert_eq!(2, store.export_json::<JsonExportTest, _>(&mut out).unwrap());
+    assert_eq!(
+        "[\n{\"key\":\"a\",\"value\":{\"a\":1}},\n{\"key\":\"b\",\"value\":{\"a\":2}}\n]",
+        String::from_utf8(out).unwrap()
+    );
+
+    store.register_type::<JsonExportTest>();
+    store.register_type::<String>();
+    let mut out = vec![];
+    assert_eq!(3, store.export_all_json(&mut out).unwrap());
+    let all: Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(
+        serde_json::json!([{ "key": "test", "value": "test" }]),
+        all["String"]
+    );
+    assert_eq!(2, all["JsonExportTest"].as_array().unwrap().len());
+}
+
+#[test]
+fn json_import() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let array = r#"[{"key":"a","value":"a"},{"key":"b","value":"b"}]"#;
+    assert_eq!(2, store.import_json::<String, _>(array.as_bytes()).unwrap());
+    let lines = "{\"key\":\"b\",\"value\":\"new\"}\n{\"key\":\"c\",\"value\":\"c\"}\n";
+    assert_eq!(2, store.import_json::<String, _>(lines.as_bytes()).unwrap());
+    assert_eq!(Some("a".to_string()), store.get::<String>("a"));
+    assert_eq!(Some("new".to_string()), store.get::<String>("b"));
+
+    let mut out = vec![];
+    store.export_json::<String, _>(&mut out).unwrap();
+    assert_eq!(3, store.import_json::<String, _>(out.as_slice()).unwrap());
+
+    assert!(store
+        .import_json::<String, _>(r#"{"key":"d","value":7}"#.as_bytes())
+        .is_err());
+    assert!(!store.contains_key::<String>("d"));
+
+    let follower: Storage = Storage::builder()
+        .temporary()
+        .read_only(true)
+        .open()
+        .unwrap();
+    assert!(follower.import_json::<String, _>(array.as_bytes()).is_err());
+}
@@ -0,0 +1,381 @@
+use std::fmt::Display;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{Storage, StorageError};
+
+// Keys are strings, numbers are encoded as fixed width zero-padded decimals so that
+// their lexicographic order is their numeric order. This is the same layout as
+// `format!("{:020}", n)`, keys written that way stay readable by the parsers below.
+const NUMBER_WIDTH: usize = 20;
+
+pub fn key_u64(n: u64) -> String {
+    format!("{:020}", n)
+}
+
+pub fn parse_key_u64(key: &str) -> Option<u64> {
+    if key.len() != NUMBER_WIDTH || !key.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    key.parse().ok()
+}
+
+// flipping the sign bit maps i64::MIN..=i64::MAX onto 0..=u64::MAX in order
+pub fn key_i64(n: i64) -> String {
+    key_u64((n as u64) ^ (1 << 63))
+}
+
+pub fn parse_key_i64(key: &str) -> Option<i64> {
+    parse_key_u64(key).map(|n| (n ^ (1 << 63)) as i64)
+}
+
+/// Nanoseconds since the unix epoch, times before the epoch are clamped to it.
+pub fn key_timestamp(t: SystemTime) -> String {
+    let nanos = t
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    key_u64(nanos)
+}
+
+pub fn parse_key_timestamp(key: &str) -> Option<SystemTime> {
+    parse_key_u64(key).map(|n| UNIX_EPOCH + Duration::from_nanos(n))
+}
+
+// composite keys end every part with NUL 0x01. NUL is the smallest char so a part sorts
+// before all of its extensions, and a NUL inside a part is escaped as NUL 0x02, which
+// sorts after the end. Every NUL is followed by one of the two, so neither the content
+// of a part nor the start of the next one can pass for the other.
+const NUL: char = '\0';
+const PART_END: char = '\u{1}';
+const PART_ESCAPE: char = '\u{2}';
+
+/// Builder for multi-part keys.
+///
+/// Parts are encoded so that keys sort by their parts in order, and no part content can
+/// be confused with a separator. A key is a valid prefix for scans over all keys that
+/// extend it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Key {
+    encoded: String,
+}
+
+impl Key {
+    pub const fn new() -> Self {
+        Self {
+            encoded: String::new(),
+        }
+    }
+
+    pub fn part(mut self, part: &str) -> Self {
+        for c in part.chars() {
+            self.encoded.push(c);
+            if c == NUL {
+                self.encoded.push(PART_ESCAPE);
+            }
+        }
+        self.encoded.push(NUL);
+        self.encoded.push(PART_END);
+        self
+    }
+
+    pub fn part_u64(self, n: u64) -> Self {
+        self.part(&key_u64(n))
+    }
+
+    pub fn part_i64(self, n: i64) -> Self {
+        self.part(&key_i64(n))
+    }
+
+    pub fn part_timestamp(self, t: SystemTime) -> Self {
+        self.part(&key_timestamp(t))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.encoded
+    }
+
+    /// Split an encoded key back into its parts.
+    pub fn parts(key: &str) -> Option<Vec<String>> {
+        let mut parts = vec![];
+        let mut part = String::new();
+        let mut chars = key.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != NUL {
+                part.push(c);
+                continue;
+            }
+            match chars.next()? {
+                PART_ESCAPE => part.push(NUL),
+                PART_END => parts.push(std::mem::take(&mut part)),
+                _ => return None,
+            }
+        }
+        part.is_empty().then_some(parts)
+    }
+}
+
+impl AsRef<str> for Key {
+    fn as_ref(&self) -> &str {
+        &self.encoded
+    }
+}
+
+impl From<Key> for String {
+    fn from(key: Key) -> Self {
+        key.encoded
+    }
+}
+
+/// Key of a structured data record, stored in the db as raw bytes.
+///
+/// Strings are stored as their UTF-8 bytes, `u64` like `key_u64` writes it, so the same
+/// number is the same key whichever of the two a caller uses and numeric keys sort
+/// numerically, and UUIDs as their 16 bytes (with the `uuid` feature).
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StorageKey(Vec<u8>);
+
+impl StorageKey {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl std::ops::Deref for StorageKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for StorageKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Display for StorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl From<&StorageKey> for StorageKey {
+    fn from(key: &StorageKey) -> Self {
+        key.clone()
+    }
+}
+
+impl From<&str> for StorageKey {
+    fn from(key: &str) -> Self {
+        Self(key.as_bytes().to_vec())
+    }
+}
+
+impl From<&String> for StorageKey {
+    fn from(key: &String) -> Self {
+        Self(key.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for StorageKey {
+    fn from(key: String) -> Self {
+        Self(key.into_bytes())
+    }
+}
+
+impl From<&[u8]> for StorageKey {
+    fn from(key: &[u8]) -> Self {
+        Self(key.to_vec())
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for StorageKey {
+    fn from(key: &[u8; N]) -> Self {
+        Self(key.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for StorageKey {
+    fn from(key: Vec<u8>) -> Self {
+        Self(key)
+    }
+}
+
+impl From<u64> for StorageKey {
+    fn from(key: u64) -> Self {
+        Self::from(key_u64(key))
+    }
+}
+
+impl From<&Key> for StorageKey {
+    fn from(key: &Key) -> Self {
+        Self::from(key.as_str())
+    }
+}
+
+impl From<Key> for StorageKey {
+    fn from(key: Key) -> Self {
+        Self::from(String::from(key))
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl From<uuid::Uuid> for StorageKey {
+    fn from(key: uuid::Uuid) -> Self {
+        Self(key.as_bytes().to_vec())
+    }
+}
+
+/// How `Storage::normalize_key` turns user input into keys, see
+/// `StorageConfig::key_rules`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyRules {
+    /// Lowercase keys, so inputs differing only in case name the same record.
+    pub lowercase: bool,
+    /// Refuse keys longer than this many bytes, after lowercasing.
+    pub max_len: Option<usize>,
+}
+
+impl KeyRules {
+    /// `key` normalized, or why it can't be one.
+    pub fn normalize(&self, key: &str) -> Result<String, StorageError> {
+        let key = if self.lowercase {
+            key.to_lowercase()
+        } else {
+            key.to_string()
+        };
+        if key.is_empty() {
+            return Err(StorageError::InvalidKey("empty key".to_string()));
+        }
+        if let Some(max) = self.max_len.filter(|max| key.len() > *max) {
+            return Err(StorageError::InvalidKey(format!(
+                "key of {} bytes is longer than {}",
+                key.len(),
+                max
+            )));
+        }
+        Ok(key)
+    }
+}
+
+impl Storage {
+    /// The key for the user input `key` under the configured `KeyRules`. Any string is
+    /// safe as a key, '/' included, the rules only make inputs comparable.
+    pub fn normalize_key(&self, key: &str) -> Result<StorageKey> {
+        Ok(StorageKey::from(
+            self.key_rules.normalize(key).map_err(|e| eyre!(e))?,
+        ))
+    }
+}
+
+#[test]
+fn composite_key() {
+    let a = Key::new().part("account").part_u64(9);
+    let b = Key::new().part("account").part_u64(10);
+    assert!(a < b);
+    assert_eq!(
+        Some(vec!["account".to_string(), key_u64(9)]),
+        Key::parts(a.as_str())
+    );
+
+    // parts containing separators neither collide nor reorder
+    let slash = Key::new().part("a/b").part("c");
+    let split = Key::new().part("a").part("b/c");
+    assert_ne!(slash, split);
+    assert!(split < slash);
+    assert!(Key::new().part("a").part("z") < Key::new().part("a-b"));
+    let nul = Key::new().part("a\0b");
+    assert_eq!(Some(vec!["a\0b".to_string()]), Key::parts(nul.as_str()));
+    assert!(Key::new().part("a").part("x") < nul);
+    assert!(Key::new().part("account").as_str() < a.as_str());
+    // the start of the next part doesn't pass for an escaped NUL
+    let next = Key::new().part("a").part("\u{ff}");
+    let escaped = Key::new().part("a\0");
+    assert_ne!(next, escaped);
+    assert_eq!(
+        Some(vec!["a".to_string(), "\u{ff}".to_string()]),
+        Key::parts(next.as_str())
+    );
+    assert_eq!(Some(vec!["a\0".to_string()]), Key::parts(escaped.as_str()));
+    assert!(next < escaped);
+    let start = Key::new().part("a").part("\u{2}");
+    assert_ne!(start, escaped);
+    assert_eq!(2, Key::parts(start.as_str()).unwrap().len());
+}
+
+#[test]
+fn key_rules() {
+    let rules = KeyRules {
+        lowercase: true,
+        max_len: Some(8),
+    };
+    assert_eq!(Ok("user/a".to_string()), rules.normalize("User/A"));
+    assert!(rules.normalize("").is_err());
+    assert_eq!(
+        Err(StorageError::InvalidKey(
+            "key of 9 bytes is longer than 8".to_string()
+        )),
+        rules.normalize("long-name")
+    );
+    assert_eq!(
+        Ok("MiXeD".to_string()),
+        KeyRules::default().normalize("MiXeD")
+    );
+
+    // keys with '/' stay apart from the tree name in cache keys, expiry included
+    let store = Storage::builder()
+        .temporary()
+        .key_rules(rules)
+        .ttl(Duration::from_secs(1))
+        .open()
+        .unwrap();
+    let key = store.normalize_key("A/B").unwrap();
+    store.insert(key.clone(), "1".to_string());
+    assert_eq!(Some("1".to_string()), store.get::<String>("a/b"));
+    std::thread::sleep(Duration::from_millis(1100));
+    store.run_pending_tasks();
+    assert!(store
+        .db
+        .open_tree("String")
+        .unwrap()
+        .get(&key)
+        .unwrap()
+        .is_none());
+    assert!(!crate::tree_names(&store.db).contains(&"a".to_string()));
+}
+
+#[test]
+fn key_order() {
+    let numbers = [0, 1, 9, 10, 99, 100, u64::MAX];
+    let keys: Vec<_> = numbers.iter().map(|n| key_u64(*n)).collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys, sorted);
+    assert!(numbers
+        .iter()
+        .all(|n| parse_key_u64(&key_u64(*n)) == Some(*n)));
+
+    let signed = [i64::MIN, -10, -1, 0, 1, 10, i64::MAX];
+    let keys: Vec<_> = signed.iter().map(|n| key_i64(*n)).collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys, sorted);
+    assert!(signed
+        .iter()
+        .all(|n| parse_key_i64(&key_i64(*n)) == Some(*n)));
+
+    let now = SystemTime::now();
+    let later = now + Duration::from_millis(1);
+    assert!(key_timestamp(now) < key_timestamp(later));
+    assert_eq!(Some(now), parse_key_timestamp(&key_timestamp(now)));
+    assert_eq!(None, parse_key_u64("12"));
+}
@@ -1,19 +1,183 @@
-use std::time::Duration;
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use std::{fmt::Debug, sync::Arc};
 
 use bytes::Bytes;
 use color_eyre::eyre::{eyre, Result};
+use dashmap::DashMap;
 use moka::notification::RemovalCause;
-use moka::sync::SegmentedCache;
+use moka::sync::{Cache, SegmentedCache};
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use sled::{CompareAndSwapSuccess, Db};
-use tracing::debug;
+use sled::{CompareAndSwapError, Db};
+use tracing::field::Empty;
+use tracing::{debug, info_span, warn};
 
+// the derive names this crate by its path, inside it too
+#[allow(unused_extern_crates)]
+extern crate self as storage_hal;
+
+#[cfg(feature = "arrow")]
+mod arrow_export;
+mod audit;
+mod backup;
+mod blob;
+mod bloom;
+mod builder;
+mod cas;
+mod codec;
+mod collection;
+mod compact;
+mod copy;
+mod counter;
+#[cfg(feature = "csv")]
+mod csv_export;
+mod deadline;
+mod dir_lock;
+mod durability;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod envelope;
+mod error;
+mod expiry;
+mod health;
+mod history;
+mod id;
+mod invalidate;
+mod journal;
+#[cfg(feature = "json")]
+mod json;
+mod key;
+mod lock;
+mod maintenance;
+mod meta;
+mod migration;
+mod namespace;
+mod negative;
+mod pin;
+mod quarantine;
+mod quota;
+mod raw;
+mod record_meta;
+mod recovery;
+mod rename;
+mod replication;
+mod retention;
+mod revision;
+mod sequence;
+mod series;
+#[cfg(feature = "grpc")]
+pub mod server;
+mod shutdown;
+mod slow_op;
+mod snapshot;
+mod stats;
+mod tag;
+#[cfg(feature = "metrics")]
+mod telemetry;
+mod verify;
+mod write_back;
+
+pub use audit::AuditEntry;
+use audit::AUDIT_TREE_NAME;
+pub use backup::{BackendMigration, BackupSchedule, BackupTask, RestoreOptions};
+pub use blob::{BlobReader, BlobWriter};
+use blob::{BLOB_TREE_NAME, DEFAULT_BLOB_CHUNK_SIZE};
+use bloom::Filters;
+pub use builder::StorageBuilder;
+pub use cas::{ContentHash, ContentRefs};
+use cas::{CAS_REFS_TREE_NAME, CAS_TREE_NAME, DEFAULT_CAS_GC_GRACE_MS};
+pub use codec::{Codec, Format, CUSTOM_CODEC_IDS};
+use codec::{Codecs, TypeCodec};
+pub use collection::Collection;
+pub use compact::Compaction;
+pub use copy::CopyProgress;
+use counter::COUNTER_TREE_NAME;
+pub use durability::Durability;
+#[cfg(feature = "encryption")]
+use encryption::Keyring;
+#[cfg(feature = "encryption")]
+pub use encryption::{EncryptionKey, EnvKeyProvider, FileKeyProvider, KeyProvider};
+use envelope::Header;
+pub use error::{CasError, StorageError};
+pub use expiry::CachePolicy;
+use expiry::TypeExpiry;
+pub use health::Health;
+use history::is_history_tree;
+pub use id::IdGenerator;
+use id::ID_TREE_NAME;
+use journal::is_journal_tree;
+pub use journal::Journal;
+pub use key::{
+    key_i64, key_timestamp, key_u64, parse_key_i64, parse_key_timestamp, parse_key_u64, Key,
+    KeyRules, StorageKey,
+};
+use lock::LOCK_TREE_NAME;
+pub use maintenance::MaintenanceTask;
+use meta::META_TREE_NAME;
+pub use migration::{MigrationPlan, MigrationStep};
+use migration::{Migrations, VERSION_TREE_NAME};
+use namespace::base_tree_name;
+use pin::PinnedSet;
+pub use quarantine::QuarantinedEntry;
+use quarantine::QUARANTINE_TREE_NAME;
+use quota::{forget_usage, QuotaUsage};
+pub use quota::{Quota, QuotaPolicy};
+pub use record_meta::RecordMeta;
+pub use recovery::{RecoveryPolicy, RecoveryProgress};
+use replication::CHANGE_LOG_TREE_NAME;
+pub use replication::{Follower, ReplicaTask, ReplicationSecret, ReplicationServer};
+pub use retention::Retention;
+use revision::RevisionClock;
+use sequence::SEQUENCE_TREE_NAME;
+pub use sequence::{SeqOptions, SequenceOverflow};
+use series::is_series_tree;
+pub use series::{Point, TimeSeries};
+use shutdown::Lifecycle;
+pub use snapshot::Snapshot;
+use snapshot::{Batch, Captures, Tree, WriteGate};
+use stats::CacheCounters;
+pub use stats::{CacheStats, TreeStats};
 pub use storage_hal_derive::StorageData;
+use tag::is_tag_tree;
+use verify::Types;
+pub use verify::{CorruptEntry, IntegrityReport};
+use write_back::{DirtySet, WriteBack};
 
-pub trait StorageData: Debug + Clone + Default + for<'a> Deserialize<'a> + Serialize {
+pub trait StorageData:
+    'static + Debug + Clone + Default + for<'a> Deserialize<'a> + Serialize
+{
     fn name() -> String;
+
+    /// Layout version of the stored data, bump it together with a registered migration.
+    fn version() -> u32 {
+        0
+    }
+
+    /// Codec new values of this type are written with, `None` uses the `Storage` default.
+    /// A codec registered with `Storage::register_codec` wins over both.
+    fn format() -> Option<Format> {
+        None
+    }
+
+    /// Compress every value of this type, whatever `StorageConfig::compress_threshold` is.
+    fn compress() -> bool {
+        false
+    }
+
+    /// Number of replaced values `insert` keeps per key, see `Storage::history`.
+    fn history() -> usize {
+        0
+    }
+
+    /// Hashes of `put_cas` content this value references, kept from `gc_blobs` while
+    /// it is stored. The derive collects the fields marked `#[storage(content)]`.
+    fn content_refs(&self) -> Vec<ContentHash> {
+        Vec::new()
+    }
 }
 
 impl StorageData for String {
@@ -22,7 +186,130 @@ impl StorageData for String {
     }
 }
 
-const SEQUENCE_TREE_NAME: &str = "SEQUENCE";
+/// Name of a type that stays the same across builds, naming the tree of a generic
+/// `StorageData` type per instantiation, e.g. `Wrapper<u32>`. The derive implements it
+/// as `StorageData::name`.
+pub trait TypeName {
+    fn type_name() -> String;
+}
+
+macro_rules! impl_type_name {
+    ($($ty:ty),*) => {
+        $(impl TypeName for $ty {
+            fn type_name() -> String {
+                stringify!($ty).to_string()
+            }
+        })*
+    };
+}
+
+impl_type_name!(
+    bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, String
+);
+
+impl<T: TypeName> TypeName for Vec<T> {
+    fn type_name() -> String {
+        format!("Vec<{}>", T::type_name())
+    }
+}
+
+impl<T: TypeName> TypeName for Option<T> {
+    fn type_name() -> String {
+        format!("Option<{}>", T::type_name())
+    }
+}
+
+impl<T: TypeName> TypeName for Box<T> {
+    fn type_name() -> String {
+        format!("Box<{}>", T::type_name())
+    }
+}
+
+impl<T: TypeName, const N: usize> TypeName for [T; N] {
+    fn type_name() -> String {
+        format!("[{};{}]", T::type_name(), N)
+    }
+}
+
+macro_rules! impl_collection_type_name {
+    ($($ty:ident<$($param:ident),*>),*) => {
+        $(impl<$($param: TypeName),*> TypeName for std::collections::$ty<$($param),*> {
+            fn type_name() -> String {
+                let params: &[String] = &[$($param::type_name()),*];
+                format!("{}<{}>", stringify!($ty), params.join(","))
+            }
+        })*
+    };
+}
+
+impl_collection_type_name!(
+    HashMap<K, V>,
+    BTreeMap<K, V>,
+    HashSet<T>,
+    BTreeSet<T>,
+    VecDeque<T>
+);
+
+macro_rules! impl_tuple_type_name {
+    ($(($($param:ident),+)),*) => {
+        $(impl<$($param: TypeName),+> TypeName for ($($param,)+) {
+            fn type_name() -> String {
+                let params: &[String] = &[$($param::type_name()),+];
+                format!("({})", params.join(","))
+            }
+        })*
+    };
+}
+
+impl_tuple_type_name!(
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+    (A, B, C, D, E, F),
+    (A, B, C, D, E, F, G),
+    (A, B, C, D, E, F, G, H)
+);
+
+impl TypeName for () {
+    fn type_name() -> String {
+        "()".to_string()
+    }
+}
+
+/// The name `f` builds for `T`, built once per type and process. Generic types derive
+/// their name from it, so their parameters' names aren't formatted on every access.
+#[doc(hidden)]
+pub fn cached_type_name<T: 'static + ?Sized>(f: impl FnOnce() -> String) -> String {
+    static NAMES: OnceLock<DashMap<TypeId, String>> = OnceLock::new();
+    let names = NAMES.get_or_init(DashMap::new);
+    if let Some(name) = names.get(&TypeId::of::<T>()) {
+        return name.clone();
+    }
+    // built unlocked, the names of generic parameters come from this cache too
+    let name = f();
+    names.entry(TypeId::of::<T>()).or_insert(name).clone()
+}
+
+const ADMISSION_MAX_CAPACITY: u64 = 100_000;
+
+const DEFAULT_RECOVERY_EAGER_LIMIT: usize = 1_000_000;
+
+const INTERNAL_TREE_NAMES: [&str; 12] = [
+    SEQUENCE_TREE_NAME,
+    VERSION_TREE_NAME,
+    META_TREE_NAME,
+    COUNTER_TREE_NAME,
+    ID_TREE_NAME,
+    QUARANTINE_TREE_NAME,
+    LOCK_TREE_NAME,
+    AUDIT_TREE_NAME,
+    BLOB_TREE_NAME,
+    CAS_TREE_NAME,
+    CAS_REFS_TREE_NAME,
+    CHANGE_LOG_TREE_NAME,
+];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
@@ -32,6 +319,96 @@ pub struct StorageConfig {
     pub cache_max_capacity: Option<u64>,
     pub cache_time_to_live: Option<u64>,
     pub cache_time_to_idle: Option<u64>,
+    /// Expiry of the values of the types named here, by `StorageData::name`, instead of
+    /// `cache_time_to_live` and `cache_time_to_idle`.
+    pub cache_policies: HashMap<String, CachePolicy>,
+    /// When set, values read from the db are only cached on their second access within
+    /// this many seconds, so one-off scans don't evict the hot set.
+    pub cache_admission_window: Option<u64>,
+    /// Remember keys found missing in the db for this many milliseconds, so hot
+    /// existence checks of absent keys don't read the db every time.
+    pub negative_cache_ttl_ms: Option<u64>,
+    /// Keep a bloom filter of the keys of each data tree, built by `recover`, so
+    /// lookups of keys that were never written skip the db.
+    pub bloom_filters: bool,
+    /// Refuse to migrate data implicitly in `recover`, pending migrations must run via `migrate`.
+    pub require_explicit_migration: bool,
+    /// How `recover` loads the types named here, by `StorageData::name`.
+    pub recovery_policies: HashMap<String, RecoveryPolicy>,
+    /// `recover` loads at most this many records of a type without a recovery policy,
+    /// the others are cached on access.
+    pub recovery_eager_limit: Option<usize>,
+    /// Number of replaced values `insert` keeps per key of the types named here, by
+    /// `StorageData::name`, instead of their `StorageData::history`.
+    pub history_limits: HashMap<String, usize>,
+    /// Limits on the records of the types named here, by `StorageData::name`, counted
+    /// in each namespace on its own.
+    pub quotas: HashMap<String, Quota>,
+    /// Limits on the records of all types of the namespaces named here together.
+    pub namespace_quotas: HashMap<String, Quota>,
+    pub sequence_overflow: SequenceOverflow,
+    /// Log a warning for every operation that takes longer than this many milliseconds.
+    pub slow_op_threshold_ms: Option<u64>,
+    /// Values whose stored bytes are larger than this are written to the db only, so a
+    /// few large values don't evict many small ones.
+    pub cache_max_value_size: Option<usize>,
+    /// Refuse to write values whose stored bytes are larger than this, with
+    /// `StorageError::ValueTooLarge`, before they reach the db.
+    pub max_value_size: Option<usize>,
+    /// Normalization of user input by `Storage::normalize_key`.
+    pub key_rules: KeyRules,
+    /// Size of the chunks blobs are split into, see `Storage::put_blob`.
+    pub blob_chunk_size: usize,
+    /// Time `gc_blobs` leaves content without references alone after it was put or
+    /// last released, so a record about to reference it can still be written.
+    pub cas_gc_grace_ms: u64,
+    /// Only write values to the cache on `insert` and persist them to the db in batches on
+    /// `run_pending_tasks`, the maintenance task and `close`. Trades the durability of the
+    /// writes since the last persist for write throughput.
+    pub write_back: bool,
+    /// Record every mutation in the audit log, see `Storage::audit_log`.
+    pub audit: bool,
+    /// Seconds audit entries are kept for, pruned by `Storage::apply_retention`.
+    pub audit_retention: Option<u64>,
+    /// Record the mutations of data trees in the change log replicas follow, see
+    /// `Storage::serve_replication`. Not available with `write_back` or cache expiry.
+    pub change_log: bool,
+    /// Secret replicas prove to the primary they replicate from, required on both.
+    #[serde(skip_serializing)]
+    pub replication_secret: Option<ReplicationSecret>,
+    /// Refuse to write records with `StorageError::ReadOnly`, see `Storage::follow`.
+    pub read_only: bool,
+    /// Open a db in a new temporary directory instead of `db_path`, deleted once the
+    /// storage and all its clones are dropped. Meant for tests and benchmarks.
+    pub temporary: bool,
+    /// Tuning of the underlying sled db.
+    pub sled: SledConfig,
+    /// Codec new values are written with, unless their type selects its own.
+    pub format: Format,
+    /// Codecs tried in order for values written before the envelope existed, when the
+    /// configured codec can't read them. Such values are rewritten in the configured codec.
+    pub format_fallbacks: Vec<Format>,
+    /// Values whose encoding is larger than this many bytes are stored zstd compressed.
+    pub compress_threshold: Option<usize>,
+    /// Move values that `get` finds damaged into a quarantine tree instead of leaving
+    /// them in place, see `Storage::quarantined`.
+    pub quarantine_corrupt: bool,
+    /// Encrypt new values with this key, values written without it stay readable.
+    #[cfg(feature = "encryption")]
+    #[serde(skip_serializing)]
+    pub encryption_key: Option<EncryptionKey>,
+    /// Id stored with the values encrypted by `encryption_key`.
+    #[cfg(feature = "encryption")]
+    pub encryption_key_id: u32,
+    /// Retired keys by id, only used to read values encrypted before a key rotation.
+    #[cfg(feature = "encryption")]
+    #[serde(skip_serializing)]
+    pub decryption_keys: HashMap<u32, EncryptionKey>,
+    /// Source of the keys not given above, including the active one when
+    /// `encryption_key` is unset. Keys are fetched by id on first use.
+    #[cfg(feature = "encryption")]
+    #[serde(skip)]
+    pub key_provider: Option<Arc<dyn KeyProvider>>,
 }
 
 impl Default for StorageConfig {
@@ -42,14 +419,155 @@ impl Default for StorageConfig {
             cache_max_capacity: None,
             cache_time_to_live: None,
             cache_time_to_idle: None,
+            cache_policies: HashMap::new(),
+            cache_admission_window: None,
+            negative_cache_ttl_ms: None,
+            bloom_filters: false,
+            require_explicit_migration: false,
+            recovery_policies: HashMap::new(),
+            recovery_eager_limit: Some(DEFAULT_RECOVERY_EAGER_LIMIT),
+            history_limits: HashMap::new(),
+            quotas: HashMap::new(),
+            namespace_quotas: HashMap::new(),
+            sequence_overflow: SequenceOverflow::default(),
+            slow_op_threshold_ms: None,
+            cache_max_value_size: None,
+            max_value_size: None,
+            key_rules: KeyRules::default(),
+            blob_chunk_size: DEFAULT_BLOB_CHUNK_SIZE,
+            cas_gc_grace_ms: DEFAULT_CAS_GC_GRACE_MS,
+            write_back: false,
+            audit: false,
+            audit_retention: None,
+            change_log: false,
+            replication_secret: None,
+            read_only: false,
+            temporary: false,
+            sled: SledConfig::default(),
+            format: Format::default(),
+            format_fallbacks: vec![Format::Bincode],
+            compress_threshold: None,
+            quarantine_corrupt: false,
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+            #[cfg(feature = "encryption")]
+            encryption_key_id: 0,
+            #[cfg(feature = "encryption")]
+            decryption_keys: HashMap::new(),
+            #[cfg(feature = "encryption")]
+            key_provider: None,
+        }
+    }
+}
+
+/// Options passed to sled when opening the db, `None` keeps sled's default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SledConfig {
+    /// Size of sled's own page cache in bytes, by default 512MiB. It sits below the
+    /// value cache of `Storage`, so both together bound the memory use.
+    pub cache_capacity_bytes: Option<usize>,
+    /// Share of sled's cache reserved for its scan resistant entry cache, by default 20.
+    pub entry_cache_percent: Option<u8>,
+    /// Interval of sled's background flushes, by default 200. `Some(0)` disables them,
+    /// then data is only durable after `run_pending_tasks` or another explicit flush.
+    pub flush_every_ms: Option<usize>,
+    /// zstd level sled compresses its files with, by default 3.
+    pub zstd_compression_level: Option<i32>,
+    /// Fill ratio below which sled rewrites its heap files, by default 0.9. Lower values
+    /// trade disk space for less write amplification.
+    pub heap_file_fill_ratio: Option<f32>,
+}
+
+impl SledConfig {
+    pub(crate) fn open(&self, path: impl AsRef<std::path::Path>) -> Result<Db> {
+        let path = path.as_ref();
+        let db = self
+            .apply(sled::Config::new().path(path))
+            .open()
+            .map_err(|e| dir_lock::open_error(path, e))?;
+        dir_lock::write_pid(path);
+        Ok(db)
+    }
+
+    // a db in a new temporary directory, removed once the db is dropped, and its path
+    pub(crate) fn open_temporary(&self) -> Result<(Db, String)> {
+        let config = self.apply(sled::Config::tmp()?);
+        Ok((config.open()?, config.path.to_string_lossy().to_string()))
+    }
+
+    fn apply(&self, mut config: sled::Config) -> sled::Config {
+        if let Some(v) = self.cache_capacity_bytes {
+            config = config.cache_capacity_bytes(v);
+        }
+        if let Some(v) = self.entry_cache_percent {
+            config = config.entry_cache_percent(v);
         }
+        if let Some(v) = self.flush_every_ms {
+            config = config.flush_every_ms((v > 0).then_some(v));
+        }
+        if let Some(v) = self.zstd_compression_level {
+            config = config.zstd_compression_level(v);
+        }
+        if let Some(v) = self.heap_file_fill_ratio {
+            config.target_heap_file_fill_ratio = v;
+        }
+        config
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Storage {
-    cache: SegmentedCache<String, Bytes>,
+    cache: SegmentedCache<Vec<u8>, Bytes>,
     db: Db,
+    migrations: Migrations,
+    codecs: Codecs,
+    // keys seen once within the admission window
+    admission: Option<Cache<Vec<u8>, ()>>,
+    missing: Option<Cache<Vec<u8>, ()>>,
+    filters: Option<Filters>,
+    sequence_overflow: SequenceOverflow,
+    format: Format,
+    format_fallbacks: Vec<Format>,
+    compress_threshold: Option<usize>,
+    quarantine_corrupt: bool,
+    #[cfg(feature = "encryption")]
+    keyring: Option<Arc<Keyring>>,
+    types: Types,
+    cache_counters: Arc<CacheCounters>,
+    db_path: String,
+    // the options backups are opened with
+    sled: SledConfig,
+    last_flush: Arc<Mutex<Option<Instant>>>,
+    slow_op_threshold: Option<Duration>,
+    cache_max_value_size: Option<usize>,
+    max_value_size: Option<usize>,
+    key_rules: KeyRules,
+    blob_chunk_size: usize,
+    cas_gc_grace_ms: u64,
+    pinned: PinnedSet,
+    recovery_policies: Arc<HashMap<String, RecoveryPolicy>>,
+    recovery_eager_limit: Option<usize>,
+    history_limits: Arc<HashMap<String, usize>>,
+    quotas: Arc<HashMap<String, Quota>>,
+    namespace_quotas: Arc<HashMap<String, Quota>>,
+    usage: QuotaUsage,
+    lifecycle: Arc<Lifecycle>,
+    writes: WriteGate,
+    // the copies taken while writes continue
+    captures: Captures,
+    revisions: Arc<RevisionClock>,
+    audit: bool,
+    audit_retention: Option<u64>,
+    change_log: bool,
+    replication_secret: Option<ReplicationSecret>,
+    read_only: bool,
+    // recorded with the mutations in the audit log
+    actor: Option<Arc<str>>,
+    // opened trees by name, `open_tree` locks and allocates on every call
+    trees: Arc<DashMap<Vec<u8>, Tree>>,
+    // the prefix of all tree names, see `namespace`
+    namespace: Option<Arc<str>>,
 }
 
 unsafe impl Send for Storage {}
@@ -64,197 +582,1027 @@ impl Default for Storage {
 
 impl Storage {
     pub fn new(config: &StorageConfig) -> Self {
-        let db = sled::open(&config.db_path).unwrap();
+        Self::open(config).unwrap()
+    }
+
+    /// Open the db ready to serve, with a cold cache.
+    ///
+    /// All existing trees and the internal metadata are opened and probed up front, so
+    /// that a failover process fails fast on an unreadable db instead of on first access.
+    /// Nothing is loaded into the cache, use `recover` once warming up is affordable.
+    pub fn open_cold(config: &StorageConfig) -> Result<Self> {
+        let storage = Self::open(config)?;
+        for name in INTERNAL_TREE_NAMES
+            .into_iter()
+            .map(str::to_string)
+            .chain(storage.data_tree_names())
+        {
+            let tree = storage.db.open_tree(&name)?;
+            tree.first()
+                .map_err(|e| eyre!("tree({}) is not readable: {}", name, e))?;
+        }
+        storage.db.first()?;
+        debug!("Opened {} cold", storage.db_path);
+        Ok(storage)
+    }
+
+    fn open(config: &StorageConfig) -> Result<Self> {
+        config.validate()?;
+        let (db, db_path) = if config.temporary {
+            config.sled.open_temporary()?
+        } else {
+            (config.sled.open(&config.db_path)?, config.db_path.clone())
+        };
         let db_clone = Arc::new(Mutex::new(db.clone()));
+        let cache_counters = Arc::new(CacheCounters::default());
+        let counters = cache_counters.clone();
+        let dirty = config.write_back.then(DirtySet::default);
+        let listener_dirty = dirty.clone();
+        let pinned: PinnedSet = Arc::default();
+        let listener_pinned = pinned.clone();
+        let usage = QuotaUsage::default();
+        let captures = Captures::default();
+        let listener_captures = captures.clone();
+        let listener_usage = usage.clone();
 
         let mut builder = SegmentedCache::builder(config.cache_num_segments)
-            .weigher(|k: &String, v: &Bytes| (k.len() + v.len()) as u32)
+            .weigher(|k: &Vec<u8>, v: &Bytes| (k.len() + v.len()) as u32)
             .eviction_listener(move |key, value, cause| {
                 debug!(
                     "Evicted ({:?},{:?}) because {:?} by cache",
                     key, value, cause
                 );
+                counters.evicted(cause);
                 match cause {
-                    RemovalCause::Explicit | RemovalCause::Expired => {
-                        if let Some(real_key) = key.strip_prefix(":/") {
-                            let (tree, key) = real_key.split_once('/').unwrap();
+                    // a pending write-back value has to reach the db before it is gone
+                    RemovalCause::Size => {
+                        if let Some(dirty) = &listener_dirty {
+                            dirty.remove_if(key.as_slice(), |_, _| {
+                                if let Some((tree, key)) = split_ckey(key.as_slice()) {
+                                    let tree = db_clone.lock().open_tree(tree).unwrap();
+                                    tree.insert(key, value.as_ref()).unwrap();
+                                } else {
+                                    db_clone
+                                        .lock()
+                                        .insert(key.as_slice(), value.as_ref())
+                                        .unwrap();
+                                }
+                                listener_captures.record(key.as_slice());
+                                true
+                            });
+                        }
+                    }
+                    // pinned values outlive their expiry
+                    RemovalCause::Expired if listener_pinned.contains_key(key.as_slice()) => {}
+                    RemovalCause::Expired => {
+                        if let Some(dirty) = &listener_dirty {
+                            dirty.remove(key.as_slice());
+                        }
+                        if let Some((tree, key)) = split_ckey(key.as_slice()) {
+                            forget_usage(&listener_usage, &String::from_utf8_lossy(tree));
                             let tree = db_clone.lock().open_tree(tree).unwrap();
                             tree.remove(key).unwrap();
                         } else {
-                            db_clone.lock().remove(key.as_str()).unwrap();
+                            db_clone.lock().remove(key.as_slice()).unwrap();
                         }
+                        listener_captures.record(key.as_slice());
                         debug!("Evicted ({:?},{:?}) because {:?} by db", key, value, cause);
                     }
+                    // removals delete from the db themselves, invalidations keep the value
                     _ => {}
                 }
             });
         if let Some(v) = config.cache_max_capacity {
             builder = builder.max_capacity(v)
         }
-        if let Some(v) = config.cache_time_to_live {
-            builder = builder.time_to_live(Duration::from_secs(v))
-        }
-        if let Some(v) = config.cache_time_to_idle {
-            builder = builder.time_to_idle(Duration::from_secs(v))
+        if let Some(expiry) = TypeExpiry::from_config(config) {
+            builder = builder.expire_after(expiry)
+        } else {
+            if let Some(v) = config.cache_time_to_live {
+                builder = builder.time_to_live(Duration::from_secs(v))
+            }
+            if let Some(v) = config.cache_time_to_idle {
+                builder = builder.time_to_idle(Duration::from_secs(v))
+            }
         }
 
         let cache = builder.build();
 
-        Self { cache, db }
+        let admission = config.cache_admission_window.map(|v| {
+            Cache::builder()
+                .max_capacity(ADMISSION_MAX_CAPACITY)
+                .time_to_live(Duration::from_secs(v))
+                .build()
+        });
+
+        let write_back = dirty
+            .map(|dirty| WriteBack::new(dirty, cache.clone(), pinned.clone(), captures.clone()));
+        let lifecycle = Arc::new(Lifecycle::new(db.clone(), write_back));
+        let storage = Self {
+            cache,
+            db,
+            migrations: Migrations::new(config.require_explicit_migration),
+            codecs: Codecs::default(),
+            admission,
+            missing: Self::missing_cache(config),
+            filters: config.bloom_filters.then(Filters::default),
+            sequence_overflow: config.sequence_overflow,
+            format: config.format,
+            format_fallbacks: config.format_fallbacks.clone(),
+            compress_threshold: config.compress_threshold,
+            quarantine_corrupt: config.quarantine_corrupt,
+            #[cfg(feature = "encryption")]
+            keyring: Keyring::from_config(config)?.map(Arc::new),
+            types: Types::default(),
+            cache_counters,
+            db_path,
+            sled: config.sled,
+            last_flush: Arc::default(),
+            slow_op_threshold: config.slow_op_threshold_ms.map(Duration::from_millis),
+            cache_max_value_size: config.cache_max_value_size,
+            max_value_size: config.max_value_size,
+            key_rules: config.key_rules,
+            blob_chunk_size: config.blob_chunk_size,
+            cas_gc_grace_ms: config.cas_gc_grace_ms,
+            pinned,
+            recovery_policies: Arc::new(config.recovery_policies.clone()),
+            recovery_eager_limit: config.recovery_eager_limit,
+            history_limits: Arc::new(config.history_limits.clone()),
+            quotas: Arc::new(config.quotas.clone()),
+            namespace_quotas: Arc::new(config.namespace_quotas.clone()),
+            usage,
+            lifecycle,
+            writes: WriteGate::default(),
+            captures,
+            revisions: Arc::default(),
+            audit: config.audit,
+            audit_retention: config.audit_retention,
+            change_log: config.change_log,
+            replication_secret: config.replication_secret.clone(),
+            read_only: config.read_only,
+            actor: None,
+            trees: Arc::default(),
+            namespace: None,
+        };
+        // removals only look for the tags of types that have a tag tree
+        for name in tree_names(&storage.db)
+            .iter()
+            .filter(|name| is_tag_tree(name))
+        {
+            storage.tree(name)?;
+        }
+        Ok(storage)
     }
 
-    pub fn recover_root(&self) {
-        self.db.iter().for_each(|r| {
-            if let Ok((k, v)) = r {
-                let key = String::from_utf8_lossy(&k).to_string();
-                debug!("Recover cache for root: {:?}", key);
-                self.cache.insert(key, Bytes::from(v.to_vec()));
-            }
-        });
+    /// Names of all trees in the db, excluding the root tree.
+    pub fn tree_names(&self) -> Vec<String> {
+        tree_names(&self.db)
     }
 
-    pub fn recover<T: StorageData>(&self) {
-        if let Ok(tree) = self.db.open_tree(T::name()) {
-            tree.iter().for_each(|r| {
-                if let Ok((k, v)) = r {
-                    let key = String::from_utf8_lossy(&k);
-                    debug!("Recover cache for tree({}): {:?}", T::name(), key);
-                    self.cache.insert(ckey::<T>(&key), Bytes::from(v.to_vec()));
-                }
-            });
+    // relative to the namespace of this storage
+    pub(crate) fn data_tree_names(&self) -> Vec<String> {
+        self.own_tree_names()
+            .into_iter()
+            .filter(|name| !is_internal_tree(name))
+            .collect()
+    }
+
+    // the tree `name` of this storage's namespace
+    fn tree(&self, name: impl AsRef<str>) -> std::io::Result<Tree> {
+        let name = name.as_ref();
+        // cache keys end the tree name at the first '/', keys may contain any byte
+        if name.contains('/') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("tree name {:?} contains '/'", name),
+            ));
         }
+        self.db_tree(self.tree_name(name).as_bytes())
+    }
+
+    // the db tree `name`, as named by cache keys, opened once per `Storage` and its
+    // clones
+    fn db_tree(&self, name: &[u8]) -> std::io::Result<Tree> {
+        if let Some(tree) = self.trees.get(name) {
+            return Ok(tree.clone());
+        }
+        let tree = Tree::new(self.db.open_tree(name)?, name, self.captures.clone());
+        self.trees.insert(name.to_vec(), tree.clone());
+        Ok(tree)
     }
 
     pub fn run_pending_tasks(&self) {
+        self.try_run_pending_tasks().unwrap();
+    }
+
+    // the cache maintenance and db flush shared with the maintenance task
+    fn try_run_pending_tasks(&self) -> Result<()> {
+        let _enter = info_span!("storage.flush").entered();
+        let _slow = self.slow_db_op("flush");
         self.cache.run_pending_tasks();
-        self.db.flush().unwrap();
+        if let Some(write_back) = self.write_back() {
+            let _writes = self.write_gate();
+            write_back.persist(&self.db)?;
+        }
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        self.db.flush()?;
+        self.record_flush();
+        #[cfg(feature = "metrics")]
+        telemetry::flushed(start);
+        Ok(())
     }
 }
 
-// SEQUENCE
+fn tree_names(db: &Db) -> Vec<String> {
+    db.export()
+        .into_iter()
+        .map(|(_, name, _)| String::from_utf8_lossy(&name).to_string())
+        .collect()
+}
+
+// the internal trees of all namespaces
+fn is_internal_tree(name: &str) -> bool {
+    INTERNAL_TREE_NAMES.contains(&base_tree_name(name))
+}
+
+// the trees holding the records of a type, not the archived versions, tags, points and
+// entries kept next to them
+fn is_record_tree(name: &str) -> bool {
+    !is_internal_tree(name)
+        && !is_history_tree(name)
+        && !is_tag_tree(name)
+        && !is_series_tree(name)
+        && !is_journal_tree(name)
+}
+
+fn tree_ckey(name: &str, key: &[u8]) -> Vec<u8> {
+    let mut ckey = Vec::with_capacity(name.len() + key.len() + 3);
+    ckey.extend_from_slice(b":/");
+    ckey.extend_from_slice(name.as_bytes());
+    ckey.push(b'/');
+    ckey.extend_from_slice(key);
+    ckey
+}
+
+// tree name and key of a structured data cache key, `None` for root keys
+fn split_ckey(ckey: &[u8]) -> Option<(&[u8], &[u8])> {
+    let real_key = ckey.strip_prefix(b":/")?;
+    let split = real_key.iter().position(|b| *b == b'/')?;
+    Some((&real_key[..split], &real_key[split + 1..]))
+}
+
+// structured data
 impl Storage {
-    pub fn next(&self, name: &str) -> u32 {
-        let tree = self.db.open_tree(SEQUENCE_TREE_NAME).unwrap();
-        match tree.get(name).ok().and_then(|v| {
-            v.and_then(|v| match v.to_vec().try_into() {
-                Ok(v) => Some(u32::from_be_bytes(v)),
-                Err(_) => None,
-            })
-        }) {
-            Some(next) => {
-                if tree.insert(name, (next + 1).to_be_bytes().to_vec()).is_ok() {
-                    next + 1
-                } else {
-                    0
-                }
+    pub fn contains_key<T: StorageData>(&self, key: impl Into<StorageKey>) -> bool {
+        let key = key.into();
+        let tree = self.tree(T::name()).unwrap();
+        self.contains_key_in(&tree, &self.ckey::<T>(&key), &key)
+    }
+
+    pub fn get<T: for<'a> Deserialize<'a> + StorageData>(
+        &self,
+        key: impl Into<StorageKey>,
+    ) -> Option<T> {
+        let key = key.into();
+        let tree = self.tree(T::name()).unwrap();
+        self.get_in(&tree, self.ckey::<T>(&key), &key)
+    }
+
+    /// Like `get`, but bytes that don't match their checksum are reported as
+    /// `StorageError::Corrupted` instead of `None`.
+    pub fn try_get<T: StorageData>(&self, key: impl Into<StorageKey>) -> Result<Option<T>> {
+        let key = key.into();
+        let tree = self.tree(T::name())?;
+        self.try_get_in(&tree, self.ckey::<T>(&key), &key)
+    }
+
+    /// Insert `value` at `key`, returning the value it replaced. `None` when the key was
+    /// absent, its value undecodable or the insert failed.
+    pub fn insert<T: Serialize + StorageData>(
+        &self,
+        key: impl Into<StorageKey>,
+        value: T,
+    ) -> Option<T> {
+        let key = key.into();
+        let tree = self.tree(T::name()).unwrap();
+        self.insert_in(&tree, self.ckey::<T>(&key), &key, value)
+    }
+
+    /// Like `insert`, failing instead of logging when the value can't be written, for
+    /// one with `StorageError::QuotaExceeded`.
+    pub fn insert_checked<T: StorageData>(
+        &self,
+        key: impl Into<StorageKey>,
+        value: T,
+    ) -> Result<Option<T>> {
+        let key = key.into();
+        let tree = self.tree(T::name())?;
+        self.try_insert_in(&tree, self.ckey::<T>(&key), &key, value)
+    }
+
+    /// Remove `key`, returning its value. `None` when it was absent or undecodable.
+    pub fn remove<T: StorageData>(&self, key: impl Into<StorageKey>) -> Option<T> {
+        let key = key.into();
+        let tree = self.tree(T::name()).unwrap();
+        self.remove_in::<T>(&tree, &self.ckey::<T>(&key), &key)
+    }
+
+    /// Get the value of `key`, initializing it with `f` if absent.
+    ///
+    /// The initialization is a compare-and-swap against an absent key, if another writer
+    /// wins the race its value is returned and `f`'s value is dropped.
+    pub fn get_or_insert_with<T: StorageData, F: FnOnce() -> T>(
+        &self,
+        key: impl Into<StorageKey>,
+        f: F,
+    ) -> Option<T> {
+        let key = key.into();
+        let key = key.as_bytes();
+        let tree = self.tree(T::name()).unwrap();
+        let ckey = self.ckey::<T>(key);
+        if let Some(v) = self.get_in(&tree, ckey.clone(), key) {
+            return Some(v);
+        }
+
+        if let Err(e) = self.check_writable() {
+            warn!("Get or insert tree({}) failed: {}", T::name(), e);
+            return None;
+        }
+        let value = f();
+        let value_bytes = self.encode(key, &value, None).ok()?;
+        self.stamp_version::<T>();
+        let _writes = self.write_gate();
+        if let Err(e) = self.reserve::<T>(key, None, &value_bytes) {
+            warn!("Get or insert tree({}) failed: {}", T::name(), e);
+            return None;
+        }
+        match tree.compare_and_swap(key, None as Option<&[u8]>, Some(value_bytes.as_ref())) {
+            Ok(Ok(_)) => {
+                self.audit("insert", &T::name(), key, Some(&value_bytes));
+                self.swap_refs(&[], &value.content_refs());
+                self.cache_put(ckey, value_bytes);
+                Some(value)
             }
-            None => {
-                if tree.insert(name, 1u32.to_be_bytes().to_vec()).is_ok() {
-                    1
-                } else {
-                    0
-                }
+            Ok(Err(CompareAndSwapError {
+                current: Some(current),
+                ..
+            })) => {
+                self.recount(&T::name());
+                self.cache_put(ckey, Bytes::from(current.to_vec()));
+                self.decode(key, &current)
+            }
+            _ => {
+                self.recount(&T::name());
+                None
             }
         }
     }
 
-    pub fn current(&self, name: &str) -> u32 {
-        let tree = self.db.open_tree(SEQUENCE_TREE_NAME).unwrap();
-        if let Ok(Some(v)) = tree.get(name) {
-            if let Ok(v) = v.to_vec().try_into() {
-                return u32::from_be_bytes(v);
-            }
-        }
-        0
+    /// Insert `value` only if `key` is absent.
+    ///
+    /// If the key exists nothing is written and the error is a `CasError::Conflict`
+    /// carrying the existing value.
+    pub fn try_insert<T: StorageData>(
+        &self,
+        key: impl Into<StorageKey>,
+        value: T,
+    ) -> std::result::Result<(), CasError<T>> {
+        self.compare_and_swap(key, None, Some(value))
     }
-}
 
-// structured data key used in cache
-fn ckey<T: for<'a> Deserialize<'a> + StorageData>(key: &str) -> String {
-    let ckey = format!(":/{}/{}", T::name(), key);
-    ckey
-}
+    // the data path shared with `Collection`, which keeps its tree open
 
-// structured data
-impl Storage {
-    pub fn contains_key<T: StorageData>(&self, key: &str) -> bool {
-        if self.cache.contains_key(&ckey::<T>(key)) {
+    fn contains_key_in(&self, tree: &Tree, ckey: &Vec<u8>, key: &[u8]) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        if self.cache.contains_key(ckey) {
             return true;
         }
+        if self.known_missing(ckey) || self.definitely_absent(ckey) {
+            return false;
+        }
 
-        let tree = self.db.open_tree(T::name()).unwrap();
         if let Ok(r) = tree.contains_key(key) {
+            if !r {
+                self.remember_missing(ckey.clone());
+            }
             return r;
         }
 
         false
     }
 
-    pub fn get<T: for<'a> Deserialize<'a> + StorageData>(&self, key: &str) -> Option<T> {
-        if let Some(v) = self.cache.get(&ckey::<T>(key)) {
-            return bincode::deserialize(&v).ok();
+    fn get_in<T: StorageData>(&self, tree: &Tree, ckey: Vec<u8>, key: &[u8]) -> Option<T> {
+        self.try_get_in(tree, ckey, key).unwrap_or_else(|e| {
+            warn!(
+                "Get tree({}) key({}) failed: {}",
+                T::name(),
+                String::from_utf8_lossy(key),
+                e
+            );
+            None
+        })
+    }
+
+    fn try_get_in<T: StorageData>(
+        &self,
+        tree: &Tree,
+        ckey: Vec<u8>,
+        key: &[u8],
+    ) -> Result<Option<T>> {
+        #[cfg(feature = "metrics")]
+        let _timer = telemetry::OpTimer::start::<T>("get");
+        let span = info_span!(
+            "storage.get",
+            tree = %T::name(),
+            key_len = key.len(),
+            hit = Empty,
+            bytes = Empty
+        );
+        let _enter = span.enter();
+        let _slow = self.slow_op::<T>("get", key);
+        self.check_open()?;
+        let corrupted = || {
+            eyre!(StorageError::Corrupted(
+                String::from_utf8_lossy(key).to_string()
+            ))
+        };
+        if let Some(v) = self.cache_get(&ckey) {
+            self.cache_counters.hit();
+            span.record("hit", true).record("bytes", v.len());
+            if !envelope::verify(&v) {
+                self.quarantine::<T>(tree, &ckey, key, &v);
+                return Err(corrupted());
+            }
+            let value = self.decode(key, &v);
+            if value.is_none() {
+                self.quarantine::<T>(tree, &ckey, key, &v);
+            }
+            return Ok(value);
         }
 
-        let tree = self.db.open_tree(T::name()).unwrap();
-        if let Ok(Some(v)) = tree.get(key) {
-            let value = bincode::deserialize(&v).ok();
-            self.cache.insert(ckey::<T>(key), Bytes::from(v.to_vec()));
-            return value;
+        self.cache_counters.miss();
+        span.record("hit", false);
+        if self.known_missing(&ckey) || self.definitely_absent(&ckey) {
+            return Ok(None);
+        }
+        if let Some(v) = tree.get(key)? {
+            span.record("bytes", v.len());
+            if !envelope::verify(&v) {
+                self.quarantine::<T>(tree, &ckey, key, &v);
+                return Err(corrupted());
+            }
+            if self.admit(&ckey) {
+                self.cache_put(ckey.clone(), Bytes::from(v.to_vec()));
+            }
+            let value = self.decode(key, &v);
+            if value.is_none() {
+                self.quarantine::<T>(tree, &ckey, key, &v);
+            }
+            return Ok(value);
         }
 
-        None
+        self.remember_missing(ckey);
+        Ok(None)
+    }
+
+    fn insert_in<T: StorageData>(
+        &self,
+        tree: &Tree,
+        ckey: Vec<u8>,
+        key: &[u8],
+        value: T,
+    ) -> Option<T> {
+        self.try_insert_in(tree, ckey, key, value)
+            .unwrap_or_else(|e| {
+                warn!(
+                    "Insert tree({}) key({}) failed: {}",
+                    T::name(),
+                    String::from_utf8_lossy(key),
+                    e
+                );
+                None
+            })
+    }
+
+    fn try_insert_in<T: StorageData>(
+        &self,
+        tree: &Tree,
+        ckey: Vec<u8>,
+        key: &[u8],
+        value: T,
+    ) -> Result<Option<T>> {
+        #[cfg(feature = "metrics")]
+        let _timer = telemetry::OpTimer::start::<T>("insert");
+        let span = info_span!(
+            "storage.insert",
+            tree = %T::name(),
+            key_len = key.len(),
+            bytes = Empty
+        );
+        let _enter = span.enter();
+        let _slow = self.slow_op::<T>("insert", key);
+        self.check_writable()?;
+        let _writes = self.write_gate();
+        // read for its creation time, in write-back mode the cached value may be newer
+        // than the db
+        let current = match self.cache_get(&ckey) {
+            Some(v) => Some(v),
+            None => tree.get(key)?.map(|v| Bytes::from(v.to_vec())),
+        };
+        let value_bytes = self.encode(key, &value, current.as_deref())?;
+        let refs = value.content_refs();
+        span.record("bytes", value_bytes.len());
+        self.reserve::<T>(key, current.as_deref(), &value_bytes)?;
+        self.stamp_version::<T>();
+        if let Some(write_back) = self.write_back() {
+            self.audit("insert", &T::name(), key, Some(&value_bytes));
+            if !self.too_large_to_cache(&value_bytes) {
+                self.cache_put(ckey.clone(), value_bytes);
+                write_back.mark(ckey);
+            } else {
+                // written through, a persist must not overwrite it with an older value
+                let _unmarked = write_back.unmark(&ckey);
+                tree.insert(key, value_bytes.as_ref())?;
+                self.cache_put(ckey, value_bytes);
+            }
+            return Ok(self.replaced::<T>(key, current.as_deref(), &refs));
+        }
+        let previous = tree.insert(key, value_bytes.as_ref())?;
+        self.audit("insert", &T::name(), key, Some(&value_bytes));
+        self.cache_put(ckey, value_bytes);
+        Ok(self.replaced::<T>(key, previous.as_deref(), &refs))
+    }
+
+    // archive the value replaced by an insert, moving its content references to the
+    // new value's `refs`
+    fn replaced<T: StorageData>(
+        &self,
+        key: &[u8],
+        previous: Option<&[u8]>,
+        refs: &[ContentHash],
+    ) -> Option<T> {
+        let Some(previous) = previous else {
+            self.swap_refs(&[], refs);
+            return None;
+        };
+        self.archive::<T>(key, previous);
+        let previous = self.decode_with::<T>(key, previous, false);
+        let old_refs = previous.as_ref().map(T::content_refs).unwrap_or_default();
+        self.swap_refs(&old_refs, refs);
+        previous
     }
 
-    pub fn insert<T: Serialize + StorageData>(&self, key: &str, value: T) -> Option<T> {
-        if let Ok(value_bytes) = bincode::serialize(&value) {
-            let tree = self.db.open_tree(T::name()).unwrap();
-            tree.insert(key, value_bytes.clone()).unwrap();
-            self.cache.insert(ckey::<T>(key), Bytes::from(value_bytes));
-            return Some(value);
+    fn remove_in<T: StorageData>(&self, tree: &Tree, ckey: &Vec<u8>, key: &[u8]) -> Option<T> {
+        #[cfg(feature = "metrics")]
+        let _timer = telemetry::OpTimer::start::<T>("remove");
+        let _enter = info_span!("storage.remove", tree = %T::name(), key_len = key.len()).entered();
+        let _slow = self.slow_op::<T>("remove", key);
+        if let Err(e) = self.check_writable() {
+            warn!("Remove tree({}) failed: {}", T::name(), e);
+            return None;
         }
-        None
+        let _writes = self.write_gate();
+        let _unmarked = self.write_back().map(|write_back| write_back.unmark(ckey));
+        // in write-back mode the cached value may be newer than the db
+        let pending = self.write_back().and_then(|_| self.cache_get(ckey));
+        let stored = tree.remove(key).unwrap();
+        if let Some(removed) = pending.as_deref().or(stored.as_deref()) {
+            self.release(&self.tree_name(&T::name()), key, removed);
+        }
+        self.audit("remove", &T::name(), key, None);
+        if let Err(e) = self.untag_tree(&T::name(), key) {
+            warn!("Untag tree({}) failed: {}", T::name(), e);
+        }
+        self.cache_remove(ckey);
+        // not persisting a migrated value, that would write the key back
+        let removed: T = match pending {
+            Some(v) => self.decode_with(key, &v, false),
+            None => self.decode_with(key, &stored?, false),
+        }?;
+        self.swap_refs(&removed.content_refs(), &[]);
+        Some(removed)
     }
 
-    pub fn remove<T: StorageData>(&self, key: &str) {
-        let tree = self.db.open_tree(T::name()).unwrap();
-        tree.remove(key).unwrap();
-        self.cache.remove(&ckey::<T>(key));
+    // CACHE VALUES
+    // Values above `cache_max_value_size` are cached as an empty marker, which reads
+    // treat as a miss. It keeps `contains_key` and expiry working without holding the
+    // value. Stored values are never empty, an empty raw payload is read from the db.
+
+    fn too_large_to_cache(&self, bytes: &[u8]) -> bool {
+        self.cache_max_value_size
+            .is_some_and(|max| bytes.len() > max)
+    }
+
+    fn cache_put(&self, ckey: Vec<u8>, bytes: Bytes) {
+        self.forget_missing(&ckey);
+        self.filter_insert(&ckey);
+        if let Some(mut pinned) = self.pinned.get_mut(&ckey) {
+            *pinned = bytes.clone();
+        }
+        if self.too_large_to_cache(&bytes) {
+            self.cache.insert(ckey, Bytes::new());
+        } else {
+            self.cache.insert(ckey, bytes);
+        }
+    }
+
+    fn cache_get(&self, ckey: &Vec<u8>) -> Option<Bytes> {
+        self.cache
+            .get(ckey)
+            .filter(|v| !v.is_empty())
+            .or_else(|| self.pinned.get(ckey).map(|v| v.clone()))
+    }
+
+    // only once the key is gone from the db, or it reads the removed value again
+    fn cache_remove(&self, ckey: &Vec<u8>) {
+        self.pinned.remove(ckey);
+        self.cache.remove(ckey);
+    }
+
+    // whether a value read from the db should be put into the cache
+    fn admit(&self, ckey: &Vec<u8>) -> bool {
+        match &self.admission {
+            None => true,
+            Some(seen) => {
+                if seen.contains_key(ckey) {
+                    seen.invalidate(ckey);
+                    true
+                } else {
+                    seen.insert(ckey.clone(), ());
+                    false
+                }
+            }
+        }
+    }
+
+    fn format_of<T: StorageData>(&self) -> Format {
+        T::format().unwrap_or(self.format)
+    }
+
+    fn codec_of<T: StorageData>(&self) -> TypeCodec<T> {
+        self.codecs
+            .get::<T>()
+            .unwrap_or_else(|| self.format_of::<T>().into())
+    }
+
+    fn compress_above<T: StorageData>(&self) -> Option<usize> {
+        if T::compress() {
+            Some(0)
+        } else {
+            self.compress_threshold
+        }
+    }
+
+    // the bytes written to both the db and the cache, sled copies them into its own
+    // buffer, the cache shares them. `previous` are the stored bytes `value` replaces at
+    // `key`.
+    fn encode<T: StorageData>(
+        &self,
+        key: &[u8],
+        value: &T,
+        previous: Option<&[u8]>,
+    ) -> Result<Bytes> {
+        let bytes = self.seal(
+            envelope::encode(self.codec_of::<T>(), self.compress_above::<T>(), value)?,
+            previous.map(envelope::created),
+            &self.tree_name(&T::name()),
+            key,
+        )?;
+        self.check_value_size(&bytes)?;
+        Ok(Bytes::from(bytes))
+    }
+
+    // only new values are checked, rewrites of stored ones keep working
+    fn check_value_size(&self, bytes: &[u8]) -> Result<()> {
+        match self.max_value_size {
+            Some(limit) if bytes.len() > limit => Err(eyre!(StorageError::ValueTooLarge {
+                size: bytes.len(),
+                limit
+            })),
+            _ => Ok(()),
+        }
+    }
+
+    // finish an enveloped value for writing, stamped with a new record version,
+    // encrypted for `key` of the db tree `tree` when a key is configured and
+    // checksummed. `created` is the creation time of the record the value replaces,
+    // `None` for a new record, which is created now, and 0 for records written before
+    // creation times existed.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn seal(
+        &self,
+        mut bytes: Vec<u8>,
+        created: Option<u64>,
+        tree: &str,
+        key: &[u8],
+    ) -> Result<Vec<u8>> {
+        let revision = self.next_revision();
+        envelope::set_revision(&mut bytes, revision);
+        let created = created.unwrap_or(revision);
+        if created != 0 {
+            envelope::set_created(&mut bytes, created);
+        }
+        #[cfg(feature = "encryption")]
+        if let Some(keyring) = &self.keyring {
+            bytes = keyring.seal(bytes, tree, key)?;
+        }
+        envelope::append_checksum(&mut bytes);
+        Ok(bytes)
+    }
+
+    // the value as `envelope::encode` wrote it, before encryption and checksum, stored
+    // at `key` of the db tree `tree`
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn unseal<'a>(&self, bytes: &'a [u8], tree: &str, key: &[u8]) -> Result<Cow<'a, [u8]>> {
+        #[cfg(feature = "encryption")]
+        if envelope::split(bytes).0.flags & envelope::FLAG_ENCRYPTED != 0 {
+            let keyring = self
+                .keyring
+                .as_ref()
+                .ok_or_else(|| eyre!("value is encrypted but no key is configured"))?;
+            return Ok(Cow::Owned(keyring.unseal(bytes, tree, key)?));
+        }
+        Ok(envelope::without_checksum(bytes))
+    }
+
+    // header and codec payload of a value stored at `key` of the db tree `tree`,
+    // verified, decrypted and decompressed
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn open_value<'a>(
+        &self,
+        bytes: &'a [u8],
+        tree: &str,
+        key: &[u8],
+    ) -> Result<(Header, Cow<'a, [u8]>)> {
+        if !envelope::verify(bytes) {
+            return Err(eyre!("checksum mismatch"));
+        }
+        #[cfg(feature = "encryption")]
+        if envelope::split(bytes).0.flags & envelope::FLAG_ENCRYPTED != 0 {
+            let keyring = self
+                .keyring
+                .as_ref()
+                .ok_or_else(|| eyre!("value is encrypted but no key is configured"))?;
+            let (header, payload) = envelope::open(&keyring.unseal(bytes, tree, key)?)
+                .map(|(header, payload)| (header, payload.into_owned()))?;
+            return Ok((header, Cow::Owned(payload)));
+        }
+        envelope::open(bytes)
+    }
+
+    // the stored bytes a swap expecting `expected` has to match. Values are compared
+    // as encoded, since encrypted values get a fresh nonce on every write.
+    fn expected_bytes<T: StorageData>(
+        &self,
+        tree: &Tree,
+        key: &[u8],
+        expected: &T,
+    ) -> Result<Vec<u8>> {
+        let plain = envelope::encode(self.codec_of::<T>(), self.compress_above::<T>(), expected)?;
+        let name = self.tree_name(&T::name()).into_owned();
+        if let Some(current) = tree.get(key)? {
+            let unsealed = self.unseal(&current, &name, key);
+            if unsealed.is_ok_and(|current| envelope::without_stamps(&current) == plain) {
+                return Ok(current.to_vec());
+            }
+        }
+        self.seal(plain, None, &name, key)
+    }
+
+    fn decode<T: StorageData>(&self, key: &[u8], bytes: &[u8]) -> Option<T> {
+        self.decode_with(key, bytes, true)
+    }
+
+    // with `persist` off, records at an older version are upgraded in memory only
+    fn decode_with<T: StorageData>(&self, key: &[u8], bytes: &[u8], persist: bool) -> Option<T> {
+        let name = T::name();
+        self.decode_in(&self.tree_name(&name), key, bytes, persist)
+    }
+
+    // a value stored at `key` of the db tree `tree`, the tree of `T` in this or another
+    // namespace
+    fn decode_in<T: StorageData>(
+        &self,
+        tree: &str,
+        key: &[u8],
+        bytes: &[u8],
+        persist: bool,
+    ) -> Option<T> {
+        let (header, payload) = match self.open_value(bytes, tree, key) {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!(
+                    "tree({}) key({}) failed to open: {}",
+                    T::name(),
+                    String::from_utf8_lossy(key),
+                    e
+                );
+                return None;
+            }
+        };
+        let codec = self.codec_of::<T>();
+        if header.format != codec.id && header.codec().is_none() {
+            warn!(
+                "tree({}) key({}) has unknown format {}",
+                T::name(),
+                String::from_utf8_lossy(key),
+                header.format
+            );
+            return None;
+        }
+        if header.version != T::version() {
+            return self.upgrade(key, bytes, header, &payload, persist);
+        }
+        if header.format != codec.id || !envelope::is_enveloped(bytes) {
+            return self.decode_foreign(key, bytes, &payload, persist);
+        }
+        codec.deserialize(&payload).ok()
+    }
+
+    /// Atomically replace the value of `key` with `f(current)`, `None` removes it.
+    ///
+    /// `f` may run several times under contention. An undecodable current value is
+    /// passed as `None`. Returns the new value.
+    pub fn update<T: StorageData, F: FnMut(Option<T>) -> Option<T>>(
+        &self,
+        key: impl Into<StorageKey>,
+        mut f: F,
+    ) -> Result<Option<T>> {
+        let key = key.into();
+        let key = key.as_bytes();
+        self.check_writable()?;
+        self.stamp_version::<T>();
+        let tree = self.tree(T::name())?;
+        let _writes = self.write_gate();
+        if let Some(write_back) = self.write_back() {
+            write_back.persist_key(&tree, &self.ckey::<T>(key), key)?;
+        }
+        let name = self.tree_name(&T::name()).into_owned();
+        // swapped in a loop rather than `update_and_fetch`, each try is counted against the
+        // quotas before it is written
+        let (stored, new_value, new_bytes, old_refs) = loop {
+            let stored = tree.get(key)?;
+            let current: Option<T> = stored
+                .as_ref()
+                .and_then(|v| self.decode_with(key, v, false));
+            let old_refs = current.as_ref().map(T::content_refs).unwrap_or_default();
+            let new_value = f(current);
+            let new_bytes = new_value
+                .as_ref()
+                .map(|v| self.encode(key, v, stored.as_deref()))
+                .transpose()?;
+            if let Some(new) = &new_bytes {
+                self.reserve::<T>(key, stored.as_deref(), new)?;
+            }
+            match tree.compare_and_swap(key, stored.as_deref(), new_bytes.as_deref())? {
+                Ok(_) => break (stored, new_value, new_bytes, old_refs),
+                Err(_) => self.recount(&T::name()),
+            }
+        };
+        if let (Some(stored), None) = (&stored, &new_bytes) {
+            self.release(&name, key, stored);
+        }
+        self.audit("update", &T::name(), key, new_bytes.as_deref());
+        let new_refs = new_value.as_ref().map(T::content_refs).unwrap_or_default();
+        self.swap_refs(&old_refs, &new_refs);
+        let ckey = self.ckey::<T>(key);
+        match new_bytes {
+            Some(new) => {
+                self.cache_put(ckey.clone(), new.clone());
+                // a concurrent update can swap after this one but put before it, the value
+                // is then read from the db again
+                let now = tree.get(key)?;
+                if now.as_deref() != Some(&new[..]) {
+                    self.cache.remove(&ckey);
+                    if let (Some(mut pinned), Some(now)) = (self.pinned.get_mut(&ckey), now) {
+                        *pinned = Bytes::from(now.to_vec());
+                    }
+                }
+            }
+            None => {
+                self.cache_remove(&ckey);
+            }
+        }
+        Ok(new_value)
     }
 
     pub fn cas<T: Serialize + StorageData>(
         &self,
-        key: &str,
+        key: impl Into<StorageKey>,
         old: Option<T>,
         new: Option<T>,
     ) -> Result<()> {
-        let old_bytes = old.map(|v| bincode::serialize(&v).unwrap_or_default());
-        let new_bytes = new.map(|v| bincode::serialize(&v).unwrap_or_default());
-        let tree = self.db.open_tree(T::name())?;
-        let cas_result = tree.compare_and_swap(key, old_bytes, new_bytes);
-        match cas_result {
-            Ok(Ok(CompareAndSwapSuccess { new_value, .. })) => match new_value {
-                Some(new) => {
-                    self.cache.insert(ckey::<T>(key), Bytes::from(new.to_vec()));
+        self.compare_and_swap(key, old.as_ref(), new)
+            .map_err(|_| eyre!("cas failed"))
+    }
+
+    /// Set `key` to `new` if its current value is `expected`, `None` meaning absent.
+    ///
+    /// The cache is only updated when the swap succeeds. On conflict the error carries
+    /// the current value.
+    pub fn compare_and_swap<T: StorageData>(
+        &self,
+        key: impl Into<StorageKey>,
+        expected: Option<&T>,
+        new: Option<T>,
+    ) -> std::result::Result<(), CasError<T>> {
+        let key = key.into();
+        let key = key.as_bytes();
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        self.check_swappable()?;
+        let tree = self.tree(T::name())?;
+        let _writes = self.write_gate();
+        if let Some(write_back) = self.write_back() {
+            write_back
+                .persist_key(&tree, &self.ckey::<T>(key), key)
+                .map_err(invalid)?;
+        }
+        let old_bytes = expected
+            .map(|v| self.expected_bytes(&tree, key, v).map_err(invalid))
+            .transpose()?;
+        let new_bytes = new
+            .as_ref()
+            .map(|v| self.encode(key, v, old_bytes.as_deref()).map_err(invalid))
+            .transpose()?;
+        let name = self.tree_name(&T::name()).into_owned();
+        if let Some(new) = &new_bytes {
+            let quota = |e| std::io::Error::new(std::io::ErrorKind::QuotaExceeded, e);
+            self.reserve::<T>(key, old_bytes.as_deref(), new)
+                .map_err(quota)?;
+        }
+        self.stamp_version::<T>();
+        match tree.compare_and_swap(key, old_bytes.as_deref(), new_bytes.as_deref())? {
+            Ok(_) => {
+                if let (Some(old), None) = (&old_bytes, &new_bytes) {
+                    self.release(&name, key, old);
                 }
-                None => {
-                    self.cache.remove(&ckey::<T>(key));
+                self.audit("compare_and_swap", &T::name(), key, new_bytes.as_deref());
+                self.swap_refs(
+                    &expected.map(T::content_refs).unwrap_or_default(),
+                    &new.as_ref().map(T::content_refs).unwrap_or_default(),
+                );
+                match new_bytes {
+                    Some(new) => {
+                        self.cache_put(self.ckey::<T>(key), new);
+                    }
+                    None => {
+                        self.cache_remove(&self.ckey::<T>(key));
+                    }
                 }
-            },
-            _ => {
-                return Err(eyre!("cas failed"));
+                Ok(())
+            }
+            Err(CompareAndSwapError { current, .. }) => {
+                self.recount(&T::name());
+                Err(CasError::Conflict {
+                    current: current.and_then(|v| self.decode_with(key, &v, false)),
+                    proposed: new,
+                })
             }
         }
-        Ok(())
+    }
+}
+
+// a directory of its own in the system's temporary one, removed when dropped, for the
+// tests that need a db at a path
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        static DIRS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let dirs = DIRS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!(
+            "storage_hal-{}-{}-{}",
+            name,
+            std::process::id(),
+            dirs
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    // `name` in the directory, as `StorageConfig::db_path` takes it
+    pub(crate) fn path(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
     }
 }
 
 #[test]
 fn sequence() {
-    // Re-operation requires replacing db_path or cleaning up db_path
-    let store: Storage = Storage::new(&StorageConfig {
-        db_path: "test_sequence.db".to_string(),
-        ..Default::default()
-    });
+    let store: Storage = Storage::builder().temporary().open().unwrap();
     // The first run starts at 0
     assert_eq!(0, store.current("test"));
     for i in 0..100 {
@@ -265,7 +1613,7 @@ fn sequence() {
 #[test]
 fn eviction() {
     let store: Storage = Storage::new(&StorageConfig {
-        db_path: "test_eviction.db".to_string(),
+        temporary: true,
         cache_time_to_live: Some(1),
         cache_max_capacity: Some(1024 * 1024 * 1024),
         cache_num_segments: 10,
@@ -277,23 +1625,26 @@ fn eviction() {
         for i in 0..10000u32 {
             store_clone
                 .cache
-                .insert(i.to_string(), Bytes::from(i.to_string()));
+                .insert(i.to_string().into_bytes(), Bytes::from(i.to_string()));
             store_clone
                 .db
                 .insert(i.to_string(), i.to_string().as_bytes())
                 .unwrap();
         }
 
-        println!("{:?}", store_clone.cache.get(&9999u32.to_string()));
+        println!(
+            "{:?}",
+            store_clone.cache.get(9999u32.to_string().as_bytes())
+        );
         println!("{:?}", store_clone.db.get(9999u32.to_string()));
         tokio::time::sleep(Duration::from_millis(700)).await;
-        println!("{:?}", store.cache.get(&9999u32.to_string()));
+        println!("{:?}", store.cache.get(9999u32.to_string().as_bytes()));
         println!("{:?}", store.db.get(9999u32.to_string()));
         tokio::time::sleep(Duration::from_millis(300)).await;
-        println!("{:?}", store.cache.get(&9999u32.to_string()));
+        println!("{:?}", store.cache.get(9999u32.to_string().as_bytes()));
         println!("{:?}", store.db.get(9999u32.to_string()));
         tokio::time::sleep(Duration::from_millis(10)).await;
-        println!("{:?}", store.cache.get(&9999u32.to_string()));
+        println!("{:?}", store.cache.get(9999u32.to_string().as_bytes()));
         println!("{:?}", store.db.get(9999u32.to_string()));
     });
     rt.block_on(async {
@@ -301,6 +1652,30 @@ fn eviction() {
     });
 }
 
+#[test]
+fn admission() {
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        cache_admission_window: Some(60),
+        ..Default::default()
+    });
+    store
+        .db
+        .open_tree(String::name())
+        .unwrap()
+        .insert(
+            "test",
+            envelope::checksummed(
+                envelope::encode(Format::Bincode, None, &"test".to_string()).unwrap(),
+            ),
+        )
+        .unwrap();
+    assert_eq!(Some("test".to_string()), store.get::<String>("test"));
+    assert!(!store.cache.contains_key(&store.ckey::<String>(b"test")));
+    assert_eq!(Some("test".to_string()), store.get::<String>("test"));
+    assert!(store.cache.contains_key(&store.ckey::<String>(b"test")));
+}
+
 #[test]
 fn structured() {
     #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
@@ -309,18 +1684,403 @@ fn structured() {
         b: String,
     }
 
-    let store: Storage = Storage::new(&StorageConfig {
-        db_path: "test_structured.db".to_string(),
-        ..Default::default()
-    });
+    let store: Storage = Storage::builder().temporary().open().unwrap();
     let test = Test {
         a: 1,
         b: "test".to_string(),
     };
-    store.insert("test", test.clone());
+    assert_eq!(None, store.insert("test", test.clone()));
     assert_eq!(test, store.get::<Test>("test").unwrap());
-    store.insert("test", test.clone());
+    assert_eq!(Some(test.clone()), store.insert("test", test.clone()));
     assert_eq!(test, store.get::<Test>("test").unwrap());
-    store.remove::<Test>("test");
+    assert_eq!(Some(test), store.remove::<Test>("test"));
     assert_eq!(None, store.get::<Test>("test"));
+    assert_eq!(None, store.remove::<Test>("test"));
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+    struct Account {
+        #[storage(key)]
+        id: u64,
+        owner: String,
+    }
+
+    let account = Account {
+        id: 42,
+        owner: "test".to_string(),
+    };
+    account.save(&store);
+    assert_eq!(Some(account.clone()), Account::load(&store, &42));
+    // the same key as the typed API
+    assert_eq!(Some(account), store.get::<Account>(42u64));
+
+    mod qualified {
+        // nothing of the crate in scope
+        #[derive(
+            crate::StorageData,
+            Debug,
+            Clone,
+            Default,
+            PartialEq,
+            serde::Deserialize,
+            serde::Serialize,
+        )]
+        #[storage(codec = "bincode")]
+        pub struct Proof {
+            #[storage(key)]
+            pub id: String,
+            #[storage(content)]
+            pub body: Option<crate::ContentHash>,
+        }
+    }
+    let proof = qualified::Proof {
+        id: "p".to_string(),
+        body: None,
+    };
+    proof.save(&store);
+    assert_eq!(
+        Some(proof),
+        qualified::Proof::load(&store, &"p".to_string())
+    );
+}
+
+#[test]
+fn get_or_insert_with() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            std::thread::spawn(move || store.get_or_insert_with("test", || i.to_string()))
+        })
+        .collect();
+    let values: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    // every caller sees the value of the single winner
+    assert!(values.iter().all(|v| v == &values[0]));
+    assert_eq!(values[0], store.get::<String>("test"));
+}
+
+#[test]
+fn try_insert() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.try_insert("test", "a".to_string()).unwrap();
+    match store.try_insert("test", "b".to_string()) {
+        Err(CasError::Conflict { current, .. }) => assert_eq!(Some("a".to_string()), current),
+        r => panic!("unexpected {:?}", r),
+    }
+    assert_eq!(Some("a".to_string()), store.get::<String>("test"));
+}
+
+#[test]
+fn open_cold() {
+    let dir = TestDir::new("open_cold");
+    let config = StorageConfig {
+        db_path: dir.path("db"),
+        ..Default::default()
+    };
+    {
+        let store = Storage::new(&config);
+        store.insert("test", "test".to_string());
+        store.run_pending_tasks();
+    }
+    let store = Storage::open_cold(&config).unwrap();
+    assert!(store.tree_names().contains(&String::name()));
+    assert_eq!(0, store.cache.entry_count());
+    assert_eq!(Some("test".to_string()), store.get::<String>("test"));
+}
+
+#[test]
+fn update() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    store
+                        .update::<String, _>("counter", |v| {
+                            let n: u32 = v.map(|v| v.parse().unwrap()).unwrap_or(0);
+                            Some((n + 1).to_string())
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+    assert_eq!(Some("800".to_string()), store.get::<String>("counter"));
+    assert_eq!(
+        None,
+        store.update::<String, _>("counter", |_| None).unwrap()
+    );
+    assert_eq!(None, store.get::<String>("counter"));
+}
+
+#[test]
+fn compare_and_swap() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store
+        .compare_and_swap("test", None, Some("a".to_string()))
+        .unwrap();
+    match store.compare_and_swap("test", None, Some("b".to_string())) {
+        Err(CasError::Conflict { current, proposed }) => {
+            assert_eq!(Some("a".to_string()), current);
+            assert_eq!(Some("b".to_string()), proposed);
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+    assert_eq!(Some("a".to_string()), store.get::<String>("test"));
+    store
+        .compare_and_swap("test", Some(&"a".to_string()), None)
+        .unwrap();
+    assert_eq!(None, store.get::<String>("test"));
+}
+
+#[test]
+fn tree_name() {
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize)]
+    #[storage(name = "chain_config")]
+    struct Config {}
+
+    assert_eq!("chain_config", Config::name());
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize)]
+    struct Wrapper<T, const N: usize> {
+        inner: T,
+    }
+
+    assert_eq!("Wrapper<u32,4>", Wrapper::<u32, 4>::name());
+    assert_ne!(Wrapper::<u32, 4>::name(), Wrapper::<u64, 4>::name());
+    assert_eq!(
+        "Wrapper<Wrapper<Vec<String>,1>,2>",
+        Wrapper::<Wrapper<Vec<String>, 1>, 2>::name()
+    );
+    assert_eq!("Wrapper<[u8;32],0>", Wrapper::<[u8; 32], 0>::name());
+    assert_eq!("Wrapper<(u8,String),0>", Wrapper::<(u8, String), 0>::name());
+    assert_eq!("Wrapper<Box<i64>,0>", Wrapper::<Box<i64>, 0>::name());
+    assert_eq!(
+        "Wrapper<HashMap<String,BTreeSet<u16>>,0>",
+        Wrapper::<HashMap<String, std::collections::BTreeSet<u16>>, 0>::name()
+    );
+    // cached per type, not per generic impl
+    assert_eq!("Wrapper<u32,4>", Wrapper::<u32, 4>::name());
+    // one namespace, one tree
+    let store = Storage::builder().temporary().open().unwrap();
+    store
+        .namespace("tenant")
+        .insert("a", Wrapper::<Option<String>, 0>::default());
+    assert_eq!(vec!["tenant"], store.iter_namespaces().collect::<Vec<_>>());
+}
+
+#[test]
+fn generic_keys() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert(7u64, "seven".to_string());
+    store.insert(&[0u8, 1, 2][..], "bytes".to_string());
+    assert_eq!(Some("seven".to_string()), store.get::<String>(7u64));
+    assert_eq!(
+        Some("bytes".to_string()),
+        store.get::<String>(b"\0\x01\x02")
+    );
+    // u64 keys are stored like `key_u64` writes them, and sort numerically
+    assert_eq!(Some("seven".to_string()), store.get::<String>(key_u64(7)));
+    store.insert(10u64, "ten".to_string());
+    let tree = store.db.open_tree(String::name()).unwrap();
+    assert!(tree.contains_key(key_u64(7)).unwrap());
+    let numbers: Vec<_> = tree
+        .range(key_u64(0)..)
+        .keys()
+        .map(|k| String::from_utf8(k.unwrap().to_vec()).unwrap())
+        .collect();
+    assert_eq!(vec![key_u64(7), key_u64(10)], numbers);
+
+    #[cfg(feature = "uuid")]
+    {
+        let id = uuid::Uuid::from_u128(42);
+        store.insert(id, "uuid".to_string());
+        assert_eq!(Some("uuid".to_string()), store.get::<String>(id));
+        assert!(tree.contains_key(id.as_bytes()).unwrap());
+    }
+
+    store.remove::<String>(7u64);
+    assert_eq!(None, store.get::<String>(7u64));
+}
+
+#[test]
+fn spans() {
+    use std::collections::BTreeMap;
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    #[derive(Debug, Clone, Default)]
+    struct Fields(BTreeMap<&'static str, String>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.insert(field.name(), format!("{:?}", value));
+        }
+    }
+
+    // the spans opened so far with their fields, in order
+    #[derive(Debug, Default)]
+    struct Spans(Mutex<Vec<(&'static str, Fields)>>);
+
+    impl Subscriber for Spans {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = Fields::default();
+            span.record(&mut fields);
+            let mut spans = self.0.lock();
+            spans.push((span.metadata().name(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            values.record(&mut self.0.lock()[span.into_u64() as usize - 1].1);
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: &Event<'_>) {}
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let subscriber = Arc::new(Spans::default());
+    tracing::subscriber::with_default(subscriber.clone(), || {
+        store.insert("test", "test".to_string());
+        store.get::<String>("test");
+        store.get::<String>("missing");
+        store.remove::<String>("test");
+    });
+
+    let spans = subscriber.0.lock();
+    let find = |name: &str| {
+        spans
+            .iter()
+            .filter(|(n, _)| *n == name)
+            .map(|(_, fields)| fields.0.clone())
+            .collect::<Vec<_>>()
+    };
+    let inserts = find("storage.insert");
+    assert_eq!("String", inserts[0]["tree"]);
+    assert_eq!("4", inserts[0]["key_len"]);
+    assert!(inserts[0].contains_key("bytes"));
+    let gets = find("storage.get");
+    assert_eq!(
+        vec![Some("true"), Some("false")],
+        gets.iter()
+            .map(|f| f.get("hit").map(String::as_str))
+            .collect::<Vec<_>>()
+    );
+    assert!(!gets[1].contains_key("bytes"));
+    assert_eq!(1, find("storage.remove").len());
+}
+
+#[test]
+fn sled_config() {
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        sled: SledConfig {
+            cache_capacity_bytes: Some(1 << 20),
+            entry_cache_percent: Some(50),
+            flush_every_ms: Some(0),
+            zstd_compression_level: Some(1),
+            heap_file_fill_ratio: Some(0.5),
+        },
+        ..Default::default()
+    });
+    store.insert("test", "test".to_string());
+    store.run_pending_tasks();
+    assert_eq!(Some("test".to_string()), store.get::<String>("test"));
+}
+
+#[test]
+fn max_value_size() {
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        max_value_size: Some(64),
+        ..Default::default()
+    });
+    let err = store.insert_checked("large", "x".repeat(1000)).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<StorageError>(),
+        Some(StorageError::ValueTooLarge { limit: 64, .. })
+    ));
+    assert_eq!(None, store.insert("large", "x".repeat(1000)));
+    assert!(!store.contains_key::<String>("large"));
+    assert!(store
+        .compare_and_swap::<String>("large", None, Some("x".repeat(1000)))
+        .is_err());
+    assert!(store
+        .insert_raw("Raw", "large", Bytes::from(vec![0; 1000]))
+        .is_err());
+    store.insert_checked("small", "small".to_string()).unwrap();
+}
+
+#[test]
+fn temporary() {
+    let store: Storage = Storage::new(&StorageConfig {
+        db_path: String::new(),
+        temporary: true,
+        ..Default::default()
+    });
+    let path = store.db_path.clone();
+    assert!(std::path::Path::new(&path).exists());
+    store.insert("test", "test".to_string());
+    assert_eq!(Some("test".to_string()), store.get::<String>("test"));
+
+    let other = Storage::builder().temporary().open().unwrap();
+    assert_ne!(path, other.db_path);
+    assert_eq!(None, other.get::<String>("test"));
+
+    drop(store);
+    assert!(!std::path::Path::new(&path).exists());
+}
+
+#[test]
+fn tree_handles() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let clone = store.clone();
+    store.insert("a", "1".to_string());
+    let opened = store.trees.len();
+    assert_eq!(Some("1".to_string()), clone.get::<String>("a"));
+    assert!(clone.contains_key::<String>("a"));
+    assert_eq!(opened, clone.trees.len());
+    assert!(store.trees.contains_key(String::name().as_bytes()));
+}
+
+#[test]
+fn cache_max_value_size() {
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        cache_max_value_size: Some(64),
+        ..Default::default()
+    });
+    let large = "x".repeat(1000);
+    store.insert("small", "small".to_string());
+    store.insert("large", large.clone());
+    store.run_pending_tasks();
+    assert_eq!(
+        Some(0),
+        store
+            .cache
+            .get(&store.ckey::<String>(b"large"))
+            .map(|v| v.len())
+    );
+    assert!(store
+        .cache
+        .get(&store.ckey::<String>(b"small"))
+        .is_some_and(|v| !v.is_empty()));
+
+    assert!(store.contains_key::<String>("large"));
+    assert_eq!(Some(large.clone()), store.get::<String>("large"));
+    assert_eq!(
+        Some(large),
+        store.collection::<String>().unwrap().get("large")
+    );
+    assert!(store.cache.weighted_size() < 200);
 }
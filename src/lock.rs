@@ -0,0 +1,129 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::Storage;
+
+pub(crate) const LOCK_TREE_NAME: &str = "LOCK";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Lease {
+    owner: String,
+    // milliseconds since the epoch
+    expires_at: u64,
+}
+
+impl Lease {
+    const fn expired(&self, now: u64) -> bool {
+        self.expires_at <= now
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+// LOCKS
+// A lock is a lease record in the lock tree, taken and given back by compare-and-swap,
+// so the workers of one process coordinate through its db. Leases are checked against
+// the system clock, an owner whose lease ran out has lost the lock even if nobody took
+// it yet. Undecodable lease records count as expired.
+impl Storage {
+    /// Take the lock `name` for `owner` until `lease` passes. Returns false when another
+    /// owner holds it. Taking a lock held by `owner` already extends its lease.
+    pub fn try_lock(&self, name: &str, owner: &str, lease: Duration) -> Result<bool> {
+        self.swap_lease(name, |current, now| match current {
+            Some(current) if !current.expired(now) && current.owner != owner => None,
+            _ => Some(Some(Lease {
+                owner: owner.to_string(),
+                expires_at: now + lease.as_millis() as u64,
+            })),
+        })
+    }
+
+    /// Extend the lease of `owner` on `name` to `lease` from now. Returns false when it
+    /// doesn't hold the lock, or its lease ran out.
+    pub fn renew(&self, name: &str, owner: &str, lease: Duration) -> Result<bool> {
+        self.swap_lease(name, |current, now| match current {
+            Some(current) if !current.expired(now) && current.owner == owner => Some(Some(Lease {
+                owner: owner.to_string(),
+                expires_at: now + lease.as_millis() as u64,
+            })),
+            _ => None,
+        })
+    }
+
+    /// Give back the lock `name`. Returns false when `owner` doesn't hold it.
+    pub fn unlock(&self, name: &str, owner: &str) -> Result<bool> {
+        self.swap_lease(name, |current, now| match current {
+            Some(current) if !current.expired(now) && current.owner == owner => Some(None),
+            _ => None,
+        })
+    }
+
+    /// The owner currently holding the lock `name`.
+    pub fn lock_owner(&self, name: &str) -> Result<Option<String>> {
+        let tree = self.tree(LOCK_TREE_NAME)?;
+        let now = now_ms();
+        Ok(tree
+            .get(name)?
+            .and_then(|v| bincode::deserialize::<Lease>(&v).ok())
+            .filter(|lease| !lease.expired(now))
+            .map(|lease| lease.owner))
+    }
+
+    // replace the lease of `name` by `f(current)`, `None` refusing the change and
+    // `Some(None)` removing it. Returns whether it was changed.
+    fn swap_lease(
+        &self,
+        name: &str,
+        f: impl Fn(Option<&Lease>, u64) -> Option<Option<Lease>>,
+    ) -> Result<bool> {
+        self.check_open()?;
+        let tree = self.tree(LOCK_TREE_NAME)?;
+        loop {
+            let stored = tree.get(name)?;
+            let current = stored
+                .as_deref()
+                .and_then(|v| bincode::deserialize::<Lease>(v).ok());
+            let Some(new) = f(current.as_ref(), now_ms()) else {
+                return Ok(false);
+            };
+            let new = new.map(|lease| bincode::serialize(&lease)).transpose()?;
+            if tree
+                .compare_and_swap(name, stored.as_deref(), new.as_deref())?
+                .is_ok()
+            {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[test]
+fn lock() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let lease = Duration::from_millis(200);
+    assert!(store.try_lock("compaction", "worker-1", lease).unwrap());
+    assert!(!store.try_lock("compaction", "worker-2", lease).unwrap());
+    assert!(store.try_lock("compaction", "worker-1", lease).unwrap());
+    assert_eq!(
+        Some("worker-1".to_string()),
+        store.lock_owner("compaction").unwrap()
+    );
+    assert!(store.renew("compaction", "worker-1", lease).unwrap());
+    assert!(!store.renew("compaction", "worker-2", lease).unwrap());
+    assert!(!store.unlock("compaction", "worker-2").unwrap());
+    assert!(store.unlock("compaction", "worker-1").unwrap());
+    assert_eq!(None, store.lock_owner("compaction").unwrap());
+
+    // an expired lease is free to take, and lost for its owner
+    assert!(store.try_lock("backup", "worker-1", lease).unwrap());
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(!store.renew("backup", "worker-1", lease).unwrap());
+    assert!(store.try_lock("backup", "worker-2", lease).unwrap());
+    assert!(!store.unlock("backup", "worker-1").unwrap());
+}
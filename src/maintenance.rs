@@ -0,0 +1,67 @@
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use tracing::{debug, warn};
+
+use crate::shutdown::Worker;
+use crate::Storage;
+
+/// The running maintenance task, stopped when dropped or when the storage is closed.
+#[derive(Debug)]
+pub struct MaintenanceTask {
+    worker: Arc<Worker>,
+}
+
+impl MaintenanceTask {
+    /// Stop the task, waiting for a running flush to finish.
+    pub fn stop(self) {}
+}
+
+impl Drop for MaintenanceTask {
+    fn drop(&mut self) {
+        self.worker.stop();
+    }
+}
+
+// MAINTENANCE
+// A worker thread does what `run_pending_tasks` does every `interval`, then applies
+// the retention of time series and audit logs. sled has no asynchronous flush, the
+// flush blocks the worker but not the writers. A failed flush or pruning is logged and
+// retried at the next interval. The worker is registered with the lifecycle, so `close`
+// stops it before the db.
+impl Storage {
+    pub fn start_maintenance(&self, interval: Duration) -> Result<MaintenanceTask> {
+        self.check_open()?;
+        let (tx, rx) = mpsc::channel::<()>();
+        let store = self.clone();
+        let handle = std::thread::Builder::new()
+            .name("storage-maintenance".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                    if let Err(e) = store.try_run_pending_tasks() {
+                        warn!("Maintenance of {} failed: {}", store.db_path, e);
+                    }
+                    if let Err(e) = store.apply_retention() {
+                        warn!("Retention of {} failed: {}", store.db_path, e);
+                    }
+                }
+                debug!("Stopped maintenance of {}", store.db_path);
+            })?;
+        let worker = self.register_worker(tx, handle);
+        Ok(MaintenanceTask { worker })
+    }
+}
+
+#[test]
+fn maintenance() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let task = store.start_maintenance(Duration::from_millis(10)).unwrap();
+    store.insert("a", "1".to_string());
+    // longer than since opening, which may have flushed
+    std::thread::sleep(Duration::from_millis(300));
+    task.stop();
+    let age = store.health().last_flush_age.unwrap();
+    assert!(age < Duration::from_millis(150));
+}
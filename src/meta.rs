@@ -0,0 +1,67 @@
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::Storage;
+
+pub(crate) const META_TREE_NAME: &str = "META";
+
+// META
+// Metadata lives in its own tree and bypasses the cache, it is never part of
+// the structured data of any type.
+impl Storage {
+    pub fn set_meta<V: Serialize>(&self, key: &str, value: &V) -> Result<()> {
+        self.check_writable()?;
+        self.put_meta(key, value)
+    }
+
+    // `set_meta` for the bookkeeping of replication and restores, which followers do too
+    pub(crate) fn put_meta<V: Serialize>(&self, key: &str, value: &V) -> Result<()> {
+        self.check_open()?;
+        let _writes = self.write_gate();
+        let tree = self.tree(META_TREE_NAME)?;
+        tree.insert(key, bincode::serialize(value)?)?;
+        Ok(())
+    }
+
+    pub fn get_meta<V: for<'a> Deserialize<'a>>(&self, key: &str) -> Option<V> {
+        let tree = self.tree(META_TREE_NAME).ok()?;
+        let v = tree.get(key).ok()??;
+        bincode::deserialize(&v).ok()
+    }
+
+    pub fn remove_meta(&self, key: &str) -> Result<()> {
+        self.check_writable()?;
+        let _writes = self.write_gate();
+        let tree = self.tree(META_TREE_NAME)?;
+        tree.remove(key)?;
+        Ok(())
+    }
+
+    /// All metadata keys, in order.
+    pub fn meta_keys(&self) -> Vec<String> {
+        let Ok(tree) = self.tree(META_TREE_NAME) else {
+            return vec![];
+        };
+        tree.iter()
+            .keys()
+            .filter_map(|k| k.ok())
+            .map(|k| String::from_utf8_lossy(&k).to_string())
+            .collect()
+    }
+}
+
+#[test]
+fn meta() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    assert_eq!(None, store.get_meta::<bool>("compressed"));
+    store.set_meta("compressed", &true).unwrap();
+    store.set_meta("rollout", &"phase-2".to_string()).unwrap();
+    assert_eq!(Some(true), store.get_meta::<bool>("compressed"));
+    assert_eq!(vec!["compressed", "rollout"], store.meta_keys());
+    store.remove_meta("compressed").unwrap();
+    assert_eq!(None, store.get_meta::<bool>("compressed"));
+
+    store.close().unwrap();
+    assert!(store.set_meta("compressed", &true).is_err());
+    assert!(store.remove_meta("rollout").is_err());
+}
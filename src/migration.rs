@@ -0,0 +1,429 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+use parking_lot::{Mutex, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::envelope::{self, Header};
+use crate::{Storage, StorageData, Tree};
+
+pub(crate) const VERSION_TREE_NAME: &str = "VERSION";
+
+type MigrationFn = Arc<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+struct Registered {
+    to_version: u32,
+    from: BTreeMap<u32, MigrationFn>,
+}
+
+// registered migrations, keyed by tree name and the version they migrate from
+#[derive(Clone, Default)]
+pub(crate) struct Migrations {
+    registry: Arc<RwLock<HashMap<String, Registered>>>,
+    // db trees whose persisted version has already been checked in this process
+    stamped: Arc<Mutex<HashSet<String>>>,
+    pub(crate) require_explicit: bool,
+}
+
+impl Migrations {
+    pub(crate) fn new(require_explicit: bool) -> Self {
+        Self {
+            require_explicit,
+            ..Default::default()
+        }
+    }
+}
+
+impl Debug for Migrations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let registry = self.registry.read();
+        let mut map = f.debug_map();
+        for (name, registered) in registry.iter() {
+            map.entry(name, &registered.from.keys().collect::<Vec<_>>());
+        }
+        map.finish()
+    }
+}
+
+/// A migration that would run for one tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStep {
+    pub tree: String,
+    pub from_version: u32,
+    pub to_version: u32,
+    /// Number of records that would be rewritten.
+    pub records: usize,
+    /// Whether a migration from `from_version` has been registered.
+    pub registered: bool,
+}
+
+/// The migrations pending for all trees with registered migrations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationPlan {
+    pub steps: Vec<MigrationStep>,
+}
+
+impl MigrationPlan {
+    pub const fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl Display for MigrationPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.steps.is_empty() {
+            return writeln!(f, "no pending migrations");
+        }
+        for step in &self.steps {
+            writeln!(
+                f,
+                "tree({}): version {} -> {}, {} records{}",
+                step.tree,
+                step.from_version,
+                step.to_version,
+                step.records,
+                if step.registered {
+                    ""
+                } else {
+                    " (missing migration)"
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage {
+    /// Register a migration that turns records of `T` persisted at `from_version`
+    /// into the current layout (`T::version()`).
+    ///
+    /// The closure receives the payload bytes of the old record (the old layout encoded
+    /// with the codec it was written with). Migrations are applied by `migrate` (and
+    /// therefore by `recover`), and for single records read by `get` whose envelope
+    /// carries an older version.
+    pub fn register_migration<T, F>(&self, from_version: u32, f: F)
+    where
+        T: StorageData,
+        F: Fn(&[u8]) -> T + Send + Sync + 'static,
+    {
+        let (codec, compress_above) = (self.codec_of::<T>(), self.compress_above::<T>());
+        let migration: MigrationFn =
+            Arc::new(move |old| envelope::encode(codec, compress_above, &f(old)));
+        self.migrations
+            .registry
+            .write()
+            .entry(T::name())
+            .or_insert_with(|| Registered {
+                to_version: T::version(),
+                from: BTreeMap::new(),
+            })
+            .from
+            .insert(from_version, migration);
+    }
+
+    /// The version of `T` persisted in the database, if any.
+    pub fn stored_version<T: StorageData>(&self) -> Option<u32> {
+        self.stored_version_of(&T::name())
+    }
+
+    fn stored_version_of(&self, name: &str) -> Option<u32> {
+        let tree = self.tree(VERSION_TREE_NAME).ok()?;
+        let v = tree.get(name).ok()??;
+        v.to_vec().try_into().ok().map(u32::from_be_bytes)
+    }
+
+    /// Report the migrations that `migrate` would run, without touching any data.
+    pub fn plan(&self) -> Result<MigrationPlan> {
+        let registry = self.migrations.registry.read();
+        let mut steps = vec![];
+        for (name, registered) in registry.iter() {
+            let tree = self.tree(name)?;
+            let from_version = match self.stored_version_of(name) {
+                Some(v) => v,
+                None if tree.is_empty()? => continue,
+                None => 0,
+            };
+            if from_version >= registered.to_version {
+                continue;
+            }
+            steps.push(MigrationStep {
+                tree: name.clone(),
+                from_version,
+                to_version: registered.to_version,
+                records: tree.len(),
+                registered: registered.from.contains_key(&from_version),
+            });
+        }
+        steps.sort_by(|a, b| a.tree.cmp(&b.tree));
+        Ok(MigrationPlan { steps })
+    }
+
+    // `recover` only migrates implicitly when explicit migration is not required
+    pub(crate) fn migrate_on_recover<T: StorageData>(&self) -> Result<()> {
+        if self.migrations.require_explicit {
+            let tree = self.tree(T::name())?;
+            let pending = match self.stored_version::<T>() {
+                Some(v) => v < T::version(),
+                None => !tree.is_empty()? && T::version() > 0,
+            };
+            if pending {
+                return Err(eyre!(
+                    "tree({}) requires an explicit migration to version {}",
+                    T::name(),
+                    T::version()
+                ));
+            }
+        }
+        self.migrate::<T>()
+    }
+
+    /// Bring the records of `T` up to `T::version()`.
+    ///
+    /// Trees without a persisted version but with data are treated as version 0.
+    pub fn migrate<T: StorageData>(&self) -> Result<()> {
+        self.check_writable()?;
+        let current = T::version();
+        let tree = self.tree(T::name())?;
+        let stored = match self.stored_version::<T>() {
+            Some(v) => v,
+            None if tree.is_empty()? => current,
+            None => 0,
+        };
+
+        if stored > current {
+            return Err(eyre!(
+                "tree({}) is at version {} which is newer than {}",
+                T::name(),
+                stored,
+                current
+            ));
+        }
+
+        if stored < current {
+            let name = T::name();
+            let name = self.tree_name(&name);
+            let mut migrated = 0usize;
+            for r in tree.iter() {
+                let (k, v) = r?;
+                let (header, payload) = self.open_value(&v, &name, &k)?;
+                if header.version == current {
+                    continue;
+                }
+                let new = self.seal(
+                    self.migration_for::<T>(header.version)?(&payload)?,
+                    Some(header.created),
+                    &name,
+                    &k,
+                )?;
+                if self.rewrite::<T>(&tree, &k, &v, Bytes::from(new), "migrate")? {
+                    migrated += 1;
+                }
+            }
+            info!(
+                "Migrated {} records of tree({}) from version {} to {}",
+                migrated,
+                T::name(),
+                stored,
+                current
+            );
+        }
+
+        self.set_stored_version::<T>(current)?;
+        let name = T::name();
+        self.migrations
+            .stamped
+            .lock()
+            .insert(self.tree_name(&name).into_owned());
+        Ok(())
+    }
+
+    fn migration_for<T: StorageData>(&self, from_version: u32) -> Result<MigrationFn> {
+        self.migrations
+            .registry
+            .read()
+            .get(&T::name())
+            .and_then(|m| m.from.get(&from_version).cloned())
+            .ok_or_else(|| {
+                eyre!(
+                    "no migration registered for tree({}) from version {}",
+                    T::name(),
+                    from_version
+                )
+            })
+    }
+
+    // decode a value `bytes` read by `get` whose envelope is not at `T::version()`,
+    // persisting the upgraded record unless migrations must be explicit or the storage
+    // is read-only
+    pub(crate) fn upgrade<T: StorageData>(
+        &self,
+        key: &[u8],
+        bytes: &[u8],
+        header: Header,
+        payload: &[u8],
+        persist: bool,
+    ) -> Option<T> {
+        let lossy = String::from_utf8_lossy(key);
+        if header.version > T::version() {
+            warn!(
+                "tree({}) key({}) is at version {} which is newer than {}",
+                T::name(),
+                lossy,
+                header.version,
+                T::version()
+            );
+            return None;
+        }
+        let new = match self
+            .migration_for::<T>(header.version)
+            .and_then(|migration| migration(payload))
+        {
+            Ok(new) => new,
+            Err(e) => {
+                warn!("Upgrade tree({}) key({}) failed: {}", T::name(), lossy, e);
+                return None;
+            }
+        };
+        let (new_header, new_payload) = envelope::open(&new).ok()?;
+        let value = self.deserialize_as(new_header.format, &new_payload)?;
+        if persist && !self.migrations.require_explicit && self.check_writable().is_ok() {
+            let name = T::name();
+            let new = self
+                .seal(new, Some(header.created), &self.tree_name(&name), key)
+                .ok()?;
+            let rewritten = self
+                .tree(&name)
+                .map_err(Into::into)
+                .and_then(|tree| self.rewrite::<T>(&tree, key, bytes, Bytes::from(new), "migrate"));
+            if let Err(e) = rewritten {
+                warn!("Upgrade tree({}) key({}) failed: {}", name, lossy, e);
+            }
+        }
+        Some(value)
+    }
+
+    // replace the stored value `old` of `key` with `new`, unless a concurrent write
+    // replaced it first. Returns whether it did.
+    pub(crate) fn rewrite<T: StorageData>(
+        &self,
+        tree: &Tree,
+        key: &[u8],
+        old: &[u8],
+        new: Bytes,
+        operation: &str,
+    ) -> Result<bool> {
+        let _writes = self.write_gate();
+        if tree
+            .compare_and_swap(key, Some(old), Some(new.as_ref()))?
+            .is_err()
+        {
+            return Ok(false);
+        }
+        self.recount(&T::name());
+        self.audit(operation, &T::name(), key, Some(&new));
+        // refresh stale cache entries, one newer than `old` is kept
+        let ckey = self.ckey::<T>(key);
+        if self.cache_get(&ckey).is_some_and(|v| v.as_ref() == old) {
+            self.cache_put(ckey, new);
+        }
+        Ok(true)
+    }
+
+    // record the version of a fresh tree on first write, so later layout
+    // changes know where the data started from
+    pub(crate) fn stamp_version<T: StorageData>(&self) {
+        let name = T::name();
+        // shared by the namespaces of this storage, each stamps its own tree
+        let tree_name = self.tree_name(&name).into_owned();
+        if self.migrations.stamped.lock().contains(&tree_name) {
+            return;
+        }
+        if self.stored_version::<T>().is_none() {
+            let is_empty = self
+                .tree(&name)
+                .and_then(|tree| tree.is_empty())
+                .unwrap_or(false);
+            if is_empty && self.set_stored_version::<T>(T::version()).is_err() {
+                return;
+            }
+            debug!("Stamped tree({}) with version {}", tree_name, T::version());
+        }
+        self.migrations.stamped.lock().insert(tree_name);
+    }
+
+    fn set_stored_version<T: StorageData>(&self, version: u32) -> Result<()> {
+        let tree = self.tree(VERSION_TREE_NAME)?;
+        tree.insert(T::name(), version.to_be_bytes().to_vec())?;
+        Ok(())
+    }
+}
+
+#[test]
+fn migration() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize)]
+    struct TestV0 {
+        a: u32,
+    }
+
+    #[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+    struct Test {
+        a: u64,
+        b: String,
+    }
+
+    impl StorageData for Test {
+        fn name() -> String {
+            "MigrationTest".to_string()
+        }
+
+        fn version() -> u32 {
+            1
+        }
+    }
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    // data written by an older build, before versions were tracked
+    let tree = store.db.open_tree(Test::name()).unwrap();
+    tree.insert("test", bincode::serialize(&TestV0 { a: 7 }).unwrap())
+        .unwrap();
+
+    assert!(store.recover::<Test>().is_err());
+    store.register_migration::<Test, _>(0, |old| {
+        let old: u32 = bincode::deserialize(old).unwrap();
+        Test {
+            a: old as u64,
+            b: "migrated".to_string(),
+        }
+    });
+    let plan = store.plan().unwrap();
+    assert_eq!(
+        vec![MigrationStep {
+            tree: Test::name(),
+            from_version: 0,
+            to_version: 1,
+            records: 1,
+            registered: true,
+        }],
+        plan.steps
+    );
+    store.recover::<Test>().unwrap();
+    assert!(store.plan().unwrap().is_empty());
+    assert_eq!(Some(1), store.stored_version::<Test>());
+    assert_eq!(
+        Test {
+            a: 7,
+            b: "migrated".to_string()
+        },
+        store.get::<Test>("test").unwrap()
+    );
+
+    // each namespace stamps its own tree
+    let tenant = store.namespace("tenant");
+    tenant.insert("test", Test::default());
+    assert_eq!(Some(1), tenant.stored_version::<Test>());
+}
@@ -0,0 +1,168 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use crate::{tree_ckey, tree_names, Storage, StorageData, StorageKey};
+
+// between the namespace and the tree name, cache keys end the tree name at '/'
+pub(crate) const SEPARATOR: &str = "::";
+
+// the tree name without namespaces, which is where the internal trees are told apart
+pub(crate) fn base_tree_name(name: &str) -> &str {
+    name.rsplit(SEPARATOR).next().unwrap_or(name)
+}
+
+// NAMESPACES
+// A namespaced storage shares the db, cache and background work of the storage it was
+// taken from, and keeps all its trees, internal ones included, under
+// `<namespace>::<name>`. Cache keys name the db tree, so entries of two namespaces
+// never meet. `tree_names`, `stats`, `verify`, cache statistics, `invalidate_all`,
+// backups and compaction still cover the whole db, and `recover` of the storage
+// without a namespace warms the caches of all namespaces.
+impl Storage {
+    /// A view of this storage keeping its records apart from all others, nested in the
+    /// namespace of `self` if it has one.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is empty or contains ':' or '/'.
+    pub fn namespace(&self, name: &str) -> Storage {
+        assert!(
+            !name.is_empty() && !name.contains([':', '/']),
+            "invalid namespace {:?}",
+            name
+        );
+        let namespace = match &self.namespace {
+            Some(outer) => format!("{}{}{}", outer, SEPARATOR, name),
+            None => name.to_string(),
+        };
+        Storage {
+            namespace: Some(Arc::from(namespace)),
+            ..self.clone()
+        }
+    }
+
+    // the db tree behind the tree `name` of this storage
+    pub(crate) fn tree_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match &self.namespace {
+            Some(namespace) => Cow::Owned(format!("{}{}{}", namespace, SEPARATOR, name)),
+            None => Cow::Borrowed(name),
+        }
+    }
+
+    // the cache key of `key` in the tree of `T`
+    pub(crate) fn ckey<T: StorageData>(&self, key: &[u8]) -> Vec<u8> {
+        self.tree_ckey(&T::name(), key)
+    }
+
+    pub(crate) fn tree_ckey(&self, name: &str, key: &[u8]) -> Vec<u8> {
+        tree_ckey(&self.tree_name(name), key)
+    }
+
+    // the db tree `name` relative to the namespace of this storage
+    pub(crate) fn own_tree_name<'a>(&self, name: &'a str) -> &'a str {
+        self.namespace
+            .as_ref()
+            .and_then(|namespace| name.strip_prefix(&**namespace)?.strip_prefix(SEPARATOR))
+            .unwrap_or(name)
+    }
+
+    // the names of the trees of this storage, relative to its namespace
+    pub(crate) fn own_tree_names(&self) -> Vec<String> {
+        let names = tree_names(&self.db);
+        let Some(namespace) = &self.namespace else {
+            return names;
+        };
+        let prefix = format!("{}{}", namespace, SEPARATOR);
+        names
+            .into_iter()
+            .filter_map(|name| name.strip_prefix(&prefix).map(str::to_string))
+            .collect()
+    }
+
+    /// The namespaces with trees in this storage, nested ones included, sorted.
+    pub fn iter_namespaces(&self) -> impl Iterator<Item = String> {
+        self.own_tree_names()
+            .into_iter()
+            .filter_map(|name| {
+                let (namespace, _) = name.rsplit_once(SEPARATOR)?;
+                Some(namespace.to_string())
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+    }
+
+    /// The records of `T` in all namespaces of this storage, by namespace and key, read
+    /// from the db like `Collection::iter`.
+    pub fn iter_all_namespaced<T: StorageData>(
+        &self,
+    ) -> impl Iterator<Item = (String, StorageKey, T)> + '_ {
+        let names = self.own_tree_names();
+        self.iter_namespaces()
+            .filter_map(move |namespace| {
+                // without opening, and so creating, the tree where it is missing
+                let name = format!("{}{}{}", namespace, SEPARATOR, T::name());
+                names
+                    .contains(&name)
+                    .then(|| (namespace, self.tree(&name), name))
+            })
+            .flat_map(move |(namespace, tree, name)| {
+                let name = self.tree_name(&name).into_owned();
+                tree.into_iter()
+                    .flat_map(|tree| tree.iter())
+                    .filter_map(move |r| {
+                        let (k, v) = r.ok()?;
+                        // not rewriting old layouts, that would go through this tree's name
+                        let value = self.decode_in(&name, &k, &v, false)?;
+                        Some((namespace.clone(), StorageKey::from(k.as_ref()), value))
+                    })
+            })
+    }
+}
+
+#[test]
+fn namespace() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let (a, b) = (store.namespace("tenant_a"), store.namespace("tenant_b"));
+    a.insert("k", "a".to_string());
+    b.insert("k", "b".to_string());
+    store.insert("k", "root".to_string());
+    assert_eq!(Some("a".to_string()), a.get::<String>("k"));
+    assert_eq!(Some("b".to_string()), b.get::<String>("k"));
+    assert_eq!(Some("root".to_string()), store.get::<String>("k"));
+    // internal trees are kept apart as well
+    assert_eq!(1, a.next("seq"));
+    assert_eq!(2, a.next("seq"));
+    assert_eq!(1, b.next("seq"));
+
+    b.remove::<String>("k");
+    assert_eq!(Some("a".to_string()), a.get::<String>("k"));
+    let nested = a.namespace("team");
+    nested.insert("n", "nested".to_string());
+    assert!(!a.contains_key::<String>("n"));
+
+    assert_eq!(
+        vec!["tenant_a", "tenant_a::team", "tenant_b"],
+        store.iter_namespaces().collect::<Vec<_>>()
+    );
+    assert_eq!(vec!["team"], a.iter_namespaces().collect::<Vec<_>>());
+    assert_eq!(
+        vec![
+            (
+                "tenant_a".to_string(),
+                StorageKey::from("k"),
+                "a".to_string()
+            ),
+            (
+                "tenant_a::team".to_string(),
+                StorageKey::from("n"),
+                "nested".to_string()
+            ),
+        ],
+        store.iter_all_namespaced::<String>().collect::<Vec<_>>()
+    );
+    // namespaces without the tree are skipped, without creating it
+    assert_eq!(1, store.namespace("tenant_c").next("seq"));
+    assert_eq!(2, store.iter_all_namespaced::<String>().count());
+    assert!(!store.tree_names().contains(&"tenant_c::String".to_string()));
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use moka::sync::Cache;
+
+use crate::{Storage, StorageConfig};
+
+const NEGATIVE_MAX_CAPACITY: u64 = 100_000;
+
+// NEGATIVE CACHING
+// Keys found missing in the db are remembered for `negative_cache_ttl_ms`, so repeated
+// `get` and `contains_key` of absent keys are answered without a db lookup. Every write
+// through the cache forgets the key again. A lookup racing an insert of the same key
+// can still remember it as missing, reads then miss the new value until the entry
+// expires, keep the ttl short.
+impl Storage {
+    pub(crate) fn missing_cache(config: &StorageConfig) -> Option<Cache<Vec<u8>, ()>> {
+        config.negative_cache_ttl_ms.map(|ms| {
+            Cache::builder()
+                .max_capacity(NEGATIVE_MAX_CAPACITY)
+                .time_to_live(Duration::from_millis(ms))
+                .build()
+        })
+    }
+
+    pub(crate) fn known_missing(&self, ckey: &Vec<u8>) -> bool {
+        self.missing
+            .as_ref()
+            .is_some_and(|missing| missing.contains_key(ckey))
+    }
+
+    pub(crate) fn remember_missing(&self, ckey: Vec<u8>) {
+        if let Some(missing) = &self.missing {
+            missing.insert(ckey, ());
+        }
+    }
+
+    pub(crate) fn forget_missing(&self, ckey: &Vec<u8>) {
+        if let Some(missing) = &self.missing {
+            missing.invalidate(ckey);
+        }
+    }
+
+    pub(crate) fn forget_all_missing(&self) {
+        if let Some(missing) = &self.missing {
+            missing.invalidate_all();
+        }
+    }
+}
+
+#[test]
+fn negative_cache() {
+    use crate::StorageData;
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        negative_cache_ttl_ms: Some(200),
+        ..Default::default()
+    });
+    assert_eq!(None, store.get::<String>("tx"));
+    assert!(store.known_missing(&store.ckey::<String>(b"tx")));
+    assert!(!store.contains_key::<String>("tx"));
+
+    // written behind the cache, hidden until the entry expires
+    store
+        .db
+        .open_tree(String::name())
+        .unwrap()
+        .insert("tx", "raw")
+        .unwrap();
+    assert!(!store.contains_key::<String>("tx"));
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(store.contains_key::<String>("tx"));
+
+    assert!(!store.contains_key::<String>("other"));
+    store.insert("other", "inserted".to_string());
+    assert!(!store.known_missing(&store.ckey::<String>(b"other")));
+    assert_eq!(Some("inserted".to_string()), store.get::<String>("other"));
+}
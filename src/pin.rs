@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use color_eyre::eyre::Result;
+use dashmap::DashMap;
+
+use crate::{Storage, StorageData, StorageKey};
+
+// values of the pinned cache keys, shared with the eviction listener
+pub(crate) type PinnedSet = Arc<DashMap<Vec<u8>, Bytes>>;
+
+// PINNING
+// moka can't exempt single entries from eviction, pinned values are kept next to the
+// cache and served from there once the cache dropped them. An expired pinned value
+// stays in the db. Pins are not persisted, they are lost on restart.
+impl Storage {
+    /// Keep the value of `key` readable from memory until `unpin`, exempt from expiry and
+    /// size eviction. Returns false when there is no such value.
+    pub fn pin<T: StorageData>(&self, key: impl Into<StorageKey>) -> Result<bool> {
+        let key = key.into();
+        let ckey = self.ckey::<T>(&key);
+        let stored = match self.cache_get(&ckey) {
+            Some(v) => Some(v),
+            None => self
+                .tree(T::name())?
+                .get(&key)?
+                .map(|v| Bytes::copy_from_slice(&v)),
+        };
+        let Some(value) = stored else {
+            return Ok(false);
+        };
+        self.pinned.insert(ckey, value);
+        Ok(true)
+    }
+
+    /// Subject the value of `key` to eviction again. Returns whether it was pinned.
+    pub fn unpin<T: StorageData>(&self, key: impl Into<StorageKey>) -> bool {
+        self.pinned.remove(&self.ckey::<T>(&key.into())).is_some()
+    }
+
+    pub fn is_pinned<T: StorageData>(&self, key: impl Into<StorageKey>) -> bool {
+        self.pinned.contains_key(&self.ckey::<T>(&key.into()))
+    }
+}
+
+#[test]
+fn pin() {
+    use crate::StorageConfig;
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        cache_time_to_live: Some(1),
+        ..Default::default()
+    });
+    store.insert("validators", "a,b,c".to_string());
+    store.insert("proposal", "p".to_string());
+    assert!(store.pin::<String>("validators").unwrap());
+    assert!(!store.pin::<String>("missing").unwrap());
+    assert!(store.is_pinned::<String>("validators"));
+
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    store.run_pending_tasks();
+    assert_eq!(0, store.cache.entry_count());
+    assert_eq!(None, store.get::<String>("proposal"));
+    assert_eq!(Some("a,b,c".to_string()), store.get::<String>("validators"));
+    assert!(store
+        .db
+        .open_tree(String::name())
+        .unwrap()
+        .contains_key("validators")
+        .unwrap());
+
+    store.insert("validators", "a,b".to_string());
+    assert!(store.unpin::<String>("validators"));
+    assert_eq!(Some("a,b".to_string()), store.get::<String>("validators"));
+    store.remove::<String>("validators");
+    assert!(!store.is_pinned::<String>("validators"));
+}
@@ -0,0 +1,176 @@
+use color_eyre::eyre::{eyre, Result};
+use tracing::{info, warn};
+
+use crate::envelope::{self, FLAG_ENCRYPTED};
+use crate::{split_ckey, Storage, StorageData, StorageKey, Tree};
+
+// damaged values keyed like the cache, `:/TreeName/key`, holding the raw bytes
+pub(crate) const QUARANTINE_TREE_NAME: &str = "__quarantine";
+
+/// A value moved out of its tree because its bytes are damaged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuarantinedEntry {
+    /// Relative to the namespace of the storage.
+    pub tree: String,
+    pub key: StorageKey,
+    /// The stored bytes as they were found.
+    pub bytes: Vec<u8>,
+}
+
+// Only bytes that are damaged for sure are moved: a failed checksum, or a value at the
+// current version in a known codec that still doesn't decode. Values from a newer
+// build, missing migrations, codecs or encryption keys are configuration problems and
+// stay.
+fn damaged<T: StorageData>(storage: &Storage, bytes: &[u8]) -> bool {
+    if !envelope::verify(bytes) {
+        return true;
+    }
+    let (header, _) = envelope::split(bytes);
+    let known = header.codec().is_some() || header.format == storage.codec_of::<T>().id;
+    header.version == T::version() && known && header.flags & FLAG_ENCRYPTED == 0
+}
+
+impl Storage {
+    // move a value `get` could not read out of its tree, when enabled
+    pub(crate) fn quarantine<T: StorageData>(
+        &self,
+        tree: &Tree,
+        ckey: &Vec<u8>,
+        key: &[u8],
+        bytes: &[u8],
+    ) {
+        // a follower keeps what its primary sent
+        if !self.quarantine_corrupt || !damaged::<T>(self, bytes) || self.check_writable().is_err()
+        {
+            return;
+        }
+        let _writes = self.write_gate();
+        let Ok(quarantine) = self.tree(QUARANTINE_TREE_NAME) else {
+            return;
+        };
+        if quarantine.insert(ckey, bytes).is_err() {
+            return;
+        }
+        // a concurrent write replaced the damaged value already
+        if let Ok(Ok(_)) = tree.compare_and_swap(key, Some(bytes), None as Option<&[u8]>) {
+            self.audit("quarantine", &T::name(), key, None);
+            self.cache_remove(ckey);
+            warn!(
+                "Quarantined tree({}) key({})",
+                T::name(),
+                String::from_utf8_lossy(key)
+            );
+        } else {
+            let _ = quarantine.remove(ckey);
+        }
+    }
+
+    /// The values moved into quarantine, see `StorageConfig::quarantine_corrupt`.
+    pub fn quarantined(&self) -> Result<Vec<QuarantinedEntry>> {
+        let quarantine = self.tree(QUARANTINE_TREE_NAME)?;
+        let mut entries = vec![];
+        for r in quarantine.iter() {
+            let (k, v) = r?;
+            let (tree, key) =
+                split_ckey(&k).ok_or_else(|| eyre!("invalid quarantine key {:?}", k))?;
+            entries.push(QuarantinedEntry {
+                tree: self
+                    .own_tree_name(&String::from_utf8_lossy(tree))
+                    .to_string(),
+                key: StorageKey::from(key),
+                bytes: v.to_vec(),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Move all quarantined values back into their trees, e.g. after repairing them
+    /// by hand. Entries whose key has been written since stay quarantined.
+    ///
+    /// Returns the number of restored values.
+    pub fn restore_quarantined(&self) -> Result<usize> {
+        self.check_writable()?;
+        let quarantine = self.tree(QUARANTINE_TREE_NAME)?;
+        let mut restored = 0;
+        for entry in self.quarantined()? {
+            let tree = self.tree(&entry.tree)?;
+            let _writes = self.write_gate();
+            let swapped = tree.compare_and_swap(
+                &entry.key,
+                None as Option<&[u8]>,
+                Some(entry.bytes.as_slice()),
+            )?;
+            if swapped.is_ok() {
+                self.audit(
+                    "restore_quarantined",
+                    &entry.tree,
+                    &entry.key,
+                    Some(&entry.bytes),
+                );
+                let ckey = self.tree_ckey(&entry.tree, &entry.key);
+                quarantine.remove(&ckey)?;
+                // `get` found the key missing while it was quarantined
+                self.forget_missing(&ckey);
+                self.filter_insert(&ckey);
+                restored += 1;
+            }
+        }
+        info!("Restored {} quarantined values", restored);
+        Ok(restored)
+    }
+}
+
+#[test]
+fn quarantine() {
+    use crate::StorageConfig;
+
+    let config = StorageConfig {
+        temporary: true,
+        quarantine_corrupt: true,
+        negative_cache_ttl_ms: Some(60_000),
+        ..Default::default()
+    };
+    let store: Storage = Storage::new(&config);
+    store.insert("test", "test".to_string());
+    let tree = store.db.open_tree(String::name()).unwrap();
+    let mut damaged = tree.get("test").unwrap().unwrap().to_vec();
+    damaged[envelope::HEADER_LEN] ^= 1;
+    for key in ["damaged", "rewritten"] {
+        tree.insert(key, damaged.clone()).unwrap();
+        assert!(store.try_get::<String>(key).is_err());
+    }
+    assert!(!tree.contains_key("damaged").unwrap());
+    assert!(!store.contains_key::<String>("damaged"));
+    let entries = store.quarantined().unwrap();
+    assert_eq!(
+        vec![StorageKey::from("damaged"), StorageKey::from("rewritten")],
+        entries.iter().map(|e| e.key.clone()).collect::<Vec<_>>()
+    );
+    assert_eq!(String::name(), entries[0].tree);
+
+    store.insert("rewritten", "new".to_string());
+    assert_eq!(1, store.restore_quarantined().unwrap());
+    assert_eq!(1, store.quarantined().unwrap().len());
+    assert_eq!(Some(damaged.clone().into()), tree.get("damaged").unwrap());
+    assert!(store.contains_key::<String>("damaged"));
+    assert_eq!(Some("new".to_string()), store.get::<String>("rewritten"));
+
+    let tenant = store.namespace("tenant");
+    let tenant_tree = tenant.tree(String::name()).unwrap();
+    tenant_tree.insert("damaged", damaged.clone()).unwrap();
+    assert!(tenant.try_get::<String>("damaged").is_err());
+    assert_eq!(String::name(), tenant.quarantined().unwrap()[0].tree);
+    assert_eq!(1, tenant.restore_quarantined().unwrap());
+    assert!(tenant_tree.contains_key("damaged").unwrap());
+
+    // a follower keeps what its primary sent
+    let follower: Storage = Storage::new(&StorageConfig {
+        read_only: true,
+        ..config
+    });
+    let tree = follower.db.open_tree(String::name()).unwrap();
+    tree.insert("damaged", damaged).unwrap();
+    assert!(follower.try_get::<String>("damaged").is_err());
+    assert!(tree.contains_key("damaged").unwrap());
+    assert!(follower.restore_quarantined().is_err());
+}
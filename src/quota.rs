@@ -0,0 +1,354 @@
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Result};
+use dashmap::mapref::one::RefMut;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::cas::{refs_of, RefsFn};
+use crate::namespace::{base_tree_name, SEPARATOR};
+use crate::{envelope, is_record_tree, tree_ckey, tree_names, Storage, StorageData, StorageError};
+
+/// Limits on the records of a type or a namespace, see `StorageConfig::quotas`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quota {
+    pub max_keys: Option<u64>,
+    /// Budget for the keys and stored values together, in bytes.
+    pub max_bytes: Option<u64>,
+    pub policy: QuotaPolicy,
+}
+
+/// What `insert` does when it would exceed a quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuotaPolicy {
+    /// Fail with `StorageError::QuotaExceeded`.
+    #[default]
+    Reject,
+    /// Remove the records created first until the new one fits.
+    EvictOldest,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Usage {
+    keys: u64,
+    bytes: u64,
+}
+
+impl Quota {
+    fn allows(&self, usage: Usage) -> bool {
+        self.max_keys.is_none_or(|max| usage.keys <= max)
+            && self.max_bytes.is_none_or(|max| usage.bytes <= max)
+    }
+}
+
+// counted usage by scope, a db tree name for the quota of a type and `<namespace>::`
+// for the quota of a namespace
+pub(crate) type QuotaUsage = Arc<DashMap<String, Usage>>;
+
+// the scopes counting the records of the db tree `name`
+pub(crate) fn scopes(name: &str) -> (String, Option<String>) {
+    let namespace = name
+        .rsplit_once(SEPARATOR)
+        .map(|(namespace, _)| format!("{}{}", namespace, SEPARATOR));
+    (name.to_string(), namespace)
+}
+
+// drop the counts of the db tree `name`, they are counted again on the next check
+pub(crate) fn forget_usage(usage: &QuotaUsage, name: &str) {
+    let (tree, namespace) = scopes(name);
+    usage.remove(&tree);
+    if let Some(namespace) = namespace {
+        usage.remove(&namespace);
+    }
+}
+
+// QUOTAS
+// Usage is counted by walking the trees of a scope on its first check, and kept up to
+// date by `insert`, `remove`, `update` and the swaps, which reserve before writing. A
+// swap that loses its race drops the counts it reserved. Other writes, moves, raw
+// writes, copies and cache expiry, drop the counts of their tree so the next
+// check walks it again. Racing first writes of a key may each count it, overstating the
+// usage until the next walk. `EvictOldest` walks the scope for every record it
+// evicts, meant for caps of thousands of records rather than millions.
+impl Storage {
+    // after a write not counted, of the tree `name` of this storage
+    pub(crate) fn recount(&self, name: &str) {
+        forget_usage(&self.usage, &self.tree_name(name));
+    }
+
+    // the quotas on the db tree `name` with their scopes
+    fn quota_scopes(&self, name: &str) -> Vec<(String, Quota)> {
+        let (tree, namespace) = scopes(name);
+        let mut quotas = vec![];
+        if let Some(quota) = self.quotas.get(base_tree_name(name)) {
+            quotas.push((tree, *quota));
+        }
+        if let Some(namespace) = namespace {
+            let quota = self
+                .namespace_quotas
+                .get(namespace.trim_end_matches(SEPARATOR));
+            if let Some(quota) = quota {
+                quotas.push((namespace, *quota));
+            }
+        }
+        quotas
+    }
+
+    // the db trees counted by `scope`
+    fn scope_trees(&self, scope: &str) -> Vec<String> {
+        match scope.strip_suffix(SEPARATOR) {
+            Some(namespace) => tree_names(&self.db)
+                .into_iter()
+                .filter(|name| {
+                    name.rsplit_once(SEPARATOR)
+                        .is_some_and(|(of, _)| of == namespace)
+                        && is_record_tree(name)
+                })
+                .collect(),
+            None => vec![scope.to_string()],
+        }
+    }
+
+    fn usage_of(&self, scope: &str) -> Result<RefMut<'_, String, Usage>> {
+        if let Some(usage) = self.usage.get_mut(scope) {
+            return Ok(usage);
+        }
+        // values pending in write-back mode are counted from the db
+        if let Some(write_back) = self.write_back() {
+            write_back.persist(&self.db)?;
+        }
+        let mut usage = Usage::default();
+        for name in self.scope_trees(scope) {
+            for r in self.db_tree(name.as_bytes())?.iter() {
+                let (k, v) = r?;
+                usage.keys += 1;
+                usage.bytes += (k.len() + v.len()) as u64;
+            }
+        }
+        Ok(self.usage.entry(scope.to_string()).or_insert(usage))
+    }
+
+    // count writing `new` over `current` at `key` of the tree of `T`, evicting or
+    // failing where that exceeds a quota
+    pub(crate) fn reserve<T: StorageData>(
+        &self,
+        key: &[u8],
+        current: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<()> {
+        let name = self.tree_name(&T::name()).into_owned();
+        let quotas = self.quota_scopes(&name);
+        if quotas.is_empty() {
+            return Ok(());
+        }
+        let added = u64::from(current.is_none());
+        let (old, new) = (
+            current.map_or(0, |v| (key.len() + v.len()) as u64),
+            (key.len() + new.len()) as u64,
+        );
+        let mut reserved: Vec<String> = vec![];
+        for (scope, quota) in quotas {
+            loop {
+                let mut usage = self.usage_of(&scope)?;
+                let after = Usage {
+                    keys: usage.keys + added,
+                    bytes: (usage.bytes + new).saturating_sub(old),
+                };
+                // a value not growing is never refused, even over a lowered quota
+                if quota.allows(after) || (added == 0 && new <= old) {
+                    *usage = after;
+                    break;
+                }
+                drop(usage);
+                if quota.policy == QuotaPolicy::Reject
+                    || !self.evict_oldest(&scope, &name, key, refs_of::<T>)?
+                {
+                    for scope in reserved {
+                        self.adjust(&scope, -(added as i64), old as i64 - new as i64);
+                    }
+                    let scope = match scope.strip_suffix(SEPARATOR) {
+                        Some(namespace) => format!("namespace({})", namespace),
+                        None => format!("tree({})", self.own_tree_name(&scope)),
+                    };
+                    return Err(eyre!(StorageError::QuotaExceeded(scope)));
+                }
+            }
+            reserved.push(scope);
+        }
+        Ok(())
+    }
+
+    // count removing `stored` at `key` of the db tree `name`
+    pub(crate) fn release(&self, name: &str, key: &[u8], stored: &[u8]) {
+        let (tree, namespace) = scopes(name);
+        let bytes = (key.len() + stored.len()) as i64;
+        self.adjust(&tree, -1, -bytes);
+        if let Some(namespace) = namespace {
+            self.adjust(&namespace, -1, -bytes);
+        }
+    }
+
+    fn adjust(&self, scope: &str, keys: i64, bytes: i64) {
+        if let Some(mut usage) = self.usage.get_mut(scope) {
+            usage.keys = usage.keys.saturating_add_signed(keys);
+            usage.bytes = usage.bytes.saturating_add_signed(bytes);
+        }
+    }
+
+    // remove the record of `scope` created first, other than `key` of the db tree
+    // `name` whose values `refs` reads. False when there is none.
+    fn evict_oldest(&self, scope: &str, name: &str, key: &[u8], refs: RefsFn) -> Result<bool> {
+        let mut oldest: Option<(u64, String, Vec<u8>)> = None;
+        for tree_name in self.scope_trees(scope) {
+            for r in self.db_tree(tree_name.as_bytes())?.iter() {
+                let (k, v) = r?;
+                if tree_name == name && k.as_ref() == key {
+                    continue;
+                }
+                // records written before creation times existed by their last write
+                let created = match envelope::created(&v) {
+                    0 => envelope::revision(&v),
+                    created => created,
+                };
+                if oldest.as_ref().is_none_or(|(at, _, _)| created < *at) {
+                    oldest = Some((created, tree_name.clone(), k.to_vec()));
+                }
+            }
+        }
+        let Some((_, tree_name, key)) = oldest else {
+            return Ok(false);
+        };
+        let ckey = tree_ckey(&tree_name, &key);
+        let _unmarked = self.write_back().map(|write_back| write_back.unmark(&ckey));
+        if let Some(stored) = self.db_tree(tree_name.as_bytes())?.remove(&key)? {
+            let own = self.own_tree_name(&tree_name);
+            self.audit("evict", own, &key, None);
+            self.cache_remove(&ckey);
+            self.untag_tree(own, &key)?;
+            self.release(&tree_name, &key, &stored);
+            // other trees of a namespace are read by their registered type
+            let refs = if tree_name == name {
+                Some(refs)
+            } else {
+                let types = self.types.0.read();
+                types
+                    .get(base_tree_name(&tree_name))
+                    .map(|fns| fns.content_refs)
+            };
+            if let Some(refs) = refs {
+                self.swap_refs(&refs(self, &tree_name, &key, &stored), &[]);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[test]
+fn quotas() {
+    use crate::{CasError, ContentHash, StorageData};
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+    struct Event {
+        n: u64,
+    }
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+    struct Proof {
+        #[storage(content)]
+        body: Option<ContentHash>,
+    }
+
+    let store = Storage::builder()
+        .temporary()
+        .quota::<String>(Quota {
+            max_keys: Some(2),
+            ..Default::default()
+        })
+        .quota::<Event>(Quota {
+            max_keys: Some(2),
+            policy: QuotaPolicy::EvictOldest,
+            ..Default::default()
+        })
+        .quota::<Proof>(Quota {
+            max_keys: Some(2),
+            policy: QuotaPolicy::EvictOldest,
+            ..Default::default()
+        })
+        .namespace_quota(
+            "tenant",
+            Quota {
+                max_bytes: Some(64),
+                ..Default::default()
+            },
+        )
+        .open()
+        .unwrap();
+    store.insert("a", "1".to_string());
+    store.insert("b", "2".to_string());
+    let err = store.insert_checked("c", "3".to_string()).unwrap_err();
+    assert_eq!(
+        Some(&StorageError::QuotaExceeded("tree(String)".to_string())),
+        err.downcast_ref::<StorageError>()
+    );
+    // replacing a value adds no key
+    store.insert_checked("a", "4".to_string()).unwrap();
+    store.remove::<String>("b");
+    store.insert_checked("c", "3".to_string()).unwrap();
+
+    // the other writes reserve too
+    let quota = |e: &CasError<String>| matches!(e, CasError::Io(e) if e.kind() == std::io::ErrorKind::QuotaExceeded);
+    assert!(quota(&store.try_insert("d", "5".to_string()).unwrap_err()));
+    assert!(quota(
+        &store
+            .compare_and_swap("d", None, Some("5".to_string()))
+            .unwrap_err()
+    ));
+    assert!(store
+        .update::<String, _>("d", |_| Some("5".to_string()))
+        .is_err());
+    assert_eq!(
+        Some("6".to_string()),
+        store
+            .update::<String, _>("a", |_| Some("6".to_string()))
+            .unwrap()
+    );
+    assert_eq!(None, store.get::<String>("d"));
+    store
+        .compare_and_swap("a", Some(&"6".to_string()), None)
+        .unwrap();
+    store.try_insert("d", "5".to_string()).unwrap();
+
+    for i in 0..4u64 {
+        store.insert_checked(i, Event { n: i }).unwrap();
+    }
+    assert_eq!(
+        vec![2, 3],
+        store
+            .collection::<Event>()
+            .unwrap()
+            .iter()
+            .map(|(_, v)| v.n)
+            .collect::<Vec<_>>()
+    );
+
+    // evicted records release their content
+    let hashes: Vec<_> = (0..3u8).map(|i| store.put_cas(&[i]).unwrap()).collect();
+    for (i, hash) in hashes.iter().enumerate() {
+        store
+            .insert_checked(i as u64, Proof { body: Some(*hash) })
+            .unwrap();
+    }
+    let refs = |hash| store.cas_refs(hash).unwrap();
+    assert_eq!(vec![0, 1, 1], hashes.iter().map(refs).collect::<Vec<_>>());
+
+    // each namespace counts on its own
+    let tenant = store.namespace("tenant");
+    tenant.insert_checked("a", "1".to_string()).unwrap();
+    assert!(tenant.insert_checked("big", "x".repeat(64)).is_err());
+    assert!(!tenant.contains_key::<String>("big"));
+    assert!(store
+        .namespace("other")
+        .insert_checked("big", "x".repeat(64))
+        .is_ok());
+}
@@ -0,0 +1,156 @@
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+use tracing::warn;
+
+use crate::{is_record_tree, Storage, StorageKey};
+
+// RAW
+// Payloads are stored exactly as given, without envelope, codec or checksum, and cached
+// like structured data. Trees written this way should not also be used through a
+// `StorageData` type, whose reads would take the payloads for values of an older
+// layout. Only trees of records are accessible, internal trees and the versions, tags,
+// points and entries kept next to records are refused, in any namespace.
+fn check_raw_tree(tree: &str) -> Result<()> {
+    if !is_record_tree(tree) {
+        return Err(eyre!("tree({}) is internal", tree));
+    }
+    Ok(())
+}
+
+impl Storage {
+    pub fn get_raw(&self, tree: &str, key: impl Into<StorageKey>) -> Option<Bytes> {
+        let key = key.into();
+        self.try_get_raw(tree, &key).unwrap_or_else(|e| {
+            warn!(
+                "Get raw tree({}) key({}) failed: {}",
+                tree,
+                String::from_utf8_lossy(&key),
+                e
+            );
+            None
+        })
+    }
+
+    pub(crate) fn try_get_raw(&self, tree: &str, key: &[u8]) -> Result<Option<Bytes>> {
+        self.check_open()?;
+        check_raw_tree(tree)?;
+        let ckey = self.tree_ckey(tree, key);
+        if let Some(v) = self.cache_get(&ckey) {
+            self.cache_counters.hit();
+            return Ok(Some(v));
+        }
+        self.cache_counters.miss();
+        let Some(v) = self.tree(tree)?.get(key)? else {
+            return Ok(None);
+        };
+        let v = Bytes::copy_from_slice(&v);
+        if self.admit(&ckey) {
+            self.cache_put(ckey, v.clone());
+        }
+        Ok(Some(v))
+    }
+
+    /// Store `value` under `key` of `tree` as is. Internal trees are refused.
+    pub fn insert_raw(&self, tree: &str, key: impl Into<StorageKey>, value: Bytes) -> Result<()> {
+        self.check_writable()?;
+        check_raw_tree(tree)?;
+        self.check_value_size(&value)?;
+        let key = key.into();
+        let _writes = self.write_gate();
+        self.tree(tree)?.insert(&key, value.as_ref())?;
+        self.audit("insert_raw", tree, &key, Some(&value));
+        self.recount(tree);
+        self.cache_put(self.tree_ckey(tree, &key), value);
+        Ok(())
+    }
+
+    /// The entries of `tree` whose keys start with `prefix`, at most `limit` of them, in
+    /// key order. Read from the db without touching the cache.
+    pub fn scan_raw(
+        &self,
+        tree: &str,
+        prefix: &[u8],
+        limit: usize,
+    ) -> Result<Vec<(StorageKey, Bytes)>> {
+        self.check_open()?;
+        check_raw_tree(tree)?;
+        self.tree(tree)?
+            .scan_prefix(prefix)
+            .take(limit)
+            .map(|r| {
+                let (k, v) = r?;
+                Ok((StorageKey::from(k.as_ref()), Bytes::copy_from_slice(&v)))
+            })
+            .collect()
+    }
+
+    pub fn remove_raw(&self, tree: &str, key: impl Into<StorageKey>) -> Result<()> {
+        self.check_writable()?;
+        check_raw_tree(tree)?;
+        let key = key.into();
+        let _writes = self.write_gate();
+        self.tree(tree)?.remove(&key)?;
+        self.audit("remove_raw", tree, &key, None);
+        self.recount(tree);
+        self.cache_remove(&self.tree_ckey(tree, &key));
+        Ok(())
+    }
+}
+
+#[test]
+fn raw() {
+    use crate::META_TREE_NAME;
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let payload = Bytes::from_static(b"\x08\x96\x01");
+    store.insert_raw("Blobs", "a", payload.clone()).unwrap();
+    assert_eq!(Some(payload.clone()), store.get_raw("Blobs", "a"));
+    assert_eq!(
+        Some(payload.as_ref()),
+        store
+            .db
+            .open_tree("Blobs")
+            .unwrap()
+            .get("a")
+            .unwrap()
+            .as_deref()
+    );
+    assert_eq!(None, store.get_raw("Blobs", "b"));
+    store.insert_raw("Blobs", "ab", payload.clone()).unwrap();
+    store.insert_raw("Blobs", "b", payload.clone()).unwrap();
+    assert_eq!(
+        vec![StorageKey::from("a"), StorageKey::from("ab")],
+        store
+            .scan_raw("Blobs", b"a", 10)
+            .unwrap()
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(1, store.scan_raw("Blobs", b"", 1).unwrap().len());
+
+    store.remove_raw("Blobs", "a").unwrap();
+    assert_eq!(None, store.get_raw("Blobs", "a"));
+    assert!(store
+        .insert_raw(META_TREE_NAME, "a", payload.clone())
+        .is_err());
+
+    // the trees kept next to records and internal trees of a namespace are refused too
+    store.insert("a", "value".to_string());
+    for tree in [
+        "String__history",
+        "String__tags",
+        "String__series",
+        "__changes__journal",
+        "tenant::META",
+    ] {
+        assert!(
+            store.insert_raw(tree, "a", payload.clone()).is_err(),
+            "{}",
+            tree
+        );
+        assert!(store.try_get_raw(tree, b"a").is_err(), "{}", tree);
+        assert!(store.scan_raw(tree, b"", 10).is_err(), "{}", tree);
+        assert!(store.remove_raw(tree, "a").is_err(), "{}", tree);
+    }
+}
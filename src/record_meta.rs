@@ -0,0 +1,94 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use color_eyre::eyre::Result;
+
+use crate::{envelope, Storage, StorageData, StorageKey};
+
+/// When a record was created and last written, from its envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordMeta {
+    /// `None` for records last written before creation times were stored.
+    pub created_at: Option<SystemTime>,
+    /// The time of the last write, including rewrites by migrations. `None` for
+    /// records written before record versions existed.
+    pub updated_at: Option<SystemTime>,
+    /// The record version, as returned by `get_versioned`.
+    pub version: u64,
+}
+
+// microseconds since the epoch, 0 meaning unknown
+fn time(micros: u64) -> Option<SystemTime> {
+    (micros != 0).then(|| UNIX_EPOCH + Duration::from_micros(micros))
+}
+
+// RECORD METADATA
+// A record is created by the write that finds its key absent, every later write keeps
+// the creation time of the value it replaces. Concurrent first writes of a key may
+// each stamp their own time, the one that lands last wins. Removing a record forgets
+// its creation time, a record moved with `move_to` keeps it.
+impl Storage {
+    /// Creation and modification times of `key`, `None` when it is absent.
+    pub fn metadata<T: StorageData>(
+        &self,
+        key: impl Into<StorageKey>,
+    ) -> Result<Option<RecordMeta>> {
+        let key = key.into();
+        let stored = match self.cache_get(&self.ckey::<T>(&key)) {
+            Some(v) => Some(v),
+            None => self
+                .tree(T::name())?
+                .get(&key)?
+                .map(|v| Bytes::copy_from_slice(&v)),
+        };
+        Ok(stored.map(|v| {
+            let version = envelope::revision(&v);
+            RecordMeta {
+                created_at: time(envelope::created(&v)),
+                updated_at: time(version),
+                version,
+            }
+        }))
+    }
+}
+
+#[test]
+fn metadata() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    assert_eq!(None, store.metadata::<String>("a").unwrap());
+
+    store.insert("a", "1".to_string());
+    let first = store.metadata::<String>("a").unwrap().unwrap();
+    assert!(first.created_at.is_some());
+    assert_eq!(first.created_at, first.updated_at);
+
+    store.insert("a", "2".to_string());
+    store
+        .update::<String, _>("a", |v| v.map(|v| v + "3"))
+        .unwrap();
+    let second = store.metadata::<String>("a").unwrap().unwrap();
+    assert_eq!(first.created_at, second.created_at);
+    assert!(second.updated_at > first.updated_at);
+    assert!(second.version > first.version);
+
+    store.remove::<String>("a");
+    store.insert("a", "4".to_string());
+    let third = store.metadata::<String>("a").unwrap().unwrap();
+    assert!(third.created_at > first.created_at);
+
+    // written before the envelope existed
+    store
+        .db
+        .open_tree(String::name())
+        .unwrap()
+        .insert("legacy", bincode::serialize(&"old".to_string()).unwrap())
+        .unwrap();
+    assert_eq!(
+        Some(RecordMeta {
+            created_at: None,
+            updated_at: None,
+            version: 0
+        }),
+        store.metadata::<String>("legacy").unwrap()
+    );
+}
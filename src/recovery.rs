@@ -0,0 +1,329 @@
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::field::Empty;
+use tracing::{debug, info, info_span, warn};
+
+use crate::{is_record_tree, Storage, StorageData};
+
+/// How `recover` warms the cache with the records of a type.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecoveryPolicy {
+    /// Load all records.
+    #[default]
+    Eager,
+    /// Load nothing, records are cached on first access.
+    Lazy,
+    /// Load the first records in key order, up to this many.
+    TopN(usize),
+    /// Load the records whose keys start with these bytes.
+    Prefix(Vec<u8>),
+}
+
+// records between two progress reports
+const PROGRESS_INTERVAL: usize = 1000;
+
+type Progress<'a> = &'a (dyn Fn(&RecoveryProgress) + Sync);
+
+/// Progress of `Storage::recover_all_with`, per tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    pub tree: String,
+    /// Records of the tree loaded so far.
+    pub loaded: usize,
+    /// Records of the tree its recovery policy loads.
+    pub total: usize,
+}
+
+// RECOVERY
+// Types without a policy in `recovery_policies` are loaded eagerly up to
+// `recovery_eager_limit` records, so an oversized tree doesn't fill the memory, the
+// records past the limit are cached lazily.
+impl Storage {
+    pub fn recover_root(&self) {
+        if let Err(e) = self.recover_root_records() {
+            warn!("Recover root failed: {}", e);
+        }
+    }
+
+    /// Warm the cache with the records of `T` by its `RecoveryPolicy`, running the
+    /// pending migrations first.
+    pub fn recover<T: StorageData>(&self) -> Result<()> {
+        self.recover_with::<T>(&self.recovery_policy::<T>())?;
+        Ok(())
+    }
+
+    pub(crate) fn recovery_policy<T: StorageData>(&self) -> RecoveryPolicy {
+        self.tree_recovery_policy(&T::name())
+    }
+
+    fn tree_recovery_policy(&self, name: &str) -> RecoveryPolicy {
+        match self.recovery_policies.get(name) {
+            Some(policy) => policy.clone(),
+            None => self
+                .recovery_eager_limit
+                .map_or(RecoveryPolicy::Eager, RecoveryPolicy::TopN),
+        }
+    }
+
+    /// Warm the cache with the records of `T` selected by `policy`, after running the
+    /// pending migrations like `recover`. Returns the number of loaded records.
+    pub fn recover_with<T: StorageData>(&self, policy: &RecoveryPolicy) -> Result<usize> {
+        self.migrate_on_recover::<T>()?;
+        self.recover_tree(&T::name(), policy, None)
+    }
+
+    /// Warm the cache with the root tree and every data tree by their recovery policies,
+    /// without knowing their types. Pending migrations don't run, values of an old
+    /// version are migrated when read. Returns the number of loaded records.
+    pub fn recover_all(&self) -> Result<usize> {
+        self.recover_all_in(1, None)
+    }
+
+    /// Like `recover_all`, loading the data trees on `threads` threads and calling
+    /// `progress` every thousand records and after each tree.
+    ///
+    /// The totals are counted before loading a tree, one more pass over its records.
+    pub fn recover_all_with<F: Fn(&RecoveryProgress) + Sync>(
+        &self,
+        threads: usize,
+        progress: F,
+    ) -> Result<usize> {
+        self.recover_all_in(threads, Some(&progress))
+    }
+
+    fn recover_all_in(&self, threads: usize, progress: Option<Progress>) -> Result<usize> {
+        // root records belong to no namespace
+        let mut loaded = match self.namespace {
+            Some(_) => 0,
+            None => self.recover_root_records()?,
+        };
+        let mut names = self.data_tree_names();
+        // archived values are only read by `history`, tags by `find_by_tag`, points and
+        // entries by their `TimeSeries` and `Journal`
+        names.retain(|name| is_record_tree(name));
+        let names = Mutex::new(names);
+        let worker = || -> Result<usize> {
+            let mut loaded = 0;
+            loop {
+                let Some(name) = names.lock().pop() else {
+                    return Ok(loaded);
+                };
+                let policy = self.tree_recovery_policy(&name);
+                loaded += self.recover_tree(&name, &policy, progress)?;
+            }
+        };
+        let results = std::thread::scope(|scope| {
+            let workers = (0..threads.max(1))
+                .map(|_| {
+                    std::thread::Builder::new()
+                        .name("storage-recovery".to_string())
+                        .spawn_scoped(scope, worker)
+                })
+                .collect::<std::io::Result<Vec<_>>>()?;
+            workers
+                .into_iter()
+                .map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|_| Err(eyre!("recovery thread panicked")))
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+        loaded += results.into_iter().sum::<usize>();
+        Ok(loaded)
+    }
+
+    fn recover_root_records(&self) -> Result<usize> {
+        let mut loaded = 0;
+        for r in self.db.iter() {
+            let (k, v) = r?;
+            self.cache_put(k.to_vec(), Bytes::from(v.to_vec()));
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    fn recover_tree(
+        &self,
+        name: &str,
+        policy: &RecoveryPolicy,
+        progress: Option<Progress>,
+    ) -> Result<usize> {
+        let span = info_span!("storage.recover", tree = %name, records = Empty);
+        let _enter = span.enter();
+        let tree = self.tree(name)?;
+        self.rebuild_filter(name)?;
+        let (records, limit) = match policy {
+            RecoveryPolicy::Lazy => return Ok(0),
+            RecoveryPolicy::Eager => (tree.iter(), None),
+            RecoveryPolicy::TopN(n) => (tree.iter(), Some(*n)),
+            RecoveryPolicy::Prefix(prefix) => (tree.scan_prefix(prefix), None),
+        };
+        let mut report = progress.map(|_| RecoveryProgress {
+            tree: name.to_string(),
+            loaded: 0,
+            total: match policy {
+                RecoveryPolicy::Prefix(prefix) => tree.scan_prefix(prefix).count(),
+                _ => tree.iter().take(limit.unwrap_or(usize::MAX)).count(),
+            },
+        });
+        let mut loaded = 0;
+        for r in records {
+            if limit.is_some_and(|limit| loaded >= limit) {
+                info!(
+                    "Recovered {} records of tree({}), the rest is loaded on access",
+                    loaded, name
+                );
+                break;
+            }
+            let (k, v) = r?;
+            debug!(
+                "Recover cache for tree({}): {:?}",
+                name,
+                String::from_utf8_lossy(&k)
+            );
+            self.cache_put(self.tree_ckey(name, &k), Bytes::from(v.to_vec()));
+            loaded += 1;
+            if let (Some(progress), Some(report)) = (progress, &mut report) {
+                report.loaded = loaded;
+                if loaded.is_multiple_of(PROGRESS_INTERVAL) {
+                    progress(report);
+                }
+            }
+        }
+        if let (Some(progress), Some(report)) = (progress, &report) {
+            progress(report);
+        }
+        span.record("records", loaded);
+        Ok(loaded)
+    }
+}
+
+#[test]
+fn recovery_policy() {
+    use std::collections::HashMap;
+
+    use crate::{Format, StorageConfig};
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize)]
+    struct Validator;
+
+    let dir = crate::TestDir::new("recovery_policy");
+    let config = StorageConfig {
+        db_path: dir.path("db"),
+        recovery_eager_limit: Some(3),
+        recovery_policies: HashMap::from([(Validator::name(), RecoveryPolicy::Lazy)]),
+        ..Default::default()
+    };
+    {
+        let db = sled::open(&config.db_path).unwrap();
+        let tree = db.open_tree(String::name()).unwrap();
+        for key in ["a/1", "a/2", "b/1", "b/2", "b/3"] {
+            let value = crate::envelope::encode(Format::default(), None, &key.to_string());
+            tree.insert(key, crate::envelope::checksummed(value.unwrap()))
+                .unwrap();
+        }
+    }
+
+    let store: Storage = Storage::new(&config);
+    assert_eq!(RecoveryPolicy::Lazy, store.recovery_policy::<Validator>());
+    assert_eq!(RecoveryPolicy::TopN(3), store.recovery_policy::<String>());
+    store.recover::<String>().unwrap();
+    store.cache.run_pending_tasks();
+    assert_eq!(3, store.cache.entry_count());
+
+    assert_eq!(
+        0,
+        store.recover_with::<String>(&RecoveryPolicy::Lazy).unwrap()
+    );
+    let prefix = RecoveryPolicy::Prefix(b"b/".to_vec());
+    assert_eq!(3, store.recover_with::<String>(&prefix).unwrap());
+    assert_eq!(
+        5,
+        store
+            .recover_with::<String>(&RecoveryPolicy::Eager)
+            .unwrap()
+    );
+    assert_eq!(Some("b/3".to_string()), store.get::<String>("b/3"));
+}
+
+#[test]
+fn recover_all() {
+    use crate::{Format, StorageConfig, SEQUENCE_TREE_NAME};
+
+    let dir = crate::TestDir::new("recover_all");
+    let config = StorageConfig {
+        db_path: dir.path("db"),
+        ..Default::default()
+    };
+    {
+        let db = sled::open(&config.db_path).unwrap();
+        for name in ["A", "B"] {
+            let value = crate::envelope::encode(Format::default(), None, &name.to_string());
+            db.open_tree(name)
+                .unwrap()
+                .insert("k", crate::envelope::checksummed(value.unwrap()))
+                .unwrap();
+        }
+        db.open_tree(SEQUENCE_TREE_NAME)
+            .unwrap()
+            .insert("seq", 1u64.to_be_bytes().to_vec())
+            .unwrap();
+        db.insert("root", "root").unwrap();
+    }
+
+    let store: Storage = Storage::new(&config);
+    assert_eq!(3, store.recover_all().unwrap());
+    assert!(store.cache.contains_key(&store.tree_ckey("A", b"k")));
+    assert!(store.cache.contains_key(&store.tree_ckey("B", b"k")));
+    assert!(store.cache.contains_key(b"root".as_slice()));
+    assert!(!store
+        .cache
+        .contains_key(&store.tree_ckey(SEQUENCE_TREE_NAME, b"seq")));
+}
+
+#[test]
+fn recover_all_with() {
+    use crate::{Format, StorageConfig};
+
+    let dir = crate::TestDir::new("recover_all_with");
+    let config = StorageConfig {
+        db_path: dir.path("db"),
+        ..Default::default()
+    };
+    {
+        let db = sled::open(&config.db_path).unwrap();
+        for name in ["A", "B", "C"] {
+            let tree = db.open_tree(name).unwrap();
+            for i in 0..1500u64 {
+                let value = crate::envelope::encode(Format::default(), None, &i.to_string());
+                tree.insert(
+                    i.to_be_bytes(),
+                    crate::envelope::checksummed(value.unwrap()),
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    let store: Storage = Storage::new(&config);
+    let reports = Mutex::new(vec![]);
+    let loaded = store
+        .recover_all_with(2, |p| reports.lock().push(p.clone()))
+        .unwrap();
+    assert_eq!(4500, loaded);
+    let mut reports = reports.into_inner();
+    reports.sort_by(|a, b| (&a.tree, a.loaded).cmp(&(&b.tree, b.loaded)));
+    assert_eq!(6, reports.len());
+    assert!(reports.iter().all(|p| p.total == 1500));
+    assert_eq!(
+        vec![1000, 1500],
+        reports
+            .iter()
+            .filter(|p| p.tree == "B")
+            .map(|p| p.loaded)
+            .collect::<Vec<_>>()
+    );
+}
@@ -0,0 +1,158 @@
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+
+use crate::quota::forget_usage;
+use crate::{envelope, split_ckey, Storage, StorageData, StorageError, StorageKey, Tree};
+
+// RENAME
+// sled has no transactions, a record is moved by a compare-and-swap creating the target
+// key and one removing the source key if it still holds the moved value. Concurrent
+// writers never see the record lost, a crash between the two steps leaves it under
+// both keys.
+impl Storage {
+    /// Move the value of `old` to `new` within the tree of `T`. Returns false when `old`
+    /// is absent, fails with `StorageError::KeyExists` when `new` has a value.
+    pub fn rename<T: StorageData>(
+        &self,
+        old: impl Into<StorageKey>,
+        new: impl Into<StorageKey>,
+    ) -> Result<bool> {
+        let (old, new) = (old.into(), new.into());
+        let tree = self.tree(T::name())?;
+        self.move_record(
+            (&tree, &self.ckey::<T>(&old), &old),
+            (&tree, self.ckey::<T>(&new), &new),
+            |bytes| self.rebind::<T>(bytes, &old, &new),
+        )
+    }
+
+    // `bytes` stored at `old` as stored at `new`, encrypted values are bound to their key
+    fn rebind<T: StorageData>(&self, bytes: &[u8], old: &[u8], new: &[u8]) -> Result<Bytes> {
+        if envelope::split(bytes).0.flags & envelope::FLAG_ENCRYPTED == 0 {
+            return Ok(Bytes::copy_from_slice(bytes));
+        }
+        let name = self.tree_name(&T::name()).into_owned();
+        let plain = self.unseal(bytes, &name, old)?.into_owned();
+        let created = Some(envelope::created(bytes));
+        Ok(Bytes::from(self.seal(plain, created, &name, new)?))
+    }
+
+    /// Move the value of `key` from the tree of `Src` to the tree of `Dst`, converted
+    /// with `From`. Returns false when `key` is absent, fails with
+    /// `StorageError::KeyExists` when it has a `Dst` value already.
+    pub fn move_to<Src: StorageData, Dst: StorageData + From<Src>>(
+        &self,
+        key: impl Into<StorageKey>,
+    ) -> Result<bool> {
+        let key = key.into();
+        let (src, dst) = (self.tree(Src::name())?, self.tree(Dst::name())?);
+        self.stamp_version::<Dst>();
+        self.move_record(
+            (&src, &self.ckey::<Src>(&key), &key),
+            (&dst, self.ckey::<Dst>(&key), &key),
+            |bytes| {
+                let value: Src = self.decode_with(&key, bytes, false).ok_or_else(|| {
+                    eyre!(StorageError::Corrupted(
+                        String::from_utf8_lossy(&key).to_string()
+                    ))
+                })?;
+                self.encode(&key, &Dst::from(value), Some(bytes))
+            },
+        )
+    }
+
+    fn move_record(
+        &self,
+        (from, from_ckey, from_key): (&Tree, &Vec<u8>, &[u8]),
+        (to, to_ckey, to_key): (&Tree, Vec<u8>, &[u8]),
+        convert: impl FnOnce(&[u8]) -> Result<Bytes>,
+    ) -> Result<bool> {
+        self.check_writable()?;
+        let _writes = self.write_gate();
+        if let Some(write_back) = self.write_back() {
+            write_back.persist_key(from, from_ckey, from_key)?;
+            write_back.persist_key(to, &to_ckey, to_key)?;
+        }
+        let Some(current) = from.get(from_key)? else {
+            return Ok(false);
+        };
+        let moved = convert(&current)?;
+        let absent = None as Option<&[u8]>;
+        if to
+            .compare_and_swap(to_key, absent, Some(moved.as_ref()))?
+            .is_err()
+        {
+            return Err(eyre!(StorageError::KeyExists(
+                String::from_utf8_lossy(to_key).to_string()
+            )));
+        }
+        if from
+            .compare_and_swap(from_key, Some(current.as_ref()), absent)?
+            .is_err()
+        {
+            // written meanwhile, the move is undone unless the target changed as well
+            let _ = to.compare_and_swap(to_key, Some(moved.as_ref()), absent);
+            return Err(eyre!(
+                "key({}) changed while moving it",
+                String::from_utf8_lossy(from_key)
+            ));
+        }
+        if let (Some((from_tree, _)), Some((to_tree, _))) =
+            (split_ckey(from_ckey), split_ckey(&to_ckey))
+        {
+            let (from_tree, to_tree) = (
+                String::from_utf8_lossy(from_tree),
+                String::from_utf8_lossy(to_tree),
+            );
+            forget_usage(&self.usage, &from_tree);
+            forget_usage(&self.usage, &to_tree);
+            let (from_tree, to_tree) =
+                (self.own_tree_name(&from_tree), self.own_tree_name(&to_tree));
+            self.audit("move_from", from_tree, from_key, None);
+            self.audit("move_to", to_tree, to_key, Some(&moved));
+        }
+        self.cache_remove(from_ckey);
+        self.cache_put(to_ckey, moved);
+        Ok(true)
+    }
+}
+
+#[test]
+fn rename() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(StorageData, Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+    struct Archived {
+        value: String,
+    }
+
+    impl From<String> for Archived {
+        fn from(value: String) -> Self {
+            Self { value }
+        }
+    }
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert("tx/1", "a".to_string());
+    store.insert("tx/2", "b".to_string());
+    assert!(store.rename::<String>("tx/1", "block/1/tx/1").unwrap());
+    assert_eq!(None, store.get::<String>("tx/1"));
+    assert_eq!(Some("a".to_string()), store.get::<String>("block/1/tx/1"));
+    assert!(!store.rename::<String>("tx/1", "tx/3").unwrap());
+
+    let exists = store.rename::<String>("tx/2", "block/1/tx/1").unwrap_err();
+    assert!(matches!(
+        exists.downcast_ref(),
+        Some(StorageError::KeyExists(_))
+    ));
+    assert_eq!(Some("b".to_string()), store.get::<String>("tx/2"));
+
+    assert!(store.move_to::<String, Archived>("tx/2").unwrap());
+    assert!(!store.contains_key::<String>("tx/2"));
+    assert_eq!(
+        Some(Archived {
+            value: "b".to_string()
+        }),
+        store.get::<Archived>("tx/2")
+    );
+}
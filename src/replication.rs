@@ -0,0 +1,1028 @@
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use color_eyre::eyre::{eyre, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::journal::{is_journal_tree, Journal};
+use crate::quota::forget_usage;
+use crate::{is_internal_tree, tree_ckey, Storage, StorageConfig};
+
+pub(crate) const CHANGE_LOG_TREE_NAME: &str = "__changes";
+// meta key of the offset of the next change a replica applies
+const REPLICATION_OFFSET: &str = "replication_offset";
+// meta key of the offset after the changes of the last increment restored
+const INCREMENT_OFFSET: &str = "increment_offset";
+// changes sent to a replica at once
+const BATCH: usize = 256;
+// how often the server looks for new changes and connections
+const POLL: Duration = Duration::from_millis(20);
+// sent to idle replicas, which reconnect when they hear nothing for `TIMEOUT`
+const HEARTBEAT: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY: Duration = Duration::from_secs(1);
+// connections proving the secret at once, the ones beyond are dropped when accepted
+const MAX_HANDSHAKES: usize = 8;
+// the longest a handshake may take, however slowly its bytes arrive
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// longest change a replica accepts, larger frames are refused before they are read
+const MAX_FRAME: usize = 256 << 20;
+// blake3 context the replication key is derived from the secret in
+const SECRET_CONTEXT: &str = "storage_hal 2024 replication secret";
+
+/// Secret shared by a primary and its replicas. Each proves it knows the secret to the
+/// other before any change is streamed.
+// not `Copy`, so key material isn't duplicated implicitly
+#[allow(missing_copy_implementations)]
+#[derive(Clone, Deserialize)]
+#[serde(from = "String")]
+pub struct ReplicationSecret([u8; 32]);
+
+impl ReplicationSecret {
+    pub fn new(secret: &str) -> Self {
+        Self(blake3::derive_key(SECRET_CONTEXT, secret.as_bytes()))
+    }
+
+    // the proof of knowing the secret for `challenge`, given by `role`. Primaries and
+    // replicas answer in their own role, so one's answer can't be passed off as the
+    // other's.
+    fn answer(&self, role: Role, challenge: &[u8; 32]) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_keyed(&self.0);
+        hasher.update(&[role as u8]);
+        hasher.update(challenge);
+        hasher.finalize()
+    }
+}
+
+impl From<String> for ReplicationSecret {
+    fn from(secret: String) -> Self {
+        Self::new(&secret)
+    }
+}
+
+impl std::fmt::Debug for ReplicationSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReplicationSecret(..)")
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Role {
+    Primary = 1,
+    Replica = 2,
+}
+
+// a db key after a mutation, `value: None` once it is removed
+#[derive(Debug, Serialize, Deserialize)]
+struct Change {
+    tree: String,
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+}
+
+/// The running replication server, stopped when dropped.
+#[derive(Debug)]
+pub struct ReplicationServer {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ReplicationServer {
+    /// The address replicas connect to, with the port picked when binding port 0.
+    pub const fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop the server, closing the connections of all replicas.
+    pub fn stop(self) {}
+}
+
+impl Drop for ReplicationServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// The running replication from a primary, stopped when dropped.
+#[derive(Debug)]
+pub struct ReplicaTask {
+    stop: Arc<AtomicBool>,
+    // the connection to the primary, shut down to stop waiting for changes
+    stream: Arc<Mutex<Option<TcpStream>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ReplicaTask {
+    /// Stop replicating, waiting for the change being applied.
+    pub fn stop(self) {}
+}
+
+impl Drop for ReplicaTask {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(stream) = self.stream.lock().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// A read-only storage applying the changes of its primary, see `Storage::follow`.
+///
+/// It reads like any `Storage`, which it dereferences to. Dropping it stops following,
+/// clones of the storage taken from it stay readable.
+#[derive(Debug)]
+pub struct Follower {
+    storage: Storage,
+    replica: ReplicaTask,
+}
+
+impl Follower {
+    /// Stop following the primary, keeping the storage as replicated so far.
+    pub fn stop(self) -> Storage {
+        self.replica.stop();
+        self.storage
+    }
+}
+
+impl std::ops::Deref for Follower {
+    type Target = Storage;
+
+    fn deref(&self) -> &Storage {
+        &self.storage
+    }
+}
+
+// fill `buf` from `stream` before `deadline`, however slowly the bytes arrive
+fn read_by(stream: &TcpStream, buf: &mut [u8], deadline: Instant) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(eyre!("handshake timed out"));
+        }
+        stream.set_read_timeout(Some(left))?;
+        match (&*stream).read(&mut buf[filled..]) {
+            Ok(0) => return Err(eyre!("connection closed during the handshake")),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    Ok(())
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+// `| offset (8, be) | length (4, be) | bincode change |`, empty for heartbeats
+fn write_frame(writer: &mut impl Write, offset: u64, entry: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&offset.to_be_bytes())?;
+    writer.write_all(&(entry.len() as u32).to_be_bytes())?;
+    writer.write_all(entry)
+}
+
+fn read_frame(reader: &mut impl Read) -> Result<(u64, Vec<u8>)> {
+    let at = read_u64(reader)?;
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(eyre!(
+            "change {} of {} bytes is over the limit of {}",
+            at,
+            len,
+            MAX_FRAME
+        ));
+    }
+    let mut entry = vec![0; len];
+    reader.read_exact(&mut entry)?;
+    Ok((at, entry))
+}
+
+// hashes what passes through it, for the checksum ending an increment
+struct Hashing<T> {
+    inner: T,
+    hasher: blake3::Hasher,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+// unique per connection, so an answer overheard once can't be replayed
+fn challenge(peer: SocketAddr) -> [u8; 32] {
+    static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = blake3::Hasher::new();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.update(&now.as_nanos().to_be_bytes());
+    hasher.update(&CONNECTIONS.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    hasher.update(&std::process::id().to_be_bytes());
+    hasher.update(peer.to_string().as_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+// sleep for `duration`, or until `stop` is set
+fn pause(duration: Duration, stop: &AtomicBool) {
+    let until = Instant::now() + duration;
+    while !stop.load(Ordering::Relaxed) && Instant::now() < until {
+        std::thread::sleep(POLL);
+    }
+}
+
+// REPLICATION
+// With `change_log` set, the mutations of data trees, the ones the audit log records as
+// well as migrations, codec rewrites, quarantines and retention, append the db tree,
+// key and the value now stored under it to the `__changes` journal. Cache expiry
+// removes values without a change, so it is refused together with the change log.
+// Reading the value back after the write makes the last change of a key carry its
+// final value even when concurrent writes log out of order. A crash between a write
+// and its change loses the change, like it loses the audit entry.
+//
+// Replicas connect over TCP and the primary sends a challenge, the replica answers
+// with its keyed hash under the `replication_secret` both are configured with, a
+// challenge of its own and the offset to resume from. The primary drops replicas
+// answering wrong, otherwise it answers the replica's challenge and sends the first
+// offset its log still holds. A replica drops a primary answering wrong, otherwise the
+// primary streams the changes from there, heartbeats while idle. At most
+// `MAX_HANDSHAKES` connections are in the handshake at once, each has
+// `HANDSHAKE_TIMEOUT` to finish it. Changes are not encrypted, replicate over a trusted
+// network or a tunnel. A replica applies them to its db trees as given, with the
+// primary's codecs and keys, and records the next offset in its metadata with each
+// change. Applying a change twice is harmless, so a replica that reconnects after a
+// disconnect or restart simply resumes. Blobs, content addressed storage, journals and
+// the internal trees of tags, history, counters and sequences are not replicated. The
+// log grows until it is truncated with `change_log().truncate_before`, a replica that
+// fell behind the truncation stops and has to be restored from a backup.
+impl Storage {
+    /// The change log replicas follow.
+    pub fn change_log(&self) -> Result<Journal> {
+        Ok(Journal {
+            storage: self.clone(),
+            name: CHANGE_LOG_TREE_NAME.to_string(),
+            tree: self.db_tree(CHANGE_LOG_TREE_NAME.as_bytes())?,
+        })
+    }
+
+    // after a write of `key`, under the write gate
+    pub(crate) fn log_change(&self, tree: &str, key: &[u8]) {
+        self.log_db_change(&self.tree_name(tree), key);
+    }
+
+    // `log_change` of the db tree `tree`, for the writes across namespaces
+    pub(crate) fn log_db_change(&self, tree: &str, key: &[u8]) {
+        if !self.change_log || is_internal_tree(tree) || is_journal_tree(tree) {
+            return;
+        }
+        if let Err(e) = self.try_log_change(tree, key) {
+            warn!(
+                "Log change of tree({}) key({}) failed: {}",
+                tree,
+                String::from_utf8_lossy(key),
+                e
+            );
+        }
+    }
+
+    fn try_log_change(&self, tree: &str, key: &[u8]) -> Result<()> {
+        let value = self.db_tree(tree.as_bytes())?.get(key)?;
+        let change = Change {
+            tree: tree.to_string(),
+            key: key.to_vec(),
+            value: value.map(|v| v.to_vec()),
+        };
+        self.change_log()?.push(&bincode::serialize(&change)?)?;
+        Ok(())
+    }
+
+    /// Stream the change log to the replicas connecting to `addr`.
+    pub fn serve_replication(&self, addr: impl ToSocketAddrs) -> Result<ReplicationServer> {
+        if !self.change_log {
+            return Err(eyre!("change_log is not enabled"));
+        }
+        if self.replication_secret.is_none() {
+            return Err(eyre!("replication_secret is not set"));
+        }
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let server_stop = stop.clone();
+        let store = self.clone();
+        let handle = std::thread::Builder::new()
+            .name("storage-replication".to_string())
+            .spawn(move || {
+                let mut replicas = vec![];
+                let handshakes = Arc::new(AtomicUsize::new(0));
+                while !server_stop.load(Ordering::Relaxed) {
+                    let (stream, peer) = match listener.accept() {
+                        Ok((stream, peer)) => {
+                            debug!("Replica {} connected", peer);
+                            (stream, peer)
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            std::thread::sleep(POLL);
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("Accept replica failed: {}", e);
+                            std::thread::sleep(POLL);
+                            continue;
+                        }
+                    };
+                    // only this thread adds handshakes, the count can only drop meanwhile
+                    if handshakes.load(Ordering::Relaxed) >= MAX_HANDSHAKES {
+                        debug!("Dropped replica {}, too many handshakes", peer);
+                        continue;
+                    }
+                    handshakes.fetch_add(1, Ordering::Relaxed);
+                    let (store, stop) = (store.clone(), server_stop.clone());
+                    let handshakes = handshakes.clone();
+                    replicas.retain(|replica: &JoinHandle<()>| !replica.is_finished());
+                    replicas.push(std::thread::spawn(move || {
+                        let offset = store.accept_replica(&stream, peer);
+                        handshakes.fetch_sub(1, Ordering::Relaxed);
+                        if let Err(e) =
+                            offset.and_then(|offset| store.serve_replica(&stream, offset, &stop))
+                        {
+                            debug!("Replica {} disconnected: {}", peer, e);
+                        }
+                    }));
+                }
+                for replica in replicas {
+                    let _ = replica.join();
+                }
+                debug!("Stopped replication of {}", store.db_path);
+            })?;
+        Ok(ReplicationServer {
+            local_addr,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    // the handshake of a replica, returning the offset it resumes from
+    fn accept_replica(&self, stream: &TcpStream, peer: SocketAddr) -> Result<u64> {
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        stream.set_nonblocking(false)?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let secret = self
+            .replication_secret
+            .as_ref()
+            .ok_or_else(|| eyre!("replication_secret is not set"))?;
+        let challenge = challenge(peer);
+        (&*stream).write_all(&challenge)?;
+        // | answer (32) | challenge of the replica (32) | offset (8, be) |
+        let mut hello = [0; 72];
+        read_by(stream, &mut hello, deadline)?;
+        let answer: [u8; 32] = hello[..32].try_into().unwrap();
+        // compared in constant time
+        if secret.answer(Role::Replica, &challenge) != blake3::Hash::from_bytes(answer) {
+            warn!("Replica {} failed to prove the replication secret", peer);
+            return Err(eyre!("replica {} is not authenticated", peer));
+        }
+        let replica_challenge: [u8; 32] = hello[32..64].try_into().unwrap();
+        let mut reply = secret
+            .answer(Role::Primary, &replica_challenge)
+            .as_bytes()
+            .to_vec();
+        reply.extend_from_slice(&self.change_log()?.start_offset()?.to_be_bytes());
+        (&*stream).write_all(&reply)?;
+        Ok(u64::from_be_bytes(hello[64..].try_into().unwrap()))
+    }
+
+    fn serve_replica(&self, stream: &TcpStream, mut offset: u64, stop: &AtomicBool) -> Result<()> {
+        let log = self.change_log()?;
+        let mut writer = BufWriter::new(stream);
+        let mut sent = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            let changes = log.read_from(offset, BATCH)?;
+            if changes.is_empty() {
+                if sent.elapsed() >= HEARTBEAT {
+                    write_frame(&mut writer, offset, &[])?;
+                    writer.flush()?;
+                    sent = Instant::now();
+                }
+                std::thread::sleep(POLL);
+                continue;
+            }
+            for (at, change) in changes {
+                write_frame(&mut writer, at, &change)?;
+                offset = at + 1;
+            }
+            writer.flush()?;
+            sent = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Open the db of `config` read-only and keep applying the changes of the primary
+    /// serving replication at `primary_addr`, so reads can be spread over followers.
+    ///
+    /// Writes of records fail with `StorageError::ReadOnly`, only the changes of the
+    /// primary reach them. Its metadata, counters, sequences and locks stay local and
+    /// writable. The config can't expire values.
+    pub fn follow(primary_addr: impl ToSocketAddrs, config: &StorageConfig) -> Result<Follower> {
+        let storage = Storage::open(&StorageConfig {
+            read_only: true,
+            ..config.clone()
+        })?;
+        let replica = storage.replicate_from(primary_addr)?;
+        Ok(Follower { storage, replica })
+    }
+
+    /// The offset of the next change of the primary this replica applies.
+    pub fn replication_offset(&self) -> u64 {
+        self.get_meta(REPLICATION_OFFSET).unwrap_or(0)
+    }
+
+    /// Apply the changes of the primary serving replication at `addr`, reconnecting
+    /// after disconnects until the task is stopped.
+    pub fn replicate_from(&self, addr: impl ToSocketAddrs) -> Result<ReplicaTask> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(eyre!("no address to replicate from"));
+        }
+        if self.replication_secret.is_none() {
+            return Err(eyre!("replication_secret is not set"));
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let stream = Arc::new(Mutex::new(None));
+        let (task_stop, task_stream) = (stop.clone(), stream.clone());
+        let store = self.clone();
+        let handle = std::thread::Builder::new()
+            .name("storage-replica".to_string())
+            .spawn(move || {
+                while !task_stop.load(Ordering::Relaxed) {
+                    match store.replicate(&addrs, &task_stream, &task_stop) {
+                        Ok(()) => break,
+                        Err(e) if !task_stop.load(Ordering::Relaxed) => {
+                            warn!("Replication from {:?} failed: {}", addrs, e);
+                            pause(RETRY, &task_stop);
+                        }
+                        Err(_) => {}
+                    }
+                }
+                debug!("Stopped replica {}", store.db_path);
+            })?;
+        Ok(ReplicaTask {
+            stop,
+            stream,
+            handle: Some(handle),
+        })
+    }
+
+    // follow the primary until stopped, or until it no longer has the changes needed
+    fn replicate(
+        &self,
+        addrs: &[SocketAddr],
+        shared: &Mutex<Option<TcpStream>>,
+        stop: &AtomicBool,
+    ) -> Result<()> {
+        let stream = addrs
+            .iter()
+            .find_map(|addr| TcpStream::connect_timeout(addr, TIMEOUT).ok())
+            .ok_or_else(|| eyre!("primary is not reachable"))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        *shared.lock() = Some(stream.try_clone()?);
+        if stop.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let secret = self
+            .replication_secret
+            .as_ref()
+            .ok_or_else(|| eyre!("replication_secret is not set"))?;
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        let mut challenge = [0; 32];
+        read_by(&stream, &mut challenge, deadline)?;
+        let mut offset = self.replication_offset();
+        let own_challenge = self::challenge(stream.peer_addr()?);
+        let mut hello = secret.answer(Role::Replica, &challenge).as_bytes().to_vec();
+        hello.extend_from_slice(&own_challenge);
+        hello.extend_from_slice(&offset.to_be_bytes());
+        (&stream).write_all(&hello)?;
+        // | answer (32) | first offset of the log (8, be) |
+        let mut reply = [0; 40];
+        read_by(&stream, &mut reply, deadline)?;
+        let answer: [u8; 32] = reply[..32].try_into().unwrap();
+        if secret.answer(Role::Primary, &own_challenge) != blake3::Hash::from_bytes(answer) {
+            return Err(eyre!("primary failed to prove the replication secret"));
+        }
+        let start = u64::from_be_bytes(reply[32..].try_into().unwrap());
+        let mut reader = BufReader::new(&stream);
+        if offset < start {
+            warn!(
+                "Replica {} needs change {}, the primary's log starts at {}, restore it from a backup",
+                self.db_path, offset, start
+            );
+            return Ok(());
+        }
+        debug!("Replicating from {:?} at {}", addrs, offset);
+        while !stop.load(Ordering::Relaxed) {
+            let (at, entry) = read_frame(&mut reader)?;
+            if entry.is_empty() {
+                continue;
+            }
+            if at != offset {
+                return Err(eyre!("expected change {}, got {}", offset, at));
+            }
+            self.apply_change(&bincode::deserialize(&entry)?)?;
+            offset = at + 1;
+            self.put_meta(REPLICATION_OFFSET, &offset)?;
+        }
+        Ok(())
+    }
+
+    fn apply_change(&self, change: &Change) -> Result<()> {
+        self.check_open()?;
+        let _writes = self.write_gate();
+        let tree = self.db_tree(change.tree.as_bytes())?;
+        let ckey = tree_ckey(&change.tree, &change.key);
+        match &change.value {
+            Some(value) => {
+                tree.insert(change.key.as_slice(), value.as_slice())?;
+                self.filter_insert(&ckey);
+            }
+            None => {
+                tree.remove(change.key.as_slice())?;
+            }
+        }
+        forget_usage(&self.usage, &change.tree);
+        self.invalidate_ckey(&ckey)
+    }
+}
+
+// INCREMENTAL BACKUP
+// An increment is a file of `| since_offset (8, be) |`, the change log's frames from
+// `since_offset` on, an empty frame carrying the offset after the last change and the
+// blake3 hash of all the bytes before it. Changes carry the final value of their key,
+// so an increment overlapping the full backup applies cleanly. The whole file is read
+// and checked before any change is applied, a file cut short or damaged is refused
+// untouched. The storage remembers the offset its last increment ended at and refuses
+// an increment starting elsewhere, so none can be skipped or applied twice. The first
+// one may start before the end of the change log restored with the full backup.
+impl Storage {
+    /// Export the changes from `since_offset` on to a new file at `path`, returning the
+    /// offset the next increment starts at.
+    ///
+    /// Take a full backup with `backup` after noting `change_log().next_offset()`, then
+    /// increments from that offset on. Needs `change_log` and fails once the log was
+    /// truncated past `since_offset`.
+    pub fn backup_incremental(&self, since_offset: u64, path: impl AsRef<Path>) -> Result<u64> {
+        self.check_open()?;
+        if !self.change_log {
+            return Err(eyre!("change_log is not enabled"));
+        }
+        let log = self.change_log()?;
+        let start = log.start_offset()?;
+        if since_offset < start {
+            return Err(eyre!(
+                "change {} was truncated, the log starts at {}",
+                since_offset,
+                start
+            ));
+        }
+        let (path, until) = (path.as_ref(), log.next_offset()?);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        let mut writer = Hashing::new(BufWriter::new(file));
+        writer.write_all(&since_offset.to_be_bytes())?;
+        let mut offset = since_offset;
+        'copy: while offset < until {
+            let changes = log.read_from(offset, BATCH)?;
+            if changes.is_empty() {
+                break;
+            }
+            for (at, change) in changes {
+                // appended after the export started, left to the next increment
+                if at >= until {
+                    break 'copy;
+                }
+                write_frame(&mut writer, at, &change)?;
+                offset = at + 1;
+            }
+        }
+        let offset = offset.max(until);
+        write_frame(&mut writer, offset, &[])?;
+        let Hashing { mut inner, hasher } = writer;
+        inner.write_all(hasher.finalize().as_bytes())?;
+        inner.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        info!(
+            "Backed up changes {}..{} to {}",
+            since_offset,
+            offset,
+            path.display()
+        );
+        Ok(offset)
+    }
+
+    /// Apply the increment at `path` written by `backup_incremental`, typically to a
+    /// storage opened on a restored full backup. Returns the offset after its changes.
+    ///
+    /// The changes are held in memory until the whole file is checked. Fails without
+    /// applying any of them when the file is incomplete or damaged, when the previous
+    /// increment restored into this storage did not end where this one starts, or when
+    /// the first one starts after the end of the full backup.
+    pub fn restore_incremental(&self, path: impl AsRef<Path>) -> Result<u64> {
+        self.check_open()?;
+        let path = path.as_ref();
+        let incomplete = |e| eyre!("increment {} is incomplete: {}", path.display(), e);
+        let mut reader = Hashing::new(BufReader::new(std::fs::File::open(path)?));
+        let since_offset = read_u64(&mut reader).map_err(|e| incomplete(e.into()))?;
+        let mut changes = vec![];
+        let until = loop {
+            let (at, entry) = read_frame(&mut reader).map_err(incomplete)?;
+            if entry.is_empty() {
+                break at;
+            }
+            changes.push(bincode::deserialize::<Change>(&entry)?);
+        };
+        let Hashing { mut inner, hasher } = reader;
+        let mut checksum = [0; 32];
+        inner
+            .read_exact(&mut checksum)
+            .map_err(|e| incomplete(e.into()))?;
+        if hasher.finalize() != blake3::Hash::from_bytes(checksum) {
+            return Err(eyre!("increment {} is damaged", path.display()));
+        }
+        match self.get_meta::<u64>(INCREMENT_OFFSET) {
+            Some(offset) if offset != since_offset => {
+                return Err(eyre!(
+                    "increment {} starts at change {}, the last one restored ended at {}",
+                    path.display(),
+                    since_offset,
+                    offset
+                ));
+            }
+            Some(_) => {}
+            // the first one, the full backup holds the change log as it was when taken
+            None => {
+                let offset = self.change_log()?.next_offset()?;
+                if since_offset > offset {
+                    return Err(eyre!(
+                        "increment {} starts at change {}, the backup ends at {}",
+                        path.display(),
+                        since_offset,
+                        offset
+                    ));
+                }
+            }
+        }
+        for change in &changes {
+            self.apply_change(change)?;
+        }
+        self.put_meta(INCREMENT_OFFSET, &until)?;
+        info!(
+            "Restored changes {}..{} from {}",
+            since_offset,
+            until,
+            path.display()
+        );
+        Ok(until)
+    }
+}
+
+#[test]
+fn replication() {
+    let primary = Storage::builder()
+        .temporary()
+        .change_log(true)
+        .replication_secret("secret")
+        .open()
+        .unwrap();
+    let server = primary.serve_replication("127.0.0.1:0").unwrap();
+    let replica = Storage::builder()
+        .temporary()
+        .replication_secret("secret")
+        .open()
+        .unwrap();
+    let wait_for = |key: &str, value: Option<&str>| {
+        let until = Instant::now() + Duration::from_secs(10);
+        while replica.get::<String>(key).as_deref() != value {
+            assert!(Instant::now() < until, "{} never became {:?}", key, value);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    primary.insert("a", "1".to_string());
+    primary.insert("b", "2".to_string());
+    primary.namespace("tenant").insert("a", "3".to_string());
+    primary.get_or_insert_with("e", || "7".to_string());
+    let task = replica.replicate_from(server.local_addr()).unwrap();
+    wait_for("e", Some("7"));
+    wait_for("b", Some("2"));
+    // cached before the change arrives
+    assert_eq!(Some("1".to_string()), replica.get::<String>("a"));
+    primary.insert("a", "4".to_string());
+    primary.remove::<String>("b");
+    wait_for("a", Some("4"));
+    wait_for("b", None);
+    assert_eq!(
+        Some("3".to_string()),
+        replica.namespace("tenant").get::<String>("a")
+    );
+
+    // resumes where it stopped
+    task.stop();
+    let offset = replica.replication_offset();
+    assert_eq!(primary.change_log().unwrap().next_offset().unwrap(), offset);
+    primary.insert("c", "5".to_string());
+    let task = replica.replicate_from(server.local_addr()).unwrap();
+    wait_for("c", Some("5"));
+    assert_eq!(offset + 1, replica.replication_offset());
+    drop(task);
+
+    // replicas need the secret of the primary
+    let stranger = Storage::builder().temporary().open().unwrap();
+    assert!(stranger.replicate_from(server.local_addr()).is_err());
+    let stranger = Storage::builder()
+        .temporary()
+        .replication_secret("guess")
+        .open()
+        .unwrap();
+    let task = stranger.replicate_from(server.local_addr()).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(None, stranger.get::<String>("a"));
+    drop(task);
+
+    // a replica behind the truncated log doesn't apply later changes
+    primary.change_log().unwrap().truncate_before(100).unwrap();
+    primary.insert("d", "6".to_string());
+    let other = Storage::builder()
+        .temporary()
+        .replication_secret("secret")
+        .open()
+        .unwrap();
+    let task = other.replicate_from(server.local_addr()).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(None, other.get::<String>("d"));
+    drop(task);
+
+    // frames over the limit are refused before they are read
+    let mut frame = 0u64.to_be_bytes().to_vec();
+    frame.extend_from_slice(&u32::MAX.to_be_bytes());
+    assert!(read_frame(&mut frame.as_slice()).is_err());
+}
+
+#[test]
+fn replication_handshake() {
+    let primary = Storage::builder()
+        .temporary()
+        .change_log(true)
+        .replication_secret("secret")
+        .open()
+        .unwrap();
+    let server = primary.serve_replication("127.0.0.1:0").unwrap();
+    primary.insert("a", "1".to_string());
+
+    // connections beyond the handshakes in progress are dropped, the others time out
+    let idle: Vec<_> = (0..MAX_HANDSHAKES)
+        .map(|_| {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            let mut challenge = [0; 32];
+            stream.read_exact(&mut challenge).unwrap();
+            stream
+        })
+        .collect();
+    let mut dropped = TcpStream::connect(server.local_addr()).unwrap();
+    dropped
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    assert_eq!(0, dropped.read(&mut [0; 32]).unwrap_or(0));
+    for mut stream in idle {
+        assert_eq!(0, stream.read(&mut [0; 32]).unwrap_or(0));
+    }
+    let replica = Storage::builder()
+        .temporary()
+        .replication_secret("secret")
+        .open()
+        .unwrap();
+    let task = replica.replicate_from(server.local_addr()).unwrap();
+    let until = Instant::now() + Duration::from_secs(10);
+    while replica.get::<String>("a").is_none() {
+        assert!(Instant::now() < until, "never replicated");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    drop(task);
+
+    // a primary that doesn't know the secret is not followed
+    let fake = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = fake.local_addr().unwrap();
+    let impostor = std::thread::spawn(move || {
+        let (mut stream, _) = fake.accept().unwrap();
+        stream.write_all(&[0; 32]).unwrap();
+        let mut hello = [0; 72];
+        stream.read_exact(&mut hello).unwrap();
+        let change = Change {
+            tree: <String as crate::StorageData>::name(),
+            key: b"b".to_vec(),
+            value: Some(bincode::serialize("forged").unwrap()),
+        };
+        let mut reply = vec![0; 40];
+        write_frame(&mut reply, 0, &bincode::serialize(&change).unwrap()).unwrap();
+        let _ = stream.write_all(&reply);
+    });
+    let task = replica.replicate_from(addr).unwrap();
+    impostor.join().unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(None, replica.get::<String>("b"));
+    drop(task);
+}
+
+#[test]
+fn follow() {
+    use crate::StorageError;
+
+    let primary = Storage::builder()
+        .temporary()
+        .change_log(true)
+        .replication_secret("secret")
+        .open()
+        .unwrap();
+    let server = primary.serve_replication("127.0.0.1:0").unwrap();
+    primary.insert("a", "1".to_string());
+    let follower = Storage::follow(
+        server.local_addr(),
+        &StorageConfig {
+            temporary: true,
+            replication_secret: Some(ReplicationSecret::new("secret")),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let until = Instant::now() + Duration::from_secs(10);
+    while follower.get::<String>("a").is_none() {
+        assert!(Instant::now() < until);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let err = follower.insert_checked("b", "2".to_string()).unwrap_err();
+    assert_eq!(
+        Some(&StorageError::ReadOnly),
+        err.downcast_ref::<StorageError>()
+    );
+    assert_eq!(None, follower.remove::<String>("a"));
+    assert!(follower.put_cas(b"proof").is_err());
+    let storage = follower.stop();
+    assert_eq!(Some("1".to_string()), storage.get::<String>("a"));
+
+    let expiring = StorageConfig {
+        temporary: true,
+        cache_time_to_live: Some(60),
+        ..Default::default()
+    };
+    assert!(Storage::follow(server.local_addr(), &expiring).is_err());
+}
+
+#[test]
+fn incremental_backup() {
+    let dir = crate::TestDir::new("incremental_backup");
+    let store = Storage::builder()
+        .temporary()
+        .change_log(true)
+        .open()
+        .unwrap();
+    store.insert("a", "1".to_string());
+    store.insert("b", "2".to_string());
+    let since = store.change_log().unwrap().next_offset().unwrap();
+    store.backup(dir.path("full")).unwrap();
+    store.insert("a", "3".to_string());
+    store.remove::<String>("b");
+    store.namespace("tenant").insert("c", "4".to_string());
+    let next = store.backup_incremental(since, dir.path("1")).unwrap();
+    assert_eq!(store.change_log().unwrap().next_offset().unwrap(), next);
+    assert!(store.backup_incremental(since, dir.path("1")).is_err());
+
+    Storage::restore(dir.path("full"), dir.path("target")).unwrap();
+    let target = Storage::builder().path(dir.path("target")).open().unwrap();
+    assert_eq!(Some("2".to_string()), target.get::<String>("b"));
+    assert_eq!(next, target.restore_incremental(dir.path("1")).unwrap());
+    assert_eq!(Some("3".to_string()), target.get::<String>("a"));
+    assert_eq!(None, target.get::<String>("b"));
+    assert_eq!(
+        Some("4".to_string()),
+        target.namespace("tenant").get::<String>("c")
+    );
+    // applying it twice is refused, as is skipping one
+    assert!(target.restore_incremental(dir.path("1")).is_err());
+    store.insert("d", "5".to_string());
+    let last = store.backup_incremental(next, dir.path("2")).unwrap();
+    store.insert("d", "6".to_string());
+    store.backup_incremental(last, dir.path("3")).unwrap();
+    assert!(target.restore_incremental(dir.path("3")).is_err());
+    assert_eq!(None, target.get::<String>("d"));
+    target.restore_incremental(dir.path("2")).unwrap();
+    target.restore_incremental(dir.path("3")).unwrap();
+    assert_eq!(Some("6".to_string()), target.get::<String>("d"));
+
+    // an increment missing its end or damaged is refused before anything is written
+    let bytes = std::fs::read(dir.path("1")).unwrap();
+    std::fs::write(dir.path("cut"), &bytes[..bytes.len() - 44]).unwrap();
+    let empty = Storage::builder().temporary().open().unwrap();
+    assert!(empty.restore_incremental(dir.path("cut")).is_err());
+    let mut damaged = bytes.clone();
+    damaged[20] ^= 1;
+    std::fs::write(dir.path("cut"), &damaged).unwrap();
+    assert!(empty.restore_incremental(dir.path("cut")).is_err());
+    assert!(empty.data_tree_names().is_empty());
+    assert_eq!(None, empty.get_meta::<u64>(INCREMENT_OFFSET));
+
+    store.change_log().unwrap().truncate_before(next).unwrap();
+    assert!(store.backup_incremental(since, dir.path("4")).is_err());
+    let plain = Storage::builder().temporary().open().unwrap();
+    assert!(plain.backup_incremental(0, dir.path("4")).is_err());
+}
+
+#[test]
+fn change_log_retention() {
+    use std::time::SystemTime;
+
+    use crate::series::series_tree_name;
+    use crate::{Retention, StorageData};
+
+    let store = Storage::builder()
+        .temporary()
+        .change_log(true)
+        .open()
+        .unwrap();
+    let series = store.time_series::<String>().unwrap();
+    let now = SystemTime::now();
+    series
+        .append("cpu", now - Duration::from_secs(2), "2".to_string())
+        .unwrap();
+    series
+        .append("cpu", now - Duration::from_secs(1), "1".to_string())
+        .unwrap();
+    series
+        .set_retention(
+            "cpu",
+            Retention {
+                max_points: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let since = store.change_log().unwrap().next_offset().unwrap();
+    assert_eq!(1, store.apply_retention().unwrap());
+
+    let changes: Vec<Change> = store
+        .change_log()
+        .unwrap()
+        .read_from(since, 10)
+        .unwrap()
+        .into_iter()
+        .map(|(_, entry)| bincode::deserialize(&entry).unwrap())
+        .collect();
+    assert_eq!(1, changes.len());
+    assert_eq!(series_tree_name(&String::name()), changes[0].tree);
+    assert_eq!(None, changes[0].value);
+}
@@ -0,0 +1,210 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{prune_audit_tree, AUDIT_TREE_NAME};
+use crate::namespace::base_tree_name;
+use crate::series::{is_series_tree, point_key, series_tree_name, TimeSeries};
+use crate::{tree_names, Key, Storage, StorageData, Tree};
+
+// `| RETENTION | series |` -> the bincode `Retention` of the series, invalid UTF-8 so it
+// is never a point key
+const RETENTION: u8 = 0xff;
+
+/// How many points a time series keeps, see `TimeSeries::set_retention`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    /// Drop points with timestamps older than this.
+    pub max_age: Option<Duration>,
+    /// Drop the oldest points beyond this many.
+    pub max_points: Option<u64>,
+}
+
+fn retention_key(series: &str) -> Vec<u8> {
+    let mut retention_key = Vec::with_capacity(1 + series.len());
+    retention_key.push(RETENTION);
+    retention_key.extend_from_slice(series.as_bytes());
+    retention_key
+}
+
+// drop the points of `series` in the db tree `name` that `retention` doesn't keep
+fn prune_series(
+    storage: &Storage,
+    tree: &Tree,
+    name: &str,
+    series: &str,
+    retention: &Retention,
+    now: SystemTime,
+) -> Result<usize> {
+    let prefix = Key::new().part(series);
+    let mut expired = vec![];
+    if let Some(max_age) = retention.max_age {
+        let cutoff = now.checked_sub(max_age).unwrap_or(UNIX_EPOCH);
+        for key in tree
+            .range(prefix.as_str()..point_key(series, cutoff).as_str())
+            .keys()
+        {
+            expired.push(key?);
+        }
+    }
+    if let Some(max_points) = retention.max_points {
+        let kept: Vec<_> = tree
+            .scan_prefix(prefix.as_str())
+            .keys()
+            .skip(expired.len())
+            .collect::<std::io::Result<_>>()?;
+        let excess = kept.len().saturating_sub(max_points as usize);
+        expired.extend(kept.into_iter().take(excess));
+    }
+    let _writes = storage.write_gate();
+    for key in &expired {
+        tree.remove(key)?;
+        storage.log_db_change(name, key);
+    }
+    Ok(expired.len())
+}
+
+// RETENTION
+// The retention of a series is stored next to its points, and enforced by
+// `apply_retention`, which the maintenance task runs on every interval, across all
+// namespaces. `max_points` counts the points of the series on every run, meant for
+// series of up to a few million points. `StorageConfig::audit_retention` bounds the
+// audit logs the same way. Points appended between runs stay until the next one. The
+// dropped points are recorded in the change log, read-only followers leave pruning
+// to their primary.
+impl<T: StorageData> TimeSeries<T> {
+    /// Keep the points of `series` by `retention` from now on, replacing its previous
+    /// retention.
+    pub fn set_retention(&self, series: &str, retention: Retention) -> Result<()> {
+        self.storage.check_writable()?;
+        let bytes = bincode::serialize(&retention)?;
+        let _writes = self.storage.write_gate();
+        self.tree.insert(retention_key(series), bytes.as_slice())?;
+        self.storage.audit(
+            "set_retention",
+            &series_tree_name(&T::name()),
+            &retention_key(series),
+            Some(&bytes),
+        );
+        Ok(())
+    }
+
+    pub fn retention(&self, series: &str) -> Result<Option<Retention>> {
+        match self.tree.get(retention_key(series))? {
+            Some(v) => Ok(Some(bincode::deserialize(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Drop the points of `series` its retention doesn't keep. Returns how many were
+    /// dropped.
+    pub fn prune(&self, series: &str) -> Result<usize> {
+        self.storage.check_writable()?;
+        let Some(retention) = self.retention(series)? else {
+            return Ok(0);
+        };
+        prune_series(
+            &self.storage,
+            &self.tree,
+            &self.storage.tree_name(&series_tree_name(&T::name())),
+            series,
+            &retention,
+            SystemTime::now(),
+        )
+    }
+}
+
+impl Storage {
+    /// Drop the points of all time series and the audit entries their retention doesn't
+    /// keep, in all namespaces. Returns how many were dropped.
+    pub fn apply_retention(&self) -> Result<usize> {
+        self.check_open()?;
+        let now = SystemTime::now();
+        let mut pruned = 0;
+        for name in tree_names(&self.db) {
+            let tree = self.db_tree(name.as_bytes())?;
+            // followers drop the points their primary drops
+            if is_series_tree(&name) && !self.read_only {
+                for r in tree.scan_prefix([RETENTION]) {
+                    let (k, v) = r?;
+                    let series = String::from_utf8_lossy(&k[1..]);
+                    let retention: Retention = bincode::deserialize(&v)?;
+                    pruned += prune_series(self, &tree, &name, &series, &retention, now)?;
+                }
+            } else if base_tree_name(&name) == AUDIT_TREE_NAME {
+                if let Some(secs) = self.audit_retention {
+                    let before = now.checked_sub(Duration::from_secs(secs));
+                    pruned += prune_audit_tree(&tree, before.unwrap_or(UNIX_EPOCH))?;
+                }
+            }
+        }
+        Ok(pruned)
+    }
+}
+
+#[test]
+fn retention() {
+    use crate::StorageConfig;
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        audit: true,
+        audit_retention: Some(3600),
+        ..Default::default()
+    });
+    let series = store.time_series::<String>().unwrap();
+    let now = SystemTime::now();
+    let ago = |secs| now - Duration::from_secs(secs);
+    for secs in [1, 2, 3, 100, 200] {
+        series.append("cpu", ago(secs), secs.to_string()).unwrap();
+        series
+            .append("memory", ago(secs), secs.to_string())
+            .unwrap();
+    }
+    series
+        .set_retention(
+            "cpu",
+            Retention {
+                max_age: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    let tenant = store.namespace("tenant").time_series::<String>().unwrap();
+    tenant
+        .set_retention(
+            "memory",
+            Retention {
+                max_points: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    tenant.append("memory", ago(1), "1".to_string()).unwrap();
+    tenant.append("memory", ago(2), "2".to_string()).unwrap();
+    // an audit entry from before the retention
+    let audit = store.tree(AUDIT_TREE_NAME).unwrap();
+    audit
+        .insert(
+            crate::audit::micros(ago(7200)).to_be_bytes(),
+            b"".as_slice(),
+        )
+        .unwrap();
+
+    assert_eq!(4, store.apply_retention().unwrap());
+    let values = |series: &TimeSeries<String>, name| {
+        series
+            .range(name, ago(1000)..now)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.value)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(vec!["3", "2", "1"], values(&series, "cpu"));
+    // without retention
+    assert_eq!(5, values(&series, "memory").len());
+    assert_eq!(vec!["1"], values(&tenant, "memory"));
+    assert_eq!(0, series.prune("cpu").unwrap());
+}
@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use color_eyre::eyre::Result;
+use sled::CompareAndSwapError;
+
+use crate::{envelope, CasError, Storage, StorageData, StorageKey};
+
+// the last record version issued by a storage and its clones
+#[derive(Debug, Default)]
+pub(crate) struct RevisionClock(AtomicU64);
+
+impl RevisionClock {
+    // microseconds since the epoch, moved past the last version when the clock lags
+    fn next(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        let mut last = self.0.load(Ordering::Relaxed);
+        loop {
+            let next = now.max(last + 1);
+            match self
+                .0
+                .compare_exchange_weak(last, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return next,
+                Err(current) => last = current,
+            }
+        }
+    }
+}
+
+// RECORD VERSIONS
+// Every encoded value carries a record version in its envelope, taken from a clock of
+// microseconds, so the versions of a key grow across restarts as long as the system
+// clock doesn't go back further than the time between them. Values written before
+// record versions existed have version 0. A version changes whenever the value is
+// rewritten, including by migrations and codec changes.
+impl Storage {
+    pub(crate) fn next_revision(&self) -> u64 {
+        self.revisions.next()
+    }
+
+    /// The value of `key` with its record version, to pass to `insert_if_version`.
+    pub fn get_versioned<T: StorageData>(
+        &self,
+        key: impl Into<StorageKey>,
+    ) -> Result<Option<(T, u64)>> {
+        let key = key.into();
+        let ckey = self.ckey::<T>(&key);
+        let stored = match self.cache_get(&ckey) {
+            Some(v) => Some(v),
+            None => self
+                .tree(T::name())?
+                .get(&key)?
+                .map(|v| Bytes::copy_from_slice(&v)),
+        };
+        // not rewriting an old layout, that would change the version
+        Ok(stored.and_then(|v| {
+            let value = self.decode_with(&key, &v, false)?;
+            Some((value, envelope::revision(&v)))
+        }))
+    }
+
+    /// Insert `value` if the record version of `key` is `expected`, `None` meaning
+    /// absent. Returns the new version, on conflict the error carries the current value.
+    pub fn insert_if_version<T: StorageData>(
+        &self,
+        key: impl Into<StorageKey>,
+        value: T,
+        expected: Option<u64>,
+    ) -> std::result::Result<u64, CasError<T>> {
+        let key = key.into();
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        self.check_swappable()?;
+        let tree = self.tree(T::name())?;
+        let ckey = self.ckey::<T>(&key);
+        let _writes = self.write_gate();
+        if let Some(write_back) = self.write_back() {
+            write_back
+                .persist_key(&tree, &ckey, &key)
+                .map_err(invalid)?;
+        }
+        let current = tree.get(&key)?;
+        let conflict = |current: Option<&[u8]>, proposed| CasError::Conflict {
+            current: current.and_then(|v| self.decode_with(&key, v, false)),
+            proposed: Some(proposed),
+        };
+        if current.as_deref().map(envelope::revision) != expected {
+            return Err(conflict(current.as_deref(), value));
+        }
+        let new = self
+            .encode(&key, &value, current.as_deref())
+            .map_err(invalid)?;
+        let quota = |e| std::io::Error::new(std::io::ErrorKind::QuotaExceeded, e);
+        self.reserve::<T>(&key, current.as_deref(), &new)
+            .map_err(quota)?;
+        self.stamp_version::<T>();
+        match tree.compare_and_swap(&key, current.as_deref(), Some(new.as_ref()))? {
+            Ok(_) => {
+                let refs = value.content_refs();
+                self.replaced::<T>(&key, current.as_deref(), &refs);
+                self.audit("insert_if_version", &T::name(), &key, Some(&new));
+                let revision = envelope::revision(&new);
+                self.cache_put(ckey, new);
+                Ok(revision)
+            }
+            Err(CompareAndSwapError { current, .. }) => {
+                self.recount(&T::name());
+                Err(conflict(current.as_deref(), value))
+            }
+        }
+    }
+}
+
+#[test]
+fn insert_if_version() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let first = store
+        .insert_if_version("order", "placed".to_string(), None)
+        .unwrap();
+    assert!(store
+        .insert_if_version("order", "again".to_string(), None)
+        .is_err());
+
+    let (value, version) = store.get_versioned::<String>("order").unwrap().unwrap();
+    assert_eq!(("placed".to_string(), first), (value, version));
+    let second = store
+        .insert_if_version("order", "paid".to_string(), Some(version))
+        .unwrap();
+    assert!(second > first);
+
+    // a stale version conflicts
+    match store.insert_if_version("order", "cancelled".to_string(), Some(first)) {
+        Err(CasError::Conflict { current, .. }) => assert_eq!(Some("paid".to_string()), current),
+        r => panic!("expected a conflict, got {:?}", r),
+    }
+    store.insert("order", "shipped".to_string());
+    let (_, third) = store.get_versioned::<String>("order").unwrap().unwrap();
+    assert!(third > second);
+    assert_eq!(None, store.get_versioned::<String>("missing").unwrap());
+}
@@ -0,0 +1,237 @@
+use std::ops::Range;
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{Storage, StorageError};
+
+pub(crate) const SEQUENCE_TREE_NAME: &str = "SEQUENCE";
+
+/// What `next` does once a sequence reaches `u64::MAX`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SequenceOverflow {
+    /// Stop issuing ids, `try_next` reports `StorageError::SequenceOverflow`.
+    #[default]
+    Error,
+    /// Start over at 1.
+    Wrap,
+}
+
+/// Where a sequence used with `next_with` starts and how far each id moves it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeqOptions {
+    pub start: u64,
+    pub step: u64,
+}
+
+impl Default for SeqOptions {
+    fn default() -> Self {
+        Self { start: 1, step: 1 }
+    }
+}
+
+// sequences were stored as 4 byte u32 before they were widened to u64
+fn decode_sequence(v: &[u8]) -> Option<u64> {
+    match v.len() {
+        4 => Some(u32::from_be_bytes(v.try_into().ok()?) as u64),
+        8 => Some(u64::from_be_bytes(v.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+// SEQUENCE
+impl Storage {
+    /// The next id of the sequence, 0 if it could not be issued.
+    pub fn next(&self, name: &str) -> u64 {
+        self.try_next(name).unwrap_or(0)
+    }
+
+    pub fn try_next(&self, name: &str) -> Result<u64> {
+        Ok(self.try_next_batch(name, 1)?.start)
+    }
+
+    /// Reserve `n` consecutive ids with a single write, empty if they could not be issued.
+    pub fn next_batch(&self, name: &str, n: u64) -> Range<u64> {
+        self.try_next_batch(name, n).unwrap_or(0..0)
+    }
+
+    pub fn try_next_batch(&self, name: &str, n: u64) -> Result<Range<u64>> {
+        if n == 0 {
+            let start = self.current(name).saturating_add(1);
+            return Ok(start..start);
+        }
+        let last = self.advance(name, |current| current.unwrap_or(0).checked_add(n), n)?;
+        Ok(last - n + 1..last + 1)
+    }
+
+    /// The next id of a sequence that starts at `options.start` and moves by `options.step`,
+    /// 0 if it could not be issued.
+    pub fn next_with(&self, name: &str, options: SeqOptions) -> u64 {
+        self.try_next_with(name, options).unwrap_or(0)
+    }
+
+    pub fn try_next_with(&self, name: &str, options: SeqOptions) -> Result<u64> {
+        self.advance(
+            name,
+            |current| match current {
+                Some(current) => current.checked_add(options.step),
+                None => Some(options.start),
+            },
+            options.start,
+        )
+    }
+
+    // atomically move the sequence to `next(current)`, a `None` from `next` is an
+    // overflow, resolved to `wrapped` or an error depending on the policy
+    fn advance(
+        &self,
+        name: &str,
+        next: impl Fn(Option<u64>) -> Option<u64>,
+        wrapped: u64,
+    ) -> Result<u64> {
+        self.check_writable()?;
+        let tree = self.tree(SEQUENCE_TREE_NAME)?;
+        // a backup racing the write copies the new value
+        let _writes = self.write_gate();
+        let mut overflowed = false;
+        let mut value = 0;
+        // the closure reruns on contention, so only its final run counts
+        tree.update_and_fetch(name, |v| {
+            let current = v.and_then(decode_sequence);
+            overflowed = false;
+            value = match next(current) {
+                Some(value) => value,
+                None if self.sequence_overflow == SequenceOverflow::Wrap => wrapped,
+                None => {
+                    overflowed = true;
+                    current.unwrap_or(0)
+                }
+            };
+            Some(value.to_be_bytes().to_vec())
+        })?;
+        if overflowed {
+            return Err(StorageError::SequenceOverflow(name.to_string()).into());
+        }
+        Ok(value)
+    }
+
+    pub fn current(&self, name: &str) -> u64 {
+        let tree = self.tree(SEQUENCE_TREE_NAME).unwrap();
+        if let Ok(Some(v)) = tree.get(name) {
+            if let Some(v) = decode_sequence(&v) {
+                return v;
+            }
+        }
+        0
+    }
+
+    /// Set the current value of the sequence, the next id issued is `value + 1`.
+    pub fn set_sequence(&self, name: &str, value: u64) -> Result<()> {
+        self.check_writable()?;
+        let tree = self.tree(SEQUENCE_TREE_NAME)?;
+        let _writes = self.write_gate();
+        tree.insert(name, value.to_be_bytes().to_vec())?;
+        Ok(())
+    }
+
+    /// Restart the sequence, the next id issued is 1.
+    pub fn reset_sequence(&self, name: &str) -> Result<()> {
+        self.set_sequence(name, 0)
+    }
+
+    pub fn delete_sequence(&self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let tree = self.tree(SEQUENCE_TREE_NAME)?;
+        let _writes = self.write_gate();
+        tree.remove(name)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn sequence_upgrade_and_overflow() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let tree = store.db.open_tree(SEQUENCE_TREE_NAME).unwrap();
+    tree.insert("legacy", u32::MAX.to_be_bytes().to_vec())
+        .unwrap();
+    assert_eq!(u32::MAX as u64, store.current("legacy"));
+    assert_eq!(u32::MAX as u64 + 1, store.next("legacy"));
+    let batch = store.next_batch("legacy", 10);
+    assert_eq!(u32::MAX as u64 + 2..u32::MAX as u64 + 12, batch);
+    assert_eq!(batch.end - 1, store.current("legacy"));
+
+    tree.insert("full", u64::MAX.to_be_bytes().to_vec())
+        .unwrap();
+    let err = store.try_next("full").unwrap_err();
+    assert_eq!(
+        Some(&StorageError::SequenceOverflow("full".to_string())),
+        err.downcast_ref::<StorageError>()
+    );
+    assert_eq!(0, store.next("full"));
+
+    let store = Storage {
+        sequence_overflow: SequenceOverflow::Wrap,
+        ..store
+    };
+    assert_eq!(1, store.next("full"));
+}
+
+#[test]
+fn sequence_concurrency() {
+    use std::collections::HashSet;
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || (0..200).map(|_| store.next("test")).collect::<Vec<_>>())
+        })
+        .collect();
+    let ids: HashSet<u64> = handles
+        .into_iter()
+        .flat_map(|h| h.join().unwrap())
+        .collect();
+    assert_eq!(1600, ids.len());
+    assert!(!ids.contains(&0));
+    assert_eq!(1600, store.current("test"));
+}
+
+#[test]
+fn sequence_admin() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.set_sequence("test", 41).unwrap();
+    assert_eq!(42, store.next("test"));
+    store.reset_sequence("test").unwrap();
+    assert_eq!(1, store.next("test"));
+    store.delete_sequence("test").unwrap();
+    assert!(!store
+        .db
+        .open_tree(SEQUENCE_TREE_NAME)
+        .unwrap()
+        .contains_key("test")
+        .unwrap());
+
+    // a follower issues no ids from a SEQUENCE tree it doesn't get from its primary
+    let follower: Storage = Storage::builder()
+        .temporary()
+        .read_only(true)
+        .open()
+        .unwrap();
+    assert!(follower.try_next("test").is_err());
+    assert!(follower.set_sequence("test", 41).is_err());
+    assert!(follower.delete_sequence("test").is_err());
+}
+
+#[test]
+fn sequence_options() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let even = SeqOptions { start: 0, step: 2 };
+    let odd = SeqOptions {
+        start: 1001,
+        step: 2,
+    };
+    assert_eq!(0, store.next_with("even", even));
+    assert_eq!(2, store.next_with("even", even));
+    assert_eq!(1001, store.next_with("odd", odd));
+    assert_eq!(1003, store.next_with("odd", odd));
+}
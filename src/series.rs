@@ -0,0 +1,152 @@
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::time::SystemTime;
+
+use color_eyre::eyre::Result;
+
+use crate::{parse_key_timestamp, Key, Storage, StorageData, Tree};
+
+const SERIES_TREE_SUFFIX: &str = "__series";
+
+// the tree holding the time series of `name`
+pub(crate) fn series_tree_name(name: &str) -> String {
+    format!("{}{}", name, SERIES_TREE_SUFFIX)
+}
+
+pub(crate) fn is_series_tree(name: &str) -> bool {
+    name.ends_with(SERIES_TREE_SUFFIX)
+}
+
+pub(crate) fn point_key(series: &str, timestamp: SystemTime) -> Key {
+    Key::new().part(series).part_timestamp(timestamp)
+}
+
+// the timestamp of a key made by `point_key`
+fn timestamp_of(key: &[u8]) -> Option<SystemTime> {
+    let parts = Key::parts(std::str::from_utf8(key).ok()?)?;
+    parse_key_timestamp(parts.get(1)?)
+}
+
+/// A value of a time series at its timestamp.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point<T> {
+    pub timestamp: SystemTime,
+    pub value: T,
+}
+
+/// Typed handle on the time series of `T`, see `Storage::time_series`.
+#[derive(Debug, Clone)]
+pub struct TimeSeries<T: StorageData> {
+    pub(crate) storage: Storage,
+    pub(crate) tree: Tree,
+    _marker: PhantomData<fn() -> T>,
+}
+
+// TIME SERIES
+// The points of all series of `T` live in `<name>__series`, keyed by the series name
+// and the timestamp in nanoseconds as composite `Key` parts, so a range read is one
+// scan in time order. Values are stored like records, with codec, compression and
+// encryption, but bypass the cache, quotas and history. Timestamps before the epoch
+// are clamped to it, appending at a timestamp already in the series replaces its
+// value.
+impl Storage {
+    pub fn time_series<T: StorageData>(&self) -> Result<TimeSeries<T>> {
+        Ok(TimeSeries {
+            storage: self.clone(),
+            tree: self.tree(series_tree_name(&T::name()))?,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: StorageData> TimeSeries<T> {
+    pub fn append(&self, series: &str, timestamp: SystemTime, value: T) -> Result<()> {
+        self.storage.check_writable()?;
+        let key = point_key(series, timestamp);
+        let bytes = self.storage.encode(key.as_str().as_bytes(), &value, None)?;
+        let _writes = self.storage.write_gate();
+        self.tree.insert(key.as_str(), bytes.as_ref())?;
+        self.storage.audit(
+            "append",
+            &series_tree_name(&T::name()),
+            key.as_str().as_bytes(),
+            Some(&bytes),
+        );
+        Ok(())
+    }
+
+    /// The points of `series` with timestamps in `range`, oldest first. Points that
+    /// fail to decode are skipped.
+    pub fn range(&self, series: &str, range: Range<SystemTime>) -> Result<Vec<Point<T>>> {
+        self.storage.check_open()?;
+        let (start, end) = (point_key(series, range.start), point_key(series, range.end));
+        let mut points = vec![];
+        for r in self.tree.range(start.as_str()..end.as_str()) {
+            let (k, v) = r?;
+            let (Some(timestamp), Some(value)) =
+                (timestamp_of(&k), self.storage.decode_with(&k, &v, false))
+            else {
+                continue;
+            };
+            points.push(Point { timestamp, value });
+        }
+        Ok(points)
+    }
+
+    /// The newest point of `series`.
+    pub fn last(&self, series: &str) -> Result<Option<Point<T>>> {
+        self.storage.check_open()?;
+        let prefix = Key::new().part(series);
+        let Some((k, v)) = self
+            .tree
+            .scan_prefix(prefix.as_str())
+            .next_back()
+            .transpose()?
+        else {
+            return Ok(None);
+        };
+        Ok(timestamp_of(&k)
+            .zip(self.storage.decode_with(&k, &v, false))
+            .map(|(timestamp, value)| Point { timestamp, value }))
+    }
+}
+
+#[test]
+fn time_series() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let series = store.time_series::<String>().unwrap();
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    // appended out of order, read back in time order
+    for secs in [30, 10, 20, 100] {
+        series.append("cpu", at(secs), secs.to_string()).unwrap();
+    }
+    // a series whose name is a prefix of another
+    series.append("cp", at(15), "other".to_string()).unwrap();
+    assert_eq!(
+        vec![
+            Point {
+                timestamp: at(10),
+                value: "10".to_string()
+            },
+            Point {
+                timestamp: at(20),
+                value: "20".to_string()
+            },
+            Point {
+                timestamp: at(30),
+                value: "30".to_string()
+            },
+        ],
+        series.range("cpu", at(0)..at(100)).unwrap()
+    );
+    assert_eq!(1, series.range("cp", at(0)..at(200)).unwrap().len());
+    assert_eq!(
+        Some(at(100)),
+        series.last("cpu").unwrap().map(|p| p.timestamp)
+    );
+    assert_eq!(None, series.last("memory").unwrap());
+    // not a record of the type
+    assert!(!store.contains_key::<String>("cpu"));
+}
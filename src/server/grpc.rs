@@ -0,0 +1,222 @@
+use color_eyre::eyre::Report;
+use tonic::{Request, Response, Status};
+
+use crate::{Storage, StorageError};
+
+#[allow(clippy::missing_const_for_fn)]
+pub mod proto {
+    tonic::include_proto!("storage_hal");
+}
+
+pub use proto::storage_server::StorageServer;
+use proto::{
+    Entry, GetRequest, GetResponse, InsertRequest, InsertResponse, RemoveRequest, RemoveResponse,
+    ScanRequest, ScanResponse, SequenceRequest, SequenceResponse,
+};
+
+/// The `storage_hal.Storage` gRPC service of `proto/storage.proto` over a `Storage`.
+#[derive(Debug, Clone)]
+pub struct StorageService {
+    storage: Storage,
+}
+
+impl StorageService {
+    pub const fn new(storage: Storage) -> Self {
+        Self { storage }
+    }
+
+    /// The service ready for `tonic::transport::Server::add_service`.
+    pub fn into_server(self) -> StorageServer<Self> {
+        StorageServer::new(self)
+    }
+}
+
+fn status(e: Report) -> Status {
+    let code = match e.downcast_ref::<StorageError>() {
+        Some(StorageError::Timeout(_)) => tonic::Code::DeadlineExceeded,
+        Some(StorageError::Corrupted(_)) => tonic::Code::DataLoss,
+        Some(StorageError::QuotaExceeded(_)) => tonic::Code::ResourceExhausted,
+        Some(StorageError::ValueTooLarge { .. } | StorageError::InvalidKey(_)) => {
+            tonic::Code::InvalidArgument
+        }
+        Some(StorageError::ReadOnly) => tonic::Code::FailedPrecondition,
+        Some(StorageError::Closed) => tonic::Code::Unavailable,
+        _ => tonic::Code::Internal,
+    };
+    Status::new(code, e.to_string())
+}
+
+// the most entries one scan returns, so a response is built within a short blocking
+// section
+const SCAN_LIMIT: usize = 1_000;
+
+// handlers return `Status` too
+#[allow(clippy::result_large_err)]
+fn tree_of(type_name: &str) -> Result<&str, Status> {
+    if type_name.is_empty() {
+        return Err(Status::invalid_argument("type_name is empty"));
+    }
+    Ok(type_name)
+}
+
+// GRPC
+// Requests map onto the raw access of the storage, see `raw.rs`, under the namespace of
+// the storage the service was made from. The storage blocks, calls run on the runtime's
+// worker threads like any short blocking section.
+#[tonic::async_trait]
+impl proto::storage_server::Storage for StorageService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let request = request.into_inner();
+        let value = self
+            .storage
+            .try_get_raw(tree_of(&request.type_name)?, &request.key)
+            .map_err(status)?;
+        Ok(Response::new(GetResponse {
+            value: value.map(|v| v.to_vec()),
+        }))
+    }
+
+    async fn insert(
+        &self,
+        request: Request<InsertRequest>,
+    ) -> Result<Response<InsertResponse>, Status> {
+        let request = request.into_inner();
+        self.storage
+            .insert_raw(
+                tree_of(&request.type_name)?,
+                request.key,
+                request.value.into(),
+            )
+            .map_err(status)?;
+        Ok(Response::new(InsertResponse {}))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> Result<Response<RemoveResponse>, Status> {
+        let request = request.into_inner();
+        self.storage
+            .remove_raw(tree_of(&request.type_name)?, request.key)
+            .map_err(status)?;
+        Ok(Response::new(RemoveResponse {}))
+    }
+
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<ScanResponse>, Status> {
+        let request = request.into_inner();
+        let limit = match request.limit {
+            0 => SCAN_LIMIT,
+            limit => (limit as usize).min(SCAN_LIMIT),
+        };
+        let entries = self
+            .storage
+            .scan_raw(tree_of(&request.type_name)?, &request.prefix, limit)
+            .map_err(status)?
+            .into_iter()
+            .map(|(key, value)| Entry {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            })
+            .collect();
+        Ok(Response::new(ScanResponse { entries }))
+    }
+
+    async fn next_sequence(
+        &self,
+        request: Request<SequenceRequest>,
+    ) -> Result<Response<SequenceResponse>, Status> {
+        let request = request.into_inner();
+        let ids = self
+            .storage
+            .try_next_batch(tree_of(&request.name)?, request.count.max(1))
+            .map_err(status)?;
+        Ok(Response::new(SequenceResponse {
+            start: ids.start,
+            end: ids.end,
+        }))
+    }
+}
+
+#[test]
+fn grpc() {
+    use proto::storage_server::Storage as _;
+
+    let service = StorageService::new(Storage::builder().temporary().open().unwrap());
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        for key in ["a", "ab", "b"] {
+            service
+                .insert(Request::new(InsertRequest {
+                    type_name: "Proof".to_string(),
+                    key: key.as_bytes().to_vec(),
+                    value: b"payload".to_vec(),
+                }))
+                .await
+                .unwrap();
+        }
+        let get = |key: &str| GetRequest {
+            type_name: "Proof".to_string(),
+            key: key.as_bytes().to_vec(),
+        };
+        let value = service.get(Request::new(get("a"))).await.unwrap();
+        assert_eq!(Some(b"payload".to_vec()), value.into_inner().value);
+
+        let scan = service
+            .scan(Request::new(ScanRequest {
+                type_name: "Proof".to_string(),
+                prefix: b"a".to_vec(),
+                limit: 0,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(2, scan.into_inner().entries.len());
+
+        for i in 0..SCAN_LIMIT + 1 {
+            service
+                .insert(Request::new(InsertRequest {
+                    type_name: "Many".to_string(),
+                    key: format!("{:04}", i).into_bytes(),
+                    value: vec![],
+                }))
+                .await
+                .unwrap();
+        }
+        for limit in [0, u32::MAX] {
+            let scan = service
+                .scan(Request::new(ScanRequest {
+                    type_name: "Many".to_string(),
+                    prefix: vec![],
+                    limit,
+                }))
+                .await
+                .unwrap();
+            assert_eq!(SCAN_LIMIT, scan.into_inner().entries.len());
+        }
+
+        service
+            .remove(Request::new(RemoveRequest {
+                type_name: "Proof".to_string(),
+                key: b"a".to_vec(),
+            }))
+            .await
+            .unwrap();
+        let value = service.get(Request::new(get("a"))).await.unwrap();
+        assert_eq!(None, value.into_inner().value);
+
+        let ids = service
+            .next_sequence(Request::new(SequenceRequest {
+                name: "proofs".to_string(),
+                count: 3,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((1, 4), (ids.start, ids.end));
+
+        let err = service
+            .get(Request::new(GetRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, err.code());
+    });
+}
@@ -0,0 +1,215 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+
+use color_eyre::eyre::Result;
+use parking_lot::Mutex;
+use sled::Db;
+use tracing::{debug, warn};
+
+use crate::write_back::WriteBack;
+use crate::{Storage, StorageError};
+
+// shared by all clones of a `Storage`, dropped with the last one
+#[derive(Debug)]
+pub(crate) struct Lifecycle {
+    // `Db` is not `Sync`
+    db: Mutex<Db>,
+    closed: AtomicBool,
+    pub(crate) write_back: Option<WriteBack>,
+    // the running backup schedules and maintenance tasks, their threads hold a clone of
+    // the storage
+    workers: Mutex<Vec<Weak<Worker>>>,
+}
+
+impl Lifecycle {
+    pub(crate) const fn new(db: Db, write_back: Option<WriteBack>) -> Self {
+        Self {
+            db: Mutex::new(db),
+            closed: AtomicBool::new(false),
+            write_back,
+            workers: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Drop for Lifecycle {
+    // also after `close`, for the writes that checked the storage before it closed but
+    // reached the db after it flushed
+    fn drop(&mut self) {
+        // best effort, there is nobody left to report the error to
+        let db = self.db.lock();
+        if let Some(write_back) = &self.write_back {
+            if let Err(e) = write_back.persist(&db) {
+                warn!("Persisting pending writes on drop failed: {}", e);
+            }
+        }
+        if let Err(e) = db.flush() {
+            warn!("Flush on drop failed: {}", e);
+        }
+    }
+}
+
+// a thread of a storage stopped by its task or by `close`, whichever comes first
+#[derive(Debug)]
+pub(crate) struct Worker {
+    stop: Mutex<Option<Sender<()>>>,
+    pub(crate) handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Worker {
+    pub(crate) fn stop(&self) {
+        drop(self.stop.lock().take());
+        let handle = self.handle.lock().take();
+        if let Some(handle) = handle {
+            // the worker itself can not wait for its end
+            if handle.thread().id() != std::thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+// SHUTDOWN
+// `close` is shared by all clones, once one of them closes the storage the reads and
+// writes of the others fail too. The cache keeps its values, they are already in the db.
+impl Storage {
+    /// Refuse further reads and writes with `StorageError::Closed`, stop the backup
+    /// schedules and the maintenance tasks, wait for the writes in flight, then persist
+    /// the pending write-back values and flush the db. Closing a closed storage does
+    /// nothing.
+    ///
+    /// Without `close`, the db is still flushed when the last clone is dropped, but a
+    /// failed flush can only be logged then.
+    pub fn close(&self) -> Result<()> {
+        if self.lifecycle.closed.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let workers = std::mem::take(&mut *self.lifecycle.workers.lock());
+        for worker in workers.iter().filter_map(Weak::upgrade) {
+            worker.stop();
+        }
+        // the writes holding the gate checked the storage before it closed
+        drop(self.writes.write());
+        self.try_run_pending_tasks()?;
+        debug!("Closed {}", self.db_path);
+        Ok(())
+    }
+
+    // stopped by `close` unless its task stops it first
+    pub(crate) fn register_worker(&self, stop: Sender<()>, handle: JoinHandle<()>) -> Arc<Worker> {
+        let worker = Arc::new(Worker {
+            stop: Mutex::new(Some(stop)),
+            handle: Mutex::new(Some(handle)),
+        });
+        let mut workers = self.lifecycle.workers.lock();
+        workers.retain(|worker| worker.strong_count() > 0);
+        workers.push(Arc::downgrade(&worker));
+        worker
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.lifecycle.closed.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn write_back(&self) -> Option<&WriteBack> {
+        self.lifecycle.write_back.as_ref()
+    }
+
+    pub(crate) fn check_open(&self) -> Result<()> {
+        if self.is_closed() {
+            return Err(StorageError::Closed.into());
+        }
+        Ok(())
+    }
+
+    // for the writes of records, which a follower only takes from its primary
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        self.check_open()?;
+        if self.read_only {
+            return Err(StorageError::ReadOnly.into());
+        }
+        Ok(())
+    }
+
+    // `check_writable` for the swaps, which fail with `std::io::Error`s
+    pub(crate) fn check_swappable(&self) -> std::io::Result<()> {
+        if self.is_closed() {
+            return Err(std::io::Error::other(StorageError::Closed));
+        }
+        if self.read_only {
+            let read_only = std::io::ErrorKind::PermissionDenied;
+            return Err(std::io::Error::new(read_only, StorageError::ReadOnly));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn close() {
+    use crate::{StorageConfig, StorageData};
+
+    let config = StorageConfig {
+        temporary: true,
+        ..Default::default()
+    };
+    let store: Storage = Storage::new(&config);
+    store.insert("a", "1".to_string());
+    let clone = store.clone();
+    store.close().unwrap();
+    store.close().unwrap();
+    assert!(clone.is_closed());
+    assert!(clone.health().last_flush_age.is_some());
+    assert_eq!(
+        Some(&StorageError::Closed),
+        clone
+            .try_get::<String>("a")
+            .unwrap_err()
+            .downcast_ref::<StorageError>()
+    );
+    assert_eq!(None, clone.get::<String>("a"));
+    assert!(!clone.contains_key::<String>("a"));
+    assert_eq!(None, clone.insert("b", "2".to_string()));
+    clone.remove::<String>("a");
+    assert!(clone
+        .compare_and_swap("a", Some(&"1".to_string()), None)
+        .is_err());
+    assert!(clone.try_insert("b", "2".to_string()).is_err());
+    assert_eq!(0, clone.next("sequence"));
+    assert!(clone.set_sequence("sequence", 7).is_err());
+    assert_eq!(0, clone.incr("counter", 1));
+    assert!(store
+        .db
+        .open_tree(String::name())
+        .unwrap()
+        .contains_key("a")
+        .unwrap());
+    assert!(!store
+        .db
+        .open_tree(String::name())
+        .unwrap()
+        .contains_key("b")
+        .unwrap());
+}
+
+#[test]
+fn close_write_back() {
+    use std::time::Duration;
+
+    use crate::StorageData;
+
+    let store: Storage = Storage::builder().temporary().write_back().open().unwrap();
+    let _task = store.start_maintenance(Duration::from_secs(3600)).unwrap();
+    store.insert("a", "1".to_string());
+    store.close().unwrap();
+    // persisted by `close`, and the maintenance task is stopped
+    assert!(store
+        .db
+        .open_tree(String::name())
+        .unwrap()
+        .contains_key("a")
+        .unwrap());
+    assert!(store.lifecycle.workers.lock().is_empty());
+    assert!(store.start_maintenance(Duration::from_secs(3600)).is_err());
+}
@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::{Storage, StorageData};
+
+// logs the operation when dropped after more than the threshold
+pub(crate) struct SlowOp<'a> {
+    threshold: Option<Duration>,
+    op: &'static str,
+    tree: fn() -> String,
+    key: &'a [u8],
+    start: Instant,
+}
+
+impl SlowOp<'_> {
+    // how long the operation took, if that is above the threshold
+    fn slow(&self) -> Option<Duration> {
+        let elapsed = self.start.elapsed();
+        (elapsed > self.threshold?).then_some(elapsed)
+    }
+}
+
+impl Drop for SlowOp<'_> {
+    fn drop(&mut self) {
+        if let Some(elapsed) = self.slow() {
+            warn!(
+                op = self.op,
+                tree = %(self.tree)(),
+                key = %String::from_utf8_lossy(self.key),
+                duration_ms = elapsed.as_millis() as u64,
+                "slow storage operation"
+            );
+        }
+    }
+}
+
+impl Storage {
+    pub(crate) fn slow_op<'a, T: StorageData>(
+        &self,
+        op: &'static str,
+        key: &'a [u8],
+    ) -> SlowOp<'a> {
+        SlowOp {
+            threshold: self.slow_op_threshold,
+            op,
+            tree: T::name,
+            key,
+            start: Instant::now(),
+        }
+    }
+
+    // operations on the whole db
+    pub(crate) fn slow_db_op(&self, op: &'static str) -> SlowOp<'static> {
+        SlowOp {
+            threshold: self.slow_op_threshold,
+            op,
+            tree: String::new,
+            key: &[],
+            start: Instant::now(),
+        }
+    }
+}
+
+#[test]
+fn slow_op() {
+    use crate::StorageConfig;
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        slow_op_threshold_ms: Some(0),
+        ..Default::default()
+    });
+    let op = store.slow_op::<String>("get", b"test");
+    std::thread::sleep(Duration::from_millis(1));
+    assert!(op.slow().is_some());
+    // still works while logging every operation
+    store.insert("test", "test".to_string());
+    assert_eq!(Some("test".to_string()), store.get::<String>("test"));
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let op = store.slow_db_op("flush");
+    std::thread::sleep(Duration::from_millis(1));
+    assert_eq!(None, op.slow());
+}
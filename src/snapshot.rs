@@ -0,0 +1,373 @@
+use std::collections::HashSet;
+use std::io;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use color_eyre::eyre::Result;
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use sled::{CompareAndSwapResult, Db, InlineArray};
+use tracing::info;
+
+use crate::{
+    is_internal_tree, split_ckey, tree_ckey, SledConfig, Storage, StorageData, StorageKey,
+};
+
+// held shared by the writes of data trees, exclusively while a copy taken by `snapshot`
+// or `backup` catches up
+pub(crate) type WriteGate = Arc<RwLock<()>>;
+
+// the cache keys written during one copy
+type Written = Arc<Mutex<HashSet<Vec<u8>>>>;
+
+// the copies being taken, shared by all clones of a `Storage`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Captures(Arc<RwLock<Vec<Written>>>);
+
+impl Captures {
+    // after a write of the db key behind `ckey`
+    pub(crate) fn record(&self, ckey: &[u8]) {
+        for written in self.0.read().iter() {
+            written.lock().insert(ckey.to_vec());
+        }
+    }
+
+    fn record_in(&self, tree: &str, key: &[u8]) {
+        let captures = self.0.read();
+        if captures.is_empty() {
+            return;
+        }
+        let ckey = tree_ckey(tree, key);
+        for written in captures.iter() {
+            written.lock().insert(ckey.clone());
+        }
+    }
+
+    fn start(&self) -> Written {
+        let written = Written::default();
+        self.0.write().push(written.clone());
+        written
+    }
+
+    fn stop(&self, written: &Written) -> HashSet<Vec<u8>> {
+        self.0.write().retain(|w| !Arc::ptr_eq(w, written));
+        std::mem::take(&mut *written.lock())
+    }
+}
+
+// A db tree opened by a `Storage`. It reads like the sled tree it dereferences to, its
+// writes are recorded for the copies being taken.
+#[derive(Debug, Clone)]
+pub(crate) struct Tree {
+    tree: sled::Tree,
+    name: Arc<str>,
+    captures: Captures,
+}
+
+impl Deref for Tree {
+    type Target = sled::Tree;
+
+    fn deref(&self) -> &sled::Tree {
+        &self.tree
+    }
+}
+
+impl<'a> IntoIterator for &'a Tree {
+    type Item = <&'a sled::Tree as IntoIterator>::Item;
+    type IntoIter = <&'a sled::Tree as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tree.iter()
+    }
+}
+
+impl Tree {
+    pub(crate) fn new(tree: sled::Tree, name: &[u8], captures: Captures) -> Self {
+        Self {
+            tree,
+            name: Arc::from(String::from_utf8_lossy(name)),
+            captures,
+        }
+    }
+
+    pub(crate) fn insert<K, V>(&self, key: K, value: V) -> io::Result<Option<InlineArray>>
+    where
+        K: AsRef<[u8]>,
+        V: Into<InlineArray>,
+    {
+        let previous = self.tree.insert(key.as_ref(), value)?;
+        self.captures.record_in(&self.name, key.as_ref());
+        Ok(previous)
+    }
+
+    pub(crate) fn remove<K: AsRef<[u8]>>(&self, key: K) -> io::Result<Option<InlineArray>> {
+        let removed = self.tree.remove(key.as_ref())?;
+        self.captures.record_in(&self.name, key.as_ref());
+        Ok(removed)
+    }
+
+    pub(crate) fn compare_and_swap<K, OV, NV>(
+        &self,
+        key: K,
+        old: Option<OV>,
+        new: Option<NV>,
+    ) -> CompareAndSwapResult
+    where
+        K: AsRef<[u8]>,
+        OV: AsRef<[u8]>,
+        NV: Into<InlineArray>,
+    {
+        let swapped = self.tree.compare_and_swap(key.as_ref(), old, new)?;
+        if swapped.is_ok() {
+            self.captures.record_in(&self.name, key.as_ref());
+        }
+        Ok(swapped)
+    }
+
+    pub(crate) fn update_and_fetch<K, V, F>(&self, key: K, f: F) -> io::Result<Option<InlineArray>>
+    where
+        K: AsRef<[u8]>,
+        F: FnMut(Option<&[u8]>) -> Option<V>,
+        V: Into<InlineArray>,
+    {
+        let updated = self.tree.update_and_fetch(key.as_ref(), f)?;
+        self.captures.record_in(&self.name, key.as_ref());
+        Ok(updated)
+    }
+
+    pub(crate) fn fetch_and_update<K, V, F>(&self, key: K, f: F) -> io::Result<Option<InlineArray>>
+    where
+        K: AsRef<[u8]>,
+        F: FnMut(Option<&[u8]>) -> Option<V>,
+        V: Into<InlineArray>,
+    {
+        let previous = self.tree.fetch_and_update(key.as_ref(), f)?;
+        self.captures.record_in(&self.name, key.as_ref());
+        Ok(previous)
+    }
+
+    pub(crate) fn apply_batch(&self, batch: Batch) -> io::Result<()> {
+        self.tree.apply_batch(batch.batch)?;
+        for key in &batch.keys {
+            self.captures.record_in(&self.name, key);
+        }
+        Ok(())
+    }
+}
+
+// a sled batch that remembers its keys, for `Tree::apply_batch`
+#[derive(Debug, Default)]
+pub(crate) struct Batch {
+    batch: sled::Batch,
+    keys: Vec<Vec<u8>>,
+}
+
+impl Batch {
+    pub(crate) fn insert<K: AsRef<[u8]>, V: Into<InlineArray>>(&mut self, key: K, value: V) {
+        self.keys.push(key.as_ref().to_vec());
+        self.batch.insert(key.as_ref(), value);
+    }
+
+    pub(crate) fn remove<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.keys.push(key.as_ref().to_vec());
+        self.batch.remove(key.as_ref());
+    }
+}
+
+/// A read-only copy of the data trees of a `Storage` at one point in time.
+///
+/// Values are decoded like the storage does, with its codecs and keys. The copy lives
+/// in a temporary db that is deleted with the snapshot.
+#[derive(Debug)]
+pub struct Snapshot {
+    storage: Storage,
+    db: Db,
+}
+
+impl Snapshot {
+    pub fn get<T: StorageData>(&self, key: impl Into<StorageKey>) -> Result<Option<T>> {
+        let key = key.into();
+        let Some(v) = self.tree::<T>()?.get(&key)? else {
+            return Ok(None);
+        };
+        Ok(self.storage.decode_with(&key, &v, false))
+    }
+
+    pub fn contains_key<T: StorageData>(&self, key: impl Into<StorageKey>) -> Result<bool> {
+        Ok(self.tree::<T>()?.contains_key(key.into())?)
+    }
+
+    /// Iterate the records of `T` in key order, undecodable ones are skipped.
+    pub fn iter<T: StorageData>(&self) -> Result<impl Iterator<Item = (StorageKey, T)> + '_> {
+        let tree = self.tree::<T>()?;
+        Ok(tree.iter().filter_map(move |r| {
+            let (k, v) = r.ok()?;
+            let value = self.storage.decode_with(&k, &v, false)?;
+            Some((StorageKey::from(k.as_ref()), value))
+        }))
+    }
+
+    /// Number of records of `T`, one pass over them.
+    pub fn count<T: StorageData>(&self) -> Result<usize> {
+        Ok(self.tree::<T>()?.len())
+    }
+
+    // trees are copied under their db names
+    fn tree<T: StorageData>(&self) -> std::io::Result<sled::Tree> {
+        self.db
+            .open_tree(self.storage.tree_name(&T::name()).as_bytes())
+    }
+}
+
+// SNAPSHOT
+// sled has no snapshots, `snapshot` and `backup` copy the trees while writes continue,
+// recording the keys written meanwhile. Once the copy is done the writes of this storage
+// wait while those keys are copied again, so the copy holds the db as it was at that
+// moment, with either all or none of each write and the pending write-back values. The
+// writes wait for as long as it takes to copy the keys written during the copy. Taking
+// one still costs a copy of the live data, it is meant for exports and audits, not for
+// every read. Entries expiring during the copy may or may not be in it, and other
+// processes writing the db are not held back.
+impl Storage {
+    pub(crate) fn write_gate(&self) -> RwLockReadGuard<'_, ()> {
+        self.writes.read_recursive()
+    }
+
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let (db, _) = SledConfig::default().open_temporary()?;
+        let copy = || {
+            let mut records = 0;
+            for name in self.data_tree_names() {
+                let mut batch = sled::Batch::default();
+                for r in self.tree(&name)?.iter() {
+                    let (k, v) = r?;
+                    batch.insert(&k, &v);
+                    records += 1;
+                }
+                db.open_tree(self.tree_name(&name).as_bytes())?
+                    .apply_batch(batch)?;
+            }
+            Ok(records)
+        };
+        // trees created during the copy included, their keys were all written meanwhile
+        let copied = |tree: Option<&[u8]>| {
+            tree.is_some_and(|tree| {
+                let name = String::from_utf8_lossy(tree);
+                let own = self.own_tree_name(&name);
+                self.tree_name(own) == name && !is_internal_tree(own)
+            })
+        };
+        let records = self.copy_live(&db, copy, copied)?;
+        info!("Took a snapshot of {} records", records);
+        Ok(Snapshot {
+            storage: self.clone(),
+            db,
+        })
+    }
+
+    // run `copy` of this db into `dst`, then copy the keys written meanwhile in the trees
+    // `copied` agrees to, `None` being the root tree, while the writes wait. Returns the
+    // record count of `copy` with the ones added and removed by catching up.
+    pub(crate) fn copy_live(
+        &self,
+        dst: &Db,
+        copy: impl FnOnce() -> Result<usize>,
+        copied: impl Fn(Option<&[u8]>) -> bool,
+    ) -> Result<usize> {
+        let written = self.captures.start();
+        let records = copy();
+        let _writes = self.writes.write();
+        let written = self.captures.stop(&written);
+        let mut records = records?;
+        let locate = |db: &Db, ckey: &[u8]| -> io::Result<(sled::Tree, Vec<u8>)> {
+            match split_ckey(ckey) {
+                Some((tree, key)) => Ok((db.open_tree(tree)?, key.to_vec())),
+                None => Ok(((**db).clone(), ckey.to_vec())),
+            }
+        };
+        for ckey in written {
+            if !copied(split_ckey(&ckey).map(|(tree, _)| tree)) {
+                continue;
+            }
+            let (from, key) = locate(&self.db, &ckey)?;
+            let (to, _) = locate(dst, &ckey)?;
+            match from.get(&key)? {
+                Some(v) => records += usize::from(to.insert(&key, v)?.is_none()),
+                None => records -= usize::from(to.remove(&key)?.is_some()),
+            }
+        }
+        // newer than the db, taken while the writes wait
+        let pending = self
+            .write_back()
+            .map(|write_back| write_back.pending())
+            .unwrap_or_default();
+        for (ckey, v) in pending {
+            // the cache is shared with other namespaces
+            if copied(split_ckey(&ckey).map(|(tree, _)| tree)) {
+                let (to, key) = locate(dst, &ckey)?;
+                records += usize::from(to.insert(&key, v.as_ref())?.is_none());
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[test]
+fn snapshot() {
+    use crate::StorageConfig;
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert("a", "1".to_string());
+    store.insert("b", "2".to_string());
+    let snapshot = store.snapshot().unwrap();
+    store.insert("a", "changed".to_string());
+    store.remove::<String>("b");
+    store.insert("c", "3".to_string());
+
+    assert_eq!(Some("1".to_string()), snapshot.get::<String>("a").unwrap());
+    assert!(snapshot.contains_key::<String>("b").unwrap());
+    assert!(!snapshot.contains_key::<String>("c").unwrap());
+    assert_eq!(2, snapshot.count::<String>().unwrap());
+    assert_eq!(
+        vec![StorageKey::from("a"), StorageKey::from("b")],
+        snapshot
+            .iter::<String>()
+            .unwrap()
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>()
+    );
+
+    let write_back: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        write_back: true,
+        ..Default::default()
+    });
+    write_back.insert("pending", "1".to_string());
+    let snapshot = write_back.snapshot().unwrap();
+    assert_eq!(
+        Some("1".to_string()),
+        snapshot.get::<String>("pending").unwrap()
+    );
+
+    // a write made while the copy runs isn't held back, the copy catches up on it
+    let live: Storage = Storage::builder().temporary().open().unwrap();
+    live.insert("a", "1".to_string());
+    let (dst, _) = SledConfig::default().open_temporary().unwrap();
+    let copy = || {
+        let (writer, (done, wait)) = (live.clone(), std::sync::mpsc::channel());
+        std::thread::spawn(move || {
+            writer.insert("a", "2".to_string());
+            writer.insert("b", "3".to_string());
+            done.send(()).unwrap();
+        });
+        wait.recv_timeout(std::time::Duration::from_secs(10))
+            .expect("the write waited for the copy");
+        Ok(0)
+    };
+    assert_eq!(2, live.copy_live(&dst, copy, |_| true).unwrap());
+    let (from, to) = (
+        live.tree(String::name()).unwrap(),
+        dst.open_tree(String::name()).unwrap(),
+    );
+    assert_eq!(from.get("a").unwrap(), to.get("a").unwrap());
+    assert_eq!(from.get("b").unwrap(), to.get("b").unwrap());
+}
@@ -0,0 +1,176 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use color_eyre::eyre::Result;
+use moka::notification::RemovalCause;
+use sled::Tree;
+
+use crate::Storage;
+
+/// Size of one tree, see `Storage::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Tree name, empty for the root tree.
+    pub name: String,
+    pub keys: usize,
+    /// Sum of the key and value lengths, before sled's own compression and overhead.
+    pub approx_bytes: u64,
+}
+
+/// Counters of the value cache since the `Storage` was opened, see `Storage::cache_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads answered by the cache.
+    pub hits: u64,
+    /// Reads that went to the db.
+    pub misses: u64,
+    /// Entries evicted to stay within `cache_max_capacity`.
+    pub evicted_size: u64,
+    /// Entries dropped by `cache_time_to_live` or `cache_time_to_idle`.
+    pub expired: u64,
+    /// Entries removed together with their record.
+    pub removed: u64,
+    /// Entries overwritten by a newer value.
+    pub replaced: u64,
+    /// Total weight of the cached entries, key and value bytes.
+    pub weighted_size: u64,
+    pub entry_count: u64,
+}
+
+impl CacheStats {
+    /// Share of reads answered by the cache, 0 before the first read.
+    pub fn hit_ratio(&self) -> f64 {
+        let reads = self.hits + self.misses;
+        if reads == 0 {
+            0.0
+        } else {
+            self.hits as f64 / reads as f64
+        }
+    }
+}
+
+// shared by all clones of a `Storage` and its eviction listener
+#[derive(Debug, Default)]
+pub(crate) struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evicted_size: AtomicU64,
+    expired: AtomicU64,
+    removed: AtomicU64,
+    replaced: AtomicU64,
+}
+
+impl CacheCounters {
+    pub(crate) fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::cache_hit();
+    }
+
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::cache_miss();
+    }
+
+    pub(crate) fn evicted(&self, cause: RemovalCause) {
+        let counter = match cause {
+            RemovalCause::Size => &self.evicted_size,
+            RemovalCause::Expired => &self.expired,
+            RemovalCause::Explicit => &self.removed,
+            RemovalCause::Replaced => &self.replaced,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn tree_stats(name: String, tree: &Tree) -> Result<TreeStats> {
+    let mut stats = TreeStats {
+        name,
+        keys: 0,
+        approx_bytes: 0,
+    };
+    for r in tree.iter() {
+        let (k, v) = r?;
+        stats.keys += 1;
+        stats.approx_bytes += (k.len() + v.len()) as u64;
+    }
+    Ok(stats)
+}
+
+// STATS
+impl Storage {
+    /// Key count and size of every tree, internal ones included, by walking all records.
+    pub fn stats(&self) -> Result<Vec<TreeStats>> {
+        let mut stats = vec![tree_stats(String::new(), &self.db)?];
+        for name in self.tree_names() {
+            let tree = self.db_tree(name.as_bytes())?;
+            stats.push(tree_stats(name, &tree)?);
+        }
+        Ok(stats)
+    }
+
+    /// Counters of the value cache. The entry count and weighted size lag behind by the
+    /// cache's pending maintenance, `run_pending_tasks` brings them up to date.
+    pub fn cache_stats(&self) -> CacheStats {
+        let counters = &self.cache_counters;
+        CacheStats {
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            evicted_size: counters.evicted_size.load(Ordering::Relaxed),
+            expired: counters.expired.load(Ordering::Relaxed),
+            removed: counters.removed.load(Ordering::Relaxed),
+            replaced: counters.replaced.load(Ordering::Relaxed),
+            weighted_size: self.cache.weighted_size(),
+            entry_count: self.cache.entry_count(),
+        }
+    }
+}
+
+#[test]
+fn stats() {
+    use crate::StorageData;
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert("a", "a".to_string());
+    store.insert("b", "b".to_string());
+    store.db.insert("root", "root").unwrap();
+
+    let stats = store.stats().unwrap();
+    assert_eq!(
+        TreeStats {
+            name: String::new(),
+            keys: 1,
+            approx_bytes: 8,
+        },
+        stats[0]
+    );
+    let strings = stats.iter().find(|s| s.name == String::name()).unwrap();
+    assert_eq!(2, strings.keys);
+    let stored = store
+        .db
+        .open_tree(String::name())
+        .unwrap()
+        .get("a")
+        .unwrap();
+    assert_eq!(2 * (1 + stored.unwrap().len() as u64), strings.approx_bytes);
+}
+
+#[test]
+fn cache_stats() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.insert("a", "a".to_string());
+    store.insert("a", "b".to_string());
+    store.insert("c", "c".to_string());
+    store.get::<String>("a");
+    store.get::<String>("a");
+    store.get::<String>("missing");
+    store.remove::<String>("c");
+    store.run_pending_tasks();
+
+    let stats = store.cache_stats();
+    assert_eq!((2, 1), (stats.hits, stats.misses));
+    assert_eq!((1, 1), (stats.replaced, stats.removed));
+    assert_eq!(1, stats.entry_count);
+    assert!(stats.weighted_size > 0);
+    assert!((stats.hit_ratio() - 2.0 / 3.0).abs() < f64::EPSILON);
+}
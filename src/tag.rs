@@ -0,0 +1,199 @@
+use color_eyre::eyre::Result;
+use sled::CompareAndSwapError;
+
+use crate::{Batch, Storage, StorageData, StorageKey, Tree};
+
+const TAG_TREE_SUFFIX: &str = "__tags";
+// `| TAG_ENTRY | tag len (4, be) | tag | key |` -> empty
+const TAG_ENTRY: u8 = 0;
+// `| TAGS_OF | key |` -> the bincode tags of the key
+const TAGS_OF: u8 = 1;
+
+// the tree indexing the records of `name` by tag
+pub(crate) fn tag_tree_name(name: &str) -> String {
+    format!("{}{}", name, TAG_TREE_SUFFIX)
+}
+
+pub(crate) fn is_tag_tree(name: &str) -> bool {
+    name.ends_with(TAG_TREE_SUFFIX)
+}
+
+fn tag_prefix(tag: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(1 + 4 + tag.len());
+    prefix.push(TAG_ENTRY);
+    prefix.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(tag.as_bytes());
+    prefix
+}
+
+fn tag_key(tag: &str, key: &[u8]) -> Vec<u8> {
+    let mut tag_key = tag_prefix(tag);
+    tag_key.extend_from_slice(key);
+    tag_key
+}
+
+fn tags_of_key(key: &[u8]) -> Vec<u8> {
+    let mut tags_key = Vec::with_capacity(1 + key.len());
+    tags_key.push(TAGS_OF);
+    tags_key.extend_from_slice(key);
+    tags_key
+}
+
+fn read_tags(bytes: Option<&[u8]>) -> Vec<String> {
+    bytes
+        .and_then(|v| bincode::deserialize(v).ok())
+        .unwrap_or_default()
+}
+
+// TAGS
+// The tags of `T` live in `<name>__tags`, one entry per tag and key to scan, and one
+// per key listing its tags, which is what counts. The per-tag entries are updated
+// after it, so racing writers may leave stale ones behind, `find_by_tag` checks them
+// against the key's own entry. Tags and record are written separately, a crash in
+// between leaves the new record with its old tags. Removing a record drops its tags,
+// inserting it without `insert_tagged` keeps them.
+impl Storage {
+    // the tag tree of the tree `name`, if it was ever written. Tag trees are opened
+    // with the db, so removals of untagged types don't create one.
+    fn existing_tag_tree(&self, name: &str) -> Option<Tree> {
+        self.trees
+            .get(self.tree_name(&tag_tree_name(name)).as_bytes())
+            .map(|tree| tree.clone())
+    }
+
+    /// Insert `value` and replace the tags of `key` with `tags`. Returns the previous
+    /// value like `insert`.
+    pub fn insert_tagged<T: StorageData>(
+        &self,
+        key: impl Into<StorageKey>,
+        value: T,
+        tags: &[&str],
+    ) -> Result<Option<T>> {
+        let key = key.into();
+        let previous = self.insert_checked(key.clone(), value)?;
+        let tree = self.tree(tag_tree_name(&T::name()))?;
+        let mut new: Vec<String> = tags.iter().map(|tag| tag.to_string()).collect();
+        new.sort();
+        new.dedup();
+        let new_bytes = bincode::serialize(&new)?;
+        let mut current = tree.get(tags_of_key(&key))?;
+        let old = loop {
+            match tree.compare_and_swap(
+                tags_of_key(&key),
+                current.as_deref(),
+                Some(new_bytes.as_slice()),
+            )? {
+                Ok(_) => break read_tags(current.as_deref()),
+                Err(CompareAndSwapError { current: now, .. }) => current = now,
+            }
+        };
+        let mut batch = Batch::default();
+        for tag in old.iter().filter(|tag| !new.contains(tag)) {
+            batch.remove(tag_key(tag, &key));
+        }
+        for tag in &new {
+            batch.insert(tag_key(tag, &key), b"");
+        }
+        tree.apply_batch(batch)?;
+        Ok(previous)
+    }
+
+    /// The records of `T` tagged with `tag`, in key order.
+    pub fn find_by_tag<T: StorageData>(&self, tag: &str) -> Result<Vec<(StorageKey, T)>> {
+        let Some(tags) = self.existing_tag_tree(&T::name()) else {
+            return Ok(vec![]);
+        };
+        let data = self.tree(T::name())?;
+        let prefix = tag_prefix(tag);
+        let mut found = vec![];
+        for r in tags.scan_prefix(&prefix).keys() {
+            let tag_key = r?;
+            let key = &tag_key[prefix.len()..];
+            // left behind by a racing tag update or removal
+            if !read_tags(tags.get(tags_of_key(key))?.as_deref()).contains(&tag.to_string()) {
+                continue;
+            }
+            if let Some(value) = self.get_in(&data, self.ckey::<T>(key), key) {
+                found.push((StorageKey::from(key), value));
+            }
+        }
+        Ok(found)
+    }
+
+    /// The tags of `key`, sorted.
+    pub fn tags<T: StorageData>(&self, key: impl Into<StorageKey>) -> Result<Vec<String>> {
+        let Some(tags) = self.existing_tag_tree(&T::name()) else {
+            return Ok(vec![]);
+        };
+        Ok(read_tags(tags.get(tags_of_key(&key.into()))?.as_deref()))
+    }
+
+    // drop the tags of a record removed from the tree `name`
+    pub(crate) fn untag_tree(&self, name: &str, key: &[u8]) -> Result<()> {
+        let Some(tree) = self.existing_tag_tree(name) else {
+            return Ok(());
+        };
+        let Some(old) = tree.remove(tags_of_key(key))? else {
+            return Ok(());
+        };
+        let mut batch = Batch::default();
+        for tag in read_tags(Some(&old)) {
+            batch.remove(tag_key(&tag, key));
+        }
+        tree.apply_batch(batch)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn tags() {
+    use crate::StorageConfig;
+
+    let dir = crate::TestDir::new("tags");
+    let config = StorageConfig {
+        db_path: dir.path("db"),
+        ..Default::default()
+    };
+    {
+        let store: Storage = Storage::new(&config);
+        assert!(store.find_by_tag::<String>("pending").unwrap().is_empty());
+        store
+            .insert_tagged("a", "1".to_string(), &["pending", "urgent"])
+            .unwrap();
+        store
+            .insert_tagged("b", "2".to_string(), &["pending"])
+            .unwrap();
+        // a tag that is a prefix of another
+        store
+            .insert_tagged("c", "3".to_string(), &["pend"])
+            .unwrap();
+        assert_eq!(
+            vec![
+                (StorageKey::from("a"), "1".to_string()),
+                (StorageKey::from("b"), "2".to_string())
+            ],
+            store.find_by_tag::<String>("pending").unwrap()
+        );
+
+        store
+            .insert_tagged("a", "4".to_string(), &["finalized"])
+            .unwrap();
+        assert_eq!(
+            vec![(StorageKey::from("b"), "2".to_string())],
+            store.find_by_tag::<String>("pending").unwrap()
+        );
+        assert_eq!(
+            vec!["finalized".to_string()],
+            store.tags::<String>("a").unwrap()
+        );
+        store.remove::<String>("b");
+        assert!(store.find_by_tag::<String>("pending").unwrap().is_empty());
+        assert!(store.tags::<String>("b").unwrap().is_empty());
+    }
+    // the tag tree is found again after a restart
+    let store: Storage = Storage::new(&config);
+    assert_eq!(
+        vec![(StorageKey::from("a"), "4".to_string())],
+        store.find_by_tag::<String>("finalized").unwrap()
+    );
+}
@@ -0,0 +1,133 @@
+use std::time::Instant;
+
+use color_eyre::eyre::Result;
+use metrics::{counter, gauge, histogram};
+
+use crate::{Storage, StorageData};
+
+// METRICS
+// Reported through the `metrics` facade, to whatever recorder the application installs,
+// e.g. `metrics-exporter-prometheus`. Without a recorder all of this is a no-op.
+//
+// storage_hal_op_seconds{op, tree}        histogram of get, insert and remove latency
+// storage_hal_cache_hits_total            counter
+// storage_hal_cache_misses_total          counter
+// storage_hal_flush_seconds               histogram of `run_pending_tasks` flushes
+// storage_hal_cache_hit_ratio             gauge, set by `publish_metrics`
+// storage_hal_cache_entries               gauge, set by `publish_metrics`
+// storage_hal_cache_bytes                 gauge, set by `publish_metrics`
+// storage_hal_tree_keys{tree}             gauge, set by `publish_metrics`
+// storage_hal_tree_bytes{tree}            gauge, set by `publish_metrics`
+
+// records the latency of an operation on `T` when dropped
+pub(crate) struct OpTimer {
+    op: &'static str,
+    tree: String,
+    start: Instant,
+}
+
+impl OpTimer {
+    pub(crate) fn start<T: StorageData>(op: &'static str) -> Self {
+        Self {
+            op,
+            tree: T::name(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        histogram!("storage_hal_op_seconds", "op" => self.op, "tree" => self.tree.clone())
+            .record(self.start.elapsed());
+    }
+}
+
+pub(crate) fn cache_hit() {
+    counter!("storage_hal_cache_hits_total").increment(1);
+}
+
+pub(crate) fn cache_miss() {
+    counter!("storage_hal_cache_misses_total").increment(1);
+}
+
+pub(crate) fn flushed(start: Instant) {
+    histogram!("storage_hal_flush_seconds").record(start.elapsed());
+}
+
+impl Storage {
+    /// Set the gauges of the cache and of every tree's size. Walks all records like
+    /// `stats`, call it as often as the scrape interval needs rather than per request.
+    pub fn publish_metrics(&self) -> Result<()> {
+        let cache = self.cache_stats();
+        gauge!("storage_hal_cache_hit_ratio").set(cache.hit_ratio());
+        gauge!("storage_hal_cache_entries").set(cache.entry_count as f64);
+        gauge!("storage_hal_cache_bytes").set(cache.weighted_size as f64);
+        for tree in self.stats()? {
+            gauge!("storage_hal_tree_keys", "tree" => tree.name.clone()).set(tree.keys as f64);
+            gauge!("storage_hal_tree_bytes", "tree" => tree.name).set(tree.approx_bytes as f64);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn telemetry() {
+    use std::sync::Mutex;
+
+    use metrics::{
+        Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+    };
+
+    // the names of the registered metrics
+    #[derive(Debug, Default)]
+    struct Names(Mutex<Vec<String>>);
+
+    impl Names {
+        fn push(&self, key: &Key) {
+            self.0.lock().unwrap().push(key.name().to_string());
+        }
+    }
+
+    impl Recorder for Names {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            self.push(key);
+            Counter::noop()
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            self.push(key);
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            self.push(key);
+            Histogram::noop()
+        }
+    }
+
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    let names = Names::default();
+    metrics::with_local_recorder(&names, || {
+        store.insert("test", "test".to_string());
+        store.get::<String>("test");
+        store.get::<String>("missing");
+        store.run_pending_tasks();
+        store.publish_metrics().unwrap();
+    });
+    let names = names.0.into_inner().unwrap();
+    for name in [
+        "storage_hal_op_seconds",
+        "storage_hal_cache_hits_total",
+        "storage_hal_cache_misses_total",
+        "storage_hal_flush_seconds",
+        "storage_hal_cache_hit_ratio",
+        "storage_hal_tree_keys",
+    ] {
+        assert!(names.iter().any(|n| n == name), "{} not reported", name);
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Arc;
+
+use color_eyre::eyre::Result;
+use parking_lot::RwLock;
+use sled::Db;
+use tracing::info;
+
+use crate::cas::{refs_of, RefsFn};
+use crate::namespace::base_tree_name;
+use crate::{envelope, is_record_tree, tree_names, Storage, StorageData, StorageKey};
+
+// whether a value of the registered type decodes, stored at a key of a db tree
+type CheckFn = fn(&Storage, &str, &[u8], &[u8]) -> bool;
+
+fn check<T: StorageData>(storage: &Storage, tree: &str, key: &[u8], bytes: &[u8]) -> bool {
+    storage.decode_in::<T>(tree, key, bytes, false).is_some()
+}
+
+// the handling of a registered type
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TypeFns {
+    pub(crate) check: CheckFn,
+    pub(crate) content_refs: RefsFn,
+    #[cfg(feature = "json")]
+    pub(crate) to_json: crate::json::ToJsonFn,
+}
+
+// the types `verify` knows about, keyed by tree name
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Types(pub(crate) Arc<RwLock<HashMap<String, TypeFns>>>);
+
+impl Types {
+    pub(crate) fn register<T: StorageData>(&self) {
+        let fns = TypeFns {
+            check: check::<T>,
+            content_refs: refs_of::<T>,
+            #[cfg(feature = "json")]
+            to_json: crate::json::to_json::<T>,
+        };
+        self.0.write().insert(T::name(), fns);
+    }
+}
+
+/// A record that failed verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptEntry {
+    pub tree: String,
+    pub key: StorageKey,
+    pub reason: String,
+}
+
+/// The result of `Storage::verify`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Number of records checked.
+    pub checked: usize,
+    pub corrupt: Vec<CorruptEntry>,
+    /// Trees with data that no registered type claims, and their record counts.
+    pub orphaned: Vec<(String, usize)>,
+}
+
+impl IntegrityReport {
+    pub const fn is_ok(&self) -> bool {
+        self.corrupt.is_empty() && self.orphaned.is_empty()
+    }
+}
+
+impl Display for IntegrityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "checked {} records, {} corrupt, {} orphaned trees",
+            self.checked,
+            self.corrupt.len(),
+            self.orphaned.len()
+        )?;
+        for entry in &self.corrupt {
+            writeln!(
+                f,
+                "corrupt: tree({}) key({}): {}",
+                entry.tree, entry.key, entry.reason
+            )?;
+        }
+        for (tree, records) in &self.orphaned {
+            writeln!(f, "orphaned: tree({}): {} records", tree, records)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage {
+    /// Make the records of `T` checkable by `verify`, and exportable by
+    /// `export_all_json` with the `json` feature.
+    pub fn register_type<T: StorageData>(&self) {
+        self.types.register::<T>();
+    }
+
+    /// Walk the trees of records without touching the cache or the data, checking every
+    /// record's checksum and, for registered types, that it decodes. The archived
+    /// versions, tags, series and journals kept next to them are not checked.
+    pub fn verify(&self) -> Result<IntegrityReport> {
+        self.verify_db(&self.db)
+    }
+
+    // check the records of `db` against the types registered here
+    pub(crate) fn verify_db(&self, db: &Db) -> Result<IntegrityReport> {
+        let types = self.types.0.read();
+        let mut report = IntegrityReport::default();
+        for name in tree_names(db)
+            .into_iter()
+            .filter(|name| is_record_tree(name))
+        {
+            let tree = db.open_tree(&name)?;
+            let check = types.get(base_tree_name(&name)).map(|fns| fns.check);
+            if check.is_none() && !tree.is_empty()? {
+                report.orphaned.push((name.clone(), tree.len()));
+            }
+            for r in tree.iter() {
+                let (k, v) = r?;
+                report.checked += 1;
+                let reason = if !envelope::verify(&v) {
+                    "checksum mismatch"
+                } else if check.is_some_and(|check| !check(self, &name, &k, &v)) {
+                    "not decodable"
+                } else {
+                    continue;
+                };
+                report.corrupt.push(CorruptEntry {
+                    tree: name.clone(),
+                    key: StorageKey::from(k.as_ref()),
+                    reason: reason.to_string(),
+                });
+            }
+        }
+        info!(
+            "Verified {} records, {} corrupt",
+            report.checked,
+            report.corrupt.len()
+        );
+        Ok(report)
+    }
+}
+
+#[test]
+fn verify() {
+    let store: Storage = Storage::builder().temporary().open().unwrap();
+    store.register_type::<String>();
+    store.insert("good", "good".to_string());
+    store.insert("damaged", "damaged".to_string());
+    let tree = store.db.open_tree(String::name()).unwrap();
+    let mut damaged = tree.get("damaged").unwrap().unwrap().to_vec();
+    damaged[envelope::HEADER_LEN] ^= 1;
+    tree.insert("damaged", damaged).unwrap();
+    tree.insert("garbage", vec![0xff]).unwrap();
+    store
+        .db
+        .open_tree("Unknown")
+        .unwrap()
+        .insert("a", "b")
+        .unwrap();
+    store.set_meta("flag", &true).unwrap();
+
+    let report = store.verify().unwrap();
+    assert_eq!(4, report.checked);
+    assert_eq!(
+        vec![
+            CorruptEntry {
+                tree: String::name(),
+                key: StorageKey::from("damaged"),
+                reason: "checksum mismatch".to_string(),
+            },
+            CorruptEntry {
+                tree: String::name(),
+                key: StorageKey::from("garbage"),
+                reason: "not decodable".to_string(),
+            },
+        ],
+        report.corrupt
+    );
+    assert_eq!(vec![("Unknown".to_string(), 1)], report.orphaned);
+    assert!(!report.is_ok());
+
+    // the trees kept next to the records are neither checked nor orphaned
+    let store = Storage::builder()
+        .temporary()
+        .history::<String>(3)
+        .open()
+        .unwrap();
+    store.register_type::<String>();
+    store.insert("a", "1".to_string());
+    store.insert("a", "2".to_string());
+    store.insert_tagged("b", "3".to_string(), &["tag"]).unwrap();
+    let report = store.verify().unwrap();
+    assert_eq!(2, report.checked);
+    assert!(report.is_ok(), "{}", report);
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use color_eyre::eyre::Result;
+use dashmap::DashMap;
+use moka::sync::SegmentedCache;
+use parking_lot::{Mutex, MutexGuard};
+use sled::{Batch, Db};
+use tracing::debug;
+
+use crate::pin::PinnedSet;
+use crate::{split_ckey, Captures, Tree};
+
+// cache keys of values written to the cache but not yet to the db
+pub(crate) type DirtySet = Arc<DashMap<Vec<u8>, ()>>;
+
+// WRITE-BACK
+// `insert` only writes the cache and marks the key dirty, `persist` writes the dirty
+// values to the db in one batch per tree. Values the cache evicts for space are written
+// by the eviction listener, removals and the other writes go to the db directly.
+//
+// A crash loses the writes since the last persist. Persisted batches are atomic per
+// tree only, after a crash some trees may hold newer writes than others. Reads through
+// the cache see the pending writes, iterators, exports and backups read the db and only
+// see them after `run_pending_tasks`.
+#[derive(Debug)]
+pub(crate) struct WriteBack {
+    dirty: DirtySet,
+    cache: SegmentedCache<Vec<u8>, Bytes>,
+    pinned: PinnedSet,
+    // writes to the db directly, so it records them for the copies being taken itself
+    captures: Captures,
+    // held by `persist` and by removals, so a persisted value can't resurrect a key
+    // removed meanwhile
+    persisting: Mutex<()>,
+}
+
+impl WriteBack {
+    pub(crate) const fn new(
+        dirty: DirtySet,
+        cache: SegmentedCache<Vec<u8>, Bytes>,
+        pinned: PinnedSet,
+        captures: Captures,
+    ) -> Self {
+        Self {
+            dirty,
+            cache,
+            pinned,
+            captures,
+            persisting: Mutex::new(()),
+        }
+    }
+
+    // after the value is in the cache, so a concurrent persist either writes it or
+    // leaves the mark for the next one
+    pub(crate) fn mark(&self, ckey: Vec<u8>) {
+        self.dirty.insert(ckey, ());
+    }
+
+    // drop the pending write of `ckey`, no persist runs until the guard is dropped
+    pub(crate) fn unmark(&self, ckey: &[u8]) -> MutexGuard<'_, ()> {
+        let persisting = self.persisting.lock();
+        self.dirty.remove(ckey);
+        persisting
+    }
+
+    // the pending value of `ckey`, pinned ones may have expired from the cache. Empty
+    // values are markers of uncached values, which are in the db already.
+    fn value(&self, ckey: &[u8]) -> Option<Bytes> {
+        self.cache
+            .get(ckey)
+            .filter(|v| !v.is_empty())
+            .or_else(|| self.pinned.get(ckey).map(|v| v.clone()))
+    }
+
+    // write the pending write of `ckey` alone, before an operation that reads the db
+    pub(crate) fn persist_key(&self, tree: &Tree, ckey: &[u8], key: &[u8]) -> Result<()> {
+        if self.dirty.remove(ckey).is_some() {
+            if let Some(value) = self.value(ckey) {
+                tree.insert(key, value.as_ref())?;
+            }
+        }
+        Ok(())
+    }
+
+    // drop `ckey` from the cache, writing its value to the db first. The removed value
+    // is written even if it was clean, an insert racing the removal may not have
+    // marked it yet.
+    pub(crate) fn invalidate(&self, db: &Db, ckey: &[u8]) -> Result<()> {
+        let _persisting = self.persisting.lock();
+        let removed = self
+            .cache
+            .remove(ckey)
+            .filter(|v| !v.is_empty())
+            .or_else(|| self.pinned.get(ckey).map(|v| v.clone()));
+        if let Some(value) = removed {
+            match split_ckey(ckey) {
+                Some((tree, key)) => db.open_tree(tree)?.insert(key, value.as_ref())?,
+                None => db.insert(ckey, value.as_ref())?,
+            };
+            self.captures.record(ckey);
+        }
+        Ok(())
+    }
+
+    // the pending values by cache key
+    pub(crate) fn pending(&self) -> Vec<(Vec<u8>, Bytes)> {
+        // the keys first, reading the cache can run the eviction listener on this thread
+        let ckeys: Vec<Vec<u8>> = self.dirty.iter().map(|e| e.key().clone()).collect();
+        ckeys
+            .into_iter()
+            .filter_map(|ckey| {
+                let value = self.value(&ckey)?;
+                Some((ckey, value))
+            })
+            .collect()
+    }
+
+    // write all pending writes, returns their count
+    pub(crate) fn persist(&self, db: &Db) -> Result<usize> {
+        let _persisting = self.persisting.lock();
+        let ckeys: Vec<Vec<u8>> = self.dirty.iter().map(|e| e.key().clone()).collect();
+        let mut batches: HashMap<Option<&[u8]>, Batch> = HashMap::new();
+        let mut persisted = 0;
+        for ckey in &ckeys {
+            // unmarked before reading, an insert racing the read marks it again
+            self.dirty.remove(ckey);
+            let Some(value) = self.value(ckey) else {
+                continue;
+            };
+            let (tree, key) = match split_ckey(ckey) {
+                Some((tree, key)) => (Some(tree), key),
+                None => (None, ckey.as_slice()),
+            };
+            batches.entry(tree).or_default().insert(key, value.as_ref());
+            persisted += 1;
+        }
+        let applied = batches
+            .into_iter()
+            .try_for_each(|(tree, batch)| match tree {
+                Some(name) => db.open_tree(name)?.apply_batch(batch),
+                None => db.apply_batch(batch),
+            });
+        if let Err(e) = applied {
+            // keep them pending, rewriting the batches that did apply is harmless
+            for ckey in ckeys {
+                self.dirty.insert(ckey, ());
+            }
+            return Err(e.into());
+        }
+        for ckey in &ckeys {
+            self.captures.record(ckey);
+        }
+        if persisted > 0 {
+            debug!("Persisted {} dirty values", persisted);
+        }
+        Ok(persisted)
+    }
+}
+
+#[test]
+fn write_back() {
+    use crate::{Storage, StorageConfig, StorageData};
+
+    let dir = crate::TestDir::new("write_back");
+    let config = StorageConfig {
+        db_path: dir.path("db"),
+        write_back: true,
+        ..Default::default()
+    };
+    let store: Storage = Storage::new(&config);
+    let tree = store.db.open_tree(String::name()).unwrap();
+    store.insert("a", "1".to_string());
+    store.insert("b", "2".to_string());
+    assert_eq!(Some("1".to_string()), store.insert("a", "1".to_string()));
+    assert_eq!(Some("1".to_string()), store.get::<String>("a"));
+    assert!(!tree.contains_key("a").unwrap());
+
+    assert_eq!(Some("2".to_string()), store.remove::<String>("b"));
+    store.run_pending_tasks();
+    assert!(tree.contains_key("a").unwrap());
+    assert!(!tree.contains_key("b").unwrap());
+
+    // reads of the db see pending writes first
+    store.insert("c", "3".to_string());
+    store
+        .update::<String, _>("c", |v| v.map(|v| v + "3"))
+        .unwrap();
+    assert_eq!(Some("33".to_string()), store.get::<String>("c"));
+
+    store.insert("d", "4".to_string());
+    drop(store);
+    drop(tree);
+    let db = sled::open(&config.db_path).unwrap();
+    assert!(db
+        .open_tree(String::name())
+        .unwrap()
+        .contains_key("d")
+        .unwrap());
+}
+
+#[test]
+fn write_back_eviction() {
+    use crate::{Storage, StorageConfig, StorageData};
+
+    let store: Storage = Storage::new(&StorageConfig {
+        temporary: true,
+        write_back: true,
+        cache_max_capacity: Some(1024),
+        ..Default::default()
+    });
+    for i in 0..100u64 {
+        store.insert(i, i.to_string());
+    }
+    store.cache.run_pending_tasks();
+    let tree = store.db.open_tree(String::name()).unwrap();
+    assert!(store.cache.entry_count() < 100);
+    for i in 0..100u64 {
+        assert!(
+            store
+                .cache
+                .contains_key(&store.ckey::<String>(crate::key_u64(i).as_bytes()))
+                || tree.contains_key(crate::key_u64(i)).unwrap()
+        );
+    }
+}